@@ -147,3 +147,45 @@ function login_passkey() {
             }
         });
 }
+
+function login_nostr(next) {
+    const flash_message = document.getElementById('flash_message');
+    flash_message.classList.remove('text-green-600');
+    flash_message.classList.add('text-red-600');
+
+    if (!window.nostr) {
+        flash_message.innerHTML = "No Nostr signer extension found.";
+        return;
+    }
+
+    fetch('/login/nostr')
+        .then((response) => response.json())
+        .then((challenge) => window.nostr.signEvent({
+            kind: 27235,
+            created_at: Math.floor(Date.now() / 1000),
+            tags: [
+                ['u', challenge.url],
+                ['method', 'POST'],
+                ['challenge', challenge.challenge]
+            ],
+            content: ''
+        }))
+        .then((event) => fetch('/login/nostr', {
+            method: 'POST',
+            headers: {
+                'Authorization': 'Nostr ' + btoa(JSON.stringify(event))
+            }
+        }))
+        .then((response) => {
+            if (response.headers.has('HX-Location')) {
+                window.location.href = next || response.headers.get('HX-Location');
+            } else {
+                response.text().then((message) => {
+                    flash_message.innerHTML = message;
+                });
+            }
+        })
+        .catch(() => {
+            flash_message.innerHTML = "Nostr login failed.";
+        });
+}
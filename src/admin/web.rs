@@ -0,0 +1,581 @@
+use crate::app::AppState;
+use crate::audit::types::{AuditAction, AuditEvent, AuditFilter};
+use crate::auth::backend::{AuthBackend, AuthSession};
+use crate::auth::types::{InviteCode, LoginFailures, Permission, Player, Role};
+use crate::auth::web::filters;
+use crate::backup::Snapshot;
+use crate::dump::{DumpFormat, ImportError, ImportMode};
+use crate::fsck::FsckMode;
+use crate::types::InternalError;
+use axum::extract::{DefaultBodyLimit, Multipart, Query, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Form, Json, Router};
+use axum_login::{login_required, permission_required};
+use chrono::{TimeDelta, Utc};
+use rinja::Template;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/admin", get(admin_page))
+        .route("/admin/audit", get(audit_page))
+        .route("/admin/audit/export", get(audit_export))
+        .route("/admin/unlock", post(unlock_form))
+        .route("/admin/reset", post(reset_form))
+        .route("/admin/invite", post(invite_form))
+        .route("/admin/invite/revoke", post(revoke_invite_form))
+        .route("/admin/sessions/revoke", post(revoke_sessions_form))
+        .route("/admin/backup", post(backup_form))
+        .route("/admin/export", get(export_data))
+        .route(
+            "/admin/import",
+            post(import_form).layer(DefaultBodyLimit::max(MAX_IMPORT_BYTES)),
+        )
+        .route("/admin/fsck", post(fsck_form))
+        .route("/admin/guess-log", get(guess_log_export))
+        .route_layer(permission_required!(AuthBackend, Permission::AssignAdm))
+        .route_layer(login_required!(AuthBackend, login_url = "/login"))
+}
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+struct AdminTemplate {
+    players: Vec<Player>,
+    login_failures: Vec<(String, LoginFailures)>,
+    roles: Vec<Role>,
+    // invite codes with their role name
+    invite_codes: Vec<(InviteCode, String)>,
+    // `None` if backups are not configured
+    snapshots: Option<Vec<Snapshot>>,
+}
+
+#[axum::debug_handler]
+async fn admin_page(
+    State(app_state): State<Arc<AppState>>,
+    auth_session: AuthSession,
+) -> Result<impl IntoResponse, InternalError> {
+    let mut players = auth_session.backend.get_players().await?;
+    players.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut login_failures = auth_session.backend.get_all_login_failures().await?;
+    // most recent failures first
+    login_failures.sort_by_key(|(_, failures)| std::cmp::Reverse(failures.last_failure));
+    let mut roles = auth_session.backend.get_roles().await?;
+    roles.sort_by(|a, b| a.name.cmp(&b.name));
+    let role_names = roles
+        .iter()
+        .map(|role| (role.uuid, role.name.clone()))
+        .collect::<HashMap<Uuid, String>>();
+    let mut invite_codes = auth_session
+        .backend
+        .get_invite_codes()
+        .await?
+        .into_iter()
+        .map(|invite_code| {
+            let role_name = invite_code
+                .role
+                .and_then(|role| role_names.get(&role).cloned())
+                .unwrap_or_default();
+            (invite_code, role_name)
+        })
+        .collect::<Vec<(InviteCode, String)>>();
+    invite_codes.sort_by_key(|(invite_code, _)| std::cmp::Reverse(invite_code.created));
+    let snapshots = match &app_state.backup {
+        Some(backup) => Some(backup.snapshots().await?),
+        None => None,
+    };
+    Ok(Html(
+        AdminTemplate {
+            players,
+            login_failures,
+            roles,
+            invite_codes,
+            snapshots,
+        }
+        .render()?,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct UnlockForm {
+    key: String,
+}
+
+async fn unlock_form(
+    auth_session: AuthSession,
+    Form(unlock_form): Form<UnlockForm>,
+) -> Result<impl IntoResponse, InternalError> {
+    let admin = auth_session.user.expect("admin must be logged in");
+    auth_session.backend.unlock_login(&unlock_form.key).await?;
+    info!("{} unlocked login for {}", admin.name, unlock_form.key);
+    let event = AuditEvent {
+        subject: Some(unlock_form.key),
+        ..AuditEvent::new(AuditAction::LoginUnlock, Some(&admin))
+    };
+    auth_session.backend.audit_log.record(event).await?;
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Refresh", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct ResetForm {
+    player: Uuid,
+}
+
+#[derive(Template)]
+#[template(path = "reset_link.html")]
+struct ResetLinkTemplate {
+    token: String,
+}
+
+async fn reset_form(
+    auth_session: AuthSession,
+    Form(reset_form): Form<ResetForm>,
+) -> Result<impl IntoResponse, InternalError> {
+    let admin = auth_session.user.expect("admin must be logged in");
+    // the player is looked up in the same transaction that stores the token
+    let Some(token) = auth_session
+        .backend
+        .create_reset_token(&reset_form.player, &admin.uuid)
+        .await?
+    else {
+        return Ok((
+            StatusCode::OK,
+            [("HX-Retarget", "#flash_message")],
+            "The player no longer exists.",
+        )
+            .into_response());
+    };
+    info!(
+        "{} created password reset link for player {}",
+        admin.name, reset_form.player
+    );
+    let player_name = auth_session
+        .backend
+        .get_player_by_uuid(&reset_form.player)
+        .await?
+        .map(|player| player.name)
+        .unwrap_or(reset_form.player.to_string());
+    let event = AuditEvent {
+        subject: Some(player_name),
+        ..AuditEvent::new(AuditAction::ResetLinkCreate, Some(&admin))
+    };
+    auth_session.backend.audit_log.record(event).await?;
+    Ok(Html(ResetLinkTemplate { token }.render()?).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "invite_code.html")]
+struct InviteCodeTemplate {
+    code: String,
+    invite_code: InviteCode,
+}
+
+#[derive(Deserialize)]
+pub struct InviteForm {
+    max_uses: Option<String>,
+    expires_days: Option<String>,
+    role: Option<String>,
+}
+
+async fn invite_form(
+    auth_session: AuthSession,
+    Form(invite_form): Form<InviteForm>,
+) -> Result<impl IntoResponse, InternalError> {
+    let admin = auth_session.user.expect("admin must be logged in");
+    // empty form fields mean no limit or no role, anything else must be valid
+    let max_uses = match optional_field(invite_form.max_uses, |max_uses| {
+        max_uses
+            .parse::<u32>()
+            .ok()
+            .filter(|max_uses| *max_uses > 0)
+    }) {
+        Ok(max_uses) => max_uses,
+        Err(()) => return Ok(invite_flash("Max uses must be a positive number.")),
+    };
+    let expires = match optional_field(invite_form.expires_days, |days| {
+        days.parse::<u32>().ok().filter(|days| *days > 0)
+    }) {
+        Ok(days) => days.map(|days| Utc::now() + TimeDelta::days(days.into())),
+        Err(()) => return Ok(invite_flash("Expires in days must be a positive number.")),
+    };
+    let role = match optional_field(invite_form.role, |role| Uuid::parse_str(role).ok()) {
+        Ok(role) => role,
+        Err(()) => return Ok(invite_flash("The role doesn't exist.")),
+    };
+    let Some((code, invite_code)) = auth_session
+        .backend
+        .create_invite_code(max_uses, expires, role, &admin.uuid)
+        .await?
+    else {
+        return Ok(invite_flash("The role doesn't exist."));
+    };
+    info!(
+        "{} created invite code {}",
+        admin.name,
+        invite_code.short_hash()
+    );
+    let event = AuditEvent {
+        subject: Some(invite_code.short_hash().to_string()),
+        ..AuditEvent::new(AuditAction::InviteCreate, Some(&admin))
+    };
+    auth_session.backend.audit_log.record(event).await?;
+    Ok(Html(InviteCodeTemplate { code, invite_code }.render()?).into_response())
+}
+
+// `None` for a missing or empty field, an error if it's not valid
+fn optional_field<T>(
+    value: Option<String>,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<Option<T>, ()> {
+    match value
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+    {
+        Some(value) => parse(value).map(Some).ok_or(()),
+        None => Ok(None),
+    }
+}
+
+fn invite_flash(message: &'static str) -> Response {
+    (StatusCode::OK, [("HX-Retarget", "#flash_message")], message).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct RevokeInviteForm {
+    code_hash: String,
+}
+
+async fn revoke_invite_form(
+    auth_session: AuthSession,
+    Form(revoke_form): Form<RevokeInviteForm>,
+) -> Result<impl IntoResponse, InternalError> {
+    let admin = auth_session.user.expect("admin must be logged in");
+    if let Some(invite_code) = auth_session
+        .backend
+        .revoke_invite_code(&revoke_form.code_hash)
+        .await?
+    {
+        info!(
+            "{} revoked invite code {}",
+            admin.name,
+            invite_code.short_hash()
+        );
+        let event = AuditEvent {
+            subject: Some(invite_code.short_hash().to_string()),
+            ..AuditEvent::new(AuditAction::InviteRevoke, Some(&admin))
+        };
+        auth_session.backend.audit_log.record(event).await?;
+    }
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Refresh", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSessionsForm {
+    player: Uuid,
+}
+
+async fn revoke_sessions_form(
+    auth_session: AuthSession,
+    Form(revoke_form): Form<RevokeSessionsForm>,
+) -> Result<impl IntoResponse, InternalError> {
+    let admin = auth_session.user.expect("admin must be logged in");
+    let revoked = auth_session
+        .backend
+        .delete_player_sessions(&revoke_form.player, None)
+        .await?;
+    info!(
+        "{} signed out {} sessions of player {}",
+        admin.name, revoked, revoke_form.player
+    );
+    let player_name = auth_session
+        .backend
+        .get_player_by_uuid(&revoke_form.player)
+        .await?
+        .map(|player| player.name)
+        .unwrap_or(revoke_form.player.to_string());
+    let event = AuditEvent {
+        subject: Some(player_name),
+        after: Some(format!("{} sessions signed out", revoked)),
+        ..AuditEvent::new(AuditAction::SessionRevoke, Some(&admin))
+    };
+    auth_session.backend.audit_log.record(event).await?;
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Refresh", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+async fn backup_form(
+    State(app_state): State<Arc<AppState>>,
+    auth_session: AuthSession,
+) -> Result<impl IntoResponse, InternalError> {
+    let admin = auth_session.user.expect("admin must be logged in");
+    let Some(backup) = &app_state.backup else {
+        return Ok((
+            StatusCode::OK,
+            [("HX-Retarget", "#flash_message")],
+            "Backups are not configured.",
+        )
+            .into_response());
+    };
+    let snapshot = backup
+        .admin_snapshot(&auth_session.backend.audit_log, &admin)
+        .await?;
+    info!("{} created backup {}", admin.name, snapshot.name);
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Refresh", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+// uploaded dumps can be larger than the default request body limit
+const MAX_IMPORT_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    format: Option<String>,
+}
+
+async fn export_data(
+    State(app_state): State<Arc<AppState>>,
+    auth_session: AuthSession,
+    Query(query): Query<ExportQuery>,
+) -> Result<impl IntoResponse, InternalError> {
+    let admin = auth_session.user.expect("admin must be logged in");
+    let format = query
+        .format
+        .and_then(|format| DumpFormat::from_str(format.trim()).ok())
+        .unwrap_or(DumpFormat::Json);
+    let bytes = app_state
+        .data_dump
+        .admin_export(format, &auth_session.backend.audit_log, &admin)
+        .await?;
+    info!("{} exported game data as {}", admin.name, format);
+    Ok((
+        [
+            (CONTENT_TYPE, format.content_type().to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"nonce_guess_dump.{}\"", format),
+            ),
+        ],
+        bytes,
+    ))
+}
+
+async fn import_form(
+    State(app_state): State<Arc<AppState>>,
+    auth_session: AuthSession,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ImportError> {
+    let admin = auth_session.user.expect("admin must be logged in");
+    let mut mode = ImportMode::Merge;
+    let mut upload = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ImportError::Decode(e.to_string()))?
+    {
+        match field.name() {
+            Some("mode") => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| ImportError::Decode(e.to_string()))?;
+                mode = ImportMode::from_str(text.trim()).map_err(ImportError::Decode)?;
+            }
+            Some("file") => {
+                let format =
+                    DumpFormat::from_path(Path::new(field.file_name().unwrap_or_default()));
+                let bytes = field
+                    .bytes()
+                    .await
+                    .map_err(|e| ImportError::Decode(e.to_string()))?;
+                upload = Some((format, bytes.to_vec())).filter(|(_, bytes)| !bytes.is_empty());
+            }
+            _ => {}
+        }
+    }
+    let Some((format, bytes)) = upload else {
+        return Ok((
+            StatusCode::OK,
+            [("HX-Retarget", "#flash_message")],
+            "Choose a dump file to import.".to_string(),
+        ));
+    };
+    let summary = app_state
+        .data_dump
+        .admin_import(format, bytes, mode, &admin)
+        .await?;
+    info!("{} imported {} with {} mode", admin.name, summary, mode);
+    Ok((
+        StatusCode::OK,
+        [("HX-Retarget", "#flash_message")],
+        format!("Imported {}.", summary),
+    ))
+}
+
+impl IntoResponse for ImportError {
+    fn into_response(self) -> Response {
+        match self {
+            ImportError::Internal(e) => e.into_response(),
+            e => {
+                info!("import failed: {}", e);
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    format!("Import failed, {}.", e),
+                )
+                    .into_response()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FsckForm {
+    mode: String,
+}
+
+async fn fsck_form(
+    State(app_state): State<Arc<AppState>>,
+    auth_session: AuthSession,
+    Form(form): Form<FsckForm>,
+) -> Result<impl IntoResponse, InternalError> {
+    let admin = auth_session.user.expect("admin must be logged in");
+    let mode = FsckMode::from_str(form.mode.trim()).unwrap_or(FsckMode::Check);
+    let report = app_state.fsck.admin_fsck(mode, &admin).await?;
+    info!("{} ran database {}: {}", admin.name, mode, report);
+    let mut message = format!("Database {}: {}.", mode, report);
+    for problem in &report.problems {
+        message.push_str(&format!(" {}.", problem));
+    }
+    Ok((StatusCode::OK, [("HX-Retarget", "#flash_message")], message))
+}
+
+#[derive(Deserialize)]
+pub struct GuessLogQuery {
+    until: Option<String>,
+}
+
+// the guess event log up to an event with the targets it results in, to replay a dispute
+async fn guess_log_export(
+    State(app_state): State<Arc<AppState>>,
+    Query(query): Query<GuessLogQuery>,
+) -> Result<impl IntoResponse, InternalError> {
+    let until = query
+        .until
+        .and_then(|until| until.trim().parse::<u64>().ok());
+    let replay = app_state.guess_backend.replay_guess_log(until).await?;
+    Ok((
+        [(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"nonce_guess_log.json\""),
+        )],
+        Json(replay),
+    ))
+}
+
+// most recent audit events shown if no limit is given
+const DEFAULT_AUDIT_LIMIT: usize = 200;
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    action: Option<String>,
+    actor: Option<String>,
+    subject: Option<String>,
+    limit: Option<String>,
+}
+
+impl AuditQuery {
+    // empty form fields don't filter
+    fn filter(&self) -> AuditFilter {
+        let non_empty = |field: &Option<String>| {
+            field
+                .as_ref()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        AuditFilter {
+            action: non_empty(&self.action).and_then(|action| AuditAction::from_str(&action).ok()),
+            actor: non_empty(&self.actor),
+            subject: non_empty(&self.subject),
+            limit: Some(
+                non_empty(&self.limit)
+                    .and_then(|limit| limit.parse::<usize>().ok())
+                    .unwrap_or(DEFAULT_AUDIT_LIMIT),
+            ),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "audit.html")]
+struct AuditTemplate {
+    events: Vec<AuditEvent>,
+    actions: Vec<String>,
+    // current filter form values
+    action: String,
+    actor: String,
+    subject: String,
+    limit: String,
+}
+
+async fn audit_page(
+    auth_session: AuthSession,
+    Query(query): Query<AuditQuery>,
+) -> Result<impl IntoResponse, InternalError> {
+    let events = auth_session
+        .backend
+        .audit_log
+        .get_events(query.filter())
+        .await?;
+    Ok(Html(
+        AuditTemplate {
+            events,
+            actions: AuditAction::ALL.map(|action| action.to_string()).to_vec(),
+            action: query.action.unwrap_or_default(),
+            actor: query.actor.unwrap_or_default(),
+            subject: query.subject.unwrap_or_default(),
+            limit: query.limit.unwrap_or_default(),
+        }
+        .render()?,
+    ))
+}
+
+async fn audit_export(
+    auth_session: AuthSession,
+    Query(query): Query<AuditQuery>,
+) -> Result<impl IntoResponse, InternalError> {
+    let events = auth_session
+        .backend
+        .audit_log
+        .get_events(query.filter())
+        .await?;
+    Ok((
+        [(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"nonce_guess_audit.json\""),
+        )],
+        Json(events),
+    ))
+}
@@ -1,66 +1,231 @@
 use crate::auth::backend::AuthBackend;
-use crate::guess::backend::{continuously_update_target_nonce, GuessBackend};
-use crate::session_store::RedbSessionStore;
-use crate::{auth, guess};
-use axum::Router;
+use crate::auth::config::AuthConfig;
+use crate::backup::{continuously_backup, restore, Backup, BackupConfig};
+use crate::dump::{export, import, DataDump, DumpFormat, ImportMode};
+use crate::encryption::{self, reencrypt_stale_records};
+use crate::fsck::{fsck, Fsck, FsckMode};
+use crate::guess::backend::{
+    continuously_update_target_nonce, GuessBackend, UPDATE_TARGET_NONCE_PERIOD,
+};
+use crate::migration::{migrate, APP_MIGRATIONS, APP_SCHEMA};
+use crate::session_store::{StorageSessionStore, SESSION_MIGRATIONS, SESSION_SCHEMA};
+use crate::storage::lease::Lease;
+use crate::storage::postgres::PostgresStorage;
+use crate::storage::redb::RedbStorage;
+use crate::storage::sqlite::SqliteStorage;
+use crate::storage::{Storage, StorageBackend};
+use crate::{admin, auth, guess};
+use axum::{middleware, Router};
 use axum_embed::ServeEmbed;
 use axum_login::{
     tower_sessions::{Expiry, SessionManagerLayer},
     AuthManagerLayerBuilder,
 };
+use chrono::Utc;
 use redb::Database;
 use reqwest::Url;
 use rust_embed::RustEmbed;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tempfile::NamedTempFile;
 use time::Duration;
-use tokio::{signal, task::AbortHandle};
+use tokio::signal;
+use tokio::task::{block_in_place, AbortHandle};
 use tower_cookies::cookie::SameSite;
 use tower_sessions::cookie::Key;
+use tracing::{info, warn};
+use uuid::Uuid;
 
 #[derive(RustEmbed, Clone)]
 #[folder = "assets/"]
 struct Assets;
 
 pub struct App {
-    db: Arc<Database>,
+    storage: Arc<dyn Storage>,
+    // the redb database for backups, `None` with other storage backends
+    db: Option<Arc<Database>>,
     http_client: reqwest::Client,
     mempool_url: Url,
+    auth_config: AuthConfig,
+    backup_config: Option<BackupConfig>,
+    // id of this server replica, the holder of the background task leases
+    replica: Uuid,
 }
 
+// how often expired sessions and stale login failures are deleted
+const DELETE_EXPIRED_PERIOD: tokio::time::Duration = tokio::time::Duration::from_secs(360);
+
 pub struct AppState {
     pub guess_backend: Arc<GuessBackend>,
+    pub backup: Option<Arc<Backup>>,
+    pub data_dump: Arc<DataDump>,
+    pub fsck: Arc<Fsck>,
 }
 
 impl App {
     pub async fn new(
         database_file: Option<PathBuf>,
+        database_url: Option<String>,
+        backend: StorageBackend,
         mempool_url: Option<Url>,
+        auth_config: AuthConfig,
+        backup_config: Option<BackupConfig>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        // setup database file
-        let db = if let Some(file) = database_file {
-            Database::create(file)?
-        } else {
-            // temp file should only be used for testing
-            let file = NamedTempFile::new()?.into_temp_path();
-            Database::create(file)?
+        let (storage, db): (Arc<dyn Storage>, _) = match backend {
+            StorageBackend::Redb => {
+                // setup database file
+                let db = if let Some(file) = database_file {
+                    Database::create(file)?
+                } else {
+                    // temp file should only be used for testing
+                    let file = NamedTempFile::new()?.into_temp_path();
+                    Database::create(file)?
+                };
+                // bring the schema up to date before the backends open their tables
+                migrate(&db, APP_SCHEMA, APP_MIGRATIONS, false)?;
+                let db = Arc::new(db);
+                (Arc::new(RedbStorage::new(db.clone())?), Some(db))
+            }
+            StorageBackend::Sqlite => {
+                let Some(file) = database_file else {
+                    return Err("the sqlite backend needs a NONCE_GUESS_DB_FILE".into());
+                };
+                (Arc::new(SqliteStorage::new(file)?), None)
+            }
+            StorageBackend::Postgres => {
+                let Some(url) = database_url else {
+                    return Err("the postgres backend needs a NONCE_GUESS_DB_URL".into());
+                };
+                // the postgres client blocks on its own runtime
+                let storage = block_in_place(|| PostgresStorage::new(&url))?;
+                (Arc::new(storage), None)
+            }
         };
+        // snapshots copy redb tables, other databases are backed up with their own tools
+        let backup_config = backup_config.filter(|_| {
+            if db.is_none() {
+                warn!("backups are only supported by the redb backend, disabled");
+            }
+            db.is_some()
+        });
         let http_client = reqwest::Client::builder()
             .use_native_tls()
             .danger_accept_invalid_certs(true)
             .build()?;
         let mempool_url = mempool_url.unwrap_or(Url::parse("https://mempool.space")?);
-
-        // TODO: call database migrations here
+        let replica = Uuid::new_v4();
+        info!("replica id: {}", replica);
 
         Ok(Self {
-            db: Arc::new(db),
+            storage,
+            db,
             http_client,
             mempool_url,
+            auth_config,
+            backup_config,
+            replica,
+        })
+    }
+
+    /// Replace the database file with the tables of a backup snapshot, before the database
+    /// is opened.
+    pub fn restore(
+        database_file: Option<PathBuf>,
+        backend: StorageBackend,
+        snapshot_file: PathBuf,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if backend != StorageBackend::Redb {
+            return Err(format!("restoring a snapshot is not supported by {}", backend).into());
+        }
+        let Some(file) = database_file else {
+            return Err("a restore needs a NONCE_GUESS_DB_FILE to restore into".into());
+        };
+        restore(&snapshot_file, &file)?;
+        Ok(())
+    }
+
+    /// Log the schema migrations a start would apply to an existing database file, without
+    /// changing it.
+    pub fn dry_run_migrations(
+        database_file: Option<PathBuf>,
+        backend: StorageBackend,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if backend != StorageBackend::Redb {
+            return Err(format!("a migration dry run is not supported by {}", backend).into());
+        }
+        let Some(file) = database_file else {
+            return Err("a migration dry run needs an existing NONCE_GUESS_DB_FILE".into());
+        };
+        let db = Database::open(file)?;
+        migrate(&db, APP_SCHEMA, APP_MIGRATIONS, true)?;
+        migrate(&db, SESSION_SCHEMA, SESSION_MIGRATIONS, true)?;
+        Ok(())
+    }
+
+    /// Write the game data to a dump file and exit, JSON or CBOR by the file extension. Password
+    /// hashes are only included with `secrets`.
+    pub fn export_data(
+        &self,
+        export_file: PathBuf,
+        secrets: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        block_in_place(|| {
+            self.open_backends()?;
+            let writer = std::io::BufWriter::new(std::fs::File::create(&export_file)?);
+            export(
+                self.storage.as_ref(),
+                DumpFormat::from_path(&export_file),
+                writer,
+                Utc::now(),
+                secrets,
+            )?;
+            Ok(())
         })
     }
 
+    /// Import the game data of a dump file and exit, JSON or CBOR by the file extension.
+    pub fn import_data(
+        &self,
+        import_file: PathBuf,
+        mode: ImportMode,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        block_in_place(|| {
+            self.open_backends()?;
+            let reader = std::io::BufReader::new(std::fs::File::open(&import_file)?);
+            import(
+                self.storage.as_ref(),
+                DumpFormat::from_path(&import_file),
+                reader,
+                mode,
+                None,
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Check the database integrity before serving, fails if problems are left unrepaired.
+    pub fn check_database(&self, mode: FsckMode) -> Result<(), Box<dyn std::error::Error>> {
+        block_in_place(|| {
+            self.open_backends()?;
+            let report = fsck(self.storage.as_ref(), mode, None)?;
+            if mode == FsckMode::Check && !report.problems.is_empty() {
+                return Err(format!(
+                    "database check: {}, start with NONCE_GUESS_FSCK=repair to repair them",
+                    report
+                )
+                .into());
+            }
+            Ok(())
+        })
+    }
+
+    // open the auth backend once so a new database has its admin player, as when serving
+    fn open_backends(&self) -> Result<(), Box<dyn std::error::Error>> {
+        AuthBackend::with_storage(self.storage.clone(), &self.auth_config)?;
+        Ok(())
+    }
+
     pub async fn serve(self) -> Result<(), Box<dyn std::error::Error>> {
         // static assets
         let serve_assets = ServeEmbed::<Assets>::new();
@@ -69,20 +234,41 @@ impl App {
         //
         // This uses `tower-sessions` to establish a layer that will provide the session
         // as a request extension.
-        let session_store = RedbSessionStore::new(self.db.clone());
+        let session_store = StorageSessionStore::with_storage(self.storage.clone());
 
-        // task to delete expired sessions
-        let delete_task = tokio::task::spawn(
-            session_store
-                .clone()
-                .continuously_delete_expired(tokio::time::Duration::from_secs(360)),
+        // Auth backend, also used by the delete task to prune stale login failures.
+        let auth_backend =
+            block_in_place(|| AuthBackend::with_storage(self.storage.clone(), &self.auth_config))?;
+
+        // task to delete expired sessions and stale login failures, on one replica at a time
+        let delete_lease = Lease::new(
+            self.storage.clone(),
+            "delete_expired_sessions",
+            self.replica,
+            DELETE_EXPIRED_PERIOD * 2,
         );
+        let delete_task = tokio::task::spawn(session_store.clone().continuously_delete_expired(
+            DELETE_EXPIRED_PERIOD,
+            delete_lease,
+            auth_backend.clone(),
+        ));
 
-        // Generate a cryptographic key to sign the session cookie.
-        let key = Key::generate();
+        // The key to sign the session cookie, generated if the replicas don't share one.
+        let key = self
+            .auth_config
+            .session_key
+            .clone()
+            .unwrap_or_else(Key::generate);
 
+        // the identity provider redirects back cross-site, so the cookie must be sent with
+        // top-level navigations when single sign-on is enabled
+        let same_site = if self.auth_config.oidc.is_some() {
+            SameSite::Lax
+        } else {
+            SameSite::Strict
+        };
         let session_layer = SessionManagerLayer::new(session_store)
-            .with_same_site(SameSite::Strict)
+            .with_same_site(same_site)
             // TODO: change this to true when running on an HTTPS/production server instead of locally
             .with_secure(false)
             .with_expiry(Expiry::OnInactivity(Duration::hours(1)))
@@ -92,25 +278,57 @@ impl App {
         //
         // This combines the session layer with our backend to establish the auth
         // service which will provide the auth session as a request extension.
-        let auth_backend = AuthBackend::new(self.db.clone())?;
         let auth_layer = AuthManagerLayerBuilder::new(auth_backend, session_layer).build();
 
-        let guess_backend = GuessBackend::new(
-            self.db.clone(),
+        let guess_backend = Arc::new(GuessBackend::with_storage(
+            self.storage.clone(),
             self.http_client.clone(),
             self.mempool_url.clone(),
-        )
-        .map(Arc::new)?;
+        ));
 
-        // task to update block hash when confirmed
-        let update_task =
-            tokio::task::spawn(continuously_update_target_nonce(guess_backend.clone()));
+        // task to update block hash when confirmed, on one replica at a time
+        let update_lease = Lease::new(
+            self.storage.clone(),
+            "update_target_nonce",
+            self.replica,
+            UPDATE_TARGET_NONCE_PERIOD * 2,
+        );
+        let update_task = tokio::task::spawn(continuously_update_target_nonce(
+            guess_backend.clone(),
+            update_lease,
+        ));
+
+        // task to take scheduled backup snapshots
+        let backup = self
+            .db
+            .clone()
+            .zip(self.backup_config.clone())
+            .map(|(db, backup_config)| Arc::new(Backup::new(db, backup_config)));
+        let backup_task = backup
+            .clone()
+            .filter(|backup| backup.config.interval_secs > 0)
+            .map(|backup| tokio::task::spawn(continuously_backup(backup)));
 
-        let app_state = Arc::new(AppState { guess_backend });
+        // task to re-encrypt records after the encryption keys changed
+        let reencrypt_task = encryption::keyring()
+            .map(|_| tokio::task::spawn(reencrypt_stale_records(self.storage.clone())));
+
+        let app_state = Arc::new(AppState {
+            guess_backend,
+            backup,
+            data_dump: Arc::new(DataDump::new(self.storage.clone())),
+            fsck: Arc::new(Fsck::new(self.storage.clone())),
+        });
 
         let router = Router::new()
+            .merge(admin::web::router())
             .merge(auth::web::router())
             .merge(guess::web::router())
+            .layer(middleware::from_fn(auth::web::require_two_factor))
+            .layer(middleware::from_fn(auth::web::require_password_change))
+            .layer(middleware::from_fn(auth::web::track_session))
+            .layer(middleware::from_fn(auth::web::bearer_auth))
+            .layer(middleware::from_fn(auth::web::proxy_auth))
             .layer(auth_layer)
             .with_state(app_state)
             .nest_service("/assets", serve_assets);
@@ -118,15 +336,27 @@ impl App {
         let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
 
         // Ensure we use a shutdown signal to abort the tasks.
-        axum::serve(listener, router.into_make_service())
-            .with_graceful_shutdown(shutdown_signal(vec![
-                update_task.abort_handle(),
-                delete_task.abort_handle(),
-            ]))
-            .await?;
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_signal(
+            [update_task.abort_handle(), delete_task.abort_handle()]
+                .into_iter()
+                .chain(backup_task.as_ref().map(|task| task.abort_handle()))
+                .chain(reencrypt_task.as_ref().map(|task| task.abort_handle()))
+                .collect(),
+        ))
+        .await?;
 
         update_task.await??;
         delete_task.await??;
+        if let Some(backup_task) = backup_task {
+            backup_task.await??;
+        }
+        if let Some(reencrypt_task) = reencrypt_task {
+            reencrypt_task.await??;
+        }
 
         Ok(())
     }
@@ -0,0 +1,124 @@
+use super::db::AuditDb;
+use super::types::{AuditEvent, AuditFilter};
+use crate::storage::redb::RedbStorage;
+use crate::storage::{Storage, UndecodableRecord};
+use crate::types::InternalError;
+use redb::{Database, ReadTransaction, WriteTransaction};
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    storage: Arc<dyn Storage>,
+}
+
+impl AuditLog {
+    pub fn new(db: Arc<Database>) -> Result<Self, InternalError> {
+        Ok(Self::with_storage(Arc::new(RedbStorage::new(db)?)))
+    }
+
+    pub fn with_storage(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    pub async fn record(&self, event: AuditEvent) -> Result<(), InternalError> {
+        let storage = self.storage.clone();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            write_txn.insert_event(&event)?;
+            write_txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Create the audit log table if needed and quarantine undecodable events.
+    pub fn init_tables(write_txn: &mut WriteTransaction) -> Result<(), InternalError> {
+        AuditDb::init(write_txn)
+    }
+
+    /// Append an event in a migration's write transaction.
+    pub fn insert_event(
+        write_txn: &WriteTransaction,
+        event: &AuditEvent,
+    ) -> Result<(), InternalError> {
+        AuditDb::insert_event(write_txn, event)
+    }
+
+    /// The audit events that can't be decoded.
+    pub fn find_undecodable(
+        read_txn: &ReadTransaction,
+    ) -> Result<Vec<UndecodableRecord>, InternalError> {
+        AuditDb::find_undecodable(read_txn)
+    }
+
+    /// Copy the audit log table into another database.
+    pub fn copy_tables(
+        read_txn: &ReadTransaction,
+        write_txn: &WriteTransaction,
+    ) -> Result<(), InternalError> {
+        AuditDb::copy_tables(read_txn, write_txn)
+    }
+
+    /// Events matching the filter, newest first.
+    pub async fn get_events(&self, filter: AuditFilter) -> Result<Vec<AuditEvent>, InternalError> {
+        let storage = self.storage.clone();
+        spawn_blocking(move || storage.begin_read()?.get_events(&filter)).await?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AuditLog;
+    use crate::audit::types::{AuditAction, AuditEvent, AuditFilter};
+    use redb::Database;
+    use std::sync::Arc;
+    use tempfile::NamedTempFile;
+
+    fn temp_log() -> AuditLog {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        let db = Arc::new(Database::create(file).unwrap());
+        AuditLog::new(db).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get_events() {
+        let audit_log = temp_log();
+        for height in 1..=3 {
+            let event = AuditEvent {
+                subject: Some(format!("height {}", height)),
+                ..AuditEvent::new(AuditAction::TargetCreate, None)
+            };
+            audit_log.record(event).await.unwrap();
+        }
+        audit_log
+            .record(AuditEvent::new(AuditAction::LoginFailed, None))
+            .await
+            .unwrap();
+
+        // newest first
+        let events = audit_log.get_events(AuditFilter::default()).await.unwrap();
+        let actions = events.iter().map(|event| event.action).collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            vec![
+                AuditAction::LoginFailed,
+                AuditAction::TargetCreate,
+                AuditAction::TargetCreate,
+                AuditAction::TargetCreate
+            ]
+        );
+
+        let filter = AuditFilter {
+            action: Some(AuditAction::TargetCreate),
+            limit: Some(2),
+            ..AuditFilter::default()
+        };
+        let events = audit_log.get_events(filter).await.unwrap();
+        let subjects = events
+            .iter()
+            .map(|event| event.subject.clone().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(subjects, vec!["height 3", "height 2"]);
+    }
+}
@@ -0,0 +1,77 @@
+use super::store::{AuditRead, AuditWrite};
+use super::types::{AuditEvent, AuditFilter};
+use crate::backup::copy_table;
+use crate::encoding::{find_undecodable, quarantine_table, Encoded, Versioned};
+use crate::storage::UndecodableRecord;
+use crate::types::{InternalError, UuidKey};
+use redb::{ReadTransaction, ReadableTable, TableDefinition, WriteTransaction};
+use tracing::info;
+
+const UUID_EVENT: TableDefinition<UuidKey, Encoded<AuditEvent>> =
+    TableDefinition::new("audit_uuid_event");
+
+pub struct AuditDb;
+
+impl AuditDb {
+    pub fn init(write_txn: &mut WriteTransaction) -> Result<(), InternalError> {
+        // open tables to make sure they exist
+        write_txn.open_table(UUID_EVENT)?;
+        // move events that can't be decoded out of the way before they are read
+        quarantine_table(write_txn, UUID_EVENT)?;
+        info!("opened tables: {}", UUID_EVENT);
+        Ok(())
+    }
+
+    /// Append an event, also used by migrations which only have a shared write transaction.
+    pub fn insert_event(
+        write_txn: &WriteTransaction,
+        event: &AuditEvent,
+    ) -> Result<(), InternalError> {
+        let mut uuid_event = write_txn.open_table(UUID_EVENT)?;
+        uuid_event.insert(&UuidKey(event.uuid), &Encoded::new(event))?;
+        Ok(())
+    }
+
+    /// The audit events that can't be decoded.
+    pub fn find_undecodable(
+        read_txn: &ReadTransaction,
+    ) -> Result<Vec<UndecodableRecord>, InternalError> {
+        find_undecodable(read_txn, UUID_EVENT)
+    }
+
+    /// Copy the audit log table into another database.
+    pub fn copy_tables(
+        read_txn: &ReadTransaction,
+        write_txn: &WriteTransaction,
+    ) -> Result<(), InternalError> {
+        copy_table(read_txn, write_txn, UUID_EVENT)
+    }
+}
+
+impl AuditRead for ReadTransaction {
+    fn get_events(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, InternalError> {
+        let uuid_event = self.open_table(UUID_EVENT)?;
+        let mut events = Vec::new();
+        for entry in uuid_event.iter()?.rev() {
+            let (_, event) = entry?;
+            let event = event.value().decode()?;
+            if filter.matches(&event) {
+                events.push(event);
+                if filter.limit.is_some_and(|limit| events.len() >= limit) {
+                    break;
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl AuditWrite for WriteTransaction {
+    fn insert_event(&mut self, event: &AuditEvent) -> Result<(), InternalError> {
+        AuditDb::insert_event(self, event)
+    }
+}
+
+impl Versioned for AuditEvent {
+    const TYPE_NAME: &'static str = "nonce_guess::AuditEvent";
+}
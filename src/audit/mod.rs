@@ -0,0 +1,4 @@
+pub mod backend;
+mod db;
+pub mod store;
+pub mod types;
@@ -0,0 +1,20 @@
+use crate::audit::types::{AuditEvent, AuditFilter};
+use crate::types::InternalError;
+
+/// Audit events of a read transaction.
+pub trait AuditRead {
+    /// Events matching the filter, newest first.
+    fn get_events(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, InternalError>;
+}
+
+/// Audit events of a write transaction.
+pub trait AuditWrite {
+    /// Append an event, the audit log is never changed or removed from.
+    fn insert_event(&mut self, event: &AuditEvent) -> Result<(), InternalError>;
+
+    /// Record events as part of another write transaction, so they are only kept if the
+    /// audited change is committed.
+    fn record_events(&mut self, events: &[AuditEvent]) -> Result<(), InternalError> {
+        events.iter().try_for_each(|event| self.insert_event(event))
+    }
+}
@@ -0,0 +1,317 @@
+use crate::auth::types::{datetime_now, NameCollision, Player, Role};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Administrative and security relevant actions recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    TargetCreate,
+    TargetReplace,
+    RoleChange,
+    PermissionChange,
+    Login,
+    LoginFailed,
+    PasswordChange,
+    PasswordReset,
+    ResetLinkCreate,
+    TwoFactorEnable,
+    TwoFactorDisable,
+    AccountDelete,
+    RecordQuarantine,
+    BackupCreate,
+    DatabaseRestore,
+    DataExport,
+    DataImport,
+    DatabaseRepair,
+    NameChange,
+    LoginUnlock,
+    InviteCreate,
+    InviteRevoke,
+    SessionRevoke,
+}
+
+impl AuditAction {
+    pub const ALL: [AuditAction; 23] = [
+        AuditAction::TargetCreate,
+        AuditAction::TargetReplace,
+        AuditAction::RoleChange,
+        AuditAction::PermissionChange,
+        AuditAction::Login,
+        AuditAction::LoginFailed,
+        AuditAction::PasswordChange,
+        AuditAction::PasswordReset,
+        AuditAction::ResetLinkCreate,
+        AuditAction::TwoFactorEnable,
+        AuditAction::TwoFactorDisable,
+        AuditAction::AccountDelete,
+        AuditAction::RecordQuarantine,
+        AuditAction::BackupCreate,
+        AuditAction::DatabaseRestore,
+        AuditAction::DataExport,
+        AuditAction::DataImport,
+        AuditAction::DatabaseRepair,
+        AuditAction::NameChange,
+        AuditAction::LoginUnlock,
+        AuditAction::InviteCreate,
+        AuditAction::InviteRevoke,
+        AuditAction::SessionRevoke,
+    ];
+}
+
+impl Display for AuditAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let action = match self {
+            AuditAction::TargetCreate => "target_create",
+            AuditAction::TargetReplace => "target_replace",
+            AuditAction::RoleChange => "role_change",
+            AuditAction::PermissionChange => "permission_change",
+            AuditAction::Login => "login",
+            AuditAction::LoginFailed => "login_failed",
+            AuditAction::PasswordChange => "password_change",
+            AuditAction::PasswordReset => "password_reset",
+            AuditAction::ResetLinkCreate => "reset_link_create",
+            AuditAction::TwoFactorEnable => "two_factor_enable",
+            AuditAction::TwoFactorDisable => "two_factor_disable",
+            AuditAction::AccountDelete => "account_delete",
+            AuditAction::RecordQuarantine => "record_quarantine",
+            AuditAction::BackupCreate => "backup_create",
+            AuditAction::DatabaseRestore => "database_restore",
+            AuditAction::DataExport => "data_export",
+            AuditAction::DataImport => "data_import",
+            AuditAction::DatabaseRepair => "database_repair",
+            AuditAction::NameChange => "name_change",
+            AuditAction::LoginUnlock => "login_unlock",
+            AuditAction::InviteCreate => "invite_create",
+            AuditAction::InviteRevoke => "invite_revoke",
+            AuditAction::SessionRevoke => "session_revoke",
+        };
+        write!(f, "{}", action)
+    }
+}
+
+impl FromStr for AuditAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AuditAction::ALL
+            .into_iter()
+            .find(|action| action.to_string() == s)
+            .ok_or(format!("unknown audit action: {}", s))
+    }
+}
+
+/// An append-only audit log entry. The uuid is a v7 uuid so entries are stored in the order
+/// they were recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub uuid: Uuid,
+    pub timestamp: DateTime<Utc>,
+    pub actor: Option<Uuid>,
+    pub actor_name: Option<String>,
+    pub action: AuditAction,
+    pub subject: Option<String>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+impl AuditEvent {
+    /// New event for an action done by `actor`, or by an anonymous client if there is none.
+    pub fn new(action: AuditAction, actor: Option<&Player>) -> Self {
+        AuditEvent {
+            uuid: Uuid::now_v7(),
+            timestamp: datetime_now(),
+            actor: actor.map(|player| player.uuid),
+            actor_name: actor.map(|player| player.name.clone()),
+            action,
+            subject: None,
+            before: None,
+            after: None,
+            ip: None,
+        }
+    }
+}
+
+/// Role and permission change events for a player, empty if neither changed.
+pub fn player_change_events(
+    actor: Option<&Player>,
+    orig_player: &Player,
+    new_player: &Player,
+    roles: &[Role],
+) -> Vec<AuditEvent> {
+    let mut events = Vec::new();
+    if orig_player.roles != new_player.roles {
+        events.push(AuditEvent {
+            subject: Some(new_player.name.clone()),
+            before: Some(role_names(orig_player, roles)),
+            after: Some(role_names(new_player, roles)),
+            ..AuditEvent::new(AuditAction::RoleChange, actor)
+        });
+    }
+    if orig_player.permissions != new_player.permissions {
+        events.push(AuditEvent {
+            subject: Some(new_player.name.clone()),
+            before: Some(permission_names(orig_player)),
+            after: Some(permission_names(new_player)),
+            ..AuditEvent::new(AuditAction::PermissionChange, actor)
+        });
+    }
+    events
+}
+
+/// The event for a player renamed by the migration because its name collided with the name of
+/// an older player.
+pub fn name_collision_event(collision: &NameCollision) -> AuditEvent {
+    AuditEvent {
+        subject: Some(collision.player.name.clone()),
+        before: Some(collision.orig_name.clone()),
+        after: Some(format!(
+            "{}, the name is kept by {}",
+            collision.player.name, collision.kept_by
+        )),
+        ..AuditEvent::new(AuditAction::NameChange, None)
+    }
+}
+
+// sorted role names, or the uuid of roles that no longer exist
+fn role_names(player: &Player, roles: &[Role]) -> String {
+    let mut names = player
+        .roles
+        .iter()
+        .map(|uuid| {
+            roles
+                .iter()
+                .find(|role| role.uuid == *uuid)
+                .map(|role| role.name.clone())
+                .unwrap_or(uuid.to_string())
+        })
+        .collect::<Vec<String>>();
+    names.sort();
+    names.join(", ")
+}
+
+fn permission_names(player: &Player) -> String {
+    let mut permissions = player.permissions.iter().collect::<Vec<_>>();
+    permissions.sort();
+    permissions
+        .into_iter()
+        .map(|permission| format!("{:?}", permission))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Which audit events to return, newest first.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub action: Option<AuditAction>,
+    // matches the actor's name at the time of the event
+    pub actor: Option<String>,
+    // matches any part of the subject
+    pub subject: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.action.is_none_or(|action| action == event.action)
+            && self
+                .actor
+                .as_ref()
+                .is_none_or(|actor| event.actor_name.as_ref() == Some(actor))
+            && self.subject.as_ref().is_none_or(|subject| {
+                event
+                    .subject
+                    .as_ref()
+                    .is_some_and(|event_subject| event_subject.contains(subject.as_str()))
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{player_change_events, AuditAction, AuditEvent, AuditFilter};
+    use crate::auth::types::{Permission, Player, Role};
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_audit_action_from_str() {
+        for action in AuditAction::ALL {
+            assert_eq!(AuditAction::from_str(&action.to_string()), Ok(action));
+        }
+        assert!(AuditAction::from_str("unknown").is_err());
+    }
+
+    #[test]
+    fn test_audit_filter_matches() {
+        let admin = Player {
+            name: "admin".to_string(),
+            ..Player::default()
+        };
+        let event = AuditEvent {
+            subject: Some("height 100".to_string()),
+            ..AuditEvent::new(AuditAction::TargetCreate, Some(&admin))
+        };
+        assert!(AuditFilter::default().matches(&event));
+        let by_action = AuditFilter {
+            action: Some(AuditAction::TargetCreate),
+            ..AuditFilter::default()
+        };
+        assert!(by_action.matches(&event));
+        let other_action = AuditFilter {
+            action: Some(AuditAction::Login),
+            ..AuditFilter::default()
+        };
+        assert!(!other_action.matches(&event));
+        let by_actor = AuditFilter {
+            actor: Some("admin".to_string()),
+            ..AuditFilter::default()
+        };
+        assert!(by_actor.matches(&event));
+        let other_actor = AuditFilter {
+            actor: Some("adm".to_string()),
+            ..AuditFilter::default()
+        };
+        assert!(!other_actor.matches(&event));
+        let by_subject = AuditFilter {
+            subject: Some("100".to_string()),
+            ..AuditFilter::default()
+        };
+        assert!(by_subject.matches(&event));
+        let anonymous = AuditEvent::new(AuditAction::LoginFailed, None);
+        assert!(!by_actor.matches(&anonymous));
+        assert!(!by_subject.matches(&anonymous));
+    }
+
+    #[test]
+    fn test_player_change_events() {
+        let role = Role {
+            uuid: uuid::Uuid::new_v4(),
+            name: "mod".to_string(),
+            permissions: HashSet::from([Permission::ChangeTarget]),
+        };
+        let orig_player = Player {
+            name: "alice".to_string(),
+            ..Player::default()
+        };
+        assert!(player_change_events(None, &orig_player, &orig_player, &[]).is_empty());
+        let new_player = Player {
+            roles: HashSet::from([role.uuid]),
+            permissions: HashSet::from([Permission::AssignMod, Permission::AssignAdm]),
+            ..orig_player.clone()
+        };
+        let events = player_change_events(None, &orig_player, &new_player, &[role]);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].action, AuditAction::RoleChange);
+        assert_eq!(events[0].subject.as_deref(), Some("alice"));
+        assert_eq!(events[0].before.as_deref(), Some(""));
+        assert_eq!(events[0].after.as_deref(), Some("mod"));
+        assert_eq!(events[1].action, AuditAction::PermissionChange);
+        assert_eq!(events[1].after.as_deref(), Some("AssignAdm, AssignMod"));
+    }
+}
@@ -1,82 +1,333 @@
+use super::config::{AdminConfig, AuthConfig, LoginThrottleConfig};
 use super::db::AuthDb;
-use super::types::{datetime_now, Permission, Player, Role};
-use crate::types::{InternalError, UuidKey};
+use super::ldap::LdapClient;
+use super::lnurl::{new_k1, LnurlChallenge};
+use super::nostr::npub;
+use super::oidc::OidcClient;
+use super::store::AuthWrite;
+use super::totp::{normalize_recovery_code, verify_code, Totp, TotpEnrollment};
+use super::types::{
+    datetime_now, generate_token, hash_token, player_name_candidates, sync_group_roles,
+    ActiveSession, ApiToken, Credentials, IdentityKey, InviteCode, LoginFailures, OidcIdentity,
+    Permission, Player, ResetToken, Role, SessionInfo, ThrottleKey, TokenScope, SESSION_INFO_KEY,
+};
+use crate::audit::backend::AuditLog;
+use crate::audit::types::{player_change_events, AuditEvent};
+use crate::encoding::ReencryptProgress;
+use crate::encryption::Keyring;
+use crate::session_store::StorageSessionStore;
+use crate::storage::redb::RedbStorage;
+use crate::storage::{Storage, UndecodableRecord, WriteTxn};
+use crate::types::InternalError;
 use async_trait::async_trait;
 use axum_login::{AuthUser, AuthnBackend, AuthzBackend, UserId};
-use password_auth::verify_password;
-use redb::Database;
-use std::collections::HashSet;
+use chrono::{DateTime, TimeDelta, Utc};
+use password_auth::{generate_hash, verify_password};
+use redb::{Database, ReadTransaction, WriteTransaction};
+use std::collections::{HashMap, HashSet};
 use std::hash::RandomState;
 use std::sync::Arc;
 use tokio::task::spawn_blocking;
+use tower_sessions::session::Id;
+use tower_sessions::SessionStore;
+use tracing::{info, warn};
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct AuthBackend {
-    pub auth_db: AuthDb,
+    storage: Arc<dyn Storage>,
+    pub config: AuthConfig,
+    pub oidc: Option<OidcClient>,
+    pub ldap: Option<LdapClient>,
+    pub session_store: StorageSessionStore,
+    pub audit_log: AuditLog,
 }
 
+/// Personal api tokens start with this, so they are easy to recognize if leaked.
+pub const API_TOKEN_PREFIX: &str = "ngt_";
+
 impl AuthBackend {
-    pub fn new(database: Arc<Database>) -> Result<Self, InternalError> {
-        let auth_db = AuthDb::new(database)?;
-        Ok(Self { auth_db })
+    pub fn new(database: Arc<Database>, config: &AuthConfig) -> Result<Self, InternalError> {
+        Self::with_storage(Arc::new(RedbStorage::new(database)?), config)
+    }
+
+    /// Create the backend on a [`Storage`], inserting an admin role and admin player if
+    /// there are no players and roles yet.
+    pub fn with_storage(
+        storage: Arc<dyn Storage>,
+        config: &AuthConfig,
+    ) -> Result<Self, InternalError> {
+        let mut write_txn = storage.begin_write()?;
+        Self::insert_admin(&mut *write_txn, &config.admin)?;
+        write_txn.commit()?;
+        let session_store = StorageSessionStore::with_storage(storage.clone());
+        let audit_log = AuditLog::with_storage(storage.clone());
+        let oidc = config
+            .oidc
+            .clone()
+            .map(|oidc_config| OidcClient::new(oidc_config, &config.public_url));
+        let ldap = config.ldap.clone().map(LdapClient::new);
+        Ok(Self {
+            storage,
+            config: config.clone(),
+            oidc,
+            ldap,
+            session_store,
+            audit_log,
+        })
+    }
+
+    // if there are no players and roles, insert an admin role and an admin player
+    fn insert_admin(
+        write_txn: &mut dyn WriteTxn,
+        admin_config: &AdminConfig,
+    ) -> Result<(), InternalError> {
+        if !write_txn.is_empty_for_update()? {
+            return Ok(());
+        }
+        let role_uuid = Uuid::new_v4();
+        let admin_role = Role {
+            uuid: role_uuid,
+            name: "admin".to_string(),
+            permissions: [Permission::AssignAdm, Permission::ChangeTarget].into(),
+        };
+        // without a configured password generate a one-time setup password
+        let password = match &admin_config.password {
+            Some(password) => password.clone(),
+            None => {
+                let setup_password = Uuid::new_v4().simple().to_string();
+                warn!(
+                    "generated one-time setup password for admin user {}: {}",
+                    admin_config.name, setup_password
+                );
+                setup_password
+            }
+        };
+        let admin = Player {
+            uuid: Uuid::new_v4(),
+            name: admin_config.name.clone(),
+            password_hash: generate_hash(password),
+            permissions: Default::default(),
+            roles: [role_uuid].into(),
+            must_change_password: true,
+            ..Default::default()
+        };
+        write_txn.insert_role(admin_role)?;
+        write_txn.insert_player(admin)?;
+        info!("inserted admin_role and admin user {}", admin_config.name);
+        Ok(())
+    }
+
+    /// Create the auth tables if needed and quarantine undecodable records.
+    pub fn init_tables(write_txn: &mut WriteTransaction) -> Result<(), InternalError> {
+        AuthDb::init(write_txn)
+    }
+
+    /// Rebuild the player name index by name key, renaming players whose names collide.
+    pub fn index_player_names(write_txn: &WriteTransaction) -> Result<(), InternalError> {
+        AuthDb::index_player_names(write_txn)
+    }
+
+    pub fn hash_invite_codes(write_txn: &WriteTransaction) -> Result<(), InternalError> {
+        AuthDb::hash_invite_codes(write_txn)
+    }
+
+    /// The auth records that can't be decoded.
+    pub fn find_undecodable(
+        read_txn: &ReadTransaction,
+    ) -> Result<Vec<UndecodableRecord>, InternalError> {
+        AuthDb::find_undecodable(read_txn)
+    }
+
+    /// Re-encrypt up to `limit` auth records that aren't sealed with the keyring's primary key.
+    pub fn reencrypt(
+        write_txn: &WriteTransaction,
+        keyring: Option<&Keyring>,
+        progress: &mut ReencryptProgress,
+        limit: usize,
+    ) -> Result<usize, InternalError> {
+        AuthDb::reencrypt(write_txn, keyring, progress, limit)
+    }
+
+    /// Copy the auth tables into another database.
+    pub fn copy_tables(
+        read_txn: &ReadTransaction,
+        write_txn: &WriteTransaction,
+    ) -> Result<(), InternalError> {
+        AuthDb::copy_tables(read_txn, write_txn)
+    }
+
+    /// Insert or replace the roles and players of a validated data import. With `replace`
+    /// all existing players and roles are removed first.
+    pub fn import_players(
+        write_txn: &mut dyn AuthWrite,
+        roles: &[Role],
+        players: &[Player],
+        replace: bool,
+    ) -> Result<(), InternalError> {
+        if replace {
+            let removed_players = write_txn.remove_all_players()?;
+            let removed_roles = write_txn.remove_all_roles()?;
+            warn!(
+                "import removed {} players and {} roles",
+                removed_players, removed_roles
+            );
+        }
+        for role in roles {
+            write_txn.insert_role(role.clone())?;
+        }
+        write_txn.upsert_players(players)
     }
 
     pub async fn insert_player(&self, player: &Player) -> Result<Option<Player>, InternalError> {
-        let auth_db = self.auth_db.clone();
+        let storage = self.storage.clone();
         let player = player.clone();
         spawn_blocking(move || {
-            let mut write_txn = auth_db.begin_write()?;
-            let insert_player_result = AuthDb::insert_player(&mut write_txn, player);
+            let mut write_txn = storage.begin_write()?;
+            let insert_player_result = write_txn.insert_player(player);
             write_txn.commit()?;
             insert_player_result
         })
         .await?
     }
 
+    /// Insert a newly registered player, using up an invite code if one is given. Returns
+    /// false without inserting the player if the invite code is not valid.
+    pub async fn register_player(
+        &self,
+        player: &Player,
+        invite_code: Option<String>,
+    ) -> Result<bool, InternalError> {
+        let storage = self.storage.clone();
+        let mut player = player.clone();
+        spawn_blocking(move || {
+            let roles = storage.begin_read()?.get_roles()?;
+            let mut write_txn = storage.begin_write()?;
+            if let Some(code) = invite_code {
+                match write_txn.use_invite_code(&hash_token(&code), datetime_now())? {
+                    Some(invite_code) => {
+                        let orig_player = player.clone();
+                        player.roles.extend(invite_code.role);
+                        // roles granted by an invite code are attributed to its creator
+                        let events = player_change_events(None, &orig_player, &player, &roles)
+                            .into_iter()
+                            .map(|event| AuditEvent {
+                                actor: Some(invite_code.created_by),
+                                ..event
+                            })
+                            .collect::<Vec<AuditEvent>>();
+                        write_txn.record_events(&events)?;
+                    }
+                    None => return Ok(false),
+                }
+            }
+            write_txn.insert_player(player)?;
+            write_txn.commit()?;
+            Ok(true)
+        })
+        .await?
+    }
+
     pub async fn change_player(
         &self,
         orig_player: &Player,
         new_player: &Player,
     ) -> Result<Option<Player>, InternalError> {
-        let auth_db = self.auth_db.clone();
+        let storage = self.storage.clone();
         let orig_player = orig_player.clone();
         let new_player = new_player.clone();
         spawn_blocking(move || {
-            let mut write_txn = auth_db.begin_write()?;
-            let change_player_result =
-                AuthDb::change_player(&mut write_txn, orig_player, new_player);
+            let mut write_txn = storage.begin_write()?;
+            let change_player_result = write_txn.change_player(orig_player, new_player);
             write_txn.commit()?;
             change_player_result
         })
         .await?
     }
 
+    /// Delete a player and sign them out of all sessions.
+    pub async fn delete_player(&self, uuid: &Uuid) -> Result<Option<Player>, InternalError> {
+        let storage = self.storage.clone();
+        let uuid = *uuid;
+        let player = spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let remove_player_result = write_txn.remove_player(uuid);
+            write_txn.commit()?;
+            remove_player_result
+        })
+        .await??;
+        self.delete_player_sessions(&uuid, None).await?;
+        Ok(player)
+    }
+
+    /// Delete a player's own account and remove or anonymize their guesses, in one
+    /// transaction. Returns the number of guesses, `None` without deleting anything if they
+    /// are the last player who can assign admins.
+    pub async fn delete_account(
+        &self,
+        uuid: &Uuid,
+        anonymize_guesses: bool,
+    ) -> Result<Option<usize>, InternalError> {
+        let storage = self.storage.clone();
+        let uuid = *uuid;
+        let guesses = spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            // keep at least one player who can assign admins, checked in the transaction so
+            // two admins can't delete their accounts at the same time
+            let Some(player) = write_txn.get_player_for_update(uuid)? else {
+                return Ok(Some(0));
+            };
+            if write_txn.has_permission_for_update(&player, &Permission::AssignAdm)? {
+                let mut other_admin = false;
+                for other in write_txn.get_players_for_update()? {
+                    if other.uuid != uuid
+                        && write_txn.has_permission_for_update(&other, &Permission::AssignAdm)?
+                    {
+                        other_admin = true;
+                        break;
+                    }
+                }
+                if !other_admin {
+                    return Ok(None);
+                }
+            }
+            write_txn.remove_player(uuid)?;
+            let guesses = write_txn.remove_player_guesses(uuid, anonymize_guesses)?;
+            write_txn.commit()?;
+            Ok::<Option<usize>, InternalError>(Some(guesses))
+        })
+        .await??;
+        if guesses.is_some() {
+            self.delete_player_sessions(&uuid, None).await?;
+        }
+        Ok(guesses)
+    }
+
     pub async fn get_player_by_uuid(&self, uuid: &Uuid) -> Result<Option<Player>, InternalError> {
-        let auth_db = self.auth_db.clone();
-        let uuid_key = UuidKey(*uuid);
+        let storage = self.storage.clone();
+        let uuid = *uuid;
         spawn_blocking(move || {
-            let read_txn = auth_db.begin_read()?;
-            AuthDb::get_player_by_uuid(&read_txn, uuid_key)
+            let read_txn = storage.begin_read()?;
+            read_txn.get_player_by_uuid(uuid)
         })
         .await?
     }
 
     pub async fn get_player_by_name(&self, name: &str) -> Result<Option<Player>, InternalError> {
-        let auth_db = self.auth_db.clone();
+        let storage = self.storage.clone();
         let name = name.to_owned();
         spawn_blocking(move || {
-            let read_txn = auth_db.begin_read()?;
-            AuthDb::get_player_by_name(&read_txn, &name)
+            let read_txn = storage.begin_read()?;
+            read_txn.get_player_by_name(&name)
         })
         .await?
     }
 
     pub async fn get_players(&self) -> Result<Vec<Player>, InternalError> {
-        let auth_db = self.auth_db.clone();
+        let storage = self.storage.clone();
         spawn_blocking(move || {
-            let read_txn = auth_db.begin_read()?;
-            AuthDb::get_players(&read_txn)
+            let read_txn = storage.begin_read()?;
+            read_txn.get_players()
         })
         .await?
     }
@@ -92,11 +343,11 @@ impl AuthBackend {
     }
 
     pub async fn insert_role(&self, role: &Role) -> Result<Option<Role>, InternalError> {
-        let auth_db = self.auth_db.clone();
+        let storage = self.storage.clone();
         let role = role.clone();
         spawn_blocking(move || {
-            let mut write_txn = auth_db.begin_write()?;
-            let insert_role_result = AuthDb::insert_role(&mut write_txn, role);
+            let mut write_txn = storage.begin_write()?;
+            let insert_role_result = write_txn.insert_role(role);
             write_txn.commit()?;
             insert_role_result
         })
@@ -104,20 +355,20 @@ impl AuthBackend {
     }
 
     pub async fn get_role_by_uuid(&self, uuid: &Uuid) -> Result<Option<Role>, InternalError> {
-        let auth_db = self.auth_db.clone();
-        let uuid_key = UuidKey(*uuid);
+        let storage = self.storage.clone();
+        let uuid = *uuid;
         spawn_blocking(move || {
-            let read_txn = auth_db.begin_read()?;
-            AuthDb::get_role_by_uuid(&read_txn, uuid_key)
+            let read_txn = storage.begin_read()?;
+            read_txn.get_role_by_uuid(uuid)
         })
         .await?
     }
 
     pub async fn get_roles(&self) -> Result<Vec<Role>, InternalError> {
-        let auth_db = self.auth_db.clone();
+        let storage = self.storage.clone();
         spawn_blocking(move || {
-            let read_txn = auth_db.begin_read()?;
-            AuthDb::get_roles(&read_txn)
+            let read_txn = storage.begin_read()?;
+            read_txn.get_roles()
         })
         .await?
     }
@@ -126,14 +377,14 @@ impl AuthBackend {
         &self,
         roles: &HashSet<Uuid>,
     ) -> Result<HashSet<Permission, RandomState>, InternalError> {
-        let auth_db = self.auth_db.clone();
+        let storage = self.storage.clone();
         let roles = roles.clone();
         spawn_blocking(move || {
-            let read_txn = auth_db.begin_read()?;
+            let read_txn = storage.begin_read()?;
             let role_opts: Vec<Option<Role>> = roles
                 .iter()
-                .map(|uuid| UuidKey(*uuid))
-                .map(|uuid_key| AuthDb::get_role_by_uuid(&read_txn, uuid_key))
+                .copied()
+                .map(|uuid| read_txn.get_role_by_uuid(uuid))
                 .collect::<Result<Vec<Option<Role>>, InternalError>>()?;
 
             let permissions = role_opts
@@ -145,24 +396,261 @@ impl AuthBackend {
         })
         .await?
     }
-}
 
-// We use a type alias for convenience.
-//
-// Note that we've supplied our concrete backend here.
-pub type AuthSession = axum_login::AuthSession<AuthBackend>;
+    /// The latest time any of the keys may attempt to log in again, if throttled.
+    pub async fn login_retry_at(
+        &self,
+        keys: Vec<ThrottleKey>,
+    ) -> Result<Option<DateTime<Utc>>, InternalError> {
+        let storage = self.storage.clone();
+        let config = self.config.login_throttle.clone();
+        spawn_blocking(move || {
+            let read_txn = storage.begin_read()?;
+            let now = datetime_now();
+            let retry_ats = keys
+                .iter()
+                .map(|key| read_txn.get_login_failures(key))
+                .collect::<Result<Vec<Option<LoginFailures>>, InternalError>>()?;
+            Ok(retry_ats
+                .into_iter()
+                .flatten()
+                .filter_map(|failures| failures.retry_at(now, &config))
+                .max())
+        })
+        .await?
+    }
 
-#[async_trait]
-impl AuthnBackend for AuthBackend {
-    type User = Player;
-    type Credentials = (String, String);
-    type Error = InternalError;
+    pub async fn record_login_failure(&self, keys: Vec<ThrottleKey>) -> Result<(), InternalError> {
+        let storage = self.storage.clone();
+        let config = self.config.login_throttle.clone();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let now = datetime_now();
+            for key in keys {
+                let key_config = if key.locks_out() {
+                    config.clone()
+                } else {
+                    LoginThrottleConfig {
+                        lockout_threshold: u32::MAX,
+                        ..config.clone()
+                    }
+                };
+                let login_failures = write_txn.add_login_failure(&key, now, &key_config)?;
+                if login_failures.failures == key_config.lockout_threshold {
+                    warn!(
+                        "locked out {} after {} failures",
+                        key, login_failures.failures
+                    );
+                }
+            }
+            write_txn.commit()
+        })
+        .await?
+    }
 
-    async fn authenticate(
+    pub async fn clear_login_failures(&self, keys: Vec<ThrottleKey>) -> Result<(), InternalError> {
+        let storage = self.storage.clone();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            for key in keys {
+                write_txn.remove_login_failures(&key.to_string())?;
+            }
+            write_txn.commit()
+        })
+        .await?
+    }
+
+    pub async fn get_all_login_failures(
         &self,
-        creds: Self::Credentials,
-    ) -> Result<Option<Self::User>, Self::Error> {
-        let (name, password) = creds;
+    ) -> Result<Vec<(String, LoginFailures)>, InternalError> {
+        let storage = self.storage.clone();
+        spawn_blocking(move || {
+            let read_txn = storage.begin_read()?;
+            read_txn.get_all_login_failures()
+        })
+        .await?
+    }
+
+    /// Remove the failures that are stale, returns the number of removed keys.
+    pub async fn prune_login_failures(&self) -> Result<usize, InternalError> {
+        let storage = self.storage.clone();
+        let config = self.config.login_throttle.clone();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let now = datetime_now();
+            let removed = write_txn.remove_login_failures_if(&|_, login_failures| {
+                login_failures.is_stale(now, &config)
+            })?;
+            write_txn.commit()?;
+            Ok(removed)
+        })
+        .await?
+    }
+
+    /// Remove the failures for the string form of a [`ThrottleKey`].
+    pub async fn unlock_login(&self, key: &str) -> Result<Option<LoginFailures>, InternalError> {
+        let storage = self.storage.clone();
+        let key = key.to_owned();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let remove_result = write_txn.remove_login_failures(&key);
+            write_txn.commit()?;
+            remove_result
+        })
+        .await?
+    }
+
+    /// Create a password reset token for a player, returns the token which is only stored
+    /// hashed, or `None` if the player doesn't exist.
+    pub async fn create_reset_token(
+        &self,
+        player: &Uuid,
+        created_by: &Uuid,
+    ) -> Result<Option<String>, InternalError> {
+        let storage = self.storage.clone();
+        let now = datetime_now();
+        let reset_token = ResetToken {
+            player: *player,
+            created_by: *created_by,
+            created: now,
+            expires: now + TimeDelta::seconds(self.config.reset_token_ttl_secs),
+        };
+        let token = generate_token();
+        let token_hash = hash_token(&token);
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            if write_txn
+                .get_player_for_update(reset_token.player)?
+                .is_none()
+            {
+                return Ok(None);
+            }
+            write_txn.insert_reset_token(token_hash, reset_token)?;
+            write_txn.commit()?;
+            Ok(Some(token))
+        })
+        .await?
+    }
+
+    /// Get an unexpired reset token without using it.
+    pub async fn get_reset_token(&self, token: &str) -> Result<Option<ResetToken>, InternalError> {
+        let storage = self.storage.clone();
+        let token_hash = hash_token(token);
+        spawn_blocking(move || {
+            let read_txn = storage.begin_read()?;
+            read_txn.get_reset_token(token_hash)
+        })
+        .await?
+        .map(|opt| opt.filter(|reset_token| reset_token.expires > datetime_now()))
+    }
+
+    /// Use a reset token to set a new player password hash. Returns the updated player
+    /// if the token was valid, the token can only be used once.
+    pub async fn reset_password(
+        &self,
+        token: &str,
+        password_hash: String,
+    ) -> Result<Option<Player>, InternalError> {
+        let storage = self.storage.clone();
+        let token_hash = hash_token(token);
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let now = datetime_now();
+            let reset_token = write_txn
+                .remove_reset_token(token_hash)?
+                .filter(|reset_token| reset_token.expires > now);
+            let orig_player = reset_token
+                .map(|reset_token| write_txn.get_player_for_update(reset_token.player))
+                .transpose()?
+                .flatten();
+            let new_player = orig_player.map(|orig_player| {
+                // a new password hash also invalidates the player's existing sessions
+                let new_player = Player {
+                    password_hash,
+                    must_change_password: false,
+                    session_key: None,
+                    updated: now,
+                    ..orig_player.clone()
+                };
+                (orig_player, new_player)
+            });
+            if let Some((orig_player, new_player)) = &new_player {
+                write_txn.change_player(orig_player.clone(), new_player.clone())?;
+                write_txn.remove_login_failures_if(&|key, _| {
+                    ThrottleKey::is_for_name(key, &new_player.name)
+                })?;
+            }
+            // commit even if the player is gone so the token is used up
+            write_txn.commit()?;
+            Ok(new_player.map(|(_, new_player)| new_player))
+        })
+        .await?
+    }
+
+    /// Create an invite code, returns the code with its record. Only the hash of the code is
+    /// stored. Returns `None` if the role doesn't exist.
+    pub async fn create_invite_code(
+        &self,
+        max_uses: Option<u32>,
+        expires: Option<DateTime<Utc>>,
+        role: Option<Uuid>,
+        created_by: &Uuid,
+    ) -> Result<Option<(String, InviteCode)>, InternalError> {
+        let storage = self.storage.clone();
+        let code = generate_token();
+        let invite_code = InviteCode {
+            code_hash: hash_token(&code),
+            max_uses,
+            uses: 0,
+            expires,
+            role,
+            created_by: *created_by,
+            created: datetime_now(),
+        };
+        let inserted_code = invite_code.clone();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            if let Some(role) = inserted_code.role {
+                if write_txn.get_role_for_update(role)?.is_none() {
+                    return Ok(None);
+                }
+            }
+            write_txn.insert_invite_code(inserted_code)?;
+            write_txn.commit()?;
+            Ok(Some((code, invite_code)))
+        })
+        .await?
+    }
+
+    pub async fn get_invite_codes(&self) -> Result<Vec<InviteCode>, InternalError> {
+        let storage = self.storage.clone();
+        spawn_blocking(move || {
+            let read_txn = storage.begin_read()?;
+            read_txn.get_invite_codes()
+        })
+        .await?
+    }
+
+    pub async fn revoke_invite_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<InviteCode>, InternalError> {
+        let storage = self.storage.clone();
+        let code_hash = code_hash.to_owned();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let remove_result = write_txn.remove_invite_code(&code_hash);
+            write_txn.commit()?;
+            remove_result
+        })
+        .await?
+    }
+
+    async fn authenticate_password(
+        &self,
+        name: String,
+        password: String,
+    ) -> Result<Option<Player>, InternalError> {
         let uuid_player = self
             .get_player_by_name(&name)
             .await?
@@ -178,6 +666,501 @@ impl AuthnBackend for AuthBackend {
         Ok(uuid_player)
     }
 
+    /// Find the player linked to a verified OIDC identity, linking it to `link_to` or
+    /// provisioning a new player if it isn't linked yet, and sync the player's mapped roles.
+    async fn authenticate_oidc(
+        &self,
+        identity: OidcIdentity,
+        link_to: Option<Uuid>,
+    ) -> Result<Option<Player>, InternalError> {
+        let Some(oidc_config) = self.config.oidc.clone() else {
+            return Ok(None);
+        };
+        let identity_key = IdentityKey::Oidc {
+            issuer: identity.issuer.clone(),
+            subject: identity.subject.clone(),
+        };
+        let provision_name = oidc_config
+            .auto_provision
+            .then(|| identity.name.unwrap_or_else(|| "player".to_string()));
+        self.authenticate_group_identity(
+            identity_key,
+            link_to,
+            provision_name,
+            identity.groups,
+            oidc_config.role_map,
+            identity.issuer,
+        )
+        .await
+    }
+
+    /// Bind to the LDAP directory with a login form name and password, provisioning a new
+    /// player on the first login, and sync the player's mapped roles.
+    async fn authenticate_ldap(
+        &self,
+        name: String,
+        password: String,
+    ) -> Result<Option<Player>, InternalError> {
+        let Some(ldap) = &self.ldap else {
+            return Ok(None);
+        };
+        let Some(ldap_user) = ldap.authenticate(&name, &password).await? else {
+            return Ok(None);
+        };
+        let provision_name = ldap.config.auto_provision.then_some(name);
+        self.authenticate_group_identity(
+            IdentityKey::Ldap(ldap_user.dn.to_lowercase()),
+            None,
+            provision_name,
+            ldap_user.groups,
+            ldap.config.role_map.clone(),
+            "directory".to_string(),
+        )
+        .await
+    }
+
+    /// Find or link the player for an identity verified by `source`, provisioning a new player
+    /// named after `provision_name` if given, and sync the player's roles with the identity's
+    /// groups.
+    async fn authenticate_group_identity(
+        &self,
+        identity_key: IdentityKey,
+        link_to: Option<Uuid>,
+        provision_name: Option<String>,
+        groups: Vec<String>,
+        role_map: HashMap<String, String>,
+        source: String,
+    ) -> Result<Option<Player>, InternalError> {
+        let storage = self.storage.clone();
+        spawn_blocking(move || {
+            let roles = storage.begin_read()?.get_roles()?;
+            let mut write_txn = storage.begin_write()?;
+            let now = datetime_now();
+            let mut provision_names = provision_name.map(|name| player_name_candidates(&name));
+            let orig_player = write_txn.find_or_link_player(
+                &identity_key,
+                link_to,
+                provision_names
+                    .as_mut()
+                    .map(|names| names as &mut dyn Iterator<Item = String>),
+            )?;
+            let new_player = orig_player
+                .map(|orig_player| {
+                    let new_player = Player {
+                        roles: sync_group_roles(&orig_player.roles, &groups, &role_map, &roles),
+                        last_login: now,
+                        ..orig_player.clone()
+                    };
+                    // roles synced from the identity provider's groups
+                    let events = player_change_events(None, &orig_player, &new_player, &roles)
+                        .into_iter()
+                        .map(|event| AuditEvent {
+                            after: event
+                                .after
+                                .map(|after| format!("{} (from {})", after, source)),
+                            ..event
+                        })
+                        .collect::<Vec<AuditEvent>>();
+                    write_txn.record_events(&events)?;
+                    write_txn
+                        .change_player(orig_player, new_player.clone())
+                        .map(|_| new_player)
+                })
+                .transpose()?;
+            write_txn.commit()?;
+            Ok(new_player)
+        })
+        .await?
+    }
+
+    /// Start a LNURL-auth login with a new challenge.
+    pub async fn create_lnurl_challenge(&self) -> Result<LnurlChallenge, InternalError> {
+        let storage = self.storage.clone();
+        let ttl_secs = self
+            .config
+            .lnurl
+            .as_ref()
+            .map(|lnurl_config| lnurl_config.challenge_ttl_secs)
+            .unwrap_or_default();
+        let now = datetime_now();
+        let challenge = LnurlChallenge {
+            k1: new_k1(),
+            created: now,
+            expires: now + TimeDelta::seconds(ttl_secs),
+            linking_key: None,
+        };
+        let inserted_challenge = challenge.clone();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            write_txn.insert_lnurl_challenge(inserted_challenge)?;
+            write_txn.commit()
+        })
+        .await??;
+        Ok(challenge)
+    }
+
+    /// Record the wallet key that signed a challenge, the signature must already be verified.
+    /// Returns false if the challenge is unknown, expired or already signed.
+    pub async fn sign_lnurl_challenge(&self, k1: &str, key: &str) -> Result<bool, InternalError> {
+        let storage = self.storage.clone();
+        let k1 = k1.to_owned();
+        let key = key.to_owned();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let challenge = write_txn.sign_lnurl_challenge(&k1, &key, datetime_now())?;
+            write_txn.commit()?;
+            Ok(challenge.is_some())
+        })
+        .await?
+    }
+
+    /// The challenge if it is still valid, removing it once it has been signed so the
+    /// signature can only complete one login.
+    pub async fn take_lnurl_challenge(
+        &self,
+        k1: &str,
+    ) -> Result<Option<LnurlChallenge>, InternalError> {
+        let storage = self.storage.clone();
+        let k1 = k1.to_owned();
+        spawn_blocking(move || {
+            let challenge = storage.begin_read()?.get_lnurl_challenge(&k1)?;
+            match challenge {
+                Some(challenge) if challenge.linking_key.is_some() => {
+                    let mut write_txn = storage.begin_write()?;
+                    let challenge = write_txn.remove_lnurl_challenge(&k1)?;
+                    write_txn.commit()?;
+                    Ok(challenge)
+                }
+                challenge => Ok(challenge.filter(|challenge| challenge.expires > datetime_now())),
+            }
+        })
+        .await?
+    }
+
+    pub async fn get_player_identities(&self, uuid: &Uuid) -> Result<Vec<String>, InternalError> {
+        let storage = self.storage.clone();
+        let uuid = *uuid;
+        spawn_blocking(move || {
+            let read_txn = storage.begin_read()?;
+            read_txn.get_player_identities(uuid)
+        })
+        .await?
+    }
+
+    pub async fn get_totp(&self, uuid: &Uuid) -> Result<Option<Totp>, InternalError> {
+        let storage = self.storage.clone();
+        let uuid = *uuid;
+        spawn_blocking(move || {
+            let read_txn = storage.begin_read()?;
+            read_txn.get_totp(uuid)
+        })
+        .await?
+    }
+
+    /// Enable two-factor authentication once the player proves their authenticator has the
+    /// enrollment secret. Returns false if the code doesn't match.
+    pub async fn enable_totp(
+        &self,
+        uuid: &Uuid,
+        enrollment: TotpEnrollment,
+        code: &str,
+    ) -> Result<bool, InternalError> {
+        let now = datetime_now();
+        let Some(step) = verify_code(&enrollment.secret, code, now, None) else {
+            return Ok(false);
+        };
+        let totp = Totp {
+            secret: enrollment.secret,
+            recovery_codes: enrollment
+                .recovery_codes
+                .iter()
+                .map(|code| hash_token(&normalize_recovery_code(code)))
+                .collect(),
+            last_step: Some(step),
+            created: now,
+        };
+        let storage = self.storage.clone();
+        let uuid = *uuid;
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            write_txn.insert_totp(uuid, totp)?;
+            write_txn.commit()?;
+            Ok(true)
+        })
+        .await?
+    }
+
+    pub async fn disable_totp(&self, uuid: &Uuid) -> Result<bool, InternalError> {
+        let storage = self.storage.clone();
+        let uuid = *uuid;
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let removed = write_txn.remove_totp(uuid)?;
+            write_txn.commit()?;
+            Ok(removed)
+        })
+        .await?
+    }
+
+    /// Check a player's authenticator or recovery code, each code can only be used once.
+    pub async fn use_totp_code(&self, uuid: &Uuid, code: &str) -> Result<bool, InternalError> {
+        let storage = self.storage.clone();
+        let uuid = *uuid;
+        let code = code.to_string();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let valid = write_txn.use_totp_code(uuid, &code, datetime_now())?;
+            write_txn.commit()?;
+            Ok(valid)
+        })
+        .await?
+    }
+
+    /// If the two-factor policy applies to a player, whether or not they are enrolled.
+    pub async fn requires_totp(&self, player: &Player) -> Result<bool, InternalError> {
+        if !self.config.require_two_factor {
+            return Ok(false);
+        }
+        let permissions = self.get_player_permissions(player).await?;
+        Ok(permissions.contains(&Permission::ChangeTarget)
+            || permissions.contains(&Permission::AssignAdm))
+    }
+
+    /// Create a named api token for a player, returns the token which is only stored hashed.
+    pub async fn create_api_token(
+        &self,
+        player: &Uuid,
+        name: &str,
+        scope: TokenScope,
+    ) -> Result<(String, ApiToken), InternalError> {
+        let storage = self.storage.clone();
+        let token = format!("{}{}", API_TOKEN_PREFIX, generate_token());
+        let api_token = ApiToken {
+            uuid: Uuid::new_v4(),
+            name: name.to_string(),
+            player: *player,
+            scope,
+            created: datetime_now(),
+            last_used: None,
+        };
+        let token_hash = hash_token(&token);
+        let inserted = api_token.clone();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            write_txn.insert_api_token(token_hash, inserted)?;
+            write_txn.commit()?;
+            Ok::<(), InternalError>(())
+        })
+        .await??;
+        Ok((token, api_token))
+    }
+
+    pub async fn get_player_api_tokens(&self, uuid: &Uuid) -> Result<Vec<ApiToken>, InternalError> {
+        let storage = self.storage.clone();
+        let uuid = *uuid;
+        spawn_blocking(move || {
+            let read_txn = storage.begin_read()?;
+            read_txn.get_player_api_tokens(uuid)
+        })
+        .await?
+    }
+
+    /// Revoke one of a player's api tokens, returns false if the player has no such token.
+    pub async fn revoke_api_token(
+        &self,
+        uuid: &Uuid,
+        token_uuid: &Uuid,
+    ) -> Result<bool, InternalError> {
+        let storage = self.storage.clone();
+        let uuid = *uuid;
+        let token_uuid = *token_uuid;
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let removed = write_txn.remove_api_token(uuid, token_uuid)?;
+            write_txn.commit()?;
+            Ok(removed)
+        })
+        .await?
+    }
+
+    /// The player and token for a valid api token.
+    pub async fn authenticate_api_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<(Player, ApiToken)>, InternalError> {
+        if !token.starts_with(API_TOKEN_PREFIX) {
+            return Ok(None);
+        }
+        let storage = self.storage.clone();
+        let token_hash = hash_token(token);
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let api_token = write_txn.use_api_token(token_hash, datetime_now())?;
+            let player = api_token
+                .as_ref()
+                .map(|api_token| write_txn.get_player_for_update(api_token.player))
+                .transpose()?
+                .flatten();
+            write_txn.commit()?;
+            Ok(player.zip(api_token))
+        })
+        .await?
+    }
+
+    /// A player's unexpired sessions, most recently seen first.
+    pub async fn get_player_sessions(
+        &self,
+        uuid: &Uuid,
+    ) -> Result<Vec<ActiveSession>, InternalError> {
+        let records = self.session_store.user_sessions(&uuid.to_string()).await?;
+        let mut sessions = records
+            .into_iter()
+            .map(|record| ActiveSession {
+                id: record.id.to_string(),
+                expires: DateTime::from_timestamp(record.expiry_date.unix_timestamp(), 0)
+                    .unwrap_or_default(),
+                info: record
+                    .data
+                    .get(SESSION_INFO_KEY)
+                    .and_then(|info| serde_json::from_value::<SessionInfo>(info.clone()).ok()),
+            })
+            .collect::<Vec<ActiveSession>>();
+        sessions.sort_by_key(|session| {
+            std::cmp::Reverse(session.info.as_ref().map(|info| info.last_seen))
+        });
+        Ok(sessions)
+    }
+
+    /// Sign a player out of all sessions except `keep`, returns the number of sessions removed.
+    pub async fn delete_player_sessions(
+        &self,
+        uuid: &Uuid,
+        keep: Option<Id>,
+    ) -> Result<usize, InternalError> {
+        Ok(self
+            .session_store
+            .delete_user_sessions(&uuid.to_string(), keep)
+            .await?)
+    }
+
+    /// Sign a player out of one of their sessions, returns false if it isn't theirs.
+    pub async fn delete_player_session(&self, uuid: &Uuid, id: Id) -> Result<bool, InternalError> {
+        let owned = self
+            .session_store
+            .user_sessions(&uuid.to_string())
+            .await?
+            .iter()
+            .any(|record| record.id == id);
+        if owned {
+            self.session_store.delete(&id).await?;
+        }
+        Ok(owned)
+    }
+
+    /// Find or link the player for a verified external identity, registering a new player
+    /// with the first available of `provision_names` if given.
+    async fn authenticate_identity(
+        &self,
+        identity_key: IdentityKey,
+        link_to: Option<Uuid>,
+        provision_names: Option<Vec<String>>,
+    ) -> Result<Option<Player>, InternalError> {
+        let storage = self.storage.clone();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let mut provision_names = provision_names.map(Vec::into_iter);
+            let orig_player = write_txn.find_or_link_player(
+                &identity_key,
+                link_to,
+                provision_names
+                    .as_mut()
+                    .map(|names| names as &mut dyn Iterator<Item = String>),
+            )?;
+            let new_player = orig_player
+                .map(|orig_player| {
+                    let new_player = Player {
+                        last_login: datetime_now(),
+                        ..orig_player.clone()
+                    };
+                    write_txn
+                        .change_player(orig_player, new_player.clone())
+                        .map(|_| new_player)
+                })
+                .transpose()?;
+            write_txn.commit()?;
+            Ok(new_player)
+        })
+        .await?
+    }
+}
+
+// We use a type alias for convenience.
+//
+// Note that we've supplied our concrete backend here.
+pub type AuthSession = axum_login::AuthSession<AuthBackend>;
+
+#[async_trait]
+impl AuthnBackend for AuthBackend {
+    type User = Player;
+    type Credentials = Credentials;
+    type Error = InternalError;
+
+    async fn authenticate(
+        &self,
+        creds: Self::Credentials,
+    ) -> Result<Option<Self::User>, Self::Error> {
+        match creds {
+            Credentials::Password { name, password } => {
+                self.authenticate_password(name, password).await
+            }
+            Credentials::Oidc { identity, link_to } => {
+                self.authenticate_oidc(identity, link_to).await
+            }
+            Credentials::Nostr { pubkey, link_to } => {
+                let Some(nostr_config) = &self.config.nostr else {
+                    return Ok(None);
+                };
+                // new players are named after their npub, which is unique per pubkey
+                let provision_names = nostr_config
+                    .auto_register
+                    .then(|| npub(&pubkey).ok())
+                    .flatten()
+                    .map(|npub| vec![npub]);
+                self.authenticate_identity(IdentityKey::Nostr(pubkey), link_to, provision_names)
+                    .await
+            }
+            Credentials::ApiToken { token } => Ok(self
+                .authenticate_api_token(&token)
+                .await?
+                .map(|(player, _)| player)),
+            Credentials::Lnurl { key, link_to } => {
+                let Some(lnurl_config) = &self.config.lnurl else {
+                    return Ok(None);
+                };
+                let provision_names = lnurl_config.auto_register.then(|| {
+                    player_name_candidates(&format!("ln_{}", key.get(2..14).unwrap_or(&key)))
+                        .take(10)
+                        .collect()
+                });
+                self.authenticate_identity(IdentityKey::Lnurl(key), link_to, provision_names)
+                    .await
+            }
+            Credentials::Proxy { user } => {
+                let Some(proxy_config) = &self.config.proxy else {
+                    return Ok(None);
+                };
+                // linking on a header alone could attach a proxy user to whoever is logged in
+                // on a shared browser, so proxy identities are never linked to existing players
+                let provision_names = proxy_config
+                    .auto_provision
+                    .then(|| player_name_candidates(&user).take(10).collect());
+                self.authenticate_identity(IdentityKey::Proxy(user), None, provision_names)
+                    .await
+            }
+            Credentials::Ldap { name, password } => self.authenticate_ldap(name, password).await,
+        }
+    }
+
     async fn get_user(&self, uuid: &UserId<Self>) -> Result<Option<Self::User>, Self::Error> {
         self.get_player_by_uuid(uuid).await
     }
@@ -221,17 +1204,34 @@ impl AuthUser for Player {
     }
 
     fn session_auth_hash(&self) -> &[u8] {
-        self.password_hash.as_bytes()
+        self.session_key
+            .as_ref()
+            .unwrap_or(&self.password_hash)
+            .as_bytes()
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::AuthBackend;
-    use crate::auth::types::{Permission, Player, Role};
+    use super::{AuthBackend, API_TOKEN_PREFIX};
+    use crate::audit::types::{AuditAction, AuditFilter};
+    use crate::auth::config::{
+        parse_role_map, AdminConfig, AuthConfig, IpCidr, LdapConfig, LnurlConfig,
+        LoginThrottleConfig, NostrConfig, OidcConfig, ProxyConfig,
+    };
+    use crate::auth::ldap::test_directory::{serve, TestEntry};
+    use crate::auth::totp::{current_code, new_recovery_codes, new_secret, TotpEnrollment};
+    use crate::auth::types::{
+        datetime_now, generate_token, hash_token, Credentials, OidcIdentity, Permission, Player,
+        Role, ThrottleKey, TokenScope,
+    };
+    use crate::guess::backend::GuessBackend;
+    use crate::guess::types::Guess;
+    use axum_login::AuthnBackend;
     use password_auth::generate_hash;
     use redb::Database;
     use std::collections::HashSet;
+    use std::str::FromStr;
     use std::sync::Arc;
     use tempfile::NamedTempFile;
     use uuid::Uuid;
@@ -241,9 +1241,16 @@ mod test {
         Arc::new(Database::create(file).unwrap())
     }
 
+    fn password(name: &str, password: &str) -> Credentials {
+        Credentials::Password {
+            name: name.to_string(),
+            password: password.to_string(),
+        }
+    }
+
     #[tokio::test]
     async fn test_insert_get_player() {
-        let backend = AuthBackend::new(temp_db()).expect("new backend");
+        let backend = AuthBackend::new(temp_db(), &AuthConfig::default()).expect("new backend");
 
         let input_password = "password";
         let password_hash = generate_hash(input_password);
@@ -295,7 +1302,7 @@ mod test {
 
     #[tokio::test]
     async fn test_insert_get_role() {
-        let backend = AuthBackend::new(temp_db()).expect("new backend");
+        let backend = AuthBackend::new(temp_db(), &AuthConfig::default()).expect("new backend");
         let inserted_role1 = Role {
             uuid: Uuid::new_v4(),
             name: "test1".to_string(),
@@ -339,4 +1346,938 @@ mod test {
         let inserted_permissions = [Permission::AssignAdm, Permission::ChangeTarget];
         assert_eq!(inserted_permissions, permissions[..]);
     }
+
+    #[tokio::test]
+    async fn test_bootstrap_admin() {
+        let config = AuthConfig {
+            admin: AdminConfig {
+                name: "root".to_string(),
+                password: Some("Setup123$".to_string()),
+            },
+            ..Default::default()
+        };
+        let backend = AuthBackend::new(temp_db(), &config).expect("new backend");
+        let admin = backend
+            .get_player_by_name("root")
+            .await
+            .expect("get admin")
+            .expect("admin exists");
+        assert!(admin.must_change_password);
+        let authenticated = backend
+            .authenticate(password("root", "Setup123$"))
+            .await
+            .expect("authenticate admin");
+        assert_eq!(authenticated.map(|player| player.uuid), Some(admin.uuid));
+        let old_default = backend
+            .authenticate(password("root", "aBcD123$"))
+            .await
+            .expect("authenticate admin");
+        assert_eq!(old_default, None);
+    }
+
+    #[tokio::test]
+    async fn test_login_throttle() {
+        let db = temp_db();
+        let config = AuthConfig::default();
+        let backend = AuthBackend::new(db.clone(), &config).expect("new backend");
+        let keys = vec![
+            ThrottleKey::Name("tester".to_string()),
+            ThrottleKey::Ip([127, 0, 0, 1].into()),
+        ];
+        for _ in 0..config.login_throttle.lockout_threshold {
+            backend
+                .record_login_failure(keys.clone())
+                .await
+                .expect("record failure");
+        }
+        // failures are persisted and survive a new backend on the same database
+        let backend = AuthBackend::new(db, &config).expect("new backend");
+        assert!(backend
+            .login_retry_at(keys[..1].to_vec())
+            .await
+            .expect("retry at")
+            .is_some());
+        let login_failures = backend.get_all_login_failures().await.expect("failures");
+        assert_eq!(login_failures.len(), 2);
+        // unlocking the name doesn't unlock the ip
+        backend.unlock_login("name:tester").await.expect("unlock");
+        assert_eq!(
+            backend
+                .login_retry_at(keys[..1].to_vec())
+                .await
+                .expect("retry at"),
+            None
+        );
+        assert!(backend
+            .login_retry_at(keys.clone())
+            .await
+            .expect("retry at")
+            .is_some());
+        backend
+            .clear_login_failures(keys.clone())
+            .await
+            .expect("clear");
+        assert_eq!(backend.login_retry_at(keys).await.expect("retry at"), None);
+    }
+
+    #[tokio::test]
+    async fn test_prune_login_failures() {
+        let db = temp_db();
+        let config = AuthConfig::default();
+        let backend = AuthBackend::new(db.clone(), &config).expect("new backend");
+        let ip = [127, 0, 0, 1].into();
+        let stale = ThrottleKey::NameIp("tester".to_string(), ip);
+        let locked = ThrottleKey::Ip(ip);
+        backend
+            .record_login_failure(vec![stale.clone()])
+            .await
+            .expect("record failure");
+        for _ in 0..config.login_throttle.lockout_threshold {
+            backend
+                .record_login_failure(vec![locked.clone()])
+                .await
+                .expect("record failure");
+        }
+        assert_eq!(backend.prune_login_failures().await.expect("prune"), 0);
+        // only failures without an active lockout are forgotten after the reset time
+        let config = AuthConfig {
+            login_throttle: LoginThrottleConfig {
+                reset_secs: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let backend = AuthBackend::new(db, &config).expect("new backend");
+        assert_eq!(backend.prune_login_failures().await.expect("prune"), 1);
+        let login_failures = backend.get_all_login_failures().await.expect("failures");
+        assert_eq!(login_failures.len(), 1);
+        assert_eq!(login_failures[0].0, locked.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_reset_password() {
+        let backend = AuthBackend::new(temp_db(), &AuthConfig::default()).expect("new backend");
+        let player = Player {
+            uuid: Uuid::new_v4(),
+            name: "tester".to_string(),
+            password_hash: generate_hash("Forgot123$"),
+            ..Default::default()
+        };
+        backend.insert_player(&player).await.expect("insert player");
+        let admin_uuid = Uuid::new_v4();
+        let first_token = backend
+            .create_reset_token(&player.uuid, &admin_uuid)
+            .await
+            .expect("create token")
+            .expect("player exists");
+        let token = backend
+            .create_reset_token(&player.uuid, &admin_uuid)
+            .await
+            .expect("create token")
+            .expect("player exists");
+        // there is no token for a missing player
+        assert_eq!(
+            backend
+                .create_reset_token(&Uuid::new_v4(), &admin_uuid)
+                .await
+                .expect("create token"),
+            None
+        );
+        // a new token replaces the previous one for the same player
+        assert_eq!(
+            backend.get_reset_token(&first_token).await.expect("get"),
+            None
+        );
+        let reset_token = backend
+            .get_reset_token(&token)
+            .await
+            .expect("get")
+            .expect("valid token");
+        assert_eq!(reset_token.player, player.uuid);
+        assert_eq!(reset_token.created_by, admin_uuid);
+
+        let reset_player = backend
+            .reset_password(&token, generate_hash("Remember123$"))
+            .await
+            .expect("reset password")
+            .expect("reset player");
+        assert_ne!(reset_player.password_hash, player.password_hash);
+        let authenticated = backend
+            .authenticate(password("tester", "Remember123$"))
+            .await
+            .expect("authenticate");
+        assert_eq!(authenticated.map(|player| player.uuid), Some(player.uuid));
+        // tokens are single use
+        assert_eq!(
+            backend
+                .reset_password(&token, generate_hash("Again123$"))
+                .await
+                .expect("reset password"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_session_auth_hash() {
+        use axum_login::AuthUser;
+        let player = Player {
+            password_hash: generate_hash("Before123$"),
+            ..Default::default()
+        };
+        assert_eq!(player.session_auth_hash(), player.password_hash.as_bytes());
+        let with_key = Player {
+            session_key: Some(generate_token()),
+            ..player.clone()
+        };
+        assert_ne!(with_key.session_auth_hash(), player.session_auth_hash());
+        // changing the password while keeping the session key keeps sessions valid
+        let kept = Player {
+            password_hash: generate_hash("After123$"),
+            ..with_key.clone()
+        };
+        assert_eq!(kept.session_auth_hash(), with_key.session_auth_hash());
+        let signed_out = Player {
+            session_key: Some(generate_token()),
+            ..kept.clone()
+        };
+        assert_ne!(signed_out.session_auth_hash(), with_key.session_auth_hash());
+    }
+
+    #[tokio::test]
+    async fn test_delete_player() {
+        let config = AuthConfig {
+            nostr: Some(NostrConfig {
+                auto_register: true,
+            }),
+            ..Default::default()
+        };
+        let backend = AuthBackend::new(temp_db(), &config).expect("new backend");
+        let player = Player {
+            uuid: Uuid::new_v4(),
+            name: "leaving".to_string(),
+            password_hash: generate_hash("Leaving123$"),
+            ..Default::default()
+        };
+        backend.insert_player(&player).await.expect("insert player");
+        let pubkey = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
+        let nostr = |link_to| Credentials::Nostr {
+            pubkey: pubkey.to_string(),
+            link_to,
+        };
+        backend
+            .authenticate(nostr(Some(player.uuid)))
+            .await
+            .expect("authenticate")
+            .expect("linked");
+        let token = backend
+            .create_reset_token(&player.uuid, &Uuid::new_v4())
+            .await
+            .expect("create token")
+            .expect("player exists");
+
+        let deleted = backend
+            .delete_player(&player.uuid)
+            .await
+            .expect("delete player");
+        assert_eq!(deleted.map(|player| player.uuid), Some(player.uuid));
+        assert_eq!(
+            backend.get_player_by_uuid(&player.uuid).await.unwrap(),
+            None
+        );
+        assert_eq!(backend.get_player_by_name("leaving").await.unwrap(), None);
+        assert!(backend
+            .get_player_identities(&player.uuid)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(backend.get_reset_token(&token).await.unwrap(), None);
+        assert!(backend
+            .authenticate(password("leaving", "Leaving123$"))
+            .await
+            .expect("authenticate")
+            .is_none());
+        // the pubkey is free to register a new player
+        let registered = backend
+            .authenticate(nostr(None))
+            .await
+            .expect("authenticate")
+            .expect("registered");
+        assert_ne!(registered.uuid, player.uuid);
+        // deleting again is a no-op
+        assert_eq!(backend.delete_player(&player.uuid).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_delete_account() {
+        let db = temp_db();
+        let backend = AuthBackend::new(db.clone(), &AuthConfig::default()).expect("new backend");
+        let guess_backend = GuessBackend::new(
+            db,
+            reqwest::Client::new(),
+            "http://localhost:1".parse().unwrap(),
+        )
+        .expect("new guess backend");
+        // the bootstrap admin is the only player who can assign admins
+        let admin = backend
+            .get_player_by_name("admin")
+            .await
+            .unwrap()
+            .expect("bootstrap admin");
+        let player = Player {
+            uuid: Uuid::new_v4(),
+            name: "player".to_string(),
+            ..Default::default()
+        };
+        backend.insert_player(&player).await.expect("insert player");
+        guess_backend.insert_target(100, None).await.unwrap();
+        guess_backend
+            .insert_guess(
+                100,
+                Guess {
+                    player: player.uuid,
+                    nonce: 42,
+                },
+            )
+            .await
+            .unwrap();
+
+        // the last player who can assign admins can't delete their account
+        assert_eq!(
+            backend.delete_account(&admin.uuid, false).await.unwrap(),
+            None
+        );
+        assert!(backend
+            .get_player_by_uuid(&admin.uuid)
+            .await
+            .unwrap()
+            .is_some());
+
+        // the player and their guesses are removed together
+        assert_eq!(
+            backend.delete_account(&player.uuid, false).await.unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            backend.get_player_by_uuid(&player.uuid).await.unwrap(),
+            None
+        );
+        assert!(guess_backend
+            .player_guesses(player.uuid)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_authenticate() {
+        let proxy = |user: &str| Credentials::Proxy {
+            user: user.to_string(),
+        };
+        // disabled without trusted proxies
+        let backend = AuthBackend::new(temp_db(), &AuthConfig::default()).expect("new backend");
+        assert_eq!(
+            backend.authenticate(proxy("alice")).await.expect("auth"),
+            None
+        );
+
+        let proxy_config = ProxyConfig {
+            header: "X-Forwarded-User".to_string(),
+            trusted_cidrs: vec![IpCidr::from_str("10.0.0.0/8").unwrap()],
+            auto_provision: true,
+        };
+        let config = AuthConfig {
+            proxy: Some(proxy_config.clone()),
+            ..AuthConfig::default()
+        };
+        let db = temp_db();
+        let backend = AuthBackend::new(db.clone(), &config).expect("new backend");
+        let provisioned = backend
+            .authenticate(proxy("alice@example.com"))
+            .await
+            .expect("auth")
+            .expect("provisioned");
+        assert_eq!(provisioned.name, "aliceexamplecom");
+        assert!(provisioned.password_hash.is_empty());
+        let again = backend
+            .authenticate(proxy("alice@example.com"))
+            .await
+            .expect("auth")
+            .expect("linked");
+        assert_eq!(again.uuid, provisioned.uuid);
+
+        // without auto provisioning only known proxy users log in
+        let config = AuthConfig {
+            proxy: Some(ProxyConfig {
+                auto_provision: false,
+                ..proxy_config
+            }),
+            ..AuthConfig::default()
+        };
+        let backend = AuthBackend::new(db, &config).expect("new backend");
+        assert_eq!(
+            backend.authenticate(proxy("bob")).await.expect("auth"),
+            None
+        );
+        let known = backend
+            .authenticate(proxy("alice@example.com"))
+            .await
+            .expect("auth")
+            .expect("known");
+        assert_eq!(known.uuid, provisioned.uuid);
+    }
+
+    #[tokio::test]
+    async fn test_totp() {
+        let config = AuthConfig {
+            require_two_factor: true,
+            ..AuthConfig::default()
+        };
+        let backend = AuthBackend::new(temp_db(), &config).expect("new backend");
+        let player = Player {
+            uuid: Uuid::new_v4(),
+            name: "guarded".to_string(),
+            password_hash: generate_hash("Guarded123$"),
+            ..Default::default()
+        };
+        backend.insert_player(&player).await.expect("insert player");
+        // only players who can change the target or assign admins need two-factor
+        assert!(!backend.requires_totp(&player).await.expect("requires"));
+        let admin = Player {
+            permissions: HashSet::from([Permission::ChangeTarget]),
+            ..player.clone()
+        };
+        assert!(backend.requires_totp(&admin).await.expect("requires"));
+
+        let enrollment = TotpEnrollment {
+            secret: new_secret(),
+            recovery_codes: new_recovery_codes(),
+        };
+        let recovery_code = enrollment.recovery_codes[0].clone();
+        let code = current_code(&enrollment.secret, datetime_now());
+        let wrong_code = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+        assert!(!backend
+            .enable_totp(&player.uuid, enrollment.clone(), &wrong_code)
+            .await
+            .expect("enable"));
+        assert_eq!(backend.get_totp(&player.uuid).await.expect("get"), None);
+        assert!(backend
+            .enable_totp(&player.uuid, enrollment.clone(), &code)
+            .await
+            .expect("enable"));
+        let totp = backend
+            .get_totp(&player.uuid)
+            .await
+            .expect("get")
+            .expect("enabled");
+        assert_eq!(totp.recovery_codes.len(), 10);
+        assert!(!totp.recovery_codes.contains(&recovery_code));
+
+        // the confirmation code can't be used again
+        assert!(!backend
+            .use_totp_code(&player.uuid, &code)
+            .await
+            .expect("use code"));
+        // recovery codes work once, in any case
+        assert!(backend
+            .use_totp_code(&player.uuid, &recovery_code.to_uppercase())
+            .await
+            .expect("use recovery code"));
+        assert!(!backend
+            .use_totp_code(&player.uuid, &recovery_code)
+            .await
+            .expect("use recovery code"));
+
+        assert!(backend.disable_totp(&player.uuid).await.expect("disable"));
+        assert_eq!(backend.get_totp(&player.uuid).await.expect("get"), None);
+
+        // deleting a player removes their enrollment
+        backend
+            .enable_totp(&player.uuid, enrollment.clone(), &code)
+            .await
+            .expect("enable");
+        backend.delete_player(&player.uuid).await.expect("delete");
+        assert_eq!(backend.get_totp(&player.uuid).await.expect("get"), None);
+    }
+
+    #[tokio::test]
+    async fn test_api_token() {
+        let backend = AuthBackend::new(temp_db(), &AuthConfig::default()).expect("new backend");
+        let player = Player {
+            uuid: Uuid::new_v4(),
+            name: "scripter".to_string(),
+            password_hash: generate_hash("Scripter123$"),
+            ..Default::default()
+        };
+        backend.insert_player(&player).await.expect("insert player");
+        let (token, api_token) = backend
+            .create_api_token(&player.uuid, "bot", TokenScope::Guess)
+            .await
+            .expect("create token");
+        assert!(token.starts_with(API_TOKEN_PREFIX));
+        assert_eq!(api_token.last_used, None);
+
+        let authenticated = backend
+            .authenticate(Credentials::ApiToken {
+                token: token.clone(),
+            })
+            .await
+            .expect("authenticate");
+        assert_eq!(authenticated.map(|player| player.uuid), Some(player.uuid));
+        let api_tokens = backend.get_player_api_tokens(&player.uuid).await.unwrap();
+        assert_eq!(api_tokens.len(), 1);
+        assert_eq!(api_tokens[0].scope, TokenScope::Guess);
+        assert!(api_tokens[0].last_used.is_some());
+        // only the hash is stored, a different token doesn't match
+        assert!(backend
+            .authenticate_api_token(&format!("{}{}", API_TOKEN_PREFIX, "0".repeat(64)))
+            .await
+            .unwrap()
+            .is_none());
+
+        // other players can't revoke the token
+        assert!(!backend
+            .revoke_api_token(&Uuid::new_v4(), &api_token.uuid)
+            .await
+            .unwrap());
+        assert!(backend
+            .revoke_api_token(&player.uuid, &api_token.uuid)
+            .await
+            .unwrap());
+        assert!(backend
+            .authenticate_api_token(&token)
+            .await
+            .unwrap()
+            .is_none());
+
+        // deleting the player removes their tokens
+        let (token, _) = backend
+            .create_api_token(&player.uuid, "bot2", TokenScope::Read)
+            .await
+            .expect("create token");
+        backend.delete_player(&player.uuid).await.unwrap();
+        assert!(backend
+            .authenticate_api_token(&token)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(backend
+            .get_player_api_tokens(&player.uuid)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_register_with_invite_code() {
+        let backend = AuthBackend::new(temp_db(), &AuthConfig::default()).expect("new backend");
+        let role = Role {
+            uuid: Uuid::new_v4(),
+            name: "moderator".to_string(),
+            permissions: [Permission::AssignMod].into(),
+        };
+        backend.insert_role(&role).await.expect("insert role");
+        let admin_uuid = Uuid::new_v4();
+        // codes can only grant existing roles
+        assert_eq!(
+            backend
+                .create_invite_code(None, None, Some(Uuid::new_v4()), &admin_uuid)
+                .await
+                .expect("create invite code"),
+            None
+        );
+        let (code, invite_code) = backend
+            .create_invite_code(Some(1), None, Some(role.uuid), &admin_uuid)
+            .await
+            .expect("create invite code")
+            .expect("role exists");
+        // only the hash of the code is stored
+        assert_eq!(invite_code.code_hash, hash_token(&code));
+        assert!(!backend
+            .get_invite_codes()
+            .await
+            .expect("invite codes")
+            .iter()
+            .any(|invite_code| invite_code.code_hash == code));
+        let new_player = |name: &str| Player {
+            uuid: Uuid::new_v4(),
+            name: name.to_string(),
+            password_hash: generate_hash("Invited123$"),
+            ..Default::default()
+        };
+
+        // unknown codes are rejected and nothing is inserted
+        let player0 = new_player("tester0");
+        assert!(!backend
+            .register_player(&player0, Some("unknown".to_string()))
+            .await
+            .expect("register player0"));
+        assert_eq!(
+            backend.get_player_by_name("tester0").await.expect("get"),
+            None
+        );
+
+        // the code's role is assigned to the new player
+        let player1 = new_player("tester1");
+        assert!(backend
+            .register_player(&player1, Some(code.clone()))
+            .await
+            .expect("register player1"));
+        let registered = backend
+            .get_player_by_name("tester1")
+            .await
+            .expect("get")
+            .expect("registered");
+        assert_eq!(registered.roles, HashSet::from([role.uuid]));
+
+        // the granted role is audited as a change by the code's creator
+        let events = backend
+            .audit_log
+            .get_events(AuditFilter::default())
+            .await
+            .expect("audit events");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, AuditAction::RoleChange);
+        assert_eq!(events[0].actor, Some(admin_uuid));
+        assert_eq!(events[0].subject.as_deref(), Some("tester1"));
+        assert_eq!(events[0].after.as_deref(), Some("moderator"));
+
+        // the code is used up after max uses
+        let player2 = new_player("tester2");
+        assert!(!backend
+            .register_player(&player2, Some(code.clone()))
+            .await
+            .expect("register player2"));
+        let invite_codes = backend.get_invite_codes().await.expect("invite codes");
+        assert_eq!(invite_codes[0].uses, 1);
+
+        backend
+            .revoke_invite_code(&invite_code.code_hash)
+            .await
+            .expect("revoke");
+        assert!(backend
+            .get_invite_codes()
+            .await
+            .expect("invite codes")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_oidc() {
+        let oidc_config = |auto_provision| OidcConfig {
+            name: "SSO".to_string(),
+            issuer_url: "http://127.0.0.1:1".parse().unwrap(),
+            client_id: "nonce_guess".to_string(),
+            client_secret: None,
+            scopes: vec!["openid".to_string()],
+            groups_claim: "groups".to_string(),
+            role_map: parse_role_map("game-admins=admin"),
+            auto_provision,
+            algorithms: vec![],
+        };
+        let db = temp_db();
+        let config = AuthConfig {
+            oidc: Some(oidc_config(true)),
+            ..Default::default()
+        };
+        let backend = AuthBackend::new(db.clone(), &config).expect("new backend");
+        let identity = |subject: &str, groups: &[&str]| OidcIdentity {
+            issuer: "http://127.0.0.1:1".to_string(),
+            subject: subject.to_string(),
+            name: Some("admin".to_string()),
+            groups: groups.iter().map(ToString::to_string).collect(),
+        };
+        let oidc = |subject: &str, groups: &[&str], link_to| Credentials::Oidc {
+            identity: identity(subject, groups),
+            link_to,
+        };
+        let admin_role = backend.get_roles().await.expect("roles")[0].clone();
+
+        // first login provisions a player with an unused name and the mapped roles
+        let provisioned = backend
+            .authenticate(oidc("subject-1", &["game-admins"], None))
+            .await
+            .expect("authenticate")
+            .expect("provisioned");
+        assert_eq!(provisioned.name, "admin2");
+        assert_eq!(provisioned.roles, HashSet::from([admin_role.uuid]));
+        assert!(backend
+            .authenticate(password("admin2", ""))
+            .await
+            .expect("authenticate")
+            .is_none());
+
+        // later logins find the linked player and sync the roles with the groups
+        let returning = backend
+            .authenticate(oidc("subject-1", &[], None))
+            .await
+            .expect("authenticate")
+            .expect("linked");
+        assert_eq!(returning.uuid, provisioned.uuid);
+        assert!(returning.roles.is_empty());
+
+        // a logged in player can link another identity, but not one linked to someone else
+        let player = Player {
+            uuid: Uuid::new_v4(),
+            name: "tester".to_string(),
+            password_hash: generate_hash("Test123$"),
+            ..Default::default()
+        };
+        backend.insert_player(&player).await.expect("insert player");
+        let linked = backend
+            .authenticate(oidc("subject-2", &[], Some(player.uuid)))
+            .await
+            .expect("authenticate")
+            .expect("linked");
+        assert_eq!(linked.uuid, player.uuid);
+        assert!(backend
+            .authenticate(oidc("subject-1", &[], Some(player.uuid)))
+            .await
+            .expect("authenticate")
+            .is_none());
+
+        // without auto-provisioning unlinked identities are rejected
+        let config = AuthConfig {
+            oidc: Some(oidc_config(false)),
+            ..Default::default()
+        };
+        let backend = AuthBackend::new(db, &config).expect("new backend");
+        assert!(backend
+            .authenticate(oidc("subject-3", &[], None))
+            .await
+            .expect("authenticate")
+            .is_none());
+        assert!(backend
+            .authenticate(oidc("subject-2", &[], None))
+            .await
+            .expect("authenticate")
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_ldap() {
+        let entry = |user: &str, groups: &[&str]| TestEntry {
+            dn: format!("uid={},ou=people,dc=example,dc=com", user),
+            password: format!("{}-secret", user),
+            groups: groups
+                .iter()
+                .map(|group| format!("cn={},ou=groups,dc=example,dc=com", group))
+                .collect(),
+        };
+        let ldap_config = |url, auto_provision| LdapConfig {
+            url,
+            starttls: false,
+            user_dn: "uid={user},ou=people,dc=example,dc=com".to_string(),
+            search_base: None,
+            user_filter: "(uid={user})".to_string(),
+            groups_attribute: "memberOf".to_string(),
+            role_map: parse_role_map("game-admins=admin"),
+            auto_provision,
+            timeout_secs: 5,
+        };
+        let ldap = |name: &str, password: &str| Credentials::Ldap {
+            name: name.to_string(),
+            password: password.to_string(),
+        };
+        let db = temp_db();
+        let url = serve(vec![entry("alice", &["game-admins"]), entry("admin", &[])]).await;
+        let config = AuthConfig {
+            ldap: Some(ldap_config(url, true)),
+            ..Default::default()
+        };
+        let backend = AuthBackend::new(db.clone(), &config).expect("new backend");
+        let admin_role = backend.get_roles().await.expect("roles")[0].clone();
+
+        // first login provisions a player with the mapped roles
+        let provisioned = backend
+            .authenticate(ldap("alice", "alice-secret"))
+            .await
+            .expect("authenticate")
+            .expect("provisioned");
+        assert_eq!(provisioned.name, "alice");
+        assert_eq!(provisioned.roles, HashSet::from([admin_role.uuid]));
+        assert_eq!(
+            backend
+                .get_player_identities(&provisioned.uuid)
+                .await
+                .expect("identities"),
+            vec!["ldap:uid=alice,ou=people,dc=example,dc=com".to_string()]
+        );
+        let events = backend
+            .audit_log
+            .get_events(AuditFilter {
+                action: Some(AuditAction::RoleChange),
+                subject: Some("alice".to_string()),
+                ..Default::default()
+            })
+            .await
+            .expect("events");
+        assert_eq!(events[0].after.as_deref(), Some("admin (from directory)"));
+        assert!(backend
+            .authenticate(ldap("alice", "wrong"))
+            .await
+            .expect("authenticate")
+            .is_none());
+
+        // directory users don't take over local players with the same name
+        let directory_admin = backend
+            .authenticate(ldap("admin", "admin-secret"))
+            .await
+            .expect("authenticate")
+            .expect("provisioned");
+        assert_eq!(directory_admin.name, "admin2");
+
+        // later logins find the player and sync the roles with the groups
+        let url = serve(vec![entry("alice", &[]), entry("bob", &[])]).await;
+        let config = AuthConfig {
+            ldap: Some(ldap_config(url, false)),
+            ..Default::default()
+        };
+        let backend = AuthBackend::new(db.clone(), &config).expect("new backend");
+        let returning = backend
+            .authenticate(ldap("alice", "alice-secret"))
+            .await
+            .expect("authenticate")
+            .expect("found");
+        assert_eq!(returning.uuid, provisioned.uuid);
+        assert!(returning.roles.is_empty());
+        // without auto-provisioning new directory users are rejected
+        assert!(backend
+            .authenticate(ldap("bob", "bob-secret"))
+            .await
+            .expect("authenticate")
+            .is_none());
+
+        // an unreachable directory is an error, not a rejected login
+        let config = AuthConfig {
+            ldap: Some(ldap_config("ldap://127.0.0.1:1".to_string(), true)),
+            ..Default::default()
+        };
+        let backend = AuthBackend::new(db, &config).expect("new backend");
+        assert!(backend
+            .authenticate(ldap("alice", "alice-secret"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_nostr() {
+        let config = AuthConfig {
+            nostr: Some(NostrConfig {
+                auto_register: true,
+            }),
+            ..Default::default()
+        };
+        let backend = AuthBackend::new(temp_db(), &config).expect("new backend");
+        let pubkey = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
+        let nostr = |link_to| Credentials::Nostr {
+            pubkey: pubkey.to_string(),
+            link_to,
+        };
+
+        // new pubkeys are registered with the npub as name
+        let registered = backend
+            .authenticate(nostr(None))
+            .await
+            .expect("authenticate")
+            .expect("registered");
+        assert_eq!(
+            registered.name,
+            "npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w6"
+        );
+        let returning = backend
+            .authenticate(nostr(None))
+            .await
+            .expect("authenticate")
+            .expect("linked");
+        assert_eq!(returning.uuid, registered.uuid);
+
+        // the pubkey can't be linked to another player
+        assert!(backend
+            .authenticate(nostr(Some(Uuid::new_v4())))
+            .await
+            .expect("authenticate")
+            .is_none());
+
+        // without nostr login configured pubkeys are rejected
+        let backend = AuthBackend {
+            config: AuthConfig::default(),
+            ..backend
+        };
+        assert!(backend
+            .authenticate(nostr(None))
+            .await
+            .expect("authenticate")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lnurl_challenge() {
+        let config = AuthConfig {
+            lnurl: Some(LnurlConfig {
+                auto_register: true,
+                challenge_ttl_secs: 60,
+            }),
+            ..Default::default()
+        };
+        let backend = AuthBackend::new(temp_db(), &config).expect("new backend");
+        let key = "031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f";
+        let challenge = backend
+            .create_lnurl_challenge()
+            .await
+            .expect("create challenge");
+
+        // waiting for the wallet
+        let waiting = backend
+            .take_lnurl_challenge(&challenge.k1)
+            .await
+            .expect("take")
+            .expect("valid challenge");
+        assert_eq!(waiting.linking_key, None);
+
+        // a challenge can only be signed once
+        assert!(backend
+            .sign_lnurl_challenge(&challenge.k1, key)
+            .await
+            .expect("sign"));
+        assert!(!backend
+            .sign_lnurl_challenge(&challenge.k1, key)
+            .await
+            .expect("sign"));
+        assert!(!backend
+            .sign_lnurl_challenge("unknown", key)
+            .await
+            .expect("sign"));
+
+        // and only completes one login
+        let signed = backend
+            .take_lnurl_challenge(&challenge.k1)
+            .await
+            .expect("take")
+            .expect("signed challenge");
+        assert_eq!(signed.linking_key.as_deref(), Some(key));
+        assert_eq!(
+            backend
+                .take_lnurl_challenge(&challenge.k1)
+                .await
+                .expect("take"),
+            None
+        );
+
+        let registered = backend
+            .authenticate(Credentials::Lnurl {
+                key: key.to_string(),
+                link_to: None,
+            })
+            .await
+            .expect("authenticate")
+            .expect("registered");
+        assert_eq!(registered.name, "ln_1b84c5567b12");
+        assert_eq!(
+            backend
+                .get_player_identities(&registered.uuid)
+                .await
+                .expect("identities"),
+            vec![format!("lnurl:{}", key)]
+        );
+    }
 }
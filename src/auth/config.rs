@@ -0,0 +1,559 @@
+use super::types::is_valid_name;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use jsonwebtoken::Algorithm;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use tower_sessions::cookie::Key;
+use tracing::warn;
+
+/// Authentication settings read from the environment at startup.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub admin: AdminConfig,
+    pub login_throttle: LoginThrottleConfig,
+    /// Use the first `X-Forwarded-For` address as the client ip, only enable behind a proxy.
+    pub trust_forwarded_for: bool,
+    /// How long an admin issued password reset link is valid.
+    pub reset_token_ttl_secs: i64,
+    pub registration: RegistrationMode,
+    /// What happens to a player's guesses when they delete their account.
+    pub deleted_guesses: DeletedGuesses,
+    /// Players with the `ChangeTarget` or `AssignAdm` permission must enable two-factor
+    /// authentication before they can use the site.
+    pub require_two_factor: bool,
+    /// The externally visible base url, used to build callback urls.
+    pub public_url: Url,
+    pub oidc: Option<OidcConfig>,
+    pub nostr: Option<NostrConfig>,
+    pub lnurl: Option<LnurlConfig>,
+    pub proxy: Option<ProxyConfig>,
+    pub ldap: Option<LdapConfig>,
+    /// Session cookie signing key, shared by the server replicas. A random key is generated
+    /// at startup if not set, which signs out everyone when the server restarts.
+    pub session_key: Option<Key>,
+}
+
+impl AuthConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let registration = env_parse("NONCE_GUESS_REGISTRATION").unwrap_or_default();
+        Ok(Self {
+            admin: AdminConfig::from_env()?,
+            login_throttle: LoginThrottleConfig::from_env(),
+            trust_forwarded_for: env_parse("NONCE_GUESS_TRUST_FORWARDED_FOR").unwrap_or(false),
+            reset_token_ttl_secs: env_parse("NONCE_GUESS_RESET_TOKEN_TTL_SECS")
+                .unwrap_or(DEFAULT_RESET_TOKEN_TTL_SECS),
+            registration,
+            deleted_guesses: env_parse("NONCE_GUESS_DELETED_GUESSES").unwrap_or_default(),
+            require_two_factor: env_parse("NONCE_GUESS_REQUIRE_2FA").unwrap_or(false),
+            public_url: env_parse("NONCE_GUESS_PUBLIC_URL").unwrap_or_else(default_public_url),
+            oidc: OidcConfig::from_env(registration),
+            nostr: NostrConfig::from_env(),
+            lnurl: LnurlConfig::from_env(),
+            proxy: ProxyConfig::from_env(),
+            ldap: LdapConfig::from_env(),
+            session_key: session_key_from_env(),
+        })
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            admin: Default::default(),
+            login_throttle: Default::default(),
+            trust_forwarded_for: false,
+            reset_token_ttl_secs: DEFAULT_RESET_TOKEN_TTL_SECS,
+            registration: Default::default(),
+            deleted_guesses: Default::default(),
+            require_two_factor: false,
+            public_url: default_public_url(),
+            oidc: None,
+            nostr: None,
+            lnurl: None,
+            proxy: None,
+            ldap: None,
+            session_key: None,
+        }
+    }
+}
+
+fn default_public_url() -> Url {
+    Url::parse("http://localhost:8080").expect("valid url")
+}
+
+const DEFAULT_RESET_TOKEN_TTL_SECS: i64 = 24 * 60 * 60;
+
+// the base64 encoded session key, at least 64 random bytes
+fn session_key_from_env() -> Option<Key> {
+    let session_key = env_secret("NONCE_GUESS_SESSION_KEY")?;
+    STANDARD
+        .decode(session_key)
+        .ok()
+        .and_then(|bytes| Key::try_from(bytes.as_slice()).ok())
+        .or_else(|| {
+            warn!("ignoring invalid NONCE_GUESS_SESSION_KEY, it must be at least 64 base64 encoded bytes");
+            None
+        })
+}
+
+// read a secret from the file named by `{name}_FILE` or else the `{name}` env variable
+pub(crate) fn env_secret(name: &str) -> Option<String> {
+    match std::env::var(format!("{}_FILE", name)) {
+        Ok(file) => std::fs::read_to_string(&file)
+            .map(|secret| secret.trim().to_string())
+            .inspect_err(|e| warn!("failed to read {}_FILE {}: {}", name, file, e))
+            .ok(),
+        Err(_) => std::env::var(name).ok(),
+    }
+    .filter(|secret| !secret.is_empty())
+}
+
+// parse an env variable, warn and ignore it if it is invalid
+pub(crate) fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    value
+        .parse()
+        .inspect_err(|_| warn!("ignoring invalid {}: {}", name, value))
+        .ok()
+}
+
+/// Who can register a new player.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum RegistrationMode {
+    /// Anyone, an invite code is optional.
+    #[default]
+    Open,
+    /// Only with a valid invite code.
+    Invite,
+}
+
+impl FromStr for RegistrationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(RegistrationMode::Open),
+            "invite" => Ok(RegistrationMode::Invite),
+            _ => Err(format!("invalid registration mode: {}", s)),
+        }
+    }
+}
+
+/// Deleted players' guesses policy.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DeletedGuesses {
+    /// Keep the guesses but no longer link them to the player.
+    #[default]
+    Anonymize,
+    /// Remove the guesses.
+    Remove,
+}
+
+impl FromStr for DeletedGuesses {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "anonymize" => Ok(DeletedGuesses::Anonymize),
+            "remove" => Ok(DeletedGuesses::Remove),
+            _ => Err(format!("invalid deleted guesses policy: {}", s)),
+        }
+    }
+}
+
+/// The bootstrap admin account created when the auth tables are empty.
+#[derive(Clone)]
+pub struct AdminConfig {
+    pub name: String,
+    /// If not set a one-time setup password is generated and written to the log.
+    pub password: Option<String>,
+}
+
+impl AdminConfig {
+    /// The admin name must be a valid player name, or the admin couldn't use the forms that
+    /// check it.
+    pub fn from_env() -> Result<Self, String> {
+        let name = std::env::var("NONCE_GUESS_ADMIN_NAME").unwrap_or_else(|_| "admin".to_string());
+        if !is_valid_name(&name) {
+            return Err(format!(
+                "invalid NONCE_GUESS_ADMIN_NAME {:?}, it must be 3 to 20 letters, digits or \
+                 underscores",
+                name
+            ));
+        }
+        let password = env_secret("NONCE_GUESS_ADMIN_PASSWORD");
+        Ok(Self { name, password })
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            name: "admin".to_string(),
+            password: None,
+        }
+    }
+}
+
+// don't leak the admin password into debug logs
+impl std::fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminConfig")
+            .field("name", &self.name)
+            .field("password", &self.password.as_ref().map(|_| "********"))
+            .finish()
+    }
+}
+
+/// Limits on failed login and registration attempts per player name and client ip.
+#[derive(Debug, Clone)]
+pub struct LoginThrottleConfig {
+    /// Failures allowed before any delay is required.
+    pub free_attempts: u32,
+    /// Delay after the first throttled failure, doubled for each further failure.
+    pub base_delay_secs: i64,
+    pub max_delay_secs: i64,
+    /// Failures after which the name or ip is locked out.
+    pub lockout_threshold: u32,
+    pub lockout_secs: i64,
+    /// Quiet time after which the failures are forgotten and pruned.
+    pub reset_secs: i64,
+}
+
+impl LoginThrottleConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            free_attempts: env_parse("NONCE_GUESS_LOGIN_FREE_ATTEMPTS")
+                .unwrap_or(default.free_attempts),
+            base_delay_secs: env_parse("NONCE_GUESS_LOGIN_BASE_DELAY_SECS")
+                .unwrap_or(default.base_delay_secs),
+            max_delay_secs: env_parse("NONCE_GUESS_LOGIN_MAX_DELAY_SECS")
+                .unwrap_or(default.max_delay_secs),
+            lockout_threshold: env_parse("NONCE_GUESS_LOGIN_LOCKOUT_THRESHOLD")
+                .unwrap_or(default.lockout_threshold),
+            lockout_secs: env_parse("NONCE_GUESS_LOGIN_LOCKOUT_SECS")
+                .unwrap_or(default.lockout_secs),
+            reset_secs: env_parse("NONCE_GUESS_LOGIN_RESET_SECS").unwrap_or(default.reset_secs),
+        }
+    }
+}
+
+impl Default for LoginThrottleConfig {
+    fn default() -> Self {
+        Self {
+            free_attempts: 3,
+            base_delay_secs: 2,
+            max_delay_secs: 60,
+            lockout_threshold: 10,
+            lockout_secs: 15 * 60,
+            reset_secs: 60 * 60,
+        }
+    }
+}
+
+/// OpenID Connect identity provider, enabled when an issuer is configured.
+#[derive(Clone)]
+pub struct OidcConfig {
+    /// Name shown on the login button.
+    pub name: String,
+    pub issuer_url: Url,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: Vec<String>,
+    /// Id token claim with the player's IdP groups.
+    pub groups_claim: String,
+    /// IdP group name to [`Role`](super::types::Role) name.
+    pub role_map: HashMap<String, String>,
+    /// Create a new player on the first login of an unlinked IdP subject.
+    pub auto_provision: bool,
+    /// Id token signing algorithms to accept, the asymmetric ones the provider supports if
+    /// empty. HMAC signed tokens are only accepted when listed here.
+    pub algorithms: Vec<Algorithm>,
+}
+
+impl OidcConfig {
+    /// Auto-provisioning is off by default when registration requires an invite code.
+    pub fn from_env(registration: RegistrationMode) -> Option<Self> {
+        let issuer_url = env_parse::<Url>("NONCE_GUESS_OIDC_ISSUER")?;
+        let Ok(client_id) = std::env::var("NONCE_GUESS_OIDC_CLIENT_ID") else {
+            warn!("ignoring NONCE_GUESS_OIDC_ISSUER without NONCE_GUESS_OIDC_CLIENT_ID");
+            return None;
+        };
+        Some(Self {
+            name: std::env::var("NONCE_GUESS_OIDC_NAME").unwrap_or_else(|_| "SSO".to_string()),
+            issuer_url,
+            client_id,
+            client_secret: env_secret("NONCE_GUESS_OIDC_CLIENT_SECRET"),
+            scopes: std::env::var("NONCE_GUESS_OIDC_SCOPES")
+                .unwrap_or_else(|_| "openid profile email".to_string())
+                .split_whitespace()
+                .map(ToString::to_string)
+                .collect(),
+            groups_claim: std::env::var("NONCE_GUESS_OIDC_GROUPS_CLAIM")
+                .unwrap_or_else(|_| "groups".to_string()),
+            role_map: parse_role_map(
+                &std::env::var("NONCE_GUESS_OIDC_ROLE_MAP").unwrap_or_default(),
+            ),
+            auto_provision: env_parse("NONCE_GUESS_OIDC_AUTO_PROVISION")
+                .unwrap_or(registration == RegistrationMode::Open),
+            algorithms: std::env::var("NONCE_GUESS_OIDC_ALGORITHMS")
+                .unwrap_or_default()
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|alg| !alg.is_empty())
+                .filter_map(|alg| {
+                    Algorithm::from_str(alg)
+                        .inspect_err(|_| warn!("ignoring invalid OIDC algorithm {}", alg))
+                        .ok()
+                })
+                .collect(),
+        })
+    }
+}
+
+impl std::fmt::Debug for OidcConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OidcConfig")
+            .field("name", &self.name)
+            .field("issuer_url", &self.issuer_url)
+            .field("client_id", &self.client_id)
+            .field(
+                "client_secret",
+                &self.client_secret.as_ref().map(|_| "********"),
+            )
+            .field("scopes", &self.scopes)
+            .field("groups_claim", &self.groups_claim)
+            .field("role_map", &self.role_map)
+            .field("auto_provision", &self.auto_provision)
+            .field("algorithms", &self.algorithms)
+            .finish()
+    }
+}
+
+/// Nostr NIP-07/NIP-98 login, enabled with `NONCE_GUESS_NOSTR_LOGIN`.
+#[derive(Debug, Clone)]
+pub struct NostrConfig {
+    /// Register a new player named after the npub on the first login of an unlinked pubkey.
+    pub auto_register: bool,
+}
+
+impl NostrConfig {
+    pub fn from_env() -> Option<Self> {
+        env_parse::<bool>("NONCE_GUESS_NOSTR_LOGIN")
+            .filter(|enabled| *enabled)
+            .map(|_| Self {
+                auto_register: env_parse("NONCE_GUESS_NOSTR_AUTO_REGISTER").unwrap_or(false),
+            })
+    }
+}
+
+/// LNURL-auth wallet login, enabled with `NONCE_GUESS_LNURL_LOGIN`.
+#[derive(Debug, Clone)]
+pub struct LnurlConfig {
+    /// Register a new player on the first login of an unlinked wallet key.
+    pub auto_register: bool,
+    /// How long a login QR code is valid.
+    pub challenge_ttl_secs: i64,
+}
+
+impl LnurlConfig {
+    pub fn from_env() -> Option<Self> {
+        env_parse::<bool>("NONCE_GUESS_LNURL_LOGIN")
+            .filter(|enabled| *enabled)
+            .map(|_| Self {
+                auto_register: env_parse("NONCE_GUESS_LNURL_AUTO_REGISTER").unwrap_or(false),
+                challenge_ttl_secs: env_parse("NONCE_GUESS_LNURL_CHALLENGE_TTL_SECS")
+                    .unwrap_or(5 * 60),
+            })
+    }
+}
+
+/// Login with a user header set by an authenticating reverse proxy, enabled by setting the
+/// trusted proxy networks with `NONCE_GUESS_PROXY_TRUSTED_CIDRS`.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Request header with the name of the user the proxy authenticated.
+    pub header: String,
+    /// The header is only trusted on connections from these networks.
+    pub trusted_cidrs: Vec<IpCidr>,
+    /// Register a new player on the first request of an unknown user.
+    pub auto_provision: bool,
+}
+
+impl ProxyConfig {
+    pub fn from_env() -> Option<Self> {
+        let trusted_cidrs = std::env::var("NONCE_GUESS_PROXY_TRUSTED_CIDRS")
+            .ok()?
+            .split(',')
+            .map(str::trim)
+            .filter(|cidr| !cidr.is_empty())
+            .filter_map(|cidr| {
+                IpCidr::from_str(cidr)
+                    .inspect_err(|e| warn!("ignoring NONCE_GUESS_PROXY_TRUSTED_CIDRS entry: {}", e))
+                    .ok()
+            })
+            .collect::<Vec<IpCidr>>();
+        if trusted_cidrs.is_empty() {
+            warn!("proxy header login disabled, no valid NONCE_GUESS_PROXY_TRUSTED_CIDRS");
+            return None;
+        }
+        Some(Self {
+            header: std::env::var("NONCE_GUESS_PROXY_USER_HEADER")
+                .unwrap_or_else(|_| "X-Forwarded-User".to_string()),
+            trusted_cidrs,
+            auto_provision: env_parse("NONCE_GUESS_PROXY_AUTO_PROVISION").unwrap_or(true),
+        })
+    }
+
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted_cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// LDAP simple bind login with the login form, enabled when a directory url is configured.
+/// Local password accounts are tried first, so they keep working if the directory is down.
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+    /// `ldap://` or `ldaps://` directory url.
+    pub url: String,
+    /// Upgrade `ldap://` connections with StartTLS.
+    pub starttls: bool,
+    /// Bind DN of a login name, `{user}` is replaced with the escaped name.
+    pub user_dn: String,
+    /// Base to search the player's entry under after binding, the bind DN itself if not set.
+    pub search_base: Option<String>,
+    /// Filter for the player's entry under the search base, `{user}` is replaced with the
+    /// escaped name.
+    pub user_filter: String,
+    /// Entry attribute with the DNs of the player's directory groups.
+    pub groups_attribute: String,
+    /// Directory group common name to [`Role`](super::types::Role) name.
+    pub role_map: HashMap<String, String>,
+    /// Create a new player on the first login of a directory user.
+    pub auto_provision: bool,
+    pub timeout_secs: u64,
+}
+
+impl LdapConfig {
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("NONCE_GUESS_LDAP_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        let Some(user_dn) = std::env::var("NONCE_GUESS_LDAP_USER_DN")
+            .ok()
+            .filter(|user_dn| user_dn.contains("{user}"))
+        else {
+            warn!("ignoring NONCE_GUESS_LDAP_URL without a NONCE_GUESS_LDAP_USER_DN containing {{user}}");
+            return None;
+        };
+        Some(Self {
+            url,
+            starttls: env_parse("NONCE_GUESS_LDAP_STARTTLS").unwrap_or(false),
+            user_dn,
+            search_base: std::env::var("NONCE_GUESS_LDAP_SEARCH_BASE")
+                .ok()
+                .filter(|search_base| !search_base.is_empty()),
+            user_filter: std::env::var("NONCE_GUESS_LDAP_USER_FILTER")
+                .unwrap_or_else(|_| "(uid={user})".to_string()),
+            groups_attribute: std::env::var("NONCE_GUESS_LDAP_GROUPS_ATTRIBUTE")
+                .unwrap_or_else(|_| "memberOf".to_string()),
+            role_map: parse_role_map(
+                &std::env::var("NONCE_GUESS_LDAP_ROLE_MAP").unwrap_or_default(),
+            ),
+            auto_provision: env_parse("NONCE_GUESS_LDAP_AUTO_PROVISION").unwrap_or(true),
+            timeout_secs: env_parse("NONCE_GUESS_LDAP_TIMEOUT_SECS").unwrap_or(10),
+        })
+    }
+}
+
+/// An IPv4 or IPv6 network in `address/prefix` notation, a bare address is a single host.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IpCidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual stack listener show up as mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address =
+            IpAddr::from_str(address.trim()).map_err(|_| format!("invalid address: {}", s))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or(format!("invalid prefix: {}", s))?,
+            None => max_prefix,
+        };
+        Ok(Self { address, prefix })
+    }
+}
+
+/// Parse a `group=role,other group=role` list of group to role names.
+pub fn parse_role_map(role_map: &str) -> HashMap<String, String> {
+    role_map
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(group, role)| (group.trim().to_string(), role.trim().to_string()))
+        .filter(|(group, role)| !group.is_empty() && !role.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::IpCidr;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_ip_cidr() {
+        let ip = |ip: &str| IpAddr::from_str(ip).unwrap();
+        let private = IpCidr::from_str("10.0.0.0/8").unwrap();
+        assert!(private.contains(ip("10.1.2.3")));
+        assert!(private.contains(ip("::ffff:10.1.2.3")));
+        assert!(!private.contains(ip("11.0.0.1")));
+        assert!(!private.contains(ip("::1")));
+        let host = IpCidr::from_str("127.0.0.1").unwrap();
+        assert!(host.contains(ip("127.0.0.1")));
+        assert!(!host.contains(ip("127.0.0.2")));
+        let any = IpCidr::from_str("0.0.0.0/0").unwrap();
+        assert!(any.contains(ip("192.168.1.1")));
+        let v6 = IpCidr::from_str("fd00::/8").unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+        assert!(IpCidr::from_str("10.0.0.0/33").is_err());
+        assert!(IpCidr::from_str("proxy/8").is_err());
+    }
+}
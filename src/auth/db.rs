@@ -1,239 +1,801 @@
-use crate::auth::types::{Permission, Player, Role};
+use crate::audit::backend::AuditLog;
+use crate::audit::types::name_collision_event;
+use crate::auth::lnurl::LnurlChallenge;
+use crate::auth::store::{AuthRead, AuthWrite};
+use crate::auth::totp::Totp;
+use crate::auth::types::{
+    hash_token, index_player_names, name_key, ApiToken, IdentityKey, InviteCode, LoginFailures,
+    Player, ResetToken, Role, ThrottleKey,
+};
+use crate::backup::copy_table;
+use crate::encoding::{
+    find_undecodable, quarantine_table, reencrypt_table, Encoded, ReencryptProgress, Versioned,
+};
+use crate::encryption::Keyring;
+use crate::storage::{RowIter, UndecodableRecord};
 use crate::types::{InternalError, UuidKey};
-use password_auth::generate_hash;
+use chrono::Utc;
 use redb::{
-    Database, ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition, TypeName,
-    Value, WriteTransaction,
+    AccessGuard, Key, ReadOnlyTable, ReadTransaction, ReadableTable, ReadableTableMetadata,
+    TableDefinition, WriteTransaction,
 };
-use std::collections::HashSet;
-use std::sync::Arc;
 use tracing::info;
 use uuid::Uuid;
 
-const UUID_PLAYER: TableDefinition<UuidKey, Player> = TableDefinition::new("auth_uuid_player");
+const UUID_PLAYER: TableDefinition<UuidKey, Encoded<Player>> =
+    TableDefinition::new("auth_uuid_player");
+// player uuids by the `name_key` of their names
 const NAME_UUID: TableDefinition<String, UuidKey> = TableDefinition::new("auth_player_name_uuid");
-const UUID_ROLE: TableDefinition<UuidKey, Role> = TableDefinition::new("auth_uuid_role");
+const UUID_ROLE: TableDefinition<UuidKey, Encoded<Role>> = TableDefinition::new("auth_uuid_role");
+const KEY_LOGIN_FAILURES: TableDefinition<String, Encoded<LoginFailures>> =
+    TableDefinition::new("auth_key_login_failures");
+const HASH_RESET_TOKEN: TableDefinition<String, Encoded<ResetToken>> =
+    TableDefinition::new("auth_hash_reset_token");
+const CODE_INVITE: TableDefinition<String, Encoded<InviteCode>> =
+    TableDefinition::new("auth_code_invite");
+const IDENTITY_UUID: TableDefinition<String, UuidKey> = TableDefinition::new("auth_identity_uuid");
+const K1_LNURL_CHALLENGE: TableDefinition<String, Encoded<LnurlChallenge>> =
+    TableDefinition::new("auth_k1_lnurl_challenge");
+const HASH_API_TOKEN: TableDefinition<String, Encoded<ApiToken>> =
+    TableDefinition::new("auth_hash_api_token");
+const UUID_TOTP: TableDefinition<UuidKey, Encoded<Totp>> = TableDefinition::new("auth_uuid_totp");
 
-#[derive(Debug, Clone)]
-pub struct AuthDb(Arc<Database>);
+pub struct AuthDb;
 
 impl AuthDb {
-    pub fn new(db: Arc<Database>) -> Result<Self, InternalError> {
-        let mut write_txn = db.begin_write()?;
-        Self::init(&mut write_txn)?;
-        write_txn.commit()?;
-        Ok(AuthDb(db))
-    }
-
-    fn init(write_txn: &mut WriteTransaction) -> Result<(), InternalError> {
-        let tables_empty = {
-            let uuid_role = write_txn.open_table(UUID_ROLE)?;
-            let uuid_player = write_txn.open_table(UUID_PLAYER)?;
-            let name_uuid = write_txn.open_table(NAME_UUID)?;
-            info!(
-                "opened tables: {}, {}, {}",
-                UUID_ROLE, UUID_PLAYER, NAME_UUID
-            );
-            uuid_role.is_empty()? && uuid_player.is_empty()? && name_uuid.is_empty()?
-        };
-        // if all tables are empty, insert admin user and admin role
-        if tables_empty {
-            let role_uuid = Uuid::new_v4();
-            let admin_role = Role {
-                uuid: role_uuid,
-                name: "admin".to_string(),
-                permissions: [Permission::AssignAdm, Permission::ChangeTarget].into(),
-            };
-            let password_hash = generate_hash("aBcD123$");
-            let mut roles = HashSet::new();
-            roles.insert(role_uuid);
-            let admin = Player {
-                uuid: Uuid::new_v4(),
-                name: "admin".to_string(),
-                password_hash,
-                permissions: Default::default(),
-                roles,
-                ..Default::default()
+    /// Make sure the auth tables exist, moving records that can't be decoded out of the way
+    /// before they are read.
+    pub fn init(write_txn: &mut WriteTransaction) -> Result<(), InternalError> {
+        quarantine_table(write_txn, UUID_PLAYER)?;
+        quarantine_table(write_txn, UUID_ROLE)?;
+        quarantine_table(write_txn, KEY_LOGIN_FAILURES)?;
+        quarantine_table(write_txn, HASH_RESET_TOKEN)?;
+        quarantine_table(write_txn, CODE_INVITE)?;
+        quarantine_table(write_txn, K1_LNURL_CHALLENGE)?;
+        quarantine_table(write_txn, HASH_API_TOKEN)?;
+        quarantine_table(write_txn, UUID_TOTP)?;
+        write_txn.open_table(UUID_ROLE)?;
+        write_txn.open_table(UUID_PLAYER)?;
+        write_txn.open_table(NAME_UUID)?;
+        write_txn.open_table(KEY_LOGIN_FAILURES)?;
+        write_txn.open_table(HASH_RESET_TOKEN)?;
+        write_txn.open_table(CODE_INVITE)?;
+        write_txn.open_table(IDENTITY_UUID)?;
+        write_txn.open_table(K1_LNURL_CHALLENGE)?;
+        write_txn.open_table(HASH_API_TOKEN)?;
+        write_txn.open_table(UUID_TOTP)?;
+        info!(
+            "opened tables: {}, {}, {}, {}, {}, {}, {}, {}, {}, {}",
+            UUID_ROLE,
+            UUID_PLAYER,
+            NAME_UUID,
+            KEY_LOGIN_FAILURES,
+            HASH_RESET_TOKEN,
+            CODE_INVITE,
+            IDENTITY_UUID,
+            K1_LNURL_CHALLENGE,
+            HASH_API_TOKEN,
+            UUID_TOTP
+        );
+        Ok(())
+    }
+
+    /// Rebuild the player name index by `name_key`, players whose names collide are renamed and
+    /// each rename is recorded in the audit log.
+    /// Records that can't be decoded are left to be quarantined when the tables are opened.
+    pub fn index_player_names(write_txn: &WriteTransaction) -> Result<(), InternalError> {
+        let mut uuid_player = write_txn.open_table(UUID_PLAYER)?;
+        let players = uuid_player
+            .iter()?
+            .filter_map(|result| match result {
+                Ok((uuid_ag, value_ag)) => value_ag
+                    .value()
+                    .decode_keyed(uuid_ag.value().0.as_bytes())
+                    .ok()
+                    .map(Ok),
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<Result<Vec<Player>, _>>()?;
+        let (index, collisions) = index_player_names(players);
+        let mut name_uuid = write_txn.open_table(NAME_UUID)?;
+        name_uuid.retain(|_, _| false)?;
+        for (key, uuid) in index {
+            name_uuid.insert(key, UuidKey(uuid))?;
+        }
+        for collision in collisions {
+            let player = &collision.player;
+            uuid_player.insert(
+                UuidKey(player.uuid),
+                Encoded::keyed(player.uuid.as_bytes(), player),
+            )?;
+            AuditLog::insert_event(write_txn, &name_collision_event(&collision))?;
+        }
+        Ok(())
+    }
+
+    /// Key the invite codes by their hash, they were stored by the plain code before.
+    pub fn hash_invite_codes(write_txn: &WriteTransaction) -> Result<(), InternalError> {
+        let mut code_invite = write_txn.open_table(CODE_INVITE)?;
+        let invite_codes = code_invite
+            .iter()?
+            .filter_map(|result| match result {
+                Ok((code_ag, value_ag)) => value_ag
+                    .value()
+                    .decode_keyed(code_ag.value().as_bytes())
+                    .ok()
+                    .map(Ok),
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<Result<Vec<InviteCode>, _>>()?;
+        code_invite.retain(|_, _| false)?;
+        for invite_code in invite_codes {
+            let invite_code = InviteCode {
+                code_hash: hash_token(&invite_code.code_hash),
+                ..invite_code
             };
-            AuthDb::insert_role(write_txn, admin_role)?;
-            AuthDb::insert_player(write_txn, admin)?;
-            info!("inserted admin_role and admin user");
+            code_invite.insert(
+                invite_code.code_hash.clone(),
+                Encoded::keyed(invite_code.code_hash.as_bytes(), &invite_code),
+            )?;
         }
         Ok(())
     }
 
-    pub fn begin_write(&self) -> Result<WriteTransaction, InternalError> {
-        Ok(self.0.begin_write()?)
+    /// The auth records that can't be decoded.
+    pub fn find_undecodable(
+        read_txn: &ReadTransaction,
+    ) -> Result<Vec<UndecodableRecord>, InternalError> {
+        let mut records = find_undecodable(read_txn, UUID_PLAYER)?;
+        records.extend(find_undecodable(read_txn, UUID_ROLE)?);
+        records.extend(find_undecodable(read_txn, KEY_LOGIN_FAILURES)?);
+        records.extend(find_undecodable(read_txn, HASH_RESET_TOKEN)?);
+        records.extend(find_undecodable(read_txn, CODE_INVITE)?);
+        records.extend(find_undecodable(read_txn, K1_LNURL_CHALLENGE)?);
+        records.extend(find_undecodable(read_txn, HASH_API_TOKEN)?);
+        records.extend(find_undecodable(read_txn, UUID_TOTP)?);
+        Ok(records)
     }
 
-    pub fn begin_read(&self) -> Result<ReadTransaction, InternalError> {
-        Ok(self.0.begin_read()?)
+    /// Re-encrypt up to `limit` auth records that aren't sealed with the keyring's primary key.
+    pub fn reencrypt(
+        write_txn: &WriteTransaction,
+        keyring: Option<&Keyring>,
+        progress: &mut ReencryptProgress,
+        limit: usize,
+    ) -> Result<usize, InternalError> {
+        let mut count = reencrypt_table(write_txn, UUID_PLAYER, keyring, progress, limit)?;
+        count += reencrypt_table(write_txn, UUID_ROLE, keyring, progress, limit - count)?;
+        count += reencrypt_table(
+            write_txn,
+            KEY_LOGIN_FAILURES,
+            keyring,
+            progress,
+            limit - count,
+        )?;
+        count += reencrypt_table(
+            write_txn,
+            HASH_RESET_TOKEN,
+            keyring,
+            progress,
+            limit - count,
+        )?;
+        count += reencrypt_table(write_txn, CODE_INVITE, keyring, progress, limit - count)?;
+        count += reencrypt_table(
+            write_txn,
+            K1_LNURL_CHALLENGE,
+            keyring,
+            progress,
+            limit - count,
+        )?;
+        count += reencrypt_table(write_txn, HASH_API_TOKEN, keyring, progress, limit - count)?;
+        count += reencrypt_table(write_txn, UUID_TOTP, keyring, progress, limit - count)?;
+        Ok(count)
     }
 
-    pub fn insert_player(
-        write_txn: &mut WriteTransaction,
-        player: Player,
-    ) -> Result<Option<Player>, InternalError> {
-        let name_uuid = &mut write_txn.open_table(NAME_UUID)?;
+    /// Copy the auth tables into another database.
+    pub fn copy_tables(
+        read_txn: &ReadTransaction,
+        write_txn: &WriteTransaction,
+    ) -> Result<(), InternalError> {
+        copy_table(read_txn, write_txn, UUID_PLAYER)?;
+        copy_table(read_txn, write_txn, NAME_UUID)?;
+        copy_table(read_txn, write_txn, UUID_ROLE)?;
+        copy_table(read_txn, write_txn, KEY_LOGIN_FAILURES)?;
+        copy_table(read_txn, write_txn, HASH_RESET_TOKEN)?;
+        copy_table(read_txn, write_txn, CODE_INVITE)?;
+        copy_table(read_txn, write_txn, IDENTITY_UUID)?;
+        copy_table(read_txn, write_txn, K1_LNURL_CHALLENGE)?;
+        copy_table(read_txn, write_txn, HASH_API_TOKEN)?;
+        copy_table(read_txn, write_txn, UUID_TOTP)
+    }
+}
+
+// the decoded value of the table entry with the key bytes `key`, if any
+fn decode<T: Versioned>(
+    key: &[u8],
+    entry: Option<AccessGuard<'_, Encoded<T>>>,
+) -> Result<Option<T>, InternalError> {
+    Ok(entry.map(|ag| ag.value().decode_keyed(key)).transpose()?)
+}
+
+// all decoded values of a table
+fn decode_all<K: Key + 'static, T: Versioned + 'static>(
+    table: &impl ReadableTable<K, Encoded<T>>,
+) -> Result<Vec<T>, InternalError> {
+    table
+        .iter()?
+        .map(|result| {
+            result.map_err(Into::into).and_then(|(key_ag, value_ag)| {
+                let key_bytes = K::as_bytes(&key_ag.value()).as_ref().to_vec();
+                Ok(value_ag.value().decode_keyed(&key_bytes)?)
+            })
+        })
+        .collect::<Result<Vec<T>, InternalError>>()
+}
+
+// all decoded values of a table, read as the iterator advances
+fn iter_all<T: Versioned + 'static>(
+    table: ReadOnlyTable<UuidKey, Encoded<T>>,
+) -> Result<RowIter<'static, T>, InternalError> {
+    let rows = table.range::<UuidKey>(..)?.map(|result| {
+        result.map_err(Into::into).and_then(|(uuid_ag, value_ag)| {
+            Ok(value_ag
+                .value()
+                .decode_keyed(uuid_ag.value().0.as_bytes())?)
+        })
+    });
+    Ok(Box::new(rows))
+}
+
+impl AuthRead for ReadTransaction {
+    fn get_player_by_uuid(&self, uuid: Uuid) -> Result<Option<Player>, InternalError> {
+        let uuid_player = self.open_table(UUID_PLAYER)?;
+        let value = decode(uuid.as_bytes(), uuid_player.get(&UuidKey(uuid))?)?;
+        Ok(value)
+    }
+
+    fn get_player_by_name(&self, name: &str) -> Result<Option<Player>, InternalError> {
+        let name_player_uuid = self.open_table(NAME_UUID)?;
+        let player = name_player_uuid
+            .get(name_key(name))?
+            .map(|ag| ag.value().0)
+            .map(|uuid| self.get_player_by_uuid(uuid))
+            .transpose()?
+            .flatten();
+        Ok(player)
+    }
+
+    fn get_players(&self) -> Result<Vec<Player>, InternalError> {
+        decode_all(&self.open_table(UUID_PLAYER)?)
+    }
+
+    fn get_player_names(&self) -> Result<Vec<(String, Uuid)>, InternalError> {
+        let name_uuid = self.open_table(NAME_UUID)?;
         name_uuid
-            .insert(&player.name, &UuidKey(player.uuid))
+            .iter()?
+            .map(|result| {
+                result
+                    .map(|(key_ag, uuid_ag)| (key_ag.value(), uuid_ag.value().0))
+                    .map_err(Into::into)
+            })
+            .collect()
+    }
+
+    fn iter_players(&self) -> Result<RowIter<'_, Player>, InternalError> {
+        iter_all(self.open_table(UUID_PLAYER)?)
+    }
+
+    fn get_role_by_uuid(&self, uuid: Uuid) -> Result<Option<Role>, InternalError> {
+        let uuid_role = self.open_table(UUID_ROLE)?;
+        let value = decode(uuid.as_bytes(), uuid_role.get(UuidKey(uuid))?)?;
+        Ok(value)
+    }
+
+    fn get_roles(&self) -> Result<Vec<Role>, InternalError> {
+        decode_all(&self.open_table(UUID_ROLE)?)
+    }
+
+    fn iter_roles(&self) -> Result<RowIter<'_, Role>, InternalError> {
+        iter_all(self.open_table(UUID_ROLE)?)
+    }
+
+    fn get_login_failures(
+        &self,
+        key: &ThrottleKey,
+    ) -> Result<Option<LoginFailures>, InternalError> {
+        let key_login_failures = self.open_table(KEY_LOGIN_FAILURES)?;
+        let key = key.to_string();
+        let value = decode(key.as_bytes(), key_login_failures.get(&key)?)?;
+        Ok(value)
+    }
+
+    fn get_all_login_failures(&self) -> Result<Vec<(String, LoginFailures)>, InternalError> {
+        let key_login_failures = self.open_table(KEY_LOGIN_FAILURES)?;
+        key_login_failures
+            .iter()?
+            .map(|result| {
+                result
+                    .map_err(Into::into)
+                    .and_then(|(key_ag, failures_ag)| {
+                        let key = key_ag.value();
+                        let failures = failures_ag.value().decode_keyed(key.as_bytes())?;
+                        Ok((key, failures))
+                    })
+            })
+            .collect::<Result<Vec<(String, LoginFailures)>, InternalError>>()
+    }
+
+    fn get_reset_token(&self, token_hash: String) -> Result<Option<ResetToken>, InternalError> {
+        let hash_reset_token = self.open_table(HASH_RESET_TOKEN)?;
+        let value = decode(token_hash.as_bytes(), hash_reset_token.get(&token_hash)?)?;
+        Ok(value)
+    }
+
+    fn get_invite_codes(&self) -> Result<Vec<InviteCode>, InternalError> {
+        decode_all(&self.open_table(CODE_INVITE)?)
+    }
+
+    fn get_player_identities(&self, uuid: Uuid) -> Result<Vec<String>, InternalError> {
+        let identity_uuid = self.open_table(IDENTITY_UUID)?;
+        identity_uuid
+            .iter()?
+            .filter_map(|result| {
+                result
+                    .map(|(identity_ag, uuid_ag)| {
+                        (uuid_ag.value().0 == uuid).then(|| identity_ag.value())
+                    })
+                    .map_err(Into::into)
+                    .transpose()
+            })
+            .collect::<Result<Vec<String>, InternalError>>()
+    }
+
+    fn get_lnurl_challenge(&self, k1: &str) -> Result<Option<LnurlChallenge>, InternalError> {
+        let k1_challenge = self.open_table(K1_LNURL_CHALLENGE)?;
+        let value = decode(k1.as_bytes(), k1_challenge.get(k1.to_string())?)?;
+        Ok(value)
+    }
+
+    fn get_totp(&self, uuid: Uuid) -> Result<Option<Totp>, InternalError> {
+        let uuid_totp = self.open_table(UUID_TOTP)?;
+        let value = decode(uuid.as_bytes(), uuid_totp.get(&UuidKey(uuid))?)?;
+        Ok(value)
+    }
+
+    fn get_player_api_tokens(&self, uuid: Uuid) -> Result<Vec<ApiToken>, InternalError> {
+        let hash_api_token = self.open_table(HASH_API_TOKEN)?;
+        hash_api_token
+            .iter()?
+            .filter_map(|result| {
+                result
+                    .map_err(Into::into)
+                    .and_then(|(hash_ag, api_token_ag)| {
+                        let api_token = api_token_ag
+                            .value()
+                            .decode_keyed(hash_ag.value().as_bytes())?;
+                        Ok((api_token.player == uuid).then_some(api_token))
+                    })
+                    .transpose()
+            })
+            .collect::<Result<Vec<ApiToken>, InternalError>>()
+    }
+}
+
+impl AuthWrite for WriteTransaction {
+    fn insert_player(&mut self, player: Player) -> Result<Option<Player>, InternalError> {
+        self.check_name_for_update(&player.name, player.uuid)?;
+        let name_uuid = &mut self.open_table(NAME_UUID)?;
+        name_uuid
+            .insert(name_key(&player.name), &UuidKey(player.uuid))
             .map_err(Into::<InternalError>::into)?;
 
-        let uuid_player = &mut write_txn.open_table(UUID_PLAYER)?;
-        uuid_player
-            .insert(&UuidKey(player.uuid), player.clone())
-            .map(|opt| opt.map(|ag| ag.value()))
-            .map_err(Into::into)
+        let mut uuid_player = self.open_table(UUID_PLAYER)?;
+        let value = decode(
+            player.uuid.as_bytes(),
+            uuid_player.insert(
+                &UuidKey(player.uuid),
+                &Encoded::keyed(player.uuid.as_bytes(), &player),
+            )?,
+        )?;
+        Ok(value)
     }
 
-    pub fn change_player(
-        write_txn: &mut WriteTransaction,
+    fn change_player(
+        &mut self,
         orig_player: Player,
         new_player: Player,
     ) -> Result<Option<Player>, InternalError> {
         if orig_player != new_player {
             if orig_player.name != new_player.name {
-                let name_uuid = &mut write_txn.open_table(NAME_UUID)?;
-                name_uuid.remove(&orig_player.name)?;
+                self.check_name_for_update(&new_player.name, orig_player.uuid)?;
+                let name_uuid = &mut self.open_table(NAME_UUID)?;
+                name_uuid.remove(name_key(&orig_player.name))?;
                 name_uuid
-                    .insert(&new_player.name, &UuidKey(orig_player.uuid))
+                    .insert(name_key(&new_player.name), &UuidKey(orig_player.uuid))
                     .map_err(Into::<InternalError>::into)?;
             }
-            let uuid_player = &mut write_txn.open_table(UUID_PLAYER)?;
-            uuid_player
-                .insert(&UuidKey(orig_player.uuid), new_player.clone())
-                .map(|opt| opt.map(|ag| ag.value()))
-                .map_err(Into::into)
+            let mut uuid_player = self.open_table(UUID_PLAYER)?;
+            let key = orig_player.uuid.as_bytes();
+            let value = decode(
+                key,
+                uuid_player.insert(
+                    &UuidKey(orig_player.uuid),
+                    &Encoded::keyed(key, &new_player),
+                )?,
+            )?;
+            Ok(value)
         } else {
             Ok(None)
         }
     }
 
-    pub fn get_player_by_uuid(
-        read_txn: &ReadTransaction,
-        uuid_key: UuidKey,
-    ) -> Result<Option<Player>, InternalError> {
-        let uuid_player = read_txn.open_table(UUID_PLAYER)?;
-        let player = uuid_player.get(&uuid_key)?.map(|ag| ag.value());
+    fn remove_player(&mut self, uuid: Uuid) -> Result<Option<Player>, InternalError> {
+        let uuid_key = UuidKey(uuid);
+        let player = {
+            let mut uuid_player = self.open_table(UUID_PLAYER)?;
+            let player = decode(uuid.as_bytes(), uuid_player.remove(&uuid_key)?)?;
+            player
+        };
+        if let Some(player) = &player {
+            let mut name_uuid = self.open_table(NAME_UUID)?;
+            name_uuid.remove(name_key(&player.name))?;
+            drop(name_uuid);
+            // failures of the freed name would throttle the next player who takes it
+            self.remove_login_failures_if(&|key, _| ThrottleKey::is_for_name(key, &player.name))?;
+        }
+        let mut identity_uuid = self.open_table(IDENTITY_UUID)?;
+        identity_uuid.retain(|_, player_uuid| player_uuid != uuid_key)?;
+        let mut hash_reset_token = self.open_table(HASH_RESET_TOKEN)?;
+        hash_reset_token.retain(|hash, reset_token| {
+            !matches!(reset_token.decode_keyed(hash.as_bytes()), Ok(reset_token) if reset_token.player == uuid)
+        })?;
+        let mut hash_api_token = self.open_table(HASH_API_TOKEN)?;
+        hash_api_token.retain(|hash, api_token| {
+            !matches!(api_token.decode_keyed(hash.as_bytes()), Ok(api_token) if api_token.player == uuid)
+        })?;
+        let mut uuid_totp = self.open_table(UUID_TOTP)?;
+        uuid_totp.remove(&uuid_key)?;
         Ok(player)
     }
 
-    pub fn get_player_by_name(
-        read_txn: &ReadTransaction,
-        name: &str,
-    ) -> Result<Option<Player>, InternalError> {
-        let name_player_uuid = read_txn.open_table(NAME_UUID)?;
-        let player = name_player_uuid
-            .get(name.to_string())?
-            .map(|ag| ag.value().0)
-            .map(|uuid| AuthDb::get_player_by_uuid(read_txn, UuidKey(uuid)))
-            .transpose()?
-            .flatten();
-        Ok(player)
+    fn get_player_for_update(&self, uuid: Uuid) -> Result<Option<Player>, InternalError> {
+        let uuid_player = self.open_table(UUID_PLAYER)?;
+        let value = decode(uuid.as_bytes(), uuid_player.get(&UuidKey(uuid))?)?;
+        Ok(value)
     }
 
-    pub fn get_players(read_txn: &ReadTransaction) -> Result<Vec<Player>, InternalError> {
-        let uuid_player = read_txn.open_table(UUID_PLAYER)?;
-        uuid_player
-            .iter()?
-            .map(|result| {
-                result
-                    .map(|(_, player_ag)| player_ag.value())
-                    .map_err(Into::into)
-            })
-            .collect::<Result<Vec<Player>, InternalError>>()
-    }
-
-    pub fn insert_role(
-        write_txn: &mut WriteTransaction,
-        role: Role,
-    ) -> Result<Option<Role>, InternalError> {
-        let uuid_key = UuidKey(role.uuid);
-        let mut uuid_role = write_txn.open_table(UUID_ROLE)?;
-        uuid_role
-            .insert(&uuid_key, &role)
-            .map(|opt| opt.map(|ag| ag.value()))
-            .map_err(Into::into)
+    fn get_players_for_update(&self) -> Result<Vec<Player>, InternalError> {
+        decode_all(&self.open_table(UUID_PLAYER)?)
     }
 
-    pub fn get_role_by_uuid(
-        read_txn: &ReadTransaction,
-        uuid_key: UuidKey,
-    ) -> Result<Option<Role>, InternalError> {
-        let name_role = read_txn.open_table(UUID_ROLE)?;
-        name_role
-            .get(uuid_key)
-            .map(|opt| opt.map(|ag| ag.value()))
+    fn get_name_key_for_update(&self, key: &str) -> Result<Option<Uuid>, InternalError> {
+        let name_uuid = self.open_table(NAME_UUID)?;
+        name_uuid
+            .get(key.to_string())
+            .map(|opt| opt.map(|ag| ag.value().0))
             .map_err(Into::into)
     }
 
-    pub fn get_roles(read_txn: &ReadTransaction) -> Result<Vec<Role>, InternalError> {
-        let uuid_role = read_txn.open_table(UUID_ROLE)?;
-        uuid_role
-            .iter()?
-            .map(|result| {
-                result
-                    .map(|(_, role_ag)| role_ag.value())
-                    .map_err(Into::into)
-            })
-            .collect::<Result<Vec<Role>, InternalError>>()
+    fn repair_player_name(&mut self, key: &str, uuid: Option<Uuid>) -> Result<(), InternalError> {
+        let mut name_uuid = self.open_table(NAME_UUID)?;
+        match uuid {
+            Some(uuid) => name_uuid.insert(key.to_string(), UuidKey(uuid))?,
+            None => name_uuid.remove(key.to_string())?,
+        };
+        Ok(())
     }
-}
 
-impl Value for Player {
-    type SelfType<'a> = Player;
-    type AsBytes<'a> = Vec<u8>;
+    fn is_empty_for_update(&self) -> Result<bool, InternalError> {
+        let uuid_role = self.open_table(UUID_ROLE)?;
+        let uuid_player = self.open_table(UUID_PLAYER)?;
+        let name_uuid = self.open_table(NAME_UUID)?;
+        Ok(uuid_role.is_empty()? && uuid_player.is_empty()? && name_uuid.is_empty()?)
+    }
 
-    fn fixed_width() -> Option<usize> {
-        None
+    fn upsert_players(&mut self, players: &[Player]) -> Result<(), InternalError> {
+        let replaced = players
+            .iter()
+            .map(|player| self.get_player_for_update(player.uuid))
+            .collect::<Result<Vec<Option<Player>>, InternalError>>()?;
+        {
+            let mut name_uuid = self.open_table(NAME_UUID)?;
+            for player in replaced.iter().flatten() {
+                name_uuid.remove(name_key(&player.name))?;
+            }
+        }
+        for player in players {
+            self.insert_player(player.clone())?;
+        }
+        Ok(())
     }
 
-    fn from_bytes<'a>(serialized_player: &'a [u8]) -> Self::SelfType<'a>
-    where
-        Self: 'a,
-    {
-        ciborium::from_reader(serialized_player).unwrap()
+    fn remove_all_players(&mut self) -> Result<usize, InternalError> {
+        let uuid_keys = {
+            let uuid_player = self.open_table(UUID_PLAYER)?;
+            let uuid_keys = uuid_player
+                .iter()?
+                .map(|result| result.map(|(uuid_ag, _)| uuid_ag.value()))
+                .collect::<Result<Vec<UuidKey>, _>>()?;
+            uuid_keys
+        };
+        for uuid_key in &uuid_keys {
+            self.remove_player(uuid_key.0)?;
+        }
+        let mut name_uuid = self.open_table(NAME_UUID)?;
+        name_uuid.retain(|_, _| false)?;
+        Ok(uuid_keys.len())
     }
 
-    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
-    where
-        Self: 'b,
-    {
-        let mut serialized_player = Vec::<u8>::new();
-        ciborium::into_writer(value, &mut serialized_player).expect("Failed to serialize player");
-        serialized_player
+    fn insert_role(&mut self, role: Role) -> Result<Option<Role>, InternalError> {
+        let mut uuid_role = self.open_table(UUID_ROLE)?;
+        let key = role.uuid.as_bytes();
+        let value = decode(
+            key,
+            uuid_role.insert(&UuidKey(role.uuid), &Encoded::keyed(key, &role))?,
+        )?;
+        Ok(value)
     }
 
-    fn type_name() -> TypeName {
-        TypeName::new("nonce_guess::Player")
+    fn get_role_for_update(&self, uuid: Uuid) -> Result<Option<Role>, InternalError> {
+        let uuid_role = self.open_table(UUID_ROLE)?;
+        let value = decode(uuid.as_bytes(), uuid_role.get(UuidKey(uuid))?)?;
+        Ok(value)
+    }
+
+    fn remove_all_roles(&mut self) -> Result<usize, InternalError> {
+        let mut uuid_role = self.open_table(UUID_ROLE)?;
+        let before = uuid_role.len()?;
+        uuid_role.retain(|_, _| false)?;
+        Ok(before as usize)
+    }
+
+    fn get_login_failures_for_update(
+        &self,
+        key: &ThrottleKey,
+    ) -> Result<Option<LoginFailures>, InternalError> {
+        let key_login_failures = self.open_table(KEY_LOGIN_FAILURES)?;
+        let key = key.to_string();
+        let value = decode(key.as_bytes(), key_login_failures.get(&key)?)?;
+        Ok(value)
+    }
+
+    fn insert_login_failures(
+        &mut self,
+        key: &ThrottleKey,
+        login_failures: &LoginFailures,
+    ) -> Result<(), InternalError> {
+        let mut key_login_failures = self.open_table(KEY_LOGIN_FAILURES)?;
+        let key = key.to_string();
+        key_login_failures.insert(&key, &Encoded::keyed(key.as_bytes(), login_failures))?;
+        Ok(())
+    }
+
+    fn remove_login_failures(&mut self, key: &str) -> Result<Option<LoginFailures>, InternalError> {
+        let mut key_login_failures = self.open_table(KEY_LOGIN_FAILURES)?;
+        let value = decode(key.as_bytes(), key_login_failures.remove(key.to_string())?)?;
+        Ok(value)
+    }
+
+    fn remove_login_failures_if(
+        &mut self,
+        remove: &dyn Fn(&str, &LoginFailures) -> bool,
+    ) -> Result<usize, InternalError> {
+        let mut key_login_failures = self.open_table(KEY_LOGIN_FAILURES)?;
+        let mut keys = vec![];
+        for result in key_login_failures.iter()? {
+            let (key_ag, failures_ag) = result?;
+            let key = key_ag.value();
+            if remove(&key, &failures_ag.value().decode_keyed(key.as_bytes())?) {
+                keys.push(key);
+            }
+        }
+        for key in &keys {
+            key_login_failures.remove(key)?;
+        }
+        Ok(keys.len())
     }
-}
 
-impl Value for Role {
-    type SelfType<'a> = Role;
-    type AsBytes<'a> = Vec<u8>;
+    fn insert_reset_token(
+        &mut self,
+        token_hash: String,
+        reset_token: ResetToken,
+    ) -> Result<(), InternalError> {
+        let mut hash_reset_token = self.open_table(HASH_RESET_TOKEN)?;
+        let now = Utc::now();
+        hash_reset_token
+            .retain(|hash, token| {
+                !matches!(token.decode_keyed(hash.as_bytes()), Ok(token) if token.player == reset_token.player || token.expires <= now)
+            })?;
+        let value = Encoded::keyed(token_hash.as_bytes(), &reset_token);
+        hash_reset_token.insert(token_hash, &value)?;
+        Ok(())
+    }
+
+    fn remove_reset_token(
+        &mut self,
+        token_hash: String,
+    ) -> Result<Option<ResetToken>, InternalError> {
+        let mut hash_reset_token = self.open_table(HASH_RESET_TOKEN)?;
+        let value = decode(token_hash.as_bytes(), hash_reset_token.remove(&token_hash)?)?;
+        Ok(value)
+    }
+
+    fn insert_invite_code(
+        &mut self,
+        invite_code: InviteCode,
+    ) -> Result<Option<InviteCode>, InternalError> {
+        let mut code_invite = self.open_table(CODE_INVITE)?;
+        let key = invite_code.code_hash.as_bytes();
+        let value = decode(
+            key,
+            code_invite.insert(
+                invite_code.code_hash.clone(),
+                &Encoded::keyed(key, &invite_code),
+            )?,
+        )?;
+        Ok(value)
+    }
 
-    fn fixed_width() -> Option<usize> {
-        None
+    fn get_invite_code_for_update(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<InviteCode>, InternalError> {
+        let code_invite = self.open_table(CODE_INVITE)?;
+        let value = decode(
+            code_hash.as_bytes(),
+            code_invite.get(code_hash.to_string())?,
+        )?;
+        Ok(value)
     }
 
-    fn from_bytes<'a>(serialized_role: &'a [u8]) -> Self::SelfType<'a>
-    where
-        Self: 'a,
-    {
-        ciborium::from_reader(serialized_role).unwrap()
+    fn remove_invite_code(&mut self, code_hash: &str) -> Result<Option<InviteCode>, InternalError> {
+        let mut code_invite = self.open_table(CODE_INVITE)?;
+        let value = decode(
+            code_hash.as_bytes(),
+            code_invite.remove(code_hash.to_string())?,
+        )?;
+        Ok(value)
     }
 
-    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
-    where
-        Self: 'b,
-    {
-        let mut serialized_role = Vec::<u8>::new();
-        ciborium::into_writer(value, &mut serialized_role).expect("Failed to serialize role");
-        serialized_role
+    fn link_identity(
+        &mut self,
+        identity_key: &IdentityKey,
+        player_uuid: Uuid,
+    ) -> Result<Option<Uuid>, InternalError> {
+        let mut identity_uuid = self.open_table(IDENTITY_UUID)?;
+        identity_uuid
+            .insert(identity_key.to_string(), UuidKey(player_uuid))
+            .map(|opt| opt.map(|ag| ag.value().0))
+            .map_err(Into::into)
+    }
+
+    fn get_identity_for_update(
+        &self,
+        identity_key: &IdentityKey,
+    ) -> Result<Option<Uuid>, InternalError> {
+        let identity_uuid = self.open_table(IDENTITY_UUID)?;
+        identity_uuid
+            .get(identity_key.to_string())
+            .map(|opt| opt.map(|ag| ag.value().0))
+            .map_err(Into::into)
     }
 
-    fn type_name() -> TypeName {
-        TypeName::new("nonce_guess::Role")
+    fn insert_lnurl_challenge(&mut self, challenge: LnurlChallenge) -> Result<(), InternalError> {
+        let mut k1_challenge = self.open_table(K1_LNURL_CHALLENGE)?;
+        let now = Utc::now();
+        k1_challenge.retain(|k1, challenge| {
+            !matches!(challenge.decode_keyed(k1.as_bytes()), Ok(challenge) if challenge.expires <= now)
+        })?;
+        let value = Encoded::keyed(challenge.k1.as_bytes(), &challenge);
+        k1_challenge.insert(challenge.k1.clone(), &value)?;
+        Ok(())
     }
+
+    fn get_lnurl_challenge_for_update(
+        &self,
+        k1: &str,
+    ) -> Result<Option<LnurlChallenge>, InternalError> {
+        let k1_challenge = self.open_table(K1_LNURL_CHALLENGE)?;
+        let value = decode(k1.as_bytes(), k1_challenge.get(k1.to_string())?)?;
+        Ok(value)
+    }
+
+    fn remove_lnurl_challenge(
+        &mut self,
+        k1: &str,
+    ) -> Result<Option<LnurlChallenge>, InternalError> {
+        let mut k1_challenge = self.open_table(K1_LNURL_CHALLENGE)?;
+        let value = decode(k1.as_bytes(), k1_challenge.remove(k1.to_string())?)?;
+        Ok(value)
+    }
+
+    fn insert_api_token(
+        &mut self,
+        token_hash: String,
+        api_token: ApiToken,
+    ) -> Result<(), InternalError> {
+        let mut hash_api_token = self.open_table(HASH_API_TOKEN)?;
+        let value = Encoded::keyed(token_hash.as_bytes(), &api_token);
+        hash_api_token.insert(token_hash, &value)?;
+        Ok(())
+    }
+
+    fn get_api_token_for_update(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<ApiToken>, InternalError> {
+        let hash_api_token = self.open_table(HASH_API_TOKEN)?;
+        let value = decode(
+            token_hash.as_bytes(),
+            hash_api_token.get(token_hash.to_string())?,
+        )?;
+        Ok(value)
+    }
+
+    fn remove_api_token(&mut self, uuid: Uuid, token_uuid: Uuid) -> Result<bool, InternalError> {
+        let mut hash_api_token = self.open_table(HASH_API_TOKEN)?;
+        let before = hash_api_token.len()?;
+        hash_api_token.retain(|hash, api_token| {
+            !matches!(api_token.decode_keyed(hash.as_bytes()), Ok(api_token) if api_token.uuid == token_uuid && api_token.player == uuid)
+        })?;
+        Ok(hash_api_token.len()? < before)
+    }
+
+    fn insert_totp(&mut self, uuid: Uuid, totp: Totp) -> Result<(), InternalError> {
+        let mut uuid_totp = self.open_table(UUID_TOTP)?;
+        uuid_totp.insert(&UuidKey(uuid), &Encoded::keyed(uuid.as_bytes(), &totp))?;
+        Ok(())
+    }
+
+    fn get_totp_for_update(&self, uuid: Uuid) -> Result<Option<Totp>, InternalError> {
+        let uuid_totp = self.open_table(UUID_TOTP)?;
+        let value = decode(uuid.as_bytes(), uuid_totp.get(&UuidKey(uuid))?)?;
+        Ok(value)
+    }
+
+    fn remove_totp(&mut self, uuid: Uuid) -> Result<bool, InternalError> {
+        let mut uuid_totp = self.open_table(UUID_TOTP)?;
+        let removed = uuid_totp.remove(&UuidKey(uuid))?.is_some();
+        Ok(removed)
+    }
+}
+
+impl Versioned for Player {
+    const TYPE_NAME: &'static str = "nonce_guess::Player";
+    const ENCRYPTED: bool = true;
+}
+
+impl Versioned for Role {
+    const TYPE_NAME: &'static str = "nonce_guess::Role";
+    const ENCRYPTED: bool = true;
+}
+
+impl Versioned for LoginFailures {
+    const TYPE_NAME: &'static str = "nonce_guess::LoginFailures";
+    const ENCRYPTED: bool = true;
+}
+
+impl Versioned for ResetToken {
+    const TYPE_NAME: &'static str = "nonce_guess::ResetToken";
+    const ENCRYPTED: bool = true;
+}
+
+impl Versioned for InviteCode {
+    const TYPE_NAME: &'static str = "nonce_guess::InviteCode";
+    const ENCRYPTED: bool = true;
+}
+
+impl Versioned for ApiToken {
+    const TYPE_NAME: &'static str = "nonce_guess::ApiToken";
+    const ENCRYPTED: bool = true;
+}
+
+impl Versioned for Totp {
+    const TYPE_NAME: &'static str = "nonce_guess::Totp";
+    const ENCRYPTED: bool = true;
+}
+
+impl Versioned for LnurlChallenge {
+    const TYPE_NAME: &'static str = "nonce_guess::LnurlChallenge";
+    const ENCRYPTED: bool = true;
 }
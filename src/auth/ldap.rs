@@ -0,0 +1,324 @@
+use super::config::LdapConfig;
+use ldap3::{
+    dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry,
+};
+use std::time::Duration;
+use tracing::warn;
+
+// RFC 4511 invalidCredentials result code, returned for unknown users and wrong passwords
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// LDAP simple bind client, a new connection is opened for every login.
+#[derive(Debug, Clone)]
+pub struct LdapClient {
+    pub config: LdapConfig,
+}
+
+/// A directory user that bound with their password.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LdapUser {
+    pub dn: String,
+    /// Common names of the user's directory groups.
+    pub groups: Vec<String>,
+}
+
+impl LdapClient {
+    pub fn new(config: LdapConfig) -> Self {
+        Self { config }
+    }
+
+    /// Bind as `name` with `password` and read the user's groups, `None` if the directory
+    /// rejected the credentials or the user's entry wasn't found.
+    pub async fn authenticate(
+        &self,
+        name: &str,
+        password: &str,
+    ) -> Result<Option<LdapUser>, LdapError> {
+        // a bind with an empty password is an unauthenticated bind, which directories
+        // accept for any DN
+        if name.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(timeout)
+            .set_starttls(self.config.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+        ldap3::drive!(conn);
+        let bind_dn = self.config.user_dn.replace("{user}", &dn_escape(name));
+        let bind_result = ldap
+            .with_timeout(timeout)
+            .simple_bind(&bind_dn, password)
+            .await?;
+        if bind_result.rc == INVALID_CREDENTIALS {
+            ldap.unbind().await?;
+            return Ok(None);
+        }
+        bind_result.success()?;
+        // the entry is read with the user's own bind
+        let (base, scope, filter) = match &self.config.search_base {
+            Some(search_base) => (
+                search_base.clone(),
+                Scope::Subtree,
+                self.config
+                    .user_filter
+                    .replace("{user}", &ldap_escape(name)),
+            ),
+            None => (bind_dn, Scope::Base, "(objectClass=*)".to_string()),
+        };
+        let (entries, _) = ldap
+            .with_timeout(timeout)
+            .search(
+                &base,
+                scope,
+                &filter,
+                vec![self.config.groups_attribute.as_str()],
+            )
+            .await?
+            .success()?;
+        ldap.unbind().await?;
+        let mut entries = entries.into_iter().map(SearchEntry::construct);
+        let (Some(entry), None) = (entries.next(), entries.next()) else {
+            warn!("no unique directory entry for ldap user {}", name);
+            return Ok(None);
+        };
+        // attribute names are case insensitive
+        let groups = entry
+            .attrs
+            .iter()
+            .filter(|(attribute, _)| attribute.eq_ignore_ascii_case(&self.config.groups_attribute))
+            .flat_map(|(_, values)| values.iter().map(|group| group_name(group)))
+            .collect();
+        Ok(Some(LdapUser {
+            dn: entry.dn,
+            groups,
+        }))
+    }
+}
+
+/// The common name of a group DN, values that aren't a `cn=` DN are used as they are.
+pub fn group_name(group: &str) -> String {
+    // the first RDN ends at the first unescaped comma
+    let mut rdn = String::new();
+    let mut chars = group.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => rdn.extend(chars.next()),
+            ',' => break,
+            c => rdn.push(c),
+        }
+    }
+    match rdn.split_once('=') {
+        Some((attribute, value)) if attribute.trim().eq_ignore_ascii_case("cn") => {
+            value.trim().to_string()
+        }
+        _ => group.to_string(),
+    }
+}
+
+/// An in-process LDAP stand-in for tests that answers simple binds and base object
+/// searches for a fixed set of entries.
+#[cfg(test)]
+pub mod test_directory {
+    use bytes::BytesMut;
+    use ldap3::asn1::{
+        parse_tag, write, ASNTag, Enumerated, Integer, OctetString, Sequence, Set, StructureTag,
+        Tag, TagClass,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[derive(Debug, Clone)]
+    pub struct TestEntry {
+        pub dn: String,
+        pub password: String,
+        pub groups: Vec<String>,
+    }
+
+    /// Serve `entries` on a local port, returning the `ldap://` url.
+    pub async fn serve(entries: Vec<TestEntry>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle(stream, entries.clone()));
+            }
+        });
+        url
+    }
+
+    async fn handle(mut stream: TcpStream, entries: Vec<TestEntry>) {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            }
+            while let Ok((rest, message)) = parse_tag(&buffer) {
+                let consumed = buffer.len() - rest.len();
+                buffer.drain(..consumed);
+                // unbind or an unsupported operation closes the connection
+                let Some(responses) = respond(message, &entries) else {
+                    return;
+                };
+                let mut encoded = BytesMut::new();
+                for response in responses {
+                    write::encode_into(&mut encoded, response.into_structure()).unwrap();
+                }
+                if stream.write_all(&encoded).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn respond(message: StructureTag, entries: &[TestEntry]) -> Option<Vec<Tag>> {
+        let mut parts = message.expect_constructed()?.into_iter();
+        let id = parts
+            .next()?
+            .expect_primitive()?
+            .iter()
+            .fold(0i64, |id, byte| (id << 8) | *byte as i64);
+        let operation = parts.next()?.match_class(TagClass::Application)?;
+        match operation.id {
+            // bind request: version, name, simple password
+            0 => {
+                let mut fields = operation.expect_constructed()?.into_iter().skip(1);
+                let dn = string(fields.next()?)?;
+                let password = string(fields.next()?)?;
+                let bound = entries
+                    .iter()
+                    .any(|entry| entry.dn.eq_ignore_ascii_case(&dn) && entry.password == password);
+                Some(vec![message_tag(id, 1, result(if bound { 0 } else { 49 }))])
+            }
+            // search request, only the base object is used
+            3 => {
+                let base = string(operation.expect_constructed()?.into_iter().next()?)?;
+                let mut responses = entries
+                    .iter()
+                    .filter(|entry| entry.dn.eq_ignore_ascii_case(&base))
+                    .map(|entry| {
+                        let groups = Tag::Set(Set {
+                            inner: entry.groups.iter().map(|group| octet(group)).collect(),
+                            ..Default::default()
+                        });
+                        let attribute = Tag::Sequence(Sequence {
+                            inner: vec![octet("memberOf"), groups],
+                            ..Default::default()
+                        });
+                        let attributes = Tag::Sequence(Sequence {
+                            inner: vec![attribute],
+                            ..Default::default()
+                        });
+                        message_tag(id, 4, vec![octet(&entry.dn), attributes])
+                    })
+                    .collect::<Vec<Tag>>();
+                responses.push(message_tag(id, 5, result(0)));
+                Some(responses)
+            }
+            _ => None,
+        }
+    }
+
+    fn string(tag: StructureTag) -> Option<String> {
+        String::from_utf8(tag.expect_primitive()?).ok()
+    }
+
+    fn octet(value: &str) -> Tag {
+        Tag::OctetString(OctetString {
+            inner: value.as_bytes().to_vec(),
+            ..Default::default()
+        })
+    }
+
+    fn result(code: i64) -> Vec<Tag> {
+        vec![
+            Tag::Enumerated(Enumerated {
+                inner: code,
+                ..Default::default()
+            }),
+            octet(""),
+            octet(""),
+        ]
+    }
+
+    fn message_tag(id: i64, operation: u64, inner: Vec<Tag>) -> Tag {
+        Tag::Sequence(Sequence {
+            inner: vec![
+                Tag::Integer(Integer {
+                    inner: id,
+                    ..Default::default()
+                }),
+                Tag::Sequence(Sequence {
+                    id: operation,
+                    class: TagClass::Application,
+                    inner,
+                }),
+            ],
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::test_directory::{serve, TestEntry};
+    use super::{group_name, LdapClient, LdapUser};
+    use crate::auth::config::LdapConfig;
+
+    fn ldap_config(url: String) -> LdapConfig {
+        LdapConfig {
+            url,
+            starttls: false,
+            user_dn: "uid={user},ou=people,dc=example,dc=com".to_string(),
+            search_base: None,
+            user_filter: "(uid={user})".to_string(),
+            groups_attribute: "memberOf".to_string(),
+            role_map: Default::default(),
+            auto_provision: true,
+            timeout_secs: 5,
+        }
+    }
+
+    #[test]
+    fn test_group_name() {
+        assert_eq!(
+            group_name("cn=game-admins,ou=groups,dc=example,dc=com"),
+            "game-admins"
+        );
+        assert_eq!(group_name("CN=Admins\\, Games,OU=Groups"), "Admins, Games");
+        assert_eq!(group_name("game-admins"), "game-admins");
+        assert_eq!(group_name("ou=groups,dc=example"), "ou=groups,dc=example");
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let url = serve(vec![TestEntry {
+            dn: "uid=alice,ou=people,dc=example,dc=com".to_string(),
+            password: "secret".to_string(),
+            groups: vec!["cn=game-admins,ou=groups,dc=example,dc=com".to_string()],
+        }])
+        .await;
+        let client = LdapClient::new(ldap_config(url));
+        assert_eq!(
+            client.authenticate("alice", "secret").await.unwrap(),
+            Some(LdapUser {
+                dn: "uid=alice,ou=people,dc=example,dc=com".to_string(),
+                groups: vec!["game-admins".to_string()],
+            })
+        );
+        assert_eq!(client.authenticate("alice", "wrong").await.unwrap(), None);
+        assert_eq!(client.authenticate("bob", "secret").await.unwrap(), None);
+        // unauthenticated binds are never attempted
+        assert_eq!(client.authenticate("alice", "").await.unwrap(), None);
+        // names are escaped in the bind DN
+        assert_eq!(
+            client
+                .authenticate("alice,ou=people,dc=example,dc=com", "secret")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use bech32::{Bech32, Hrp};
+use chrono::{DateTime, Utc};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use reqwest::Url;
+use secp256k1::{ecdsa, Message, PublicKey, Secp256k1};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// A LNURL-auth login challenge, stored until the wallet signs it and the browser
+/// completes the login.
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct LnurlChallenge {
+    pub k1: String,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    /// Hex encoded compressed public key of the wallet that signed `k1`.
+    pub linking_key: Option<String>,
+}
+
+/// The challenge a browser is waiting on, kept in the session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LnurlLogin {
+    pub k1: String,
+    pub next: Option<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum LnurlError {
+    #[error("lnurl login is not enabled")]
+    NotEnabled,
+    #[error("unknown, used or expired k1 challenge")]
+    InvalidChallenge,
+    #[error("invalid hex")]
+    Hex(#[from] hex::FromHexError),
+    #[error(transparent)]
+    Secp256k1(#[from] secp256k1::Error),
+    #[error(transparent)]
+    Bech32(#[from] bech32::EncodeError),
+    #[error(transparent)]
+    Qr(#[from] qrcode::types::QrError),
+    #[error(transparent)]
+    Session(#[from] tower_sessions::session::Error),
+}
+
+/// A new random 32 byte hex encoded challenge.
+pub fn new_k1() -> String {
+    let mut k1 = Uuid::new_v4().as_bytes().to_vec();
+    k1.extend_from_slice(Uuid::new_v4().as_bytes());
+    hex::encode(k1)
+}
+
+/// The LUD-04 wallet callback url for a challenge.
+pub fn callback_url(public_url: &Url, k1: &str) -> Url {
+    let mut url = public_url.join("/login/lnurl/callback").expect("valid url");
+    url.query_pairs_mut()
+        .append_pair("tag", "login")
+        .append_pair("k1", k1)
+        .append_pair("action", "login");
+    url
+}
+
+/// Bech32 encode a url as an uppercase `LNURL...` string, uppercase makes a smaller QR code.
+pub fn encode_lnurl(url: &Url) -> Result<String, LnurlError> {
+    let hrp = Hrp::parse("lnurl").expect("valid hrp");
+    Ok(bech32::encode::<Bech32>(hrp, url.as_str().as_bytes())?.to_uppercase())
+}
+
+/// Verify the wallet's DER encoded ECDSA signature over the `k1` challenge bytes.
+pub fn verify_signature(k1: &str, sig: &str, key: &str) -> Result<(), LnurlError> {
+    let k1: [u8; 32] = hex::decode(k1)?
+        .try_into()
+        .map_err(|_| LnurlError::InvalidChallenge)?;
+    let mut sig = ecdsa::Signature::from_der(&hex::decode(sig)?)?;
+    // some wallets don't produce low-S signatures
+    sig.normalize_s();
+    let key = PublicKey::from_str(key)?;
+    Secp256k1::verification_only().verify_ecdsa(&Message::from_digest(k1), &sig, &key)?;
+    Ok(())
+}
+
+/// Render data as an SVG QR code.
+pub fn qr_svg(data: &str) -> Result<String, qrcode::types::QrError> {
+    let qr_code = QrCode::new(data.as_bytes())?;
+    Ok(qr_code
+        .render::<svg::Color>()
+        .min_dimensions(240, 240)
+        .build())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{callback_url, encode_lnurl, new_k1, verify_signature};
+    use reqwest::Url;
+    use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+    #[test]
+    fn test_verify_signature() {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let key = PublicKey::from_secret_key(&secp, &secret_key).to_string();
+        let k1 = new_k1();
+        let k1_bytes: [u8; 32] = hex::decode(&k1).unwrap().try_into().unwrap();
+        let sig = secp.sign_ecdsa(&Message::from_digest(k1_bytes), &secret_key);
+        let sig = hex::encode(sig.serialize_der());
+
+        assert!(verify_signature(&k1, &sig, &key).is_ok());
+        assert!(verify_signature(&new_k1(), &sig, &key).is_err());
+        let other_key =
+            PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[2; 32]).unwrap());
+        assert!(verify_signature(&k1, &sig, &other_key.to_string()).is_err());
+        assert!(verify_signature(&k1, "not hex", &key).is_err());
+    }
+
+    #[test]
+    fn test_encode_lnurl() {
+        // LUD-01 example
+        let url = Url::parse(
+            "https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df",
+        )
+        .unwrap();
+        assert_eq!(
+            encode_lnurl(&url).unwrap(),
+            "LNURL1DP68GURN8GHJ7UM9WFMXJCM99E3K7MF0V9CXJ0M385EKVCENXC6R2C35XVUKXEFCV5MKVV34X5EKZD3EV56NYD3HXQURZEPEXEJXXEPNXSCRVWFNV9NXZCN9XQ6XYEFHVGCXXCMYXYMNSERXFQ5FNS"
+        );
+        let callback = callback_url(&Url::parse("http://localhost:8080").unwrap(), "abcd");
+        assert_eq!(
+            callback.as_str(),
+            "http://localhost:8080/login/lnurl/callback?tag=login&k1=abcd&action=login"
+        );
+    }
+}
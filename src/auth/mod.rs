@@ -1,4 +1,11 @@
 pub mod backend;
+pub mod config;
 mod db;
+pub mod ldap;
+pub mod lnurl;
+pub mod nostr;
+pub mod oidc;
+pub mod store;
+pub mod totp;
 pub mod types;
 pub mod web;
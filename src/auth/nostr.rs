@@ -0,0 +1,235 @@
+use bech32::{Bech32, Hrp};
+use chrono::{DateTime, Utc};
+use secp256k1::{schnorr, Message, Secp256k1, XOnlyPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+/// NIP-98 HTTP auth event kind.
+pub const HTTP_AUTH_KIND: u32 = 27235;
+
+/// How far the event `created_at` may be from the server time.
+const MAX_CLOCK_SKEW_SECS: i64 = 60;
+
+/// A signed Nostr event.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct NostrEvent {
+    pub id: String,
+    pub pubkey: String,
+    pub created_at: i64,
+    pub kind: u32,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    pub sig: String,
+}
+
+/// Server issued login challenge, kept in the session until the signed event is posted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NostrChallenge {
+    pub challenge: String,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum NostrError {
+    #[error("nostr login is not enabled")]
+    NotEnabled,
+    #[error("missing or malformed nostr authorization header")]
+    InvalidHeader,
+    #[error("missing or expired login challenge")]
+    InvalidChallenge,
+    #[error("invalid event: {0}")]
+    InvalidEvent(String),
+    #[error(transparent)]
+    Secp256k1(#[from] secp256k1::Error),
+    #[error(transparent)]
+    Session(#[from] tower_sessions::session::Error),
+}
+
+impl NostrEvent {
+    /// Decode the event from a NIP-98 `Authorization: Nostr <base64 event>` header value.
+    pub fn from_authorization(value: &str) -> Result<Self, NostrError> {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+        let encoded = value
+            .strip_prefix("Nostr ")
+            .ok_or(NostrError::InvalidHeader)?;
+        let decoded = STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| NostrError::InvalidHeader)?;
+        serde_json::from_slice(&decoded).map_err(|_| NostrError::InvalidHeader)
+    }
+
+    /// The NIP-01 event id, the sha256 of the serialized event data.
+    pub fn compute_id(&self) -> [u8; 32] {
+        let serialized = json!([
+            0,
+            self.pubkey,
+            self.created_at,
+            self.kind,
+            self.tags,
+            self.content
+        ])
+        .to_string();
+        Sha256::digest(serialized.as_bytes()).into()
+    }
+
+    /// Check the event id and Schnorr signature.
+    pub fn verify_signature(&self) -> Result<(), NostrError> {
+        let id = self.compute_id();
+        let id_hex = id
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        if id_hex != self.id {
+            return Err(NostrError::InvalidEvent("id mismatch".to_string()));
+        }
+        let pubkey = XOnlyPublicKey::from_str(&self.pubkey)?;
+        let sig = schnorr::Signature::from_str(&self.sig)?;
+        Secp256k1::verification_only().verify_schnorr(&sig, &Message::from_digest(id), &pubkey)?;
+        Ok(())
+    }
+
+    fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().map(String::as_str) == Some(name))
+            .and_then(|tag| tag.get(1))
+            .map(String::as_str)
+    }
+
+    /// Verify a NIP-98 auth event for a request, returns the signer's hex pubkey.
+    pub fn verify_http_auth(
+        &self,
+        url: &str,
+        method: &str,
+        challenge: &str,
+        now: DateTime<Utc>,
+    ) -> Result<String, NostrError> {
+        if self.kind != HTTP_AUTH_KIND {
+            return Err(NostrError::InvalidEvent(format!("kind {}", self.kind)));
+        }
+        if (now.timestamp() - self.created_at).abs() > MAX_CLOCK_SKEW_SECS {
+            return Err(NostrError::InvalidEvent(
+                "created_at out of range".to_string(),
+            ));
+        }
+        if self.tag("u") != Some(url) {
+            return Err(NostrError::InvalidEvent("url mismatch".to_string()));
+        }
+        if !self
+            .tag("method")
+            .is_some_and(|tag_method| tag_method.eq_ignore_ascii_case(method))
+        {
+            return Err(NostrError::InvalidEvent("method mismatch".to_string()));
+        }
+        // the challenge ties the event to this login, an event can't be replayed
+        if self.tag("challenge") != Some(challenge) {
+            return Err(NostrError::InvalidChallenge);
+        }
+        self.verify_signature()?;
+        Ok(self.pubkey.to_lowercase())
+    }
+}
+
+/// Bech32 `npub` encoding of a hex pubkey.
+pub fn npub(pubkey: &str) -> Result<String, NostrError> {
+    let pubkey = XOnlyPublicKey::from_str(pubkey)?;
+    let hrp = Hrp::parse("npub").expect("valid hrp");
+    bech32::encode::<Bech32>(hrp, &pubkey.serialize())
+        .map_err(|e| NostrError::InvalidEvent(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{npub, NostrError, NostrEvent, HTTP_AUTH_KIND};
+    use chrono::{TimeDelta, Utc};
+    use secp256k1::{Keypair, Message, Secp256k1, SecretKey};
+
+    const URL: &str = "http://localhost:8080/login/nostr";
+
+    fn signed_event(secret_key: [u8; 32], created_at: i64, challenge: &str) -> NostrEvent {
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_secret_key(&secp, &SecretKey::from_slice(&secret_key).unwrap());
+        let mut event = NostrEvent {
+            id: String::new(),
+            pubkey: keypair.x_only_public_key().0.to_string(),
+            created_at,
+            kind: HTTP_AUTH_KIND,
+            tags: vec![
+                vec!["u".to_string(), URL.to_string()],
+                vec!["method".to_string(), "POST".to_string()],
+                vec!["challenge".to_string(), challenge.to_string()],
+            ],
+            content: String::new(),
+            sig: String::new(),
+        };
+        let id = event.compute_id();
+        event.id = id.iter().map(|byte| format!("{:02x}", byte)).collect();
+        event.sig = secp
+            .sign_schnorr_no_aux_rand(&Message::from_digest(id), &keypair)
+            .to_string();
+        event
+    }
+
+    #[test]
+    fn test_verify_http_auth() {
+        let now = Utc::now();
+        let event = signed_event([1; 32], now.timestamp(), "challenge1");
+        let pubkey = event
+            .verify_http_auth(URL, "POST", "challenge1", now)
+            .expect("valid event");
+        assert_eq!(pubkey, event.pubkey);
+
+        // wrong challenge, url, method or time
+        assert!(matches!(
+            event.verify_http_auth(URL, "POST", "challenge2", now),
+            Err(NostrError::InvalidChallenge)
+        ));
+        assert!(event
+            .verify_http_auth("http://evil.example/login/nostr", "POST", "challenge1", now)
+            .is_err());
+        assert!(event
+            .verify_http_auth(URL, "GET", "challenge1", now)
+            .is_err());
+        assert!(event
+            .verify_http_auth(URL, "POST", "challenge1", now + TimeDelta::minutes(5))
+            .is_err());
+
+        // tampered content or signature from another key
+        let tampered = NostrEvent {
+            content: "tampered".to_string(),
+            ..event.clone()
+        };
+        assert!(tampered.verify_signature().is_err());
+        let other = signed_event([2; 32], now.timestamp(), "challenge1");
+        let forged = NostrEvent {
+            sig: other.sig,
+            ..event
+        };
+        assert!(forged.verify_signature().is_err());
+    }
+
+    #[test]
+    fn test_from_authorization() {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+        let event = signed_event([1; 32], Utc::now().timestamp(), "challenge1");
+        let header = format!(
+            "Nostr {}",
+            STANDARD.encode(serde_json::to_string(&event).unwrap())
+        );
+        assert_eq!(NostrEvent::from_authorization(&header).unwrap(), event);
+        assert!(NostrEvent::from_authorization("Bearer abc").is_err());
+    }
+
+    #[test]
+    fn test_npub() {
+        // NIP-19 test vector
+        assert_eq!(
+            npub("3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d").unwrap(),
+            "npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w6"
+        );
+    }
+}
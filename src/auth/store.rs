@@ -0,0 +1,390 @@
+use crate::auth::config::LoginThrottleConfig;
+use crate::auth::lnurl::LnurlChallenge;
+use crate::auth::totp::{normalize_recovery_code, verify_code, Totp};
+use crate::auth::types::{
+    hash_token, name_key, ApiToken, IdentityKey, InviteCode, LoginFailures, Permission, Player,
+    ResetToken, Role, ThrottleKey,
+};
+use crate::storage::RowIter;
+use crate::types::InternalError;
+use chrono::{DateTime, Utc};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Auth records of a read transaction.
+pub trait AuthRead {
+    fn get_player_by_uuid(&self, uuid: Uuid) -> Result<Option<Player>, InternalError>;
+
+    fn get_player_by_name(&self, name: &str) -> Result<Option<Player>, InternalError>;
+
+    fn get_players(&self) -> Result<Vec<Player>, InternalError>;
+
+    /// All players, ordered by uuid.
+    fn iter_players(&self) -> Result<RowIter<'_, Player>, InternalError>;
+
+    /// The rows of the player name index, the name key with its player, ordered by key.
+    fn get_player_names(&self) -> Result<Vec<(String, Uuid)>, InternalError>;
+
+    fn get_role_by_uuid(&self, uuid: Uuid) -> Result<Option<Role>, InternalError>;
+
+    fn get_roles(&self) -> Result<Vec<Role>, InternalError>;
+
+    /// All roles, ordered by uuid.
+    fn iter_roles(&self) -> Result<RowIter<'_, Role>, InternalError>;
+
+    fn get_login_failures(&self, key: &ThrottleKey)
+        -> Result<Option<LoginFailures>, InternalError>;
+
+    fn get_all_login_failures(&self) -> Result<Vec<(String, LoginFailures)>, InternalError>;
+
+    fn get_reset_token(&self, token_hash: String) -> Result<Option<ResetToken>, InternalError>;
+
+    fn get_invite_codes(&self) -> Result<Vec<InviteCode>, InternalError>;
+
+    /// The linked identities of a player, in their string form.
+    fn get_player_identities(&self, uuid: Uuid) -> Result<Vec<String>, InternalError>;
+
+    fn get_lnurl_challenge(&self, k1: &str) -> Result<Option<LnurlChallenge>, InternalError>;
+
+    fn get_totp(&self, uuid: Uuid) -> Result<Option<Totp>, InternalError>;
+
+    fn get_player_api_tokens(&self, uuid: Uuid) -> Result<Vec<ApiToken>, InternalError>;
+}
+
+/// Auth records of a write transaction. The `_for_update` reads see the transaction's own
+/// changes.
+pub trait AuthWrite {
+    /// Insert or replace a player, fails with [`InternalError::NameTaken`] if the name is
+    /// registered to another player.
+    fn insert_player(&mut self, player: Player) -> Result<Option<Player>, InternalError>;
+
+    /// Change a player, fails with [`InternalError::NameTaken`] if the new name is registered
+    /// to another player.
+    fn change_player(
+        &mut self,
+        orig_player: Player,
+        new_player: Player,
+    ) -> Result<Option<Player>, InternalError>;
+
+    /// Remove a player along with their name, linked identities, reset token, api tokens,
+    /// authenticator and login failures.
+    fn remove_player(&mut self, uuid: Uuid) -> Result<Option<Player>, InternalError>;
+
+    // get a player inside a write transaction before changing it
+    fn get_player_for_update(&self, uuid: Uuid) -> Result<Option<Player>, InternalError>;
+
+    // all players inside a write transaction, before a change that depends on them
+    fn get_players_for_update(&self) -> Result<Vec<Player>, InternalError>;
+
+    // the player a name is registered to, inside a write transaction before changing it
+    fn get_name_for_update(&self, name: &str) -> Result<Option<Uuid>, InternalError> {
+        self.get_name_key_for_update(&name_key(name))
+    }
+
+    // the player a row of the name index points at, inside a write transaction before
+    // repairing it
+    fn get_name_key_for_update(&self, key: &str) -> Result<Option<Uuid>, InternalError>;
+
+    // fail if a name is registered to another player, inside a write transaction before
+    // giving the name to a player
+    fn check_name_for_update(&self, name: &str, uuid: Uuid) -> Result<(), InternalError> {
+        match self.get_name_for_update(name)? {
+            Some(owner) if owner != uuid => Err(InternalError::NameTaken(name.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Point a row of the player name index at a player, or remove it with `None`. Player
+    /// changes keep the index up to date, this is only used to repair it.
+    fn repair_player_name(&mut self, key: &str, uuid: Option<Uuid>) -> Result<(), InternalError>;
+
+    // whether there are no players and roles yet, inside a write transaction before
+    // inserting the first ones
+    fn is_empty_for_update(&self) -> Result<bool, InternalError>;
+
+    /// Insert or replace players by uuid. The names of replaced players are released first,
+    /// so players can swap names within the same write transaction.
+    fn upsert_players(&mut self, players: &[Player]) -> Result<(), InternalError>;
+
+    /// Remove all players with everything [`AuthWrite::remove_player`] removes, and any name
+    /// left without a player. Returns the number of players removed.
+    fn remove_all_players(&mut self) -> Result<usize, InternalError>;
+
+    fn insert_role(&mut self, role: Role) -> Result<Option<Role>, InternalError>;
+
+    // get a role inside a write transaction before referencing it
+    fn get_role_for_update(&self, uuid: Uuid) -> Result<Option<Role>, InternalError>;
+
+    /// Remove all roles, returns the number of roles removed.
+    fn remove_all_roles(&mut self) -> Result<usize, InternalError>;
+
+    fn get_login_failures_for_update(
+        &self,
+        key: &ThrottleKey,
+    ) -> Result<Option<LoginFailures>, InternalError>;
+
+    fn insert_login_failures(
+        &mut self,
+        key: &ThrottleKey,
+        login_failures: &LoginFailures,
+    ) -> Result<(), InternalError>;
+
+    // the key is the stored string form of a [`ThrottleKey`]
+    fn remove_login_failures(&mut self, key: &str) -> Result<Option<LoginFailures>, InternalError>;
+
+    /// Remove the failures `remove` returns true for, by the stored string form of their key.
+    /// Returns the number of removed keys.
+    fn remove_login_failures_if(
+        &mut self,
+        remove: &dyn Fn(&str, &LoginFailures) -> bool,
+    ) -> Result<usize, InternalError>;
+
+    /// Insert a reset token, replacing any other token for the same player and removing
+    /// expired tokens.
+    fn insert_reset_token(
+        &mut self,
+        token_hash: String,
+        reset_token: ResetToken,
+    ) -> Result<(), InternalError>;
+
+    fn remove_reset_token(
+        &mut self,
+        token_hash: String,
+    ) -> Result<Option<ResetToken>, InternalError>;
+
+    fn insert_invite_code(
+        &mut self,
+        invite_code: InviteCode,
+    ) -> Result<Option<InviteCode>, InternalError>;
+
+    fn get_invite_code_for_update(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<InviteCode>, InternalError>;
+
+    fn remove_invite_code(&mut self, code_hash: &str) -> Result<Option<InviteCode>, InternalError>;
+
+    /// Link an external identity to a player, returns the previously linked player.
+    fn link_identity(
+        &mut self,
+        identity_key: &IdentityKey,
+        player_uuid: Uuid,
+    ) -> Result<Option<Uuid>, InternalError>;
+
+    // get a linked player inside a write transaction before changing it
+    fn get_identity_for_update(
+        &self,
+        identity_key: &IdentityKey,
+    ) -> Result<Option<Uuid>, InternalError>;
+
+    /// Insert a LNURL-auth challenge and remove expired challenges.
+    fn insert_lnurl_challenge(&mut self, challenge: LnurlChallenge) -> Result<(), InternalError>;
+
+    fn get_lnurl_challenge_for_update(
+        &self,
+        k1: &str,
+    ) -> Result<Option<LnurlChallenge>, InternalError>;
+
+    fn remove_lnurl_challenge(&mut self, k1: &str)
+        -> Result<Option<LnurlChallenge>, InternalError>;
+
+    fn insert_api_token(
+        &mut self,
+        token_hash: String,
+        api_token: ApiToken,
+    ) -> Result<(), InternalError>;
+
+    fn get_api_token_for_update(&self, token_hash: &str)
+        -> Result<Option<ApiToken>, InternalError>;
+
+    /// Remove a player's api token, returns false if the player has no such token.
+    fn remove_api_token(&mut self, uuid: Uuid, token_uuid: Uuid) -> Result<bool, InternalError>;
+
+    fn insert_totp(&mut self, uuid: Uuid, totp: Totp) -> Result<(), InternalError>;
+
+    fn get_totp_for_update(&self, uuid: Uuid) -> Result<Option<Totp>, InternalError>;
+
+    fn remove_totp(&mut self, uuid: Uuid) -> Result<bool, InternalError>;
+
+    /// The first of the candidate names that isn't registered yet.
+    fn available_player_name(
+        &self,
+        candidates: &mut dyn Iterator<Item = String>,
+    ) -> Result<String, InternalError> {
+        const MAX_TRIES: u8 = 100;
+        let mut first_name = None;
+        for name in candidates.take(MAX_TRIES.into()) {
+            if self.get_name_for_update(&name)?.is_none() {
+                return Ok(name);
+            }
+            first_name.get_or_insert(name);
+        }
+        Err(InternalError::NewName(
+            first_name.unwrap_or_default(),
+            MAX_TRIES,
+        ))
+    }
+
+    /// The player linked to an external identity. An unlinked identity is linked to the
+    /// `link_to` player, or else to a new player named after the first available candidate
+    /// name if `provision_names` are given. Returns `None` if the identity is already linked
+    /// to a player other than `link_to`.
+    fn find_or_link_player(
+        &mut self,
+        identity_key: &IdentityKey,
+        link_to: Option<Uuid>,
+        provision_names: Option<&mut dyn Iterator<Item = String>>,
+    ) -> Result<Option<Player>, InternalError> {
+        let linked_uuid = self.get_identity_for_update(identity_key)?;
+        match (linked_uuid, link_to) {
+            (Some(linked_uuid), Some(link_to)) if linked_uuid != link_to => {
+                warn!("{} is already linked to another player", identity_key);
+                Ok(None)
+            }
+            (Some(linked_uuid), _) => self.get_player_for_update(linked_uuid),
+            (None, Some(link_to)) => {
+                let player = self.get_player_for_update(link_to)?;
+                if player.is_some() {
+                    self.link_identity(identity_key, link_to)?;
+                    info!("linked {} to player {}", identity_key, link_to);
+                }
+                Ok(player)
+            }
+            (None, None) => match provision_names {
+                Some(names) => {
+                    // without a password hash the player can only log in with the identity
+                    let player = Player {
+                        uuid: Uuid::new_v4(),
+                        name: self.available_player_name(names)?,
+                        ..Default::default()
+                    };
+                    self.insert_player(player.clone())?;
+                    self.link_identity(identity_key, player.uuid)?;
+                    info!("provisioned player {} for {}", player.name, identity_key);
+                    Ok(Some(player))
+                }
+                None => Ok(None),
+            },
+        }
+    }
+
+    fn add_login_failure(
+        &mut self,
+        key: &ThrottleKey,
+        now: DateTime<Utc>,
+        config: &LoginThrottleConfig,
+    ) -> Result<LoginFailures, InternalError> {
+        let login_failures = self
+            .get_login_failures_for_update(key)?
+            .unwrap_or_default()
+            .add_failure(now, config);
+        self.insert_login_failures(key, &login_failures)?;
+        Ok(login_failures)
+    }
+
+    /// Count a use of an invite code by its hash, returns the code if it was still valid.
+    fn use_invite_code(
+        &mut self,
+        code_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<InviteCode>, InternalError> {
+        let invite_code = self
+            .get_invite_code_for_update(code_hash)?
+            .filter(|invite_code| invite_code.is_valid(now))
+            .map(|invite_code| InviteCode {
+                uses: invite_code.uses + 1,
+                ..invite_code
+            });
+        if let Some(invite_code) = &invite_code {
+            self.insert_invite_code(invite_code.clone())?;
+        }
+        Ok(invite_code)
+    }
+
+    /// Whether a player has a permission, directly or by one of their roles.
+    fn has_permission_for_update(
+        &self,
+        player: &Player,
+        permission: &Permission,
+    ) -> Result<bool, InternalError> {
+        if player.permissions.contains(permission) {
+            return Ok(true);
+        }
+        for role in &player.roles {
+            if self
+                .get_role_for_update(*role)?
+                .is_some_and(|role| role.permissions.contains(permission))
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Record the wallet key that signed an unexpired, not yet signed challenge. Returns the
+    /// signed challenge if it was valid.
+    fn sign_lnurl_challenge(
+        &mut self,
+        k1: &str,
+        linking_key: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<LnurlChallenge>, InternalError> {
+        let challenge = self
+            .get_lnurl_challenge_for_update(k1)?
+            .filter(|challenge| challenge.expires > now && challenge.linking_key.is_none())
+            .map(|challenge| LnurlChallenge {
+                linking_key: Some(linking_key.to_string()),
+                ..challenge
+            });
+        if let Some(challenge) = &challenge {
+            self.insert_lnurl_challenge(challenge.clone())?;
+        }
+        Ok(challenge)
+    }
+
+    /// Get an api token by token hash and record that it was used.
+    fn use_api_token(
+        &mut self,
+        token_hash: String,
+        now: DateTime<Utc>,
+    ) -> Result<Option<ApiToken>, InternalError> {
+        let api_token = self
+            .get_api_token_for_update(&token_hash)?
+            .map(|api_token| ApiToken {
+                last_used: Some(now),
+                ..api_token
+            });
+        if let Some(api_token) = &api_token {
+            self.insert_api_token(token_hash, api_token.clone())?;
+        }
+        Ok(api_token)
+    }
+
+    /// Check an authenticator or recovery code for a player, using it up so it can't be
+    /// used again. Returns false if the code is not valid or the player isn't enrolled.
+    fn use_totp_code(
+        &mut self,
+        uuid: Uuid,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, InternalError> {
+        let Some(mut totp) = self.get_totp_for_update(uuid)? else {
+            return Ok(false);
+        };
+        if let Some(step) = verify_code(&totp.secret, code, now, totp.last_step) {
+            totp.last_step = Some(step);
+        } else {
+            let code_hash = hash_token(&normalize_recovery_code(code));
+            let Some(index) = totp
+                .recovery_codes
+                .iter()
+                .position(|recovery_code| *recovery_code == code_hash)
+            else {
+                return Ok(false);
+            };
+            totp.recovery_codes.remove(index);
+        }
+        self.insert_totp(uuid, totp)?;
+        Ok(true)
+    }
+}
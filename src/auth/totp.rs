@@ -0,0 +1,232 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use uuid::Uuid;
+
+const TOTP_DIGITS: u32 = 6;
+const TOTP_STEP_SECS: i64 = 30;
+// accepted time steps before and after the current one, for clock drift
+const TOTP_SKEW_STEPS: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A player's confirmed RFC 6238 authenticator enrollment, stored by player uuid.
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct Totp {
+    pub secret: Vec<u8>,
+    /// Hashes of the unused single-use recovery codes.
+    pub recovery_codes: Vec<String>,
+    /// The last accepted time step, codes can't be used twice.
+    pub last_step: Option<i64>,
+    pub created: DateTime<Utc>,
+}
+
+impl std::fmt::Debug for Totp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Totp")
+            .field("secret", &"[redacted]")
+            .field("recovery_codes", &self.recovery_codes.len())
+            .field("last_step", &self.last_step)
+            .field("created", &self.created)
+            .finish()
+    }
+}
+
+/// An enrollment shown to the player but not yet confirmed with a code, kept in the session.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TotpEnrollment {
+    pub secret: Vec<u8>,
+    pub recovery_codes: Vec<String>,
+}
+
+/// A login that passed the first factor and is waiting for a code, kept in the session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TotpLogin {
+    pub player: Uuid,
+    /// How the first factor was authenticated, for the audit log.
+    pub method: String,
+    pub next: Option<String>,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TotpError {
+    #[error("no pending two-factor enrollment")]
+    NoEnrollment,
+    #[error("no pending two-factor login")]
+    NoLogin,
+    #[error("two-factor authentication is already enabled")]
+    AlreadyEnabled,
+    #[error("invalid two-factor code")]
+    InvalidCode,
+    #[error("two-factor authentication is required for player: {0}")]
+    Required(String),
+    #[error(transparent)]
+    Qr(#[from] qrcode::types::QrError),
+    #[error(transparent)]
+    Session(#[from] tower_sessions::session::Error),
+}
+
+/// A new random 20 byte secret, the size authenticator apps expect for SHA-1.
+pub fn new_secret() -> Vec<u8> {
+    let mut secret = Uuid::new_v4().as_bytes().to_vec();
+    secret.extend_from_slice(&Uuid::new_v4().as_bytes()[..4]);
+    secret
+}
+
+/// New single-use recovery codes, formatted as `xxxx-xxxx`.
+pub fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = hex::encode(&Uuid::new_v4().as_bytes()[..4]);
+            format!("{}-{}", &code[..4], &code[4..])
+        })
+        .collect()
+}
+
+/// Unpadded RFC 4648 base32, the secret encoding used by authenticator apps.
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer
+            .iter()
+            .fold(0u64, |bits, byte| (bits << 8) | *byte as u64);
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// The `otpauth://` url an authenticator app scans from the enrollment QR code.
+pub fn otpauth_url(issuer: &str, account: &str, secret: &[u8]) -> Url {
+    let mut url = Url::parse("otpauth://totp/").expect("valid url");
+    url.set_path(&format!("{}:{}", issuer, account));
+    url.query_pairs_mut()
+        .append_pair("secret", &base32_encode(secret))
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &TOTP_DIGITS.to_string())
+        .append_pair("period", &TOTP_STEP_SECS.to_string());
+    url
+}
+
+// RFC 4226 HOTP value for a counter
+fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("any key size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes(hash[offset..offset + 4].try_into().expect("4 bytes"));
+    (code & 0x7fff_ffff) % 10u32.pow(digits)
+}
+
+/// The time step a code is valid for at `now`.
+pub fn time_step(now: DateTime<Utc>) -> i64 {
+    now.timestamp().div_euclid(TOTP_STEP_SECS)
+}
+
+/// Check a code against the steps around `now`, returning the matching step. Steps at or
+/// before `last_step` are rejected so a code can't be replayed.
+pub fn verify_code(
+    secret: &[u8],
+    code: &str,
+    now: DateTime<Utc>,
+    last_step: Option<i64>,
+) -> Option<i64> {
+    let code = code.trim().replace(' ', "");
+    if code.len() != TOTP_DIGITS as usize {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    let current = time_step(now);
+    (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS)
+        .filter(|step| *step >= 0 && last_step.is_none_or(|last_step| *step > last_step))
+        .find(|step| hotp(secret, *step as u64, TOTP_DIGITS) == code)
+}
+
+// the current code, as an authenticator app would show it
+#[cfg(test)]
+pub fn current_code(secret: &[u8], now: DateTime<Utc>) -> String {
+    format!(
+        "{:0width$}",
+        hotp(secret, time_step(now) as u64, TOTP_DIGITS),
+        width = TOTP_DIGITS as usize
+    )
+}
+
+/// Recovery codes are compared without separators or case.
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.trim().replace('-', "").to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{base32_encode, hotp, normalize_recovery_code, otpauth_url, verify_code};
+    use chrono::{DateTime, Utc};
+
+    // RFC 6238 appendix B SHA-1 test secret
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_hotp_rfc_vectors() {
+        let vectors = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(hotp(RFC_SECRET, time / 30, 8), code);
+        }
+    }
+
+    #[test]
+    fn test_verify_code() {
+        let now = DateTime::<Utc>::from_timestamp(59, 0).unwrap();
+        assert_eq!(verify_code(RFC_SECRET, "287082", now, None), Some(1));
+        assert_eq!(verify_code(RFC_SECRET, " 287 082 ", now, None), Some(1));
+        // one step of clock drift is accepted
+        let later = DateTime::<Utc>::from_timestamp(89, 0).unwrap();
+        assert_eq!(verify_code(RFC_SECRET, "287082", later, None), Some(1));
+        let too_late = DateTime::<Utc>::from_timestamp(120, 0).unwrap();
+        assert_eq!(verify_code(RFC_SECRET, "287082", too_late, None), None);
+        // replays are rejected
+        assert_eq!(verify_code(RFC_SECRET, "287082", now, Some(1)), None);
+        assert_eq!(verify_code(RFC_SECRET, "287083", now, None), None);
+        assert_eq!(verify_code(RFC_SECRET, "28708", now, None), None);
+    }
+
+    #[test]
+    fn test_base32_encode() {
+        assert_eq!(base32_encode(b""), "");
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32_encode(b"fo"), "MZXQ");
+        assert_eq!(base32_encode(b"foo"), "MZXW6");
+        assert_eq!(base32_encode(b"foob"), "MZXW6YQ");
+        assert_eq!(base32_encode(b"fooba"), "MZXW6YTB");
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+    }
+
+    #[test]
+    fn test_otpauth_url() {
+        let url = otpauth_url("Nonce Guess", "alice", b"foobar");
+        assert_eq!(
+            url.as_str(),
+            "otpauth://totp/Nonce%20Guess:alice?secret=MZXW6YTBOI&issuer=Nonce+Guess&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_normalize_recovery_code() {
+        assert_eq!(normalize_recovery_code(" ABCD-1234 "), "abcd1234");
+    }
+}
@@ -1,7 +1,20 @@
 use super::backend::AuthBackend;
-use chrono::{DateTime, Utc};
+use super::config::LoginThrottleConfig;
+use super::lnurl::LnurlError;
+use super::nostr::NostrError;
+use super::oidc::OidcError;
+use super::totp::TotpError;
+use axum::http::Method;
+use caseless::default_case_fold_str;
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+use tracing::warn;
+use unicode_security::skeleton;
 use uuid::Uuid;
 
 // A helper functions that return the current date time.
@@ -9,16 +22,40 @@ pub fn datetime_now() -> DateTime<Utc> {
     Utc::now()
 }
 
+/// Generate a random url safe token, 244 bits from two v4 uuids.
+pub fn generate_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Hex encoded sha256 hash of a token, tokens are only stored hashed.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
 /// The players information
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
-#[serde(deny_unknown_fields)]
 pub struct Player {
     pub uuid: Uuid,
     pub name: String,
     pub password_hash: String,
     pub permissions: HashSet<Permission>,
     pub roles: HashSet<Uuid>,
+    /// Player is redirected to the profile page until the password is changed.
+    #[serde(default)]
+    pub must_change_password: bool,
+    /// Player was renamed because the name collided with an older player's, redirected to the
+    /// profile page until the name is changed or kept.
+    #[serde(default)]
+    pub renamed: bool,
+    /// Sessions are only valid while this matches, the password hash is used if not set. A
+    /// random key set on the first password change, kept across later changes to keep other
+    /// sessions logged in.
+    #[serde(default)]
+    pub session_key: Option<String>,
     #[serde(default = "datetime_now")]
     pub last_login: DateTime<Utc>,
     #[serde(default = "datetime_now")]
@@ -35,6 +72,9 @@ impl Default for Player {
             password_hash: "".to_string(),
             permissions: Default::default(),
             roles: Default::default(),
+            must_change_password: false,
+            renamed: false,
+            session_key: None,
             last_login: datetime_now(),
             updated: datetime_now(),
             created: datetime_now(),
@@ -58,13 +98,486 @@ pub enum Permission {
 /// Role (collection of permissions) that can be granted to a player
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
-#[serde(deny_unknown_fields)]
 pub struct Role {
     pub uuid: Uuid,
     pub name: String,
     pub permissions: HashSet<Permission>,
 }
 
+/// A single-use password reset token issued by an admin, stored by token hash.
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ResetToken {
+    pub player: Uuid,
+    pub created_by: Uuid,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+/// An admin created code required to register when registration is closed.
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct InviteCode {
+    /// The [`hash_token`] of the code, the code itself is only shown once when it is created.
+    /// Codes from before they were hashed were stored as `code` and are hashed by a migration.
+    #[serde(alias = "code")]
+    pub code_hash: String,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+    pub expires: Option<DateTime<Utc>>,
+    /// Role assigned to players registered with this code.
+    pub role: Option<Uuid>,
+    pub created_by: Uuid,
+    pub created: DateTime<Utc>,
+}
+
+impl InviteCode {
+    pub fn is_valid(&self, now: DateTime<Utc>) -> bool {
+        self.max_uses.is_none_or(|max_uses| self.uses < max_uses)
+            && self.expires.is_none_or(|expires| expires > now)
+    }
+
+    /// The start of the code hash, to tell codes apart without showing the code.
+    pub fn short_hash(&self) -> &str {
+        self.code_hash.get(..8).unwrap_or(&self.code_hash)
+    }
+}
+
+/// What requests a personal API token may make, each scope includes the ones before it.
+#[derive(Ord, PartialOrd, Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Only read the game pages, `GET` and `HEAD` requests.
+    Read,
+    /// Read the game pages and submit guesses.
+    Guess,
+    /// Anything the player's permissions allow.
+    Admin,
+}
+
+/// The game pages read and guess scoped tokens may use, everything else such as `/admin`,
+/// `/profile` and `/logout` needs an admin scoped token.
+const GAME_PATHS: [&str; 4] = ["/", "/target", "/target/table", "/guess/table"];
+
+impl TokenScope {
+    pub fn allows(&self, method: &Method, path: &str) -> bool {
+        match self {
+            TokenScope::Read => {
+                matches!(*method, Method::GET | Method::HEAD) && GAME_PATHS.contains(&path)
+            }
+            TokenScope::Guess => {
+                TokenScope::Read.allows(method, path) || (*method == Method::POST && path == "/")
+            }
+            TokenScope::Admin => true,
+        }
+    }
+}
+
+impl Display for TokenScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenScope::Read => write!(f, "read"),
+            TokenScope::Guess => write!(f, "guess"),
+            TokenScope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl FromStr for TokenScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(TokenScope::Read),
+            "guess" => Ok(TokenScope::Guess),
+            "admin" => Ok(TokenScope::Admin),
+            _ => Err(format!("invalid token scope: {}", s)),
+        }
+    }
+}
+
+/// A named personal API token, stored by token hash.
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ApiToken {
+    pub uuid: Uuid,
+    pub name: String,
+    pub player: Uuid,
+    pub scope: TokenScope,
+    pub created: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+/// Session data key for the [`SessionInfo`].
+pub const SESSION_INFO_KEY: &str = "session.info";
+
+/// Details of a logged in session, kept in the session data and refreshed as it is used.
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct SessionInfo {
+    pub created: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+/// One of a player's unexpired sessions.
+#[derive(Serialize, Debug, Clone)]
+pub struct ActiveSession {
+    // the session id is a bearer secret, never export it
+    #[serde(skip)]
+    pub id: String,
+    pub expires: DateTime<Utc>,
+    /// Missing for sessions that logged in before session details were recorded.
+    pub info: Option<SessionInfo>,
+}
+
+/// What a player can authenticate with.
+pub enum Credentials {
+    Password {
+        name: String,
+        password: String,
+    },
+    /// A verified OpenID Connect identity, linked to `link_to` if a player is logged in.
+    Oidc {
+        identity: OidcIdentity,
+        link_to: Option<Uuid>,
+    },
+    /// The hex pubkey of a verified NIP-98 event signer.
+    Nostr {
+        pubkey: String,
+        link_to: Option<Uuid>,
+    },
+    /// The hex linking key of a wallet that signed a LNURL-auth challenge.
+    Lnurl {
+        key: String,
+        link_to: Option<Uuid>,
+    },
+    /// A personal API token from an `Authorization: Bearer` header.
+    ApiToken {
+        token: String,
+    },
+    /// The user header of a request from a trusted reverse proxy.
+    Proxy {
+        user: String,
+    },
+    /// A login form name and password to bind to the LDAP directory with.
+    Ldap {
+        name: String,
+        password: String,
+    },
+}
+
+// don't leak passwords into debug logs
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Password { name, .. } => f
+                .debug_struct("Password")
+                .field("name", name)
+                .field("password", &"********")
+                .finish(),
+            Credentials::Oidc { identity, link_to } => f
+                .debug_struct("Oidc")
+                .field("identity", identity)
+                .field("link_to", link_to)
+                .finish(),
+            Credentials::Nostr { pubkey, link_to } => f
+                .debug_struct("Nostr")
+                .field("pubkey", pubkey)
+                .field("link_to", link_to)
+                .finish(),
+            Credentials::Lnurl { key, link_to } => f
+                .debug_struct("Lnurl")
+                .field("key", key)
+                .field("link_to", link_to)
+                .finish(),
+            Credentials::ApiToken { .. } => f
+                .debug_struct("ApiToken")
+                .field("token", &"********")
+                .finish(),
+            Credentials::Proxy { user } => f.debug_struct("Proxy").field("user", user).finish(),
+            Credentials::Ldap { name, .. } => f
+                .debug_struct("Ldap")
+                .field("name", name)
+                .field("password", &"********")
+                .finish(),
+        }
+    }
+}
+
+/// An identity verified by an OpenID Connect provider.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+    /// Preferred name, used for auto-provisioned players.
+    pub name: Option<String>,
+    pub groups: Vec<String>,
+}
+
+/// An external identity linked to a player.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum IdentityKey {
+    Oidc {
+        issuer: String,
+        subject: String,
+    },
+    /// Hex encoded x-only public key.
+    Nostr(String),
+    /// Hex encoded compressed LNURL-auth linking key.
+    Lnurl(String),
+    /// User name set by a trusted authenticating reverse proxy.
+    Proxy(String),
+    /// Lowercase DN of a directory user entry.
+    Ldap(String),
+}
+
+impl Display for IdentityKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentityKey::Oidc { issuer, subject } => write!(f, "oidc:{}#{}", issuer, subject),
+            IdentityKey::Nostr(pubkey) => write!(f, "nostr:{}", pubkey),
+            IdentityKey::Lnurl(key) => write!(f, "lnurl:{}", key),
+            IdentityKey::Proxy(user) => write!(f, "proxy:{}", user),
+            IdentityKey::Ldap(dn) => write!(f, "ldap:{}", dn),
+        }
+    }
+}
+
+/// Player roles after syncing with the player's IdP groups. Only roles named in the role
+/// map are added or removed, other roles are kept.
+pub fn sync_group_roles(
+    player_roles: &HashSet<Uuid>,
+    groups: &[String],
+    role_map: &HashMap<String, String>,
+    roles: &[Role],
+) -> HashSet<Uuid> {
+    let role_uuid = |name: &String| {
+        roles
+            .iter()
+            .find(|role| &role.name == name)
+            .map(|role| role.uuid)
+    };
+    let mapped_roles = role_map
+        .values()
+        .filter_map(role_uuid)
+        .collect::<HashSet<_>>();
+    let group_roles = groups
+        .iter()
+        .filter_map(|group| role_map.get(group))
+        .filter_map(role_uuid)
+        .collect::<HashSet<_>>();
+    player_roles
+        .iter()
+        .filter(|uuid| !mapped_roles.contains(uuid))
+        .copied()
+        .chain(group_roles)
+        .collect()
+}
+
+/// Whether a name follows the player name rule, 3 to 20 letters, digits or underscores as the
+/// name input patterns require.
+pub fn is_valid_name(name: &str) -> bool {
+    (3..=20).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A unique player name based on an external name, only valid name characters are kept.
+pub fn player_name_candidates(name: &str) -> impl Iterator<Item = String> {
+    let mut base = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .take(16)
+        .collect::<String>();
+    if base.len() < 3 {
+        base = format!("player{}", base);
+    }
+    std::iter::once(base.clone()).chain((2..).map(move |suffix| format!("{}{}", base, suffix)))
+}
+
+/// The key player names are unique by, names with the same confusable skeleton (Unicode
+/// TR39) are the same name regardless of case. `Alice`, `alice` and `AIice` share a key, the
+/// player's name is kept as typed.
+pub fn name_key(name: &str) -> String {
+    // a capital `I` passes for an `l` but folds to an `i`, so the skeleton is taken of the
+    // upper case name and again once folded, for lower case confusables such as `rn` and `m`
+    let upper_skeleton = skeleton(&name.to_uppercase()).collect::<String>();
+    let folded = default_case_fold_str(&upper_skeleton);
+    default_case_fold_str(&skeleton(&folded).collect::<String>())
+}
+
+/// A player renamed because its name had the same [`name_key`] as an older player's.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NameCollision {
+    /// The renamed player.
+    pub player: Player,
+    pub orig_name: String,
+    /// The older player that kept the name.
+    pub kept_by: Uuid,
+}
+
+/// Index players by [`name_key`]. The oldest player keeps its name, newer players whose name
+/// collides with it are renamed to the first free name candidate and reported. Renamed players
+/// are sent to the profile page, where they see their new name and can pick another.
+pub fn index_player_names(
+    mut players: Vec<Player>,
+) -> (BTreeMap<String, Uuid>, Vec<NameCollision>) {
+    players.sort_by_key(|player| (player.created, player.uuid));
+    let taken = players
+        .iter()
+        .map(|player| name_key(&player.name))
+        .collect::<HashSet<String>>();
+    let mut index = BTreeMap::new();
+    let mut collisions = Vec::new();
+    for mut player in players {
+        let key = name_key(&player.name);
+        let Some(kept_by) = index.get(&key).copied() else {
+            index.insert(key, player.uuid);
+            continue;
+        };
+        let name = player_name_candidates(&player.name)
+            .find(|name| {
+                let key = name_key(name);
+                !taken.contains(&key) && !index.contains_key(&key)
+            })
+            .expect("name candidates are endless");
+        warn!(
+            "player name {} of {} collides with the name of {}, renamed to {}",
+            player.name, player.uuid, kept_by, name
+        );
+        index.insert(name_key(&name), player.uuid);
+        let orig_name = std::mem::replace(&mut player.name, name);
+        player.renamed = true;
+        player.updated = Utc::now();
+        collisions.push(NameCollision {
+            player,
+            orig_name,
+            kept_by,
+        });
+    }
+    (index, collisions)
+}
+
+/// What failed login attempts are counted against, names by their [`name_key`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ThrottleKey {
+    Name(String),
+    /// A name tried from one ip, so failures from elsewhere can't lock its player out.
+    NameIp(String, IpAddr),
+    /// A name tried from any ip, only delayed and never locked out so it can't be used to
+    /// lock its player out.
+    Account(String),
+    Ip(IpAddr),
+}
+
+impl ThrottleKey {
+    /// Whether the string form of a key counts failures against the name.
+    pub fn is_for_name(key: &str, name: &str) -> bool {
+        let name_key = ThrottleKey::Name(name.to_string()).to_string();
+        key.strip_prefix(&name_key)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Whether enough failures lock the key out, or only delay the next attempt.
+    pub fn locks_out(&self) -> bool {
+        !matches!(self, ThrottleKey::Account(_))
+    }
+}
+
+// ipv6 clients usually get a whole /64, so its addresses are counted together
+fn ip_group(ip: &IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let prefix = Ipv6Addr::from(u128::from(ip) & !(u128::MAX >> 64));
+            format!("{}/64", prefix)
+        }
+    }
+}
+
+impl Display for ThrottleKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThrottleKey::Name(name) => write!(f, "name:{}", name_key(name)),
+            ThrottleKey::NameIp(name, ip) => {
+                write!(f, "name:{}/ip:{}", name_key(name), ip_group(ip))
+            }
+            ThrottleKey::Account(name) => write!(f, "name:{}/any", name_key(name)),
+            ThrottleKey::Ip(ip) => write!(f, "ip:{}", ip_group(ip)),
+        }
+    }
+}
+
+/// Failed login attempts for a [`ThrottleKey`].
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct LoginFailures {
+    pub failures: u32,
+    pub last_failure: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl LoginFailures {
+    /// Whether the failures are forgotten, no lockout is active and there was no failure
+    /// for the reset time.
+    pub fn is_stale(&self, now: DateTime<Utc>, config: &LoginThrottleConfig) -> bool {
+        self.locked_until.is_none_or(|until| until <= now)
+            && self.last_failure + TimeDelta::seconds(config.reset_secs) <= now
+    }
+
+    /// Add a failure, locking out once the threshold is reached. Stale failures are
+    /// forgotten first.
+    pub fn add_failure(self, now: DateTime<Utc>, config: &LoginThrottleConfig) -> Self {
+        let current = if self.is_stale(now, config) {
+            Self::default()
+        } else {
+            self
+        };
+        let failures = current.failures.saturating_add(1);
+        let locked_until = if failures >= config.lockout_threshold {
+            Some(now + TimeDelta::seconds(config.lockout_secs))
+        } else {
+            current.locked_until
+        };
+        Self {
+            failures,
+            last_failure: now,
+            locked_until,
+        }
+    }
+
+    /// The earliest time another attempt is allowed, if it is later than `now`.
+    pub fn retry_at(
+        &self,
+        now: DateTime<Utc>,
+        config: &LoginThrottleConfig,
+    ) -> Option<DateTime<Utc>> {
+        if let Some(locked_until) = self.locked_until.filter(|until| *until > now) {
+            return Some(locked_until);
+        }
+        if self.failures <= config.free_attempts || self.is_stale(now, config) {
+            return None;
+        }
+        // double the delay for each failure over the free attempts
+        let exponent = (self.failures - config.free_attempts - 1).min(30);
+        let delay_secs = config
+            .base_delay_secs
+            .saturating_mul(1 << exponent)
+            .min(config.max_delay_secs);
+        Some(self.last_failure + TimeDelta::seconds(delay_secs)).filter(|retry| *retry > now)
+    }
+}
+
+impl Default for LoginFailures {
+    fn default() -> Self {
+        Self {
+            failures: 0,
+            last_failure: datetime_now(),
+            locked_until: None,
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum RegisterError {
     #[error("invalid user name")]
@@ -73,10 +586,32 @@ pub enum RegisterError {
     InvalidPassword,
     #[error("password not confirmed")]
     UnconfirmedPassword,
+    #[error("new password same as current password")]
+    UnchangedPassword,
+    #[error("incorrect current password for name: {0}")]
+    IncorrectPassword(String),
+    #[error("invalid api token name")]
+    InvalidTokenName,
+    #[error("api token scope not allowed: {0}")]
+    InvalidTokenScope(TokenScope),
+    #[error("admin api token requires two-factor authentication for name: {0}")]
+    TokenRequiresTotp(String),
+    #[error("account deletion not confirmed")]
+    UnconfirmedDelete,
+    #[error("last admin can not be deleted: {0}")]
+    LastAdmin(String),
+    #[error("invalid or expired password reset token")]
+    InvalidResetToken,
+    #[error("invalid, used or expired invite code")]
+    InvalidInviteCode,
     #[error("user already registered: {0}")]
     UserAlreadyRegistered(String),
     #[error("failed authentication for name: {0}")]
     Authentication(String),
+    #[error("too many failed attempts, locked until: {0}")]
+    LockedOut(DateTime<Utc>),
+    #[error(transparent)]
+    Totp(#[from] TotpError),
     #[error(transparent)]
     Internal(#[from] axum_login::Error<AuthBackend>),
 }
@@ -85,18 +620,35 @@ pub enum RegisterError {
 pub enum LoginError {
     #[error("failed authentication for name: {0}")]
     Authentication(String),
+    #[error("too many failed attempts, locked until: {0}")]
+    LockedOut(DateTime<Utc>),
+    #[error(transparent)]
+    Oidc(#[from] OidcError),
+    #[error(transparent)]
+    Nostr(#[from] NostrError),
+    #[error(transparent)]
+    Lnurl(#[from] LnurlError),
+    #[error(transparent)]
+    Totp(#[from] TotpError),
     #[error(transparent)]
     Internal(#[from] axum_login::Error<AuthBackend>),
 }
 
 #[cfg(test)]
 mod test {
+    use crate::auth::config::LoginThrottleConfig;
     use crate::auth::types::Permission::AssignAdm;
-    use crate::auth::types::{Permission, Player, Role};
+    use crate::auth::types::{
+        index_player_names, name_key, player_name_candidates, sync_group_roles, LoginFailures,
+        Permission, Player, Role, ThrottleKey, TokenScope,
+    };
+    use crate::encoding::Encoded;
     use crate::types::UuidKey;
+    use axum::http::Method;
+    use chrono::{TimeDelta, Utc};
     use password_auth::generate_hash;
     use redb::{Key, Value};
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
     use uuid::Uuid;
 
     #[test]
@@ -114,8 +666,9 @@ mod test {
             roles,
             ..Default::default()
         };
-        let encoded_player = Player::as_bytes(&orig_player);
-        let decoded_player = Player::from_bytes(&encoded_player);
+        let key = orig_player.uuid.as_bytes();
+        let encoded_player = Encoded::keyed(key, &orig_player);
+        let decoded_player = encoded_player.decode_keyed(key).unwrap();
         assert_eq!(orig_player, decoded_player);
     }
 
@@ -126,11 +679,192 @@ mod test {
             name: "test".to_string(),
             permissions: HashSet::from([Permission::AssignAdm]),
         };
-        let encoded_role = Role::as_bytes(&orig_role);
-        let decoded_role = Role::from_bytes(&encoded_role);
+        let key = orig_role.uuid.as_bytes();
+        let encoded_role = Encoded::keyed(key, &orig_role);
+        let decoded_role = encoded_role.decode_keyed(key).unwrap();
         assert_eq!(orig_role, decoded_role);
     }
 
+    #[test]
+    fn test_login_failures_retry_at() {
+        let config = LoginThrottleConfig::default();
+        let now = Utc::now();
+        let mut login_failures = LoginFailures::default();
+        for _ in 0..config.free_attempts {
+            login_failures = login_failures.add_failure(now, &config);
+            assert_eq!(login_failures.retry_at(now, &config), None);
+        }
+        // progressive delays after the free attempts
+        login_failures = login_failures.add_failure(now, &config);
+        let first_delay = login_failures.retry_at(now, &config).expect("delay") - now;
+        assert_eq!(first_delay, TimeDelta::seconds(config.base_delay_secs));
+        login_failures = login_failures.add_failure(now, &config);
+        let second_delay = login_failures.retry_at(now, &config).expect("delay") - now;
+        assert_eq!(second_delay, TimeDelta::seconds(config.base_delay_secs * 2));
+        let after_delay = now + second_delay + TimeDelta::seconds(1);
+        assert_eq!(login_failures.retry_at(after_delay, &config), None);
+        // lockout after the threshold
+        while login_failures.failures < config.lockout_threshold {
+            login_failures = login_failures.add_failure(now, &config);
+        }
+        let locked_until = now + TimeDelta::seconds(config.lockout_secs);
+        assert_eq!(login_failures.retry_at(now, &config), Some(locked_until));
+        assert_eq!(
+            login_failures.retry_at(locked_until + TimeDelta::seconds(1), &config),
+            None
+        );
+        // failures are forgotten after a quiet reset time, but not while locked out
+        assert!(!login_failures.is_stale(now, &config));
+        let quiet = now + TimeDelta::seconds(config.reset_secs.max(config.lockout_secs));
+        assert!(login_failures.is_stale(quiet, &config));
+        login_failures = login_failures.add_failure(quiet, &config);
+        assert_eq!(login_failures.failures, 1);
+        assert_eq!(login_failures.locked_until, None);
+        assert_eq!(login_failures.retry_at(quiet, &config), None);
+    }
+
+    #[test]
+    fn test_throttle_key_for_name() {
+        let ip = [127, 0, 0, 1].into();
+        let name_ip = ThrottleKey::NameIp("Tester".to_string(), ip).to_string();
+        assert_eq!(name_ip, "name:tester/ip:127.0.0.1");
+        assert!(ThrottleKey::is_for_name(&name_ip, "tester"));
+        assert!(ThrottleKey::is_for_name("name:tester", "Tester"));
+        assert!(!ThrottleKey::is_for_name(
+            "name:tester2/ip:127.0.0.1",
+            "tester"
+        ));
+        assert!(!ThrottleKey::is_for_name("ip:127.0.0.1", "tester"));
+        let account = ThrottleKey::Account("Tester".to_string());
+        assert!(ThrottleKey::is_for_name(&account.to_string(), "tester"));
+        assert!(!account.locks_out());
+        assert!(ThrottleKey::Ip(ip).locks_out());
+    }
+
+    #[test]
+    fn test_throttle_key_ip_group() {
+        let ip = |ip: &str| ip.parse::<std::net::IpAddr>().unwrap();
+        assert_eq!(
+            ThrottleKey::Ip(ip("2001:db8:1:2:3:4:5:6")).to_string(),
+            "ip:2001:db8:1:2::/64"
+        );
+        assert_eq!(
+            ThrottleKey::Ip(ip("2001:db8:1:2:ffff::1")).to_string(),
+            ThrottleKey::Ip(ip("2001:db8:1:2::2")).to_string()
+        );
+        assert_eq!(
+            ThrottleKey::NameIp("tester".to_string(), ip("::ffff:10.0.0.1")).to_string(),
+            "name:tester/ip:10.0.0.1"
+        );
+    }
+
+    #[test]
+    fn test_sync_group_roles() {
+        let role = |name: &str| Role {
+            uuid: Uuid::new_v4(),
+            name: name.to_string(),
+            permissions: HashSet::new(),
+        };
+        let roles = vec![role("admin"), role("moderator"), role("local")];
+        let role_map = HashMap::from([
+            ("game-admins".to_string(), "admin".to_string()),
+            ("mods".to_string(), "moderator".to_string()),
+        ]);
+        // mapped roles follow the groups, unmapped roles are kept
+        let player_roles = HashSet::from([roles[1].uuid, roles[2].uuid]);
+        let synced = sync_group_roles(
+            &player_roles,
+            &["game-admins".to_string(), "staff".to_string()],
+            &role_map,
+            &roles,
+        );
+        assert_eq!(synced, HashSet::from([roles[0].uuid, roles[2].uuid]));
+        let synced = sync_group_roles(&synced, &[], &role_map, &roles);
+        assert_eq!(synced, HashSet::from([roles[2].uuid]));
+    }
+
+    #[test]
+    fn test_player_name_candidates() {
+        let names = player_name_candidates("alice.smith@example").take(2);
+        assert_eq!(
+            names.collect::<Vec<_>>(),
+            vec!["alicesmithexampl", "alicesmithexampl2"]
+        );
+        let name = player_name_candidates("Zé").next();
+        assert_eq!(name.as_deref(), Some("playerZ"));
+    }
+
+    #[test]
+    fn test_name_key() {
+        assert_eq!(name_key("Alice"), name_key("alice"));
+        assert_eq!(name_key("ALICE"), name_key("alice"));
+        // confusable characters
+        assert_eq!(name_key("AIice"), name_key("alice"));
+        assert_eq!(name_key("a1ice"), name_key("alice"));
+        assert_eq!(name_key("b0b"), name_key("BOB"));
+        assert_eq!(name_key("Аlice"), name_key("alice"), "cyrillic A");
+        assert_eq!(name_key("rnike"), name_key("Mike"));
+        assert_ne!(name_key("alice"), name_key("alicia"));
+        assert_ne!(name_key("bob"), name_key("bob_"));
+    }
+
+    #[test]
+    fn test_index_player_names() {
+        let now = Utc::now();
+        let alice = Player {
+            uuid: Uuid::new_v4(),
+            name: "alice".to_string(),
+            created: now - TimeDelta::days(2),
+            ..Default::default()
+        };
+        let upper_alice = Player {
+            uuid: Uuid::new_v4(),
+            name: "Alice".to_string(),
+            created: now - TimeDelta::days(1),
+            ..Default::default()
+        };
+        // the first rename candidate is taken by another player
+        let alice2 = Player {
+            uuid: Uuid::new_v4(),
+            name: "alice2".to_string(),
+            created: now,
+            ..Default::default()
+        };
+        let (index, collisions) =
+            index_player_names(vec![upper_alice.clone(), alice2.clone(), alice.clone()]);
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.get(&name_key("ALICE")), Some(&alice.uuid));
+        assert_eq!(index.get(&name_key("alice2")), Some(&alice2.uuid));
+        assert_eq!(index.get(&name_key("alice3")), Some(&upper_alice.uuid));
+        assert_eq!(collisions.len(), 1);
+        let collision = &collisions[0];
+        assert_eq!(collision.player.uuid, upper_alice.uuid);
+        assert_eq!(collision.player.name, "Alice3");
+        assert!(collision.player.renamed);
+        assert!(!collision.player.must_change_password);
+        assert_eq!(collision.orig_name, "Alice");
+        assert_eq!(collision.kept_by, alice.uuid);
+    }
+
+    #[test]
+    fn test_token_scope_allows() {
+        assert!(TokenScope::Read.allows(&Method::GET, "/guess/table"));
+        assert!(TokenScope::Read.allows(&Method::HEAD, "/target/table"));
+        assert!(!TokenScope::Read.allows(&Method::POST, "/"));
+        assert!(!TokenScope::Read.allows(&Method::GET, "/admin"));
+        assert!(!TokenScope::Read.allows(&Method::GET, "/admin/export"));
+        assert!(!TokenScope::Read.allows(&Method::GET, "/profile/tokens"));
+        assert!(!TokenScope::Read.allows(&Method::GET, "/logout"));
+        assert!(TokenScope::Guess.allows(&Method::POST, "/"));
+        assert!(!TokenScope::Guess.allows(&Method::POST, "/target"));
+        assert!(!TokenScope::Guess.allows(&Method::POST, "/profile/tokens"));
+        assert!(!TokenScope::Guess.allows(&Method::GET, "/admin/audit/export"));
+        assert!(!TokenScope::Guess.allows(&Method::POST, "/logout"));
+        assert!(TokenScope::Admin.allows(&Method::POST, "/target"));
+        assert_eq!("guess".parse::<TokenScope>(), Ok(TokenScope::Guess));
+        assert!("write".parse::<TokenScope>().is_err());
+    }
+
     #[test]
     fn test_uuidkey_encode_decode() {
         let orig_uuidkey = UuidKey(Uuid::new_v4());
@@ -1,32 +1,98 @@
 use super::backend::{AuthBackend, AuthSession};
-use super::types::{datetime_now, LoginError, Player, RegisterError};
+use super::config::{DeletedGuesses, RegistrationMode};
+use super::lnurl::{callback_url, encode_lnurl, qr_svg, verify_signature, LnurlError, LnurlLogin};
+use super::nostr::{NostrChallenge, NostrError, NostrEvent};
+use super::oidc::{OidcError, OidcLogin};
+use super::totp::{
+    base32_encode, new_recovery_codes, new_secret, otpauth_url, TotpEnrollment, TotpError,
+    TotpLogin,
+};
+use super::types::{
+    datetime_now, generate_token, is_valid_name, ActiveSession, ApiToken, Credentials, LoginError,
+    Permission, Player, RegisterError, SessionInfo, ThrottleKey, TokenScope, SESSION_INFO_KEY,
+};
 use crate::app::AppState;
+use crate::audit::types::{AuditAction, AuditEvent};
 use crate::types::InternalError;
-use axum::extract::Query;
-use axum::http::{HeaderValue, StatusCode};
-use axum::response::{Html, IntoResponse, Response};
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts, Path, Query, Request, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_DISPOSITION, USER_AGENT};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{Html, IntoResponse, Redirect, Response};
 use axum::routing::{get, post};
-use axum::{Form, Router};
-use axum_login::login_required;
+use axum::{Form, Json, Router};
 use axum_login::Error::Backend;
-use password_auth::generate_hash;
-use regex::Regex;
+use axum_login::{login_required, AuthnBackend};
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use password_auth::{generate_hash, verify_password};
 use rinja::Template;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use std::sync::Arc;
-use tracing::{error, info};
+use tower_sessions::session::Id;
+use tower_sessions::Session;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
+// session key for the pending OpenID Connect login
+const OIDC_LOGIN_KEY: &str = "oidc.login";
+// session key for the pending Nostr login challenge
+const NOSTR_CHALLENGE_KEY: &str = "nostr.challenge";
+const NOSTR_CHALLENGE_TTL_SECS: i64 = 5 * 60;
+// session key for the LNURL-auth challenge shown to the browser
+const LNURL_LOGIN_KEY: &str = "lnurl.login";
+// how often a session's last seen time is written back
+const SESSION_SEEN_INTERVAL_SECS: i64 = 60;
+const MAX_API_TOKEN_NAME_LEN: usize = 40;
+// session key for a login waiting for its two-factor code
+const TOTP_LOGIN_KEY: &str = "totp.login";
+const TOTP_LOGIN_TTL_SECS: i64 = 5 * 60;
+const TOTP_LOGIN_PATH: &str = "/login/totp";
+// session key for an authenticator enrollment that isn't confirmed yet
+const TOTP_ENROLLMENT_KEY: &str = "totp.enrollment";
+// shown as the account issuer in authenticator apps
+const TOTP_ISSUER: &str = "Nonce Guess";
+// session key for the reverse proxy user a session was logged in as
+const PROXY_USER_KEY: &str = "proxy.user";
+
 pub fn router() -> Router<Arc<AppState>> {
     Router::new()
         .route("/profile", get(profile_page))
-        .route("/profile", post(change_profile))
+        .route("/profile/name", post(change_name))
+        .route("/profile/password", post(change_password))
+        .route("/profile/export", get(export_profile))
+        .route("/profile/delete", post(delete_account))
+        .route("/profile/tokens", post(create_api_token))
+        .route("/profile/tokens/revoke", post(revoke_api_token))
+        .route("/profile/sessions/revoke", post(revoke_session))
+        .route("/profile/totp", post(start_totp_enrollment))
+        .route("/profile/totp/confirm", post(confirm_totp_enrollment))
+        .route("/profile/totp/disable", post(disable_totp))
+        .route(
+            "/profile/sessions/revoke_others",
+            post(revoke_other_sessions),
+        )
         .route("/logout", get(logout))
         .route_layer(login_required!(AuthBackend, login_url = "/login"))
         .route("/login", get(login_page))
         .route("/login", post(login_password))
+        .route("/login/oidc", get(login_oidc))
+        .route("/login/oidc/callback", get(oidc_callback))
+        .route("/login/nostr", get(nostr_challenge))
+        .route("/login/nostr", post(login_nostr))
+        .route("/login/lnurl", get(lnurl_page))
+        .route("/login/lnurl/callback", get(lnurl_callback))
+        .route("/login/lnurl/status", get(lnurl_status))
+        .route(TOTP_LOGIN_PATH, get(totp_login_page))
+        .route(TOTP_LOGIN_PATH, post(login_totp))
         .route("/register", get(register_page))
         .route("/register", post(register_password))
+        .route("/reset/:token", get(reset_page))
+        .route("/reset/:token", post(reset_password))
 }
 
 /// login page template
@@ -34,13 +100,44 @@ pub fn router() -> Router<Arc<AppState>> {
 #[template(path = "login.html")]
 struct LoginTemplate {
     next: Option<String>,
+    /// Name of the single sign-on provider, if configured.
+    oidc_name: Option<String>,
+    nostr_enabled: bool,
+    lnurl_enabled: bool,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginQuery {
+    next: Option<String>,
+    error: Option<String>,
 }
 
 #[axum::debug_handler]
 async fn login_page(
-    Query(NextUrl { next }): Query<NextUrl>,
+    auth_session: AuthSession,
+    Query(LoginQuery { next, error }): Query<LoginQuery>,
 ) -> Result<impl IntoResponse, InternalError> {
-    let page = LoginTemplate { next };
+    let oidc_name = auth_session
+        .backend
+        .config
+        .oidc
+        .as_ref()
+        .map(|oidc_config| oidc_config.name.clone());
+    let error = error.map(|error| match error.as_str() {
+        "sso" => "Single sign-on failed.".to_string(),
+        "sso_unlinked" => "No player is linked to this single sign-on account.".to_string(),
+        _ => "Login failed.".to_string(),
+    });
+    let nostr_enabled = auth_session.backend.config.nostr.is_some();
+    let lnurl_enabled = auth_session.backend.config.lnurl.is_some();
+    let page = LoginTemplate {
+        next,
+        oidc_name,
+        nostr_enabled,
+        lnurl_enabled,
+        error,
+    };
     let mut response = Html(page.render()?).into_response();
     response
         .headers_mut()
@@ -50,17 +147,69 @@ async fn login_page(
 
 #[derive(Template)]
 #[template(path = "register.html")]
-struct RegisterTemplate {}
+struct RegisterTemplate {
+    invite_required: bool,
+    invite_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InviteQuery {
+    invite: Option<String>,
+}
+
+#[axum::debug_handler]
+async fn register_page(
+    auth_session: AuthSession,
+    Query(InviteQuery { invite }): Query<InviteQuery>,
+) -> Result<impl IntoResponse, InternalError> {
+    let invite_required = auth_session.backend.config.registration == RegistrationMode::Invite;
+    Ok(Html(
+        RegisterTemplate {
+            invite_required,
+            invite_code: invite,
+        }
+        .render()?,
+    ))
+}
+
+#[derive(Template)]
+#[template(path = "reset.html")]
+struct ResetTemplate {
+    token: String,
+    valid: bool,
+}
 
 #[axum::debug_handler]
-async fn register_page() -> Result<impl IntoResponse, InternalError> {
-    Ok(Html(RegisterTemplate {}.render()?))
+async fn reset_page(
+    auth_session: AuthSession,
+    Path(token): Path<String>,
+) -> Result<impl IntoResponse, InternalError> {
+    let valid = auth_session
+        .backend
+        .get_reset_token(&token)
+        .await?
+        .is_some();
+    Ok(Html(ResetTemplate { token, valid }.render()?))
 }
 
 #[derive(Template)]
 #[template(path = "profile.html")]
 struct ProfileTemplate {
     player: Player,
+    is_admin: bool,
+    oidc_name: Option<String>,
+    nostr_enabled: bool,
+    lnurl_enabled: bool,
+    /// Linked external logins.
+    identities: Vec<String>,
+    sessions: Vec<ActiveSession>,
+    current_session: Option<String>,
+    api_tokens: Vec<ApiToken>,
+    /// The token scopes the player may create.
+    token_scopes: Vec<TokenScope>,
+    /// Unused recovery codes if two-factor authentication is enabled.
+    totp_recovery_codes_left: Option<usize>,
+    totp_required: bool,
 }
 
 // Any filter defined in the module `filters` is accessible in your template.
@@ -74,9 +223,59 @@ pub mod filters {
 }
 
 #[axum::debug_handler]
-async fn profile_page(auth_session: AuthSession) -> Result<impl IntoResponse, InternalError> {
+async fn profile_page(
+    auth_session: AuthSession,
+    session: Session,
+) -> Result<impl IntoResponse, InternalError> {
     let player = auth_session.user.expect("player must be logged in");
-    Ok(Html(ProfileTemplate { player }.render()?))
+    let permissions = auth_session.backend.get_player_permissions(&player).await?;
+    let is_admin = permissions.contains(&Permission::AssignAdm);
+    let oidc_name = auth_session
+        .backend
+        .config
+        .oidc
+        .as_ref()
+        .map(|oidc_config| oidc_config.name.clone());
+    let nostr_enabled = auth_session.backend.config.nostr.is_some();
+    let lnurl_enabled = auth_session.backend.config.lnurl.is_some();
+    let identities = auth_session
+        .backend
+        .get_player_identities(&player.uuid)
+        .await?;
+    let sessions = auth_session
+        .backend
+        .get_player_sessions(&player.uuid)
+        .await?;
+    let current_session = session.id().map(|id| id.to_string());
+    let mut api_tokens = auth_session
+        .backend
+        .get_player_api_tokens(&player.uuid)
+        .await?;
+    api_tokens.sort_by_key(|api_token| api_token.created);
+    let token_scopes = token_scopes(&permissions);
+    let totp_recovery_codes_left = auth_session
+        .backend
+        .get_totp(&player.uuid)
+        .await?
+        .map(|totp| totp.recovery_codes.len());
+    let totp_required = auth_session.backend.requires_totp(&player).await?;
+    Ok(Html(
+        ProfileTemplate {
+            player,
+            is_admin,
+            oidc_name,
+            nostr_enabled,
+            lnurl_enabled,
+            identities,
+            sessions,
+            current_session,
+            api_tokens,
+            token_scopes,
+            totp_recovery_codes_left,
+            totp_required,
+        }
+        .render()?,
+    ))
 }
 
 #[derive(Deserialize)]
@@ -91,16 +290,33 @@ impl LoginForm {
         generate_hash(&self.password)
     }
 
-    pub fn credentials(&self) -> (String, String) {
-        (self.username.clone(), self.password.clone())
+    pub fn credentials(&self) -> Credentials {
+        Credentials::Password {
+            name: self.username.clone(),
+            password: self.password.clone(),
+        }
+    }
+
+    pub fn ldap_credentials(&self) -> Credentials {
+        Credentials::Ldap {
+            name: self.username.clone(),
+            password: self.password.clone(),
+        }
     }
 }
 
+#[derive(Deserialize)]
+pub struct ResetForm {
+    new_password: String,
+    confirm_password: String,
+}
+
 #[derive(Deserialize)]
 pub struct RegisterForm {
     new_username: String,
     new_password: String,
     confirm_password: String,
+    invite_code: Option<String>,
     next: Option<String>,
 }
 
@@ -109,8 +325,11 @@ impl RegisterForm {
         generate_hash(&self.new_password)
     }
 
-    pub fn credentials(&self) -> (String, String) {
-        (self.new_username.clone(), self.new_password.clone())
+    pub fn credentials(&self) -> Credentials {
+        Credentials::Password {
+            name: self.new_username.clone(),
+            password: self.new_password.clone(),
+        }
     }
 }
 
@@ -121,15 +340,164 @@ pub struct NextUrl {
     next: Option<String>,
 }
 
+/// The client ip address, from `X-Forwarded-For` only if configured to trust it.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let trust_forwarded_for = parts
+            .extensions
+            .get::<AuthSession>()
+            .is_some_and(|auth_session| auth_session.backend.config.trust_forwarded_for);
+        let forwarded_ip = parts
+            .headers
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+            .filter(|_| trust_forwarded_for);
+        let connect_ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        forwarded_ip
+            .or(connect_ip)
+            .map(ClientIp)
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+// record a successful login and the method used in the audit log
+async fn audit_login(
+    auth_session: &AuthSession,
+    player: &Player,
+    method: &str,
+    ip: Option<IpAddr>,
+) -> Result<(), InternalError> {
+    let event = AuditEvent {
+        subject: Some(player.name.clone()),
+        after: Some(method.to_string()),
+        ip,
+        ..AuditEvent::new(AuditAction::Login, Some(player))
+    };
+    auth_session.backend.audit_log.record(event).await
+}
+
+// record a failed login attempt for a name, pubkey or wallet key
+async fn audit_login_failed(
+    auth_session: &AuthSession,
+    subject: &str,
+    method: &str,
+    ip: Option<IpAddr>,
+) -> Result<(), InternalError> {
+    let event = AuditEvent {
+        subject: Some(subject.to_string()),
+        after: Some(method.to_string()),
+        ip,
+        ..AuditEvent::new(AuditAction::LoginFailed, None)
+    };
+    auth_session.backend.audit_log.record(event).await
+}
+
+/// Log a player in once the first factor is verified, or start the two-factor step if they
+/// have an authenticator enabled. Returns where to send the browser next.
+async fn finish_login(
+    auth_session: &mut AuthSession,
+    session: &Session,
+    player: &Player,
+    method: &str,
+    ip: Option<IpAddr>,
+    next: Option<String>,
+) -> Result<String, LoginError> {
+    // a player linking an identity to their own account is already fully logged in
+    let linking = auth_session
+        .user
+        .as_ref()
+        .is_some_and(|user| user.uuid == player.uuid);
+    let totp_enabled = auth_session
+        .backend
+        .get_totp(&player.uuid)
+        .await
+        .map_err(Backend)?
+        .is_some();
+    if totp_enabled && !linking {
+        let totp_login = TotpLogin {
+            player: player.uuid,
+            method: method.to_string(),
+            next,
+            expires: datetime_now() + TimeDelta::seconds(TOTP_LOGIN_TTL_SECS),
+        };
+        session
+            .insert(TOTP_LOGIN_KEY, totp_login)
+            .await
+            .map_err(TotpError::from)?;
+        return Ok(TOTP_LOGIN_PATH.to_string());
+    }
+    auth_session.login(player).await?;
+    audit_login(auth_session, player, method, ip)
+        .await
+        .map_err(Backend)?;
+    Ok(next.unwrap_or("/".to_string()))
+}
+
 async fn login_password(
     mut auth_session: AuthSession,
+    session: Session,
+    ClientIp(ip): ClientIp,
     Form(login_form): Form<LoginForm>,
 ) -> Result<Response, LoginError> {
-    if let Some(player) = auth_session.authenticate(login_form.credentials()).await? {
+    let throttle_keys = vec![
+        ThrottleKey::NameIp(login_form.username.clone(), ip),
+        ThrottleKey::Account(login_form.username.clone()),
+        ThrottleKey::Ip(ip),
+    ];
+    if let Some(retry_at) = auth_session
+        .backend
+        .login_retry_at(throttle_keys.clone())
+        .await
+        .map_err(Backend)?
+    {
+        return Err(LoginError::LockedOut(retry_at));
+    }
+    // local accounts are tried first, so they keep working when the directory is down
+    let mut method = "password";
+    let mut player = auth_session.authenticate(login_form.credentials()).await?;
+    if player.is_none() && auth_session.backend.ldap.is_some() {
+        method = "ldap";
+        // a directory error counts as a failed login, so it can't be used to skip throttling
+        player = auth_session
+            .authenticate(login_form.ldap_credentials())
+            .await
+            .unwrap_or_else(|e| {
+                error!("ldap login of {} failed: {}", login_form.username, e);
+                None
+            });
+    }
+    if let Some(player) = player {
         // update session so user is logged in
-        auth_session.login(&player).await?;
+        let next = finish_login(
+            &mut auth_session,
+            &session,
+            &player,
+            method,
+            Some(ip),
+            login_form.next,
+        )
+        .await?;
+        // failures are only cleared once the second factor is verified too, so the password
+        // can't be used to reset the code throttling
+        if next != TOTP_LOGIN_PATH {
+            auth_session
+                .backend
+                .clear_login_failures(throttle_keys)
+                .await
+                .map_err(Backend)?;
+        }
         let mut response = StatusCode::OK.into_response();
-        let next = login_form.next.unwrap_or("/".to_string());
         response.headers_mut().insert(
             "HX-Location",
             HeaderValue::try_from(next).expect("next value"),
@@ -137,30 +505,499 @@ async fn login_password(
         Ok(response)
     } else {
         // failed authentication
+        auth_session
+            .backend
+            .record_login_failure(throttle_keys)
+            .await
+            .map_err(Backend)?;
+        audit_login_failed(&auth_session, &login_form.username, method, Some(ip))
+            .await
+            .map_err(Backend)?;
         Err(LoginError::Authentication(login_form.username))
     }
 }
 
+/// Start a single sign-on login by redirecting to the identity provider.
+async fn login_oidc(
+    auth_session: AuthSession,
+    session: Session,
+    Query(NextUrl { next }): Query<NextUrl>,
+) -> Result<Response, LoginError> {
+    let oidc = auth_session.backend.oidc.ok_or(OidcError::NotConfigured)?;
+    // only redirect back to local paths
+    let next = next.filter(|next| next.starts_with('/') && !next.starts_with("//"));
+    let (authorization_url, oidc_login) = oidc.authorization_url(next).await?;
+    session
+        .insert(OIDC_LOGIN_KEY, oidc_login)
+        .await
+        .map_err(OidcError::from)?;
+    Ok(Redirect::to(authorization_url.as_str()).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// Finish a single sign-on login, linking the identity if a player is already logged in.
+async fn oidc_callback(
+    mut auth_session: AuthSession,
+    session: Session,
+    client_ip: Option<ClientIp>,
+    Query(query): Query<OidcCallbackQuery>,
+) -> Response {
+    match oidc_authenticate(&auth_session, &session, query).await {
+        Ok((Some(player), next)) => {
+            let ip = client_ip.map(|ClientIp(ip)| ip);
+            match finish_login(&mut auth_session, &session, &player, "oidc", ip, next).await {
+                Ok(next) => Redirect::to(&next).into_response(),
+                Err(e) => {
+                    error!("{}", e);
+                    Redirect::to("/login?error=sso").into_response()
+                }
+            }
+        }
+        Ok((None, _)) => Redirect::to("/login?error=sso_unlinked").into_response(),
+        Err(e) => {
+            warn!("single sign-on failed: {}", e);
+            Redirect::to("/login?error=sso").into_response()
+        }
+    }
+}
+
+async fn oidc_authenticate(
+    auth_session: &AuthSession,
+    session: &Session,
+    query: OidcCallbackQuery,
+) -> Result<(Option<Player>, Option<String>), LoginError> {
+    let oidc = auth_session
+        .backend
+        .oidc
+        .as_ref()
+        .ok_or(OidcError::NotConfigured)?;
+    // the pending login is single use
+    let oidc_login = session
+        .remove::<OidcLogin>(OIDC_LOGIN_KEY)
+        .await
+        .map_err(OidcError::from)?
+        .filter(|oidc_login| query.state.as_ref() == Some(&oidc_login.state))
+        .ok_or(OidcError::InvalidState)?;
+    if let Some(error) = query.error {
+        return Err(OidcError::Provider(error).into());
+    }
+    let code = query
+        .code
+        .ok_or_else(|| OidcError::Provider("missing code".to_string()))?;
+    let identity = oidc.exchange_code(&oidc_login, &code).await?;
+    let credentials = Credentials::Oidc {
+        identity,
+        link_to: auth_session.user.as_ref().map(|player| player.uuid),
+    };
+    let player = auth_session
+        .backend
+        .authenticate(credentials)
+        .await
+        .map_err(Backend)?;
+    Ok((player, oidc_login.next))
+}
+
+fn nostr_login_url(auth_session: &AuthSession) -> String {
+    auth_session
+        .backend
+        .config
+        .public_url
+        .join("/login/nostr")
+        .expect("valid url")
+        .to_string()
+}
+
+#[derive(Serialize)]
+struct NostrChallengeResponse {
+    challenge: String,
+    /// The `u` tag value the signed event must have.
+    url: String,
+}
+
+/// Issue a challenge for the browser to sign with a NIP-07 signer.
+async fn nostr_challenge(
+    auth_session: AuthSession,
+    session: Session,
+) -> Result<Response, LoginError> {
+    if auth_session.backend.config.nostr.is_none() {
+        return Err(NostrError::NotEnabled.into());
+    }
+    let nostr_challenge = NostrChallenge {
+        challenge: generate_token(),
+        expires: datetime_now() + TimeDelta::seconds(NOSTR_CHALLENGE_TTL_SECS),
+    };
+    let challenge = nostr_challenge.challenge.clone();
+    session
+        .insert(NOSTR_CHALLENGE_KEY, nostr_challenge)
+        .await
+        .map_err(NostrError::from)?;
+    Ok(Json(NostrChallengeResponse {
+        challenge,
+        url: nostr_login_url(&auth_session),
+    })
+    .into_response())
+}
+
+/// Log in with a NIP-98 `Authorization: Nostr` signed challenge event, linking the pubkey
+/// if a player is already logged in.
+async fn login_nostr(
+    mut auth_session: AuthSession,
+    session: Session,
+    client_ip: Option<ClientIp>,
+    headers: HeaderMap,
+) -> Result<Response, LoginError> {
+    if auth_session.backend.config.nostr.is_none() {
+        return Err(NostrError::NotEnabled.into());
+    }
+    let event = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(NostrError::InvalidHeader)
+        .and_then(NostrEvent::from_authorization)?;
+    // the challenge is single use
+    let now = datetime_now();
+    let nostr_challenge = session
+        .remove::<NostrChallenge>(NOSTR_CHALLENGE_KEY)
+        .await
+        .map_err(NostrError::from)?
+        .filter(|nostr_challenge| nostr_challenge.expires > now)
+        .ok_or(NostrError::InvalidChallenge)?;
+    let pubkey = event.verify_http_auth(
+        &nostr_login_url(&auth_session),
+        "POST",
+        &nostr_challenge.challenge,
+        now,
+    )?;
+    let ip = client_ip.map(|ClientIp(ip)| ip);
+    let credentials = Credentials::Nostr {
+        pubkey: pubkey.clone(),
+        link_to: auth_session.user.as_ref().map(|player| player.uuid),
+    };
+    match auth_session.authenticate(credentials).await? {
+        Some(player) => {
+            let next =
+                finish_login(&mut auth_session, &session, &player, "nostr", ip, None).await?;
+            let mut response = StatusCode::OK.into_response();
+            response.headers_mut().insert(
+                "HX-Location",
+                HeaderValue::try_from(next).expect("next value"),
+            );
+            Ok(response)
+        }
+        None => {
+            audit_login_failed(&auth_session, &pubkey, "nostr", ip)
+                .await
+                .map_err(Backend)?;
+            Err(LoginError::Authentication(pubkey))
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "lnurl.html")]
+struct LnurlTemplate {
+    lnurl: String,
+    qr_svg: String,
+}
+
+/// Show a LNURL-auth QR code for a new challenge, the page polls until a wallet signs it.
+async fn lnurl_page(
+    auth_session: AuthSession,
+    session: Session,
+    Query(NextUrl { next }): Query<NextUrl>,
+) -> Result<Response, LoginError> {
+    if auth_session.backend.config.lnurl.is_none() {
+        return Err(LnurlError::NotEnabled.into());
+    }
+    let challenge = auth_session
+        .backend
+        .create_lnurl_challenge()
+        .await
+        .map_err(Backend)?;
+    let lnurl = encode_lnurl(&callback_url(
+        &auth_session.backend.config.public_url,
+        &challenge.k1,
+    ))?;
+    let qr_svg = qr_svg(&lnurl).map_err(LnurlError::from)?;
+    // only redirect back to local paths
+    let next = next.filter(|next| next.starts_with('/') && !next.starts_with("//"));
+    session
+        .insert(
+            LNURL_LOGIN_KEY,
+            LnurlLogin {
+                k1: challenge.k1,
+                next,
+            },
+        )
+        .await
+        .map_err(LnurlError::from)?;
+    let page = LnurlTemplate { lnurl, qr_svg };
+    Ok(Html(
+        page.render()
+            .map_err(InternalError::from)
+            .map_err(Backend)?,
+    )
+    .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LnurlCallbackQuery {
+    k1: String,
+    sig: String,
+    key: String,
+}
+
+/// LUD-04 wallet callback with the signed challenge.
+async fn lnurl_callback(
+    auth_session: AuthSession,
+    Query(query): Query<LnurlCallbackQuery>,
+) -> Json<serde_json::Value> {
+    let result = match &auth_session.backend.config.lnurl {
+        None => Err("lnurl login is not enabled".to_string()),
+        Some(_) => match verify_signature(&query.k1, &query.sig, &query.key) {
+            Err(e) => Err(e.to_string()),
+            Ok(()) => match auth_session
+                .backend
+                .sign_lnurl_challenge(&query.k1, &query.key.to_lowercase())
+                .await
+            {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(LnurlError::InvalidChallenge.to_string()),
+                Err(e) => {
+                    error!("{}", e);
+                    Err("internal server error".to_string())
+                }
+            },
+        },
+    };
+    match result {
+        Ok(()) => Json(serde_json::json!({ "status": "OK" })),
+        Err(reason) => {
+            info!("lnurl login failed: {}", reason);
+            Json(serde_json::json!({ "status": "ERROR", "reason": reason }))
+        }
+    }
+}
+
+/// Polled by the QR code page, completes the login once the wallet has signed the challenge.
+async fn lnurl_status(
+    mut auth_session: AuthSession,
+    session: Session,
+    client_ip: Option<ClientIp>,
+) -> Result<Response, LoginError> {
+    // htmx stops polling on this status code
+    const STOP_POLLING: u16 = 286;
+    let lnurl_login = session
+        .get::<LnurlLogin>(LNURL_LOGIN_KEY)
+        .await
+        .map_err(LnurlError::from)?;
+    let challenge = match &lnurl_login {
+        Some(lnurl_login) => auth_session
+            .backend
+            .take_lnurl_challenge(&lnurl_login.k1)
+            .await
+            .map_err(Backend)?,
+        None => None,
+    };
+    let (Some(lnurl_login), Some(challenge)) = (lnurl_login, challenge) else {
+        return Ok((
+            StatusCode::from_u16(STOP_POLLING).expect("valid status"),
+            "Login QR code expired, reload the page to try again.",
+        )
+            .into_response());
+    };
+    let Some(key) = challenge.linking_key else {
+        return Ok("Waiting for wallet...".into_response());
+    };
+    session
+        .remove::<LnurlLogin>(LNURL_LOGIN_KEY)
+        .await
+        .map_err(LnurlError::from)?;
+    let ip = client_ip.map(|ClientIp(ip)| ip);
+    let credentials = Credentials::Lnurl {
+        key: key.clone(),
+        link_to: auth_session.user.as_ref().map(|player| player.uuid),
+    };
+    match auth_session.authenticate(credentials).await? {
+        Some(player) => {
+            let next = finish_login(
+                &mut auth_session,
+                &session,
+                &player,
+                "lnurl",
+                ip,
+                lnurl_login.next,
+            )
+            .await?;
+            let mut response = StatusCode::OK.into_response();
+            response.headers_mut().insert(
+                "HX-Redirect",
+                HeaderValue::try_from(next).expect("next value"),
+            );
+            Ok(response)
+        }
+        None => {
+            audit_login_failed(&auth_session, &key, "lnurl", ip)
+                .await
+                .map_err(Backend)?;
+            Ok((
+                StatusCode::from_u16(STOP_POLLING).expect("valid status"),
+                "No player is linked to this wallet.",
+            )
+                .into_response())
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "totp_login.html")]
+struct TotpLoginTemplate {}
+
+/// Ask for the second factor of a login started with a password or external login.
+async fn totp_login_page(session: Session) -> Result<Response, LoginError> {
+    let pending = session
+        .get::<TotpLogin>(TOTP_LOGIN_KEY)
+        .await
+        .map_err(TotpError::from)?
+        .is_some_and(|totp_login| totp_login.expires > datetime_now());
+    if !pending {
+        return Ok(Redirect::to("/login").into_response());
+    }
+    Ok(Html(
+        TotpLoginTemplate {}
+            .render()
+            .map_err(InternalError::from)
+            .map_err(Backend)?,
+    )
+    .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct TotpCodeForm {
+    code: String,
+}
+
+/// Finish a pending login with an authenticator or recovery code.
+async fn login_totp(
+    mut auth_session: AuthSession,
+    session: Session,
+    ClientIp(ip): ClientIp,
+    Form(code_form): Form<TotpCodeForm>,
+) -> Result<Response, LoginError> {
+    let totp_login = session
+        .get::<TotpLogin>(TOTP_LOGIN_KEY)
+        .await
+        .map_err(TotpError::from)?
+        .filter(|totp_login| totp_login.expires > datetime_now())
+        .ok_or(TotpError::NoLogin)?;
+    let player = auth_session
+        .backend
+        .get_player_by_uuid(&totp_login.player)
+        .await
+        .map_err(Backend)?
+        .ok_or(TotpError::NoLogin)?;
+    let throttle_keys = vec![
+        ThrottleKey::NameIp(player.name.clone(), ip),
+        ThrottleKey::Account(player.name.clone()),
+        ThrottleKey::Ip(ip),
+    ];
+    if let Some(retry_at) = auth_session
+        .backend
+        .login_retry_at(throttle_keys.clone())
+        .await
+        .map_err(Backend)?
+    {
+        return Err(LoginError::LockedOut(retry_at));
+    }
+    if !auth_session
+        .backend
+        .use_totp_code(&player.uuid, &code_form.code)
+        .await
+        .map_err(Backend)?
+    {
+        auth_session
+            .backend
+            .record_login_failure(throttle_keys)
+            .await
+            .map_err(Backend)?;
+        audit_login_failed(&auth_session, &player.name, "totp", Some(ip))
+            .await
+            .map_err(Backend)?;
+        return Err(TotpError::InvalidCode.into());
+    }
+    session
+        .remove::<TotpLogin>(TOTP_LOGIN_KEY)
+        .await
+        .map_err(TotpError::from)?;
+    auth_session
+        .backend
+        .clear_login_failures(throttle_keys)
+        .await
+        .map_err(Backend)?;
+    auth_session.login(&player).await?;
+    let method = format!("{}+totp", totp_login.method);
+    audit_login(&auth_session, &player, &method, Some(ip))
+        .await
+        .map_err(Backend)?;
+    let next = totp_login.next.unwrap_or("/".to_string());
+    let mut response = StatusCode::OK.into_response();
+    response.headers_mut().insert(
+        "HX-Location",
+        HeaderValue::try_from(next).expect("next value"),
+    );
+    Ok(response)
+}
+
 async fn register_password(
     mut auth_session: AuthSession,
+    ClientIp(ip): ClientIp,
     Form(register_form): Form<RegisterForm>,
 ) -> Result<impl IntoResponse, RegisterError> {
     let new_username = register_form.new_username.clone();
     let new_password = register_form.new_password.clone();
     let confirm_password = register_form.confirm_password.clone();
-    // validate username is unique
+    let throttle_keys = vec![ThrottleKey::Ip(ip)];
+    if let Some(retry_at) = auth_session
+        .backend
+        .login_retry_at(throttle_keys.clone())
+        .await
+        .map_err(Backend)?
+    {
+        return Err(RegisterError::LockedOut(retry_at));
+    }
+    // validate username is unique, probing for registered names counts as a failure
     if let Some(_player) = auth_session
         .backend
         .get_player_by_name(&new_username)
         .await
         .map_err(Backend)?
     {
+        auth_session
+            .backend
+            .record_login_failure(throttle_keys)
+            .await
+            .map_err(Backend)?;
         return Err(RegisterError::UserAlreadyRegistered(new_username));
     }
     // validate new credentials
     validate_name_password(&new_username, &new_password)?;
+    let invite_code = register_form
+        .invite_code
+        .clone()
+        .map(|code| code.trim().to_string())
+        .filter(|code| !code.is_empty());
     if new_password != confirm_password {
         Err(RegisterError::UnconfirmedPassword)
+    } else if invite_code.is_none()
+        && auth_session.backend.config.registration == RegistrationMode::Invite
+    {
+        Err(RegisterError::InvalidInviteCode)
     } else {
         let uuid = Uuid::new_v4();
         let password_hash = register_form.password_hash();
@@ -170,11 +1007,20 @@ async fn register_password(
             password_hash,
             ..Default::default()
         };
-        auth_session
+        if !auth_session
             .backend
-            .insert_player(&player)
+            .register_player(&player, invite_code)
             .await
-            .map_err(Backend)?;
+            .map_err(name_taken_error)?
+        {
+            // guessing invite codes counts as a failure
+            auth_session
+                .backend
+                .record_login_failure(throttle_keys)
+                .await
+                .map_err(Backend)?;
+            return Err(RegisterError::InvalidInviteCode);
+        }
         if let Some(player) = auth_session
             .authenticate(register_form.credentials())
             .await?
@@ -195,14 +1041,18 @@ async fn register_password(
     }
 }
 
-async fn change_profile(
-    mut auth_session: AuthSession,
-    Form(register_form): Form<RegisterForm>,
+#[derive(Deserialize)]
+pub struct NameForm {
+    new_username: String,
+}
+
+async fn change_name(
+    auth_session: AuthSession,
+    Form(name_form): Form<NameForm>,
 ) -> Result<impl IntoResponse, RegisterError> {
     let orig_player = auth_session.user.clone().expect("player must be logged in");
-    let new_username = register_form.new_username.clone();
-    let new_password = register_form.new_password.clone();
-    let new_confirm_password = register_form.confirm_password.clone();
+    let new_username = name_form.new_username;
+    validate_name(&new_username)?;
     // validate username, if not the same, is unique
     if let Some(found_player) = auth_session
         .backend
@@ -214,47 +1064,880 @@ async fn change_profile(
             return Err(RegisterError::UserAlreadyRegistered(new_username));
         }
     }
-    // validate new credentials
-    validate_name_password(&new_username, &new_password)?;
-    if new_password != new_confirm_password {
-        Err(RegisterError::UnconfirmedPassword)
-    } else {
-        let new_password_hash = register_form.password_hash();
-        let new_player = Player {
-            name: new_username,
-            password_hash: new_password_hash,
-            updated: datetime_now(),
-            ..orig_player.clone()
-        };
-        auth_session
+    // the password hash is unchanged so the player's sessions stay valid. submitting the same
+    // name keeps a name given by a rename
+    let new_player = Player {
+        name: new_username,
+        renamed: false,
+        updated: datetime_now(),
+        ..orig_player.clone()
+    };
+    auth_session
+        .backend
+        .change_player(&orig_player, &new_player)
+        .await
+        .map_err(name_taken_error)?;
+    info!("{} changed name to {}", orig_player.name, new_player.name);
+    let event = AuditEvent {
+        subject: Some(new_player.name.clone()),
+        before: Some(orig_player.name.clone()),
+        after: Some(new_player.name.clone()),
+        ..AuditEvent::new(AuditAction::NameChange, Some(&orig_player))
+    };
+    auth_session
+        .backend
+        .audit_log
+        .record(event)
+        .await
+        .map_err(Backend)?;
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Refresh", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct PasswordForm {
+    current_password: Option<String>,
+    new_password: String,
+    confirm_password: String,
+    // checkbox, only sent when checked
+    sign_out_others: Option<String>,
+}
+
+async fn change_password(
+    mut auth_session: AuthSession,
+    session: Session,
+    client_ip: Option<ClientIp>,
+    Form(password_form): Form<PasswordForm>,
+) -> Result<impl IntoResponse, RegisterError> {
+    let orig_player = auth_session.user.clone().expect("player must be logged in");
+    verify_current_password(&auth_session, &orig_player, password_form.current_password).await?;
+    validate_password(&password_form.new_password)?;
+    if password_form.new_password != password_form.confirm_password {
+        return Err(RegisterError::UnconfirmedPassword);
+    }
+    if verify_password(&password_form.new_password, &orig_player.password_hash).is_ok() {
+        return Err(RegisterError::UnchangedPassword);
+    }
+    let sign_out_others = password_form.sign_out_others.is_some();
+    let session_key = match &orig_player.session_key {
+        // keep the session key other sessions were logged in with
+        Some(session_key) if !sign_out_others => Some(session_key.clone()),
+        // a new random key signs out the other sessions. sessions logged in before the player
+        // had a session key match the old password hash, which isn't kept, so they are
+        // signed out too
+        _ => Some(generate_token()),
+    };
+    let new_player = Player {
+        password_hash: generate_hash(&password_form.new_password),
+        must_change_password: false,
+        session_key,
+        updated: datetime_now(),
+        ..orig_player.clone()
+    };
+    auth_session
+        .backend
+        .change_player(&orig_player, &new_player)
+        .await
+        .map_err(Backend)?;
+    if sign_out_others {
+        let revoked = auth_session
             .backend
-            .change_player(&orig_player, &new_player)
+            .delete_player_sessions(&orig_player.uuid, session.id())
             .await
             .map_err(Backend)?;
-        // if the player record changed, re-authenticate
-        if let Some(player) = auth_session
-            .authenticate(register_form.credentials())
-            .await?
-        {
-            // update session so user is logged in
-            auth_session.login(&player).await?;
-        }
-        let mut response = StatusCode::OK.into_response();
-        response.headers_mut().insert(
-            "HX-Location",
-            HeaderValue::try_from("/".to_string()).expect("next value"),
+        info!(
+            "{} changed password and signed out {} other sessions",
+            orig_player.name, revoked
         );
-        Ok(response)
+    } else {
+        info!("{} changed password", orig_player.name);
     }
-}
-
-fn validate_name_password(new_username: &str, new_password: &str) -> Result<(), RegisterError> {
-    let name_re = Regex::new(r#"[0-9a-zA-Z_]{3,20}"#).unwrap();
-    let password_re = Regex::new(r#"[0-9a-zA-Z\d@$!%*?&#^_\.\-]{4,20}"#).unwrap();
-
-    if !name_re.is_match(new_username) {
+    let event = AuditEvent {
+        subject: Some(orig_player.name.clone()),
+        after: sign_out_others.then(|| "signed out other sessions".to_string()),
+        ip: client_ip.map(|ClientIp(ip)| ip),
+        ..AuditEvent::new(AuditAction::PasswordChange, Some(&orig_player))
+    };
+    auth_session
+        .backend
+        .audit_log
+        .record(event)
+        .await
+        .map_err(Backend)?;
+    // update this session with the new session auth hash
+    auth_session.login(&new_player).await?;
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Location", HeaderValue::from_static("/"));
+    Ok(response)
+}
+
+// check a logged in player's current password, throttled like logins
+async fn verify_current_password(
+    auth_session: &AuthSession,
+    player: &Player,
+    current_password: Option<String>,
+) -> Result<(), RegisterError> {
+    // players provisioned by an external login have no password to confirm
+    if player.password_hash.is_empty() {
+        return Ok(());
+    }
+    let throttle_keys = vec![ThrottleKey::Name(player.name.clone())];
+    if let Some(retry_at) = auth_session
+        .backend
+        .login_retry_at(throttle_keys.clone())
+        .await
+        .map_err(Backend)?
+    {
+        return Err(RegisterError::LockedOut(retry_at));
+    }
+    let current_password = current_password.unwrap_or_default();
+    if verify_password(&current_password, &player.password_hash).is_err() {
+        auth_session
+            .backend
+            .record_login_failure(throttle_keys)
+            .await
+            .map_err(Backend)?;
+        return Err(RegisterError::IncorrectPassword(player.name.clone()));
+    }
+    Ok(())
+}
+
+/// A player's personal data, as exported from the profile page.
+#[derive(Serialize)]
+struct PlayerExport {
+    uuid: Uuid,
+    name: String,
+    permissions: Vec<Permission>,
+    roles: Vec<String>,
+    last_login: DateTime<Utc>,
+    updated: DateTime<Utc>,
+    created: DateTime<Utc>,
+    identities: Vec<String>,
+    sessions: Vec<ActiveSession>,
+    guesses: Vec<GuessExport>,
+    exported: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct GuessExport {
+    height: u32,
+    nonce: u32,
+    /// The block nonce, once the target block is confirmed.
+    target_nonce: Option<u32>,
+}
+
+async fn export_profile(
+    auth_session: AuthSession,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, InternalError> {
+    let player = auth_session.user.expect("player must be logged in");
+    let mut permissions = player
+        .permissions
+        .iter()
+        .cloned()
+        .collect::<Vec<Permission>>();
+    permissions.sort();
+    let mut roles = auth_session
+        .backend
+        .get_roles()
+        .await?
+        .into_iter()
+        .filter(|role| player.roles.contains(&role.uuid))
+        .map(|role| role.name)
+        .collect::<Vec<String>>();
+    roles.sort();
+    let identities = auth_session
+        .backend
+        .get_player_identities(&player.uuid)
+        .await?;
+    let sessions = auth_session
+        .backend
+        .get_player_sessions(&player.uuid)
+        .await?;
+    let mut guesses = Vec::new();
+    for (height, guess) in app_state.guess_backend.player_guesses(player.uuid).await? {
+        let target_nonce = app_state
+            .guess_backend
+            .get_target_nonce(height)
+            .await?
+            .flatten();
+        guesses.push(GuessExport {
+            height,
+            nonce: guess.nonce,
+            target_nonce,
+        });
+    }
+    let export = PlayerExport {
+        uuid: player.uuid,
+        name: player.name.clone(),
+        permissions,
+        roles,
+        last_login: player.last_login,
+        updated: player.updated,
+        created: player.created,
+        identities,
+        sessions,
+        guesses,
+        exported: datetime_now(),
+    };
+    info!("{} exported their data", player.name);
+    // names from before the name rules may not be valid in a header, the uuid always is
+    Ok((
+        [(
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"nonce_guess_{}.json\"", player.uuid),
+        )],
+        Json(export),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteForm {
+    confirm_name: String,
+    password: Option<String>,
+}
+
+async fn delete_account(
+    mut auth_session: AuthSession,
+    client_ip: Option<ClientIp>,
+    Form(delete_form): Form<DeleteForm>,
+) -> Result<impl IntoResponse, RegisterError> {
+    let player = auth_session.user.clone().expect("player must be logged in");
+    if delete_form.confirm_name != player.name {
+        return Err(RegisterError::UnconfirmedDelete);
+    }
+    verify_current_password(&auth_session, &player, delete_form.password).await?;
+    let backend = &auth_session.backend;
+    let anonymize = backend.config.deleted_guesses == DeletedGuesses::Anonymize;
+    let Some(guesses) = backend
+        .delete_account(&player.uuid, anonymize)
+        .await
+        .map_err(Backend)?
+    else {
+        return Err(RegisterError::LastAdmin(player.name));
+    };
+    info!(
+        "{} deleted their account, {} {} guesses",
+        player.name,
+        if anonymize { "anonymized" } else { "removed" },
+        guesses
+    );
+    let event = AuditEvent {
+        subject: Some(player.name.clone()),
+        after: Some(format!(
+            "{} {} guesses",
+            if anonymize { "anonymized" } else { "removed" },
+            guesses
+        )),
+        ip: client_ip.map(|ClientIp(ip)| ip),
+        ..AuditEvent::new(AuditAction::AccountDelete, Some(&player))
+    };
+    backend.audit_log.record(event).await.map_err(Backend)?;
+    auth_session.logout().await?;
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Redirect", HeaderValue::from_static("/login"));
+    Ok(response)
+}
+
+// only players with some permission have a use for admin scoped tokens
+fn token_scopes(permissions: &HashSet<Permission>) -> Vec<TokenScope> {
+    let mut token_scopes = vec![TokenScope::Read, TokenScope::Guess];
+    if !permissions.is_empty() {
+        token_scopes.push(TokenScope::Admin);
+    }
+    token_scopes
+}
+
+#[derive(Template)]
+#[template(path = "api_token.html")]
+struct ApiTokenTemplate {
+    token: String,
+    api_token: ApiToken,
+}
+
+#[derive(Deserialize)]
+pub struct ApiTokenForm {
+    name: String,
+    scope: TokenScope,
+}
+
+async fn create_api_token(
+    auth_session: AuthSession,
+    Form(token_form): Form<ApiTokenForm>,
+) -> Result<impl IntoResponse, RegisterError> {
+    let player = auth_session.user.clone().expect("player must be logged in");
+    let name = token_form.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_API_TOKEN_NAME_LEN {
+        return Err(RegisterError::InvalidTokenName);
+    }
+    let permissions = auth_session
+        .backend
+        .get_player_permissions(&player)
+        .await
+        .map_err(Backend)?;
+    if !token_scopes(&permissions).contains(&token_form.scope) {
+        return Err(RegisterError::InvalidTokenScope(token_form.scope));
+    }
+    // admin tokens skip the two-factor redirect, so only players who passed it may create them
+    if token_form.scope == TokenScope::Admin
+        && auth_session
+            .backend
+            .requires_totp(&player)
+            .await
+            .map_err(Backend)?
+        && auth_session
+            .backend
+            .get_totp(&player.uuid)
+            .await
+            .map_err(Backend)?
+            .is_none()
+    {
+        return Err(RegisterError::TokenRequiresTotp(player.name));
+    }
+    let (token, api_token) = auth_session
+        .backend
+        .create_api_token(&player.uuid, name, token_form.scope)
+        .await
+        .map_err(Backend)?;
+    info!(
+        "{} created {} api token {}",
+        player.name, api_token.scope, api_token.name
+    );
+    Ok(Html(
+        ApiTokenTemplate { token, api_token }
+            .render()
+            .map_err(InternalError::from)
+            .map_err(Backend)?,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct RevokeApiTokenForm {
+    uuid: Uuid,
+}
+
+async fn revoke_api_token(
+    auth_session: AuthSession,
+    Form(revoke_form): Form<RevokeApiTokenForm>,
+) -> Result<impl IntoResponse, InternalError> {
+    let player = auth_session.user.expect("player must be logged in");
+    if auth_session
+        .backend
+        .revoke_api_token(&player.uuid, &revoke_form.uuid)
+        .await?
+    {
+        info!("{} revoked api token {}", player.name, revoke_form.uuid);
+    }
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Refresh", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+#[derive(Template)]
+#[template(path = "totp_enroll.html")]
+struct TotpEnrollTemplate {
+    qr_svg: String,
+    secret: String,
+    recovery_codes: Vec<String>,
+}
+
+/// Show a new authenticator secret and recovery codes, they are only saved once the player
+/// confirms a code from their authenticator.
+async fn start_totp_enrollment(
+    auth_session: AuthSession,
+    session: Session,
+) -> Result<impl IntoResponse, RegisterError> {
+    let player = auth_session.user.clone().expect("player must be logged in");
+    if auth_session
+        .backend
+        .get_totp(&player.uuid)
+        .await
+        .map_err(Backend)?
+        .is_some()
+    {
+        return Err(TotpError::AlreadyEnabled.into());
+    }
+    let enrollment = TotpEnrollment {
+        secret: new_secret(),
+        recovery_codes: new_recovery_codes(),
+    };
+    let qr_svg = qr_svg(otpauth_url(TOTP_ISSUER, &player.name, &enrollment.secret).as_str())
+        .map_err(TotpError::from)?;
+    let page = TotpEnrollTemplate {
+        qr_svg,
+        secret: base32_encode(&enrollment.secret),
+        recovery_codes: enrollment.recovery_codes.clone(),
+    };
+    session
+        .insert(TOTP_ENROLLMENT_KEY, enrollment)
+        .await
+        .map_err(TotpError::from)?;
+    Ok(Html(
+        page.render()
+            .map_err(InternalError::from)
+            .map_err(Backend)?,
+    ))
+}
+
+async fn confirm_totp_enrollment(
+    auth_session: AuthSession,
+    session: Session,
+    client_ip: Option<ClientIp>,
+    Form(code_form): Form<TotpCodeForm>,
+) -> Result<impl IntoResponse, RegisterError> {
+    let player = auth_session.user.clone().expect("player must be logged in");
+    let enrollment = session
+        .get::<TotpEnrollment>(TOTP_ENROLLMENT_KEY)
+        .await
+        .map_err(TotpError::from)?
+        .ok_or(TotpError::NoEnrollment)?;
+    if !auth_session
+        .backend
+        .enable_totp(&player.uuid, enrollment, &code_form.code)
+        .await
+        .map_err(Backend)?
+    {
+        return Err(TotpError::InvalidCode.into());
+    }
+    session
+        .remove::<TotpEnrollment>(TOTP_ENROLLMENT_KEY)
+        .await
+        .map_err(TotpError::from)?;
+    info!("{} enabled two-factor authentication", player.name);
+    let event = AuditEvent {
+        subject: Some(player.name.clone()),
+        ip: client_ip.map(|ClientIp(ip)| ip),
+        ..AuditEvent::new(AuditAction::TwoFactorEnable, Some(&player))
+    };
+    auth_session
+        .backend
+        .audit_log
+        .record(event)
+        .await
+        .map_err(Backend)?;
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Refresh", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+async fn disable_totp(
+    auth_session: AuthSession,
+    client_ip: Option<ClientIp>,
+    Form(code_form): Form<TotpCodeForm>,
+) -> Result<impl IntoResponse, RegisterError> {
+    let player = auth_session.user.clone().expect("player must be logged in");
+    if auth_session
+        .backend
+        .requires_totp(&player)
+        .await
+        .map_err(Backend)?
+    {
+        return Err(TotpError::Required(player.name).into());
+    }
+    let throttle_keys = vec![ThrottleKey::Name(player.name.clone())];
+    if let Some(retry_at) = auth_session
+        .backend
+        .login_retry_at(throttle_keys.clone())
+        .await
+        .map_err(Backend)?
+    {
+        return Err(RegisterError::LockedOut(retry_at));
+    }
+    if !auth_session
+        .backend
+        .use_totp_code(&player.uuid, &code_form.code)
+        .await
+        .map_err(Backend)?
+    {
+        auth_session
+            .backend
+            .record_login_failure(throttle_keys)
+            .await
+            .map_err(Backend)?;
+        return Err(TotpError::InvalidCode.into());
+    }
+    auth_session
+        .backend
+        .disable_totp(&player.uuid)
+        .await
+        .map_err(Backend)?;
+    info!("{} disabled two-factor authentication", player.name);
+    let event = AuditEvent {
+        subject: Some(player.name.clone()),
+        ip: client_ip.map(|ClientIp(ip)| ip),
+        ..AuditEvent::new(AuditAction::TwoFactorDisable, Some(&player))
+    };
+    auth_session
+        .backend
+        .audit_log
+        .record(event)
+        .await
+        .map_err(Backend)?;
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Refresh", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSessionForm {
+    id: String,
+}
+
+async fn revoke_session(
+    auth_session: AuthSession,
+    Form(revoke_form): Form<RevokeSessionForm>,
+) -> Result<impl IntoResponse, InternalError> {
+    let player = auth_session.user.expect("player must be logged in");
+    if let Ok(id) = Id::from_str(&revoke_form.id) {
+        if auth_session
+            .backend
+            .delete_player_session(&player.uuid, id)
+            .await?
+        {
+            info!("{} signed out a session", player.name);
+        }
+    }
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Refresh", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+async fn revoke_other_sessions(
+    auth_session: AuthSession,
+    session: Session,
+) -> Result<impl IntoResponse, InternalError> {
+    let player = auth_session.user.expect("player must be logged in");
+    let revoked = auth_session
+        .backend
+        .delete_player_sessions(&player.uuid, session.id())
+        .await?;
+    info!("{} signed out {} other sessions", player.name, revoked);
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Refresh", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+async fn reset_password(
+    auth_session: AuthSession,
+    client_ip: Option<ClientIp>,
+    Path(token): Path<String>,
+    Form(reset_form): Form<ResetForm>,
+) -> Result<impl IntoResponse, RegisterError> {
+    validate_password(&reset_form.new_password)?;
+    if reset_form.new_password != reset_form.confirm_password {
+        return Err(RegisterError::UnconfirmedPassword);
+    }
+    let password_hash = generate_hash(&reset_form.new_password);
+    let player = auth_session
+        .backend
+        .reset_password(&token, password_hash)
+        .await
+        .map_err(Backend)?
+        .ok_or(RegisterError::InvalidResetToken)?;
+    info!("reset password for {}", player.name);
+    let event = AuditEvent {
+        subject: Some(player.name.clone()),
+        ip: client_ip.map(|ClientIp(ip)| ip),
+        ..AuditEvent::new(AuditAction::PasswordReset, None)
+    };
+    auth_session
+        .backend
+        .audit_log
+        .record(event)
+        .await
+        .map_err(Backend)?;
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Location", HeaderValue::from_static("/login"));
+    Ok(response)
+}
+
+/// Redirect players who must change their password, or who were renamed and haven't changed
+/// or kept their new name, to the profile page.
+pub async fn require_password_change(
+    auth_session: AuthSession,
+    request: Request,
+    next: Next,
+) -> Response {
+    let must_visit_profile = auth_session
+        .user
+        .as_ref()
+        .is_some_and(|player| player.must_change_password || player.renamed);
+    let path = request.uri().path();
+    if must_visit_profile
+        && !matches!(
+            path,
+            "/profile" | "/profile/name" | "/profile/password" | "/logout" | "/login"
+        )
+    {
+        redirect_to_profile(&request)
+    } else {
+        next.run(request).await
+    }
+}
+
+/// Redirect players the two-factor policy applies to the profile page until they enable an
+/// authenticator.
+pub async fn require_two_factor(
+    auth_session: AuthSession,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    let allowed = matches!(
+        path,
+        "/profile"
+            | "/profile/password"
+            | "/profile/totp"
+            | "/profile/totp/confirm"
+            | "/logout"
+            | "/login"
+    );
+    // api token requests are limited by the token's scope instead
+    let api_request = request.extensions().get::<ApiToken>().is_some();
+    let Some(player) = auth_session
+        .user
+        .as_ref()
+        .filter(|_| !allowed && !api_request)
+    else {
+        return next.run(request).await;
+    };
+    let missing_totp = match auth_session.backend.requires_totp(player).await {
+        Ok(false) => Ok(false),
+        Ok(true) => auth_session
+            .backend
+            .get_totp(&player.uuid)
+            .await
+            .map(|totp| totp.is_none()),
+        Err(e) => Err(e),
+    };
+    match missing_totp {
+        Ok(true) => redirect_to_profile(&request),
+        Ok(false) => next.run(request).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+// htmx requests are redirected with a header so the whole page changes
+fn redirect_to_profile(request: &Request) -> Response {
+    if request.headers().contains_key("HX-Request") {
+        let mut response = StatusCode::OK.into_response();
+        response
+            .headers_mut()
+            .insert("HX-Redirect", HeaderValue::from_static("/profile"));
+        response
+    } else {
+        Redirect::to("/profile").into_response()
+    }
+}
+
+/// Authenticate a request with a personal api token from an `Authorization: Bearer`
+/// header. The player is only logged in for this request, no session is created.
+pub async fn bearer_auth(mut request: Request, next: Next) -> Response {
+    let Some(token) = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+    else {
+        return next.run(request).await;
+    };
+    let Some(backend) = request
+        .extensions()
+        .get::<AuthSession>()
+        .map(|auth_session| auth_session.backend.clone())
+    else {
+        return next.run(request).await;
+    };
+    match backend.authenticate_api_token(&token).await {
+        Ok(Some((player, api_token))) => {
+            if !api_token
+                .scope
+                .allows(request.method(), request.uri().path())
+            {
+                return (
+                    StatusCode::FORBIDDEN,
+                    "Api token scope does not allow this.",
+                )
+                    .into_response();
+            }
+            if let Some(auth_session) = request.extensions_mut().get_mut::<AuthSession>() {
+                auth_session.user = Some(player);
+            }
+            request.extensions_mut().insert(api_token);
+            next.run(request).await
+        }
+        Ok(None) => (StatusCode::UNAUTHORIZED, "Invalid api token.").into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Log in the player named by a trusted authenticating reverse proxy's user header. The
+/// header is ignored unless the connection comes directly from a trusted proxy address.
+pub async fn proxy_auth(mut request: Request, next: Next) -> Response {
+    let Some(mut auth_session) = request.extensions().get::<AuthSession>().cloned() else {
+        return next.run(request).await;
+    };
+    let Some(proxy_config) = auth_session.backend.config.proxy.clone() else {
+        return next.run(request).await;
+    };
+    let Some(user) = request
+        .headers()
+        .get(&proxy_config.header)
+        .and_then(|value| value.to_str().ok())
+        .map(|user| user.trim().to_string())
+        .filter(|user| !user.is_empty())
+    else {
+        return next.run(request).await;
+    };
+    // the socket address, X-Forwarded-For could be set by anyone
+    let peer_ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if !peer_ip.is_some_and(|ip| proxy_config.trusts(ip)) {
+        warn!(
+            "ignoring {} header from untrusted address {:?}",
+            proxy_config.header, peer_ip
+        );
+        return next.run(request).await;
+    }
+    let Some(session) = request.extensions().get::<Session>().cloned() else {
+        return next.run(request).await;
+    };
+    let session_user = match session.get::<String>(PROXY_USER_KEY).await {
+        Ok(session_user) => session_user,
+        Err(e) => {
+            error!("{}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if auth_session.user.is_some() && session_user.as_deref() == Some(user.as_str()) {
+        return next.run(request).await;
+    }
+    let credentials = Credentials::Proxy { user: user.clone() };
+    match auth_session.authenticate(credentials).await {
+        Ok(Some(player)) => {
+            if let Err(e) = auth_session.login(&player).await {
+                return LoginError::from(e).into_response();
+            }
+            if let Err(e) = session.insert(PROXY_USER_KEY, &user).await {
+                error!("{}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            if let Err(e) = audit_login(&auth_session, &player, "proxy", peer_ip).await {
+                return e.into_response();
+            }
+            request.extensions_mut().insert(auth_session);
+        }
+        Ok(None) => {
+            info!("no player for proxy user {}", user);
+            // the proxy switched to a user without a player, don't stay logged in as the
+            // previous one
+            if session_user.is_some() {
+                if let Err(e) = auth_session.logout().await {
+                    return LoginError::from(e).into_response();
+                }
+                request.extensions_mut().insert(auth_session);
+            }
+        }
+        Err(e) => return LoginError::from(e).into_response(),
+    }
+    next.run(request).await
+}
+
+/// Record when and from where a logged in session was last used.
+pub async fn track_session(
+    auth_session: AuthSession,
+    session: Session,
+    client_ip: Option<ClientIp>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // api token requests have no session to track
+    let api_token = request.extensions().get::<ApiToken>().is_some();
+    if auth_session.user.is_some() && !api_token {
+        let now = datetime_now();
+        let info = session
+            .get::<SessionInfo>(SESSION_INFO_KEY)
+            .await
+            .ok()
+            .flatten();
+        let stale = info.as_ref().is_none_or(|info| {
+            now - info.last_seen > TimeDelta::seconds(SESSION_SEEN_INTERVAL_SECS)
+        });
+        if stale {
+            let user_agent = request
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.chars().take(256).collect::<String>());
+            let info = SessionInfo {
+                created: info.map(|info| info.created).unwrap_or(now),
+                last_seen: now,
+                user_agent,
+                ip: client_ip.map(|ClientIp(ip)| ip),
+            };
+            if let Err(e) = session.insert(SESSION_INFO_KEY, info).await {
+                warn!("failed to update session info: {}", e);
+            }
+        }
+    }
+    next.run(request).await
+}
+
+fn validate_name_password(new_username: &str, new_password: &str) -> Result<(), RegisterError> {
+    validate_name(new_username)?;
+    validate_password(new_password)
+}
+
+fn validate_name(new_username: &str) -> Result<(), RegisterError> {
+    if !is_valid_name(new_username) {
         Err(RegisterError::InvalidName)
-    } else if !password_re.is_match(new_password) {
+    } else {
+        Ok(())
+    }
+}
+
+// the name was free when checked, but taken by another player before it was stored
+fn name_taken_error(e: InternalError) -> RegisterError {
+    match e {
+        InternalError::NameTaken(name) => RegisterError::UserAlreadyRegistered(name),
+        e => Backend(e).into(),
+    }
+}
+
+// the rule of the confirm password input pattern: at least 8 characters with a lower and upper
+// case letter, a digit and one of the special characters
+fn validate_password(new_password: &str) -> Result<(), RegisterError> {
+    let special = |c: char| "@$!%*?&#^_.-".contains(c);
+    let chars = || new_password.chars();
+    if chars().count() < 8
+        || !chars().all(|c| c.is_ascii_alphanumeric() || special(c))
+        || !chars().any(|c| c.is_ascii_lowercase())
+        || !chars().any(|c| c.is_ascii_uppercase())
+        || !chars().any(|c| c.is_ascii_digit())
+        || !chars().any(special)
+    {
         Err(RegisterError::InvalidPassword)
     } else {
         Ok(())
@@ -301,6 +1984,82 @@ impl IntoResponse for RegisterError {
                 )
                     .into_response()
             }
+            RegisterError::UnchangedPassword => {
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "New password must be different from the current password.",
+                )
+                    .into_response()
+            }
+            RegisterError::IncorrectPassword(name) => {
+                info!("incorrect current password for: {}", name);
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "Current password is incorrect.",
+                )
+                    .into_response()
+            }
+            RegisterError::UnconfirmedDelete => {
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "Type your name to confirm deleting your account.",
+                )
+                    .into_response()
+            }
+            RegisterError::LastAdmin(name) => {
+                info!("last admin can not be deleted: {}", name);
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "The last admin can't delete their account.",
+                )
+                    .into_response()
+            }
+            RegisterError::InvalidTokenName => {
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "Token name must be 1-40 characters.",
+                )
+                    .into_response()
+            }
+            RegisterError::InvalidTokenScope(scope) => {
+                info!("invalid token scope: {}", scope);
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "You can't create a token with that scope.",
+                )
+                    .into_response()
+            }
+            RegisterError::TokenRequiresTotp(name) => {
+                info!("admin api token requires two-factor authentication: {}", name);
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "Enable an authenticator before creating an admin token.",
+                )
+                    .into_response()
+            }
+            RegisterError::InvalidResetToken => {
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "Password reset link is invalid or expired, ask an admin for a new one.",
+                )
+                    .into_response()
+            }
+            RegisterError::InvalidInviteCode => {
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "A valid invite code is required to register.",
+                )
+                    .into_response()
+            }
             RegisterError::UserAlreadyRegistered(user) => {
                 info!("user already registered: {}", user);
                 (
@@ -319,6 +2078,19 @@ impl IntoResponse for RegisterError {
                 )
                     .into_response()
             }
+            RegisterError::LockedOut(retry_at) => {
+                info!("registration locked out until: {}", retry_at);
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    format!(
+                        "Too many failed attempts, try again after {}.",
+                        retry_at.with_timezone(&Local).format("%H:%M:%S")
+                    ),
+                )
+                    .into_response()
+            }
+            RegisterError::Totp(e) => e.into_response(),
             RegisterError::Internal(e) => {
                 error!("{}", e);
                 (
@@ -332,6 +2104,33 @@ impl IntoResponse for RegisterError {
     }
 }
 
+impl IntoResponse for TotpError {
+    fn into_response(self) -> Response {
+        let message = match &self {
+            TotpError::NoEnrollment => "Set up the authenticator again.".to_string(),
+            TotpError::NoLogin => "Login expired, sign in again.".to_string(),
+            TotpError::AlreadyEnabled => {
+                "Two-factor authentication is already enabled.".to_string()
+            }
+            TotpError::InvalidCode => "Invalid code.".to_string(),
+            TotpError::Required(_) => {
+                "Your permissions require two-factor authentication.".to_string()
+            }
+            TotpError::Qr(_) | TotpError::Session(_) => {
+                error!("{}", self);
+                return (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "Internal server error.",
+                )
+                    .into_response();
+            }
+        };
+        info!("{}", self);
+        (StatusCode::OK, [("HX-Retarget", "#flash_message")], message).into_response()
+    }
+}
+
 impl IntoResponse for LoginError {
     fn into_response(self) -> Response {
         match self {
@@ -344,6 +2143,46 @@ impl IntoResponse for LoginError {
                 )
                     .into_response()
             }
+            LoginError::LockedOut(retry_at) => {
+                info!("login locked out until: {}", retry_at);
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    format!(
+                        "Too many failed attempts, try again after {}.",
+                        retry_at.with_timezone(&Local).format("%H:%M:%S")
+                    ),
+                )
+                    .into_response()
+            }
+            LoginError::Oidc(e) => {
+                warn!("single sign-on failed: {}", e);
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "Single sign-on failed.",
+                )
+                    .into_response()
+            }
+            LoginError::Nostr(e) => {
+                warn!("nostr login failed: {}", e);
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "Nostr login failed.",
+                )
+                    .into_response()
+            }
+            LoginError::Lnurl(e) => {
+                warn!("lnurl login failed: {}", e);
+                (
+                    StatusCode::OK,
+                    [("HX-Retarget", "#flash_message")],
+                    "Lightning login failed.",
+                )
+                    .into_response()
+            }
+            LoginError::Totp(e) => e.into_response(),
             LoginError::Internal(_) => {
                 error!("{}", self);
                 (
@@ -369,6 +2208,9 @@ mod test {
         assert!(validate_name_password("te", "Test123$").is_err());
         assert!(validate_name_password("tester", "Test1234").is_err());
         assert!(validate_name_password("tester", "Te1$").is_err());
+        assert!(validate_name_password("te ster", "Test123$").is_err());
+        assert!(validate_name_password("tester", "Test 123$").is_err());
         assert!(validate_name_password("tester", "Test123$").is_ok());
+        assert!(validate_name_password("tester", "Test123$Test123$Test123$").is_ok());
     }
 }
@@ -0,0 +1,577 @@
+use crate::audit::backend::AuditLog;
+use crate::audit::store::AuditWrite;
+use crate::audit::types::{AuditAction, AuditEvent};
+use crate::auth::backend::AuthBackend;
+use crate::auth::config::env_parse;
+use crate::auth::types::Player;
+use crate::encoding::copy_quarantine_table;
+use crate::guess::backend::GuessBackend;
+use crate::migration::copy_schema_table;
+use crate::session_store::StorageSessionStore;
+use crate::types::InternalError;
+use chrono::{DateTime, Utc};
+use redb::{
+    Database, Key, MultimapTableDefinition, MultimapTableHandle, ReadTransaction,
+    ReadableMultimapTable, ReadableTable, TableDefinition, TableError, TableHandle, Value,
+    WriteTransaction,
+};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+use tracing::{info, warn};
+
+// snapshot file names are `nonce_guess-{timestamp}.redb`, so they sort by age
+const SNAPSHOT_PREFIX: &str = "nonce_guess-";
+const SNAPSHOT_SUFFIX: &str = ".redb";
+const SNAPSHOT_TIMESTAMP: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+// the snapshot a database was restored from, so a restart doesn't restore it again
+const KEY_RESTORED_FROM: TableDefinition<&str, String> = TableDefinition::new("backup_restored");
+const RESTORED_FROM: &str = "snapshot";
+
+/// Database snapshots, enabled by setting the snapshot directory with `NONCE_GUESS_BACKUP_DIR`.
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    pub dir: PathBuf,
+    /// Seconds between scheduled snapshots, 0 to only take admin triggered snapshots.
+    pub interval_secs: u64,
+    /// How many of the newest snapshots are kept.
+    pub retain: usize,
+}
+
+impl BackupConfig {
+    pub fn from_env() -> Option<Self> {
+        env_parse::<PathBuf>("NONCE_GUESS_BACKUP_DIR")
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(|dir| Self {
+                dir,
+                interval_secs: env_parse("NONCE_GUESS_BACKUP_INTERVAL_SECS")
+                    .unwrap_or(24 * 60 * 60),
+                retain: env_parse("NONCE_GUESS_BACKUP_RETAIN").unwrap_or(7).max(1),
+            })
+    }
+}
+
+/// A snapshot file in the backup directory.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Backup {
+    db: Arc<Database>,
+    pub config: BackupConfig,
+}
+
+impl Backup {
+    pub fn new(db: Arc<Database>, config: BackupConfig) -> Self {
+        Self { db, config }
+    }
+
+    /// Take a snapshot and remove the snapshots beyond the retention count, returns the new
+    /// snapshot.
+    pub async fn snapshot(&self) -> Result<Snapshot, InternalError> {
+        let db = self.db.clone();
+        let config = self.config.clone();
+        spawn_blocking(move || {
+            let snapshot = snapshot(&db, &config.dir, Utc::now())?;
+            rotate(&config.dir, config.retain)?;
+            Ok(snapshot)
+        })
+        .await?
+    }
+
+    /// Take a snapshot requested by an admin, recorded in the audit log.
+    pub async fn admin_snapshot(
+        &self,
+        audit_log: &AuditLog,
+        admin: &Player,
+    ) -> Result<Snapshot, InternalError> {
+        let snapshot = self.snapshot().await?;
+        let event = AuditEvent {
+            subject: Some(snapshot.name.clone()),
+            ..AuditEvent::new(AuditAction::BackupCreate, Some(admin))
+        };
+        audit_log.record(event).await?;
+        Ok(snapshot)
+    }
+
+    /// The snapshots in the backup directory, newest first.
+    pub async fn snapshots(&self) -> Result<Vec<Snapshot>, InternalError> {
+        let dir = self.config.dir.clone();
+        spawn_blocking(move || snapshots(&dir)).await?
+    }
+}
+
+/// Take scheduled snapshots until the task is aborted, a failed snapshot is logged and
+/// retried at the next interval.
+pub async fn continuously_backup(backup: Arc<Backup>) -> Result<(), InternalError> {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
+        backup.config.interval_secs,
+    ));
+    interval.tick().await; // The first tick completes immediately; skip.
+    loop {
+        interval.tick().await;
+        match backup.snapshot().await {
+            Ok(snapshot) => info!("created scheduled backup {}", snapshot.name),
+            Err(e) => warn!("scheduled backup failed: {}", e),
+        }
+    }
+}
+
+/// Copy every table from a read transaction of `db` into a new snapshot file in `dir`. The
+/// file is written under a temporary name and renamed when complete.
+pub fn snapshot(db: &Database, dir: &Path, now: DateTime<Utc>) -> Result<Snapshot, InternalError> {
+    std::fs::create_dir_all(dir)?;
+    let name = format!(
+        "{}{}{}",
+        SNAPSHOT_PREFIX,
+        now.format(SNAPSHOT_TIMESTAMP),
+        SNAPSHOT_SUFFIX
+    );
+    let path = dir.join(&name);
+    let partial_path = dir.join(format!("{}.partial", name));
+    let read_txn = db.begin_read()?;
+    copy_database(&read_txn, &partial_path, None)?;
+    std::fs::rename(&partial_path, &path)?;
+    let size = std::fs::metadata(&path)?.len();
+    info!("created backup {} ({} bytes)", path.display(), size);
+    Ok(Snapshot { name, size })
+}
+
+/// Remove all but the newest `retain` snapshots in `dir`.
+pub fn rotate(dir: &Path, retain: usize) -> Result<Vec<Snapshot>, InternalError> {
+    let removed = snapshots(dir)?
+        .into_iter()
+        .skip(retain)
+        .collect::<Vec<Snapshot>>();
+    for snapshot in &removed {
+        std::fs::remove_file(dir.join(&snapshot.name))?;
+        info!("removed old backup {}", snapshot.name);
+    }
+    Ok(removed)
+}
+
+/// The snapshots in `dir`, newest first.
+pub fn snapshots(dir: &Path) -> Result<Vec<Snapshot>, InternalError> {
+    let mut snapshots = Vec::new();
+    if !dir.exists() {
+        return Ok(snapshots);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(SNAPSHOT_SUFFIX) {
+            snapshots.push(Snapshot {
+                name,
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+    snapshots.sort_by(|a, b| b.name.cmp(&a.name));
+    Ok(snapshots)
+}
+
+/// Replace the database file with the tables of a snapshot, before the database is opened.
+/// The replaced file is kept next to it with a `.pre-restore` suffix. A database that was
+/// already restored from the same snapshot is left as it is, so the restore setting can stay
+/// in place across restarts.
+pub fn restore(snapshot_file: &Path, database_file: &Path) -> Result<bool, InternalError> {
+    let snapshot_name = snapshot_file
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if database_file.exists() && restored_from(database_file)?.as_ref() == Some(&snapshot_name) {
+        info!(
+            "database {} was already restored from {}",
+            database_file.display(),
+            snapshot_name
+        );
+        return Ok(false);
+    }
+    let snapshot_db = Database::open(snapshot_file)?;
+    let read_txn = snapshot_db.begin_read()?;
+    let restoring_file = with_suffix(database_file, ".restoring");
+    copy_database(&read_txn, &restoring_file, Some(&snapshot_name))?;
+    if database_file.exists() {
+        let pre_restore_file = with_suffix(database_file, ".pre-restore");
+        std::fs::rename(database_file, &pre_restore_file)?;
+        warn!(
+            "replaced database {} is kept as {}",
+            database_file.display(),
+            pre_restore_file.display()
+        );
+    }
+    std::fs::rename(&restoring_file, database_file)?;
+    info!(
+        "restored database {} from {}",
+        database_file.display(),
+        snapshot_file.display()
+    );
+    Ok(true)
+}
+
+fn with_suffix(file: &Path, suffix: &str) -> PathBuf {
+    let mut file = file.as_os_str().to_owned();
+    file.push(suffix);
+    PathBuf::from(file)
+}
+
+// the snapshot name a database was restored from
+fn restored_from(database_file: &Path) -> Result<Option<String>, InternalError> {
+    let db = Database::open(database_file)?;
+    let read_txn = db.begin_read()?;
+    let key_restored_from = match read_txn.open_table(KEY_RESTORED_FROM) {
+        Ok(key_restored_from) => key_restored_from,
+        Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let restored_from = key_restored_from.get(RESTORED_FROM)?.map(|ag| ag.value());
+    Ok(restored_from)
+}
+
+// copy all tables of `read_txn` into a new database file, with a restore marker if restoring
+fn copy_database(
+    read_txn: &ReadTransaction,
+    file: &Path,
+    restored_from: Option<&str>,
+) -> Result<(), InternalError> {
+    let copy = Database::create(file)?;
+    let mut write_txn = copy.begin_write()?;
+    AuthBackend::copy_tables(read_txn, &write_txn)?;
+    GuessBackend::copy_tables(read_txn, &write_txn)?;
+    AuditLog::copy_tables(read_txn, &write_txn)?;
+    StorageSessionStore::copy_tables(read_txn, &write_txn)?;
+    copy_schema_table(read_txn, &write_txn)?;
+    copy_quarantine_table(read_txn, &write_txn)?;
+    match restored_from {
+        Some(snapshot_name) => {
+            write_txn
+                .open_table(KEY_RESTORED_FROM)?
+                .insert(RESTORED_FROM, snapshot_name.to_string())?;
+            let event = AuditEvent {
+                subject: Some(snapshot_name.to_string()),
+                ..AuditEvent::new(AuditAction::DatabaseRestore, None)
+            };
+            write_txn.record_events(&[event])?;
+        }
+        None => copy_table(read_txn, &write_txn, KEY_RESTORED_FROM)?,
+    }
+    check_copied(read_txn, &write_txn)?;
+    write_txn.commit()?;
+    Ok(())
+}
+
+// every table of the source must have been copied, a table missing from the copy functions
+// would otherwise be silently left out of the backup
+fn check_copied(
+    read_txn: &ReadTransaction,
+    write_txn: &WriteTransaction,
+) -> Result<(), InternalError> {
+    let copied = write_txn
+        .list_tables()?
+        .map(|table| table.name().to_string())
+        .chain(
+            write_txn
+                .list_multimap_tables()?
+                .map(|table| table.name().to_string()),
+        )
+        .collect::<HashSet<String>>();
+    let missing = read_txn
+        .list_tables()?
+        .map(|table| table.name().to_string())
+        .chain(
+            read_txn
+                .list_multimap_tables()?
+                .map(|table| table.name().to_string()),
+        )
+        .find(|name| !copied.contains(name));
+    match missing {
+        Some(name) => Err(InternalError::BackupTable(name)),
+        None => Ok(()),
+    }
+}
+
+/// Copy a table into another database, tables that don't exist in the source are skipped.
+pub fn copy_table<K: Key + 'static, V: Value + 'static>(
+    read_txn: &ReadTransaction,
+    write_txn: &WriteTransaction,
+    definition: TableDefinition<K, V>,
+) -> Result<(), InternalError> {
+    let source = match read_txn.open_table(definition) {
+        Ok(source) => source,
+        Err(TableError::TableDoesNotExist(_)) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut destination = write_txn.open_table(definition)?;
+    for result in source.iter()? {
+        let (key, value) = result?;
+        destination.insert(key.value(), value.value())?;
+    }
+    Ok(())
+}
+
+/// Copy a multimap table into another database, tables that don't exist in the source are
+/// skipped.
+pub fn copy_multimap_table<K: Key + 'static, V: Key + 'static>(
+    read_txn: &ReadTransaction,
+    write_txn: &WriteTransaction,
+    definition: MultimapTableDefinition<K, V>,
+) -> Result<(), InternalError> {
+    let source = match read_txn.open_multimap_table(definition) {
+        Ok(source) => source,
+        Err(TableError::TableDoesNotExist(_)) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut destination = write_txn.open_multimap_table(definition)?;
+    for result in source.iter()? {
+        let (key, values) = result?;
+        for value in values {
+            destination.insert(key.value(), value?.value())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{restore, rotate, snapshot, snapshots, Backup, BackupConfig};
+    use crate::audit::backend::AuditLog;
+    use crate::audit::types::{AuditAction, AuditFilter};
+    use crate::auth::backend::AuthBackend;
+    use crate::auth::config::AuthConfig;
+    use crate::auth::types::Player;
+    use crate::guess::backend::GuessBackend;
+    use crate::guess::types::Guess;
+    use crate::migration::{migrate, APP_MIGRATIONS, APP_SCHEMA};
+    use crate::types::InternalError;
+    use chrono::{TimeDelta, Utc};
+    use redb::{Database, TableDefinition};
+    use reqwest::Url;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tempfile::{tempdir, NamedTempFile};
+    use tower_sessions::session::Record;
+    use tower_sessions::SessionStore;
+    use uuid::Uuid;
+
+    const NAME_SCORE: TableDefinition<&str, u32> = TableDefinition::new("test_name_score");
+
+    fn temp_db() -> Arc<Database> {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        Arc::new(Database::create(file).unwrap())
+    }
+
+    fn guess_backend(db: Arc<Database>) -> GuessBackend {
+        GuessBackend::new(
+            db,
+            reqwest::Client::new(),
+            Url::parse("http://localhost").unwrap(),
+        )
+        .unwrap()
+    }
+
+    // a database with players, guesses and a session
+    async fn populated_db() -> (Arc<Database>, Record) {
+        let db = temp_db();
+        migrate(&db, APP_SCHEMA, APP_MIGRATIONS, false).unwrap();
+        let auth_backend = AuthBackend::new(db.clone(), &AuthConfig::default()).unwrap();
+        let guess_backend = guess_backend(db.clone());
+        guess_backend.insert_target(100, None).await.unwrap();
+        let player = auth_backend.get_players().await.unwrap().remove(0);
+        guess_backend
+            .insert_guess(
+                100,
+                Guess {
+                    player: player.uuid,
+                    nonce: 42,
+                },
+            )
+            .await
+            .unwrap();
+        let mut record = Record {
+            id: Default::default(),
+            data: Default::default(),
+            expiry_date: time::OffsetDateTime::now_utc() + time::Duration::hours(1),
+        };
+        auth_backend
+            .session_store
+            .create(&mut record)
+            .await
+            .unwrap();
+        (db, record)
+    }
+
+    async fn assert_same_data(db: Arc<Database>, copy: Arc<Database>, record: &Record) {
+        let auth_backend = AuthBackend::new(db.clone(), &AuthConfig::default()).unwrap();
+        let copy_auth_backend = AuthBackend::new(copy.clone(), &AuthConfig::default()).unwrap();
+        assert_eq!(
+            copy_auth_backend.get_players().await.unwrap(),
+            auth_backend.get_players().await.unwrap()
+        );
+        assert_eq!(
+            copy_auth_backend.get_roles().await.unwrap(),
+            auth_backend.get_roles().await.unwrap()
+        );
+        assert_eq!(
+            guess_backend(copy.clone())
+                .target_guesses(100)
+                .await
+                .unwrap(),
+            guess_backend(db).target_guesses(100).await.unwrap()
+        );
+        assert_eq!(
+            copy_auth_backend
+                .session_store
+                .load(&record.id)
+                .await
+                .unwrap(),
+            Some(record.clone())
+        );
+        // the schema version is copied, so no migrations run on the copy
+        assert!(migrate(&copy, APP_SCHEMA, APP_MIGRATIONS, false)
+            .unwrap()
+            .applied
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let (db, record) = populated_db().await;
+        let dir = tempdir().unwrap();
+        let backup_snapshot = snapshot(&db, dir.path(), Utc::now()).unwrap();
+        assert_eq!(
+            snapshots(dir.path()).unwrap(),
+            vec![backup_snapshot.clone()]
+        );
+        let copy = Arc::new(Database::open(dir.path().join(&backup_snapshot.name)).unwrap());
+        assert_same_data(db, copy, &record).await;
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_missing_table() {
+        let (db, _) = populated_db().await;
+        let write_txn = db.begin_write().unwrap();
+        write_txn.open_table(NAME_SCORE).unwrap();
+        write_txn.commit().unwrap();
+        let dir = tempdir().unwrap();
+        let result = snapshot(&db, dir.path(), Utc::now());
+        assert!(
+            matches!(result, Err(InternalError::BackupTable(name)) if name == "test_name_score")
+        );
+        assert!(snapshots(dir.path()).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rotate() {
+        let db = temp_db();
+        let dir = tempdir().unwrap();
+        let now = Utc::now();
+        let names = (0..4)
+            .map(|hours| {
+                snapshot(&db, dir.path(), now - TimeDelta::hours(4 - hours))
+                    .unwrap()
+                    .name
+            })
+            .collect::<Vec<String>>();
+        let removed = rotate(dir.path(), 2).unwrap();
+        assert_eq!(
+            removed.into_iter().map(|s| s.name).collect::<Vec<String>>(),
+            vec![names[1].clone(), names[0].clone()]
+        );
+        assert_eq!(
+            snapshots(dir.path())
+                .unwrap()
+                .into_iter()
+                .map(|s| s.name)
+                .collect::<Vec<String>>(),
+            vec![names[3].clone(), names[2].clone()]
+        );
+
+        // admin snapshots are rotated and audited
+        let backup = Backup::new(
+            db.clone(),
+            BackupConfig {
+                dir: dir.path().to_path_buf(),
+                interval_secs: 0,
+                retain: 2,
+            },
+        );
+        let audit_log = AuditLog::new(db.clone()).unwrap();
+        let admin = Default::default();
+        let new_snapshot = backup.admin_snapshot(&audit_log, &admin).await.unwrap();
+        let snapshots = backup.snapshots().await.unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[1].name, names[3]);
+        assert_eq!(snapshots[0], new_snapshot);
+        let events = audit_log
+            .get_events(AuditFilter {
+                action: Some(AuditAction::BackupCreate),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(events[0].subject, Some(new_snapshot.name));
+    }
+
+    async fn player_names(file: &Path) -> Vec<String> {
+        let db = Arc::new(Database::open(file).unwrap());
+        let auth_backend = AuthBackend::new(db, &AuthConfig::default()).unwrap();
+        let mut names = auth_backend
+            .get_players()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|player| player.name)
+            .collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_restore() {
+        let (db, record) = populated_db().await;
+        let dir = tempdir().unwrap();
+        let backup_snapshot = snapshot(&db, dir.path(), Utc::now()).unwrap();
+        let snapshot_file = dir.path().join(&backup_snapshot.name);
+
+        // a database changed after the snapshot
+        let database_file = dir.path().join("nonce_guess.redb");
+        {
+            let current = Arc::new(Database::create(&database_file).unwrap());
+            AuthBackend::new(current, &AuthConfig::default())
+                .unwrap()
+                .insert_player(&Player {
+                    uuid: Uuid::new_v4(),
+                    name: "later".to_string(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        assert!(restore(&snapshot_file, &database_file).unwrap());
+        assert_eq!(player_names(&database_file).await, vec!["admin"]);
+        assert_eq!(
+            player_names(&dir.path().join("nonce_guess.redb.pre-restore")).await,
+            vec!["admin", "later"]
+        );
+        let restored = Arc::new(Database::open(&database_file).unwrap());
+        assert_same_data(db, restored.clone(), &record).await;
+        let events = AuditLog::new(restored.clone())
+            .unwrap()
+            .get_events(AuditFilter {
+                action: Some(AuditAction::DatabaseRestore),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        drop(restored);
+
+        // restarting with the same snapshot keeps the restored database
+        assert!(!restore(&snapshot_file, &database_file).unwrap());
+    }
+}
@@ -0,0 +1,870 @@
+use crate::audit::backend::AuditLog;
+use crate::audit::types::{AuditAction, AuditEvent};
+use crate::auth::backend::AuthBackend;
+use crate::auth::types::{datetime_now, name_key, Permission, Player, Role};
+use crate::guess::backend::GuessBackend;
+use crate::guess::types::{Guess, DELETED_PLAYER};
+use crate::storage::{ReadTxn, Storage, WriteTxn};
+use crate::types::InternalError;
+use chrono::{DateTime, Utc};
+use serde::ser::{Error, SerializeSeq, SerializeStruct};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+use tracing::info;
+use uuid::Uuid;
+
+/// The latest dump format version, dumps of newer versions are rejected by the import.
+pub const DUMP_VERSION: u32 = 1;
+
+/// A dump of the game data, see the README for the documented format.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Dump {
+    pub version: u32,
+    pub exported: DateTime<Utc>,
+    pub roles: Vec<Role>,
+    pub players: Vec<DumpPlayer>,
+    pub targets: Vec<DumpTarget>,
+}
+
+/// A player without session keys, and without the password hash unless it was exported with
+/// secrets.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct DumpPlayer {
+    pub uuid: Uuid,
+    pub name: String,
+    /// Only exported on request, when missing an import keeps the existing player's hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    pub permissions: HashSet<Permission>,
+    pub roles: HashSet<Uuid>,
+    #[serde(default)]
+    pub must_change_password: bool,
+    #[serde(default)]
+    pub renamed: bool,
+    #[serde(default = "datetime_now")]
+    pub last_login: DateTime<Utc>,
+    #[serde(default = "datetime_now")]
+    pub updated: DateTime<Utc>,
+    #[serde(default = "datetime_now")]
+    pub created: DateTime<Utc>,
+}
+
+impl DumpPlayer {
+    pub fn new(player: Player, secrets: bool) -> Self {
+        Self {
+            uuid: player.uuid,
+            name: player.name,
+            password_hash: Some(player.password_hash).filter(|_| secrets),
+            permissions: player.permissions,
+            roles: player.roles,
+            must_change_password: player.must_change_password,
+            renamed: player.renamed,
+            last_login: player.last_login,
+            updated: player.updated,
+            created: player.created,
+        }
+    }
+
+    /// The player to import. Without a password hash the existing player's hash and session
+    /// key are kept, a new player has no password and must set one with a reset link. Sessions
+    /// only stay valid while the password hash is unchanged.
+    pub fn into_player(self, existing: Option<Player>) -> Player {
+        let (password_hash, session_key, must_change_password) =
+            match (self.password_hash, existing) {
+                (Some(password_hash), existing) => {
+                    let session_key = existing
+                        .filter(|existing| existing.password_hash == password_hash)
+                        .and_then(|existing| existing.session_key);
+                    (password_hash, session_key, self.must_change_password)
+                }
+                (None, Some(existing)) => (
+                    existing.password_hash,
+                    existing.session_key,
+                    self.must_change_password,
+                ),
+                (None, None) => (String::new(), None, true),
+            };
+        Player {
+            uuid: self.uuid,
+            name: self.name,
+            password_hash,
+            permissions: self.permissions,
+            roles: self.roles,
+            must_change_password,
+            renamed: self.renamed,
+            session_key,
+            last_login: self.last_login,
+            updated: self.updated,
+            created: self.created,
+        }
+    }
+}
+
+/// A target block height with its result and the guesses for it.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct DumpTarget {
+    pub height: u32,
+    /// The nonce of the confirmed block, `None` while the target is still open.
+    pub nonce: Option<u32>,
+    pub guesses: Vec<Guess>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DumpFormat {
+    Json,
+    Cbor,
+}
+
+impl DumpFormat {
+    /// The format of a dump file, CBOR for a `.cbor` extension and JSON otherwise.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("cbor") => DumpFormat::Cbor,
+            _ => DumpFormat::Json,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            DumpFormat::Json => "application/json",
+            DumpFormat::Cbor => "application/cbor",
+        }
+    }
+}
+
+impl Display for DumpFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DumpFormat::Json => write!(f, "json"),
+            DumpFormat::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(DumpFormat::Json),
+            "cbor" => Ok(DumpFormat::Cbor),
+            _ => Err(format!("unknown dump format: {}", s)),
+        }
+    }
+}
+
+/// How an import treats the existing game data.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ImportMode {
+    /// Insert or replace players and roles by uuid and targets by height, an imported
+    /// target's guesses replace its existing guesses.
+    Merge,
+    /// Remove all existing players, roles, targets and guesses first.
+    Replace,
+}
+
+impl Display for ImportMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportMode::Merge => write!(f, "merge"),
+            ImportMode::Replace => write!(f, "replace"),
+        }
+    }
+}
+
+impl FromStr for ImportMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merge" => Ok(ImportMode::Merge),
+            "replace" => Ok(ImportMode::Replace),
+            _ => Err(format!("unknown import mode: {}", s)),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ImportError {
+    #[error("invalid dump: {0}")]
+    Decode(String),
+    #[error("dump version {0} is newer than the latest known version {1}")]
+    NewerVersion(u32, u32),
+    #[error("duplicate player: {0}")]
+    DuplicatePlayer(String),
+    #[error("duplicate role: {0}")]
+    DuplicateRole(Uuid),
+    #[error("duplicate target height: {0}")]
+    DuplicateTarget(u32),
+    #[error("duplicate guess for target height {0}: {1}")]
+    DuplicateGuess(u32, String),
+    #[error("player {0} has unknown role: {1}")]
+    UnknownRole(String, Uuid),
+    #[error("guess for target height {0} by unknown player: {1}")]
+    UnknownPlayer(u32, Uuid),
+    #[error("player name is taken by another player: {0}")]
+    NameTaken(String),
+    #[error(transparent)]
+    Internal(#[from] InternalError),
+}
+
+/// Counts of the imported records.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ImportSummary {
+    pub roles: usize,
+    pub players: usize,
+    pub targets: usize,
+    pub guesses: usize,
+}
+
+impl Display for ImportSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} roles, {} players, {} targets, {} guesses",
+            self.roles, self.players, self.targets, self.guesses
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DataDump {
+    storage: Arc<dyn Storage>,
+}
+
+impl DataDump {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    /// Export the game data requested by an admin, recorded in the audit log. Password hashes
+    /// are never included.
+    pub async fn admin_export(
+        &self,
+        format: DumpFormat,
+        audit_log: &AuditLog,
+        admin: &Player,
+    ) -> Result<Vec<u8>, InternalError> {
+        let storage = self.storage.clone();
+        let bytes = spawn_blocking(move || {
+            let mut bytes = Vec::new();
+            export(storage.as_ref(), format, &mut bytes, Utc::now(), false)?;
+            Ok::<Vec<u8>, InternalError>(bytes)
+        })
+        .await??;
+        let event = AuditEvent {
+            subject: Some(format.to_string()),
+            ..AuditEvent::new(AuditAction::DataExport, Some(admin))
+        };
+        audit_log.record(event).await?;
+        Ok(bytes)
+    }
+
+    /// Import game data uploaded by an admin, recorded in the audit log.
+    pub async fn admin_import(
+        &self,
+        format: DumpFormat,
+        bytes: Vec<u8>,
+        mode: ImportMode,
+        admin: &Player,
+    ) -> Result<ImportSummary, ImportError> {
+        let storage = self.storage.clone();
+        let admin = admin.clone();
+        spawn_blocking(move || {
+            import(
+                storage.as_ref(),
+                format,
+                bytes.as_slice(),
+                mode,
+                Some(&admin),
+            )
+        })
+        .await
+        .map_err(Into::<InternalError>::into)?
+    }
+}
+
+/// Write the game data of a single read transaction. Rows are serialized as they are read, so
+/// exporting to a file doesn't hold the tables in memory. Password hashes are only included
+/// with `secrets`.
+pub fn export(
+    storage: &dyn Storage,
+    format: DumpFormat,
+    mut writer: impl Write,
+    now: DateTime<Utc>,
+    secrets: bool,
+) -> Result<(), InternalError> {
+    let read_txn = storage.begin_read()?;
+    let dump = DumpExport {
+        read_txn: read_txn.as_ref(),
+        exported: now,
+        secrets,
+    };
+    match format {
+        DumpFormat::Json => serde_json::to_writer(&mut writer, &dump)
+            .map_err(|e| InternalError::Export(e.to_string()))?,
+        DumpFormat::Cbor => ciborium::into_writer(&dump, &mut writer)
+            .map_err(|e| InternalError::Export(e.to_string()))?,
+    }
+    writer.flush()?;
+    info!("exported game data as {}", format);
+    Ok(())
+}
+
+// serialized like a `Dump`, with the sequences read from the tables while writing
+struct DumpExport<'a> {
+    read_txn: &'a dyn ReadTxn,
+    exported: DateTime<Utc>,
+    secrets: bool,
+}
+
+impl Serialize for DumpExport<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let read_txn = self.read_txn;
+        let mut dump = serializer.serialize_struct("Dump", 5)?;
+        dump.serialize_field("version", &DUMP_VERSION)?;
+        dump.serialize_field("exported", &self.exported)?;
+        dump.serialize_field("roles", &Rows(|| read_txn.iter_roles()))?;
+        dump.serialize_field(
+            "players",
+            &Rows(|| {
+                let players = read_txn
+                    .iter_players()?
+                    .map(|result| result.map(|player| DumpPlayer::new(player, self.secrets)));
+                Ok(players)
+            }),
+        )?;
+        dump.serialize_field(
+            "targets",
+            &Rows(|| {
+                let targets = read_txn.iter_targets()?.map(|result| {
+                    let (height, nonce) = result?;
+                    Ok(DumpTarget {
+                        height,
+                        nonce,
+                        guesses: read_txn.target_guesses(height)?,
+                    })
+                });
+                Ok(targets)
+            }),
+        )?;
+        dump.end()
+    }
+}
+
+// a sequence of unknown length serialized from the rows of a table iterator
+struct Rows<F>(F);
+
+impl<F, I, T> Serialize for Rows<F>
+where
+    F: Fn() -> Result<I, InternalError>,
+    I: Iterator<Item = Result<T, InternalError>>,
+    T: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let rows = (self.0)().map_err(S::Error::custom)?;
+        let mut seq = serializer.serialize_seq(None)?;
+        for row in rows {
+            seq.serialize_element(&row.map_err(S::Error::custom)?)?;
+        }
+        seq.end()
+    }
+}
+
+/// Import a dump in a single write transaction, nothing is changed if the dump is invalid or
+/// references players or roles that don't exist.
+pub fn import(
+    storage: &dyn Storage,
+    format: DumpFormat,
+    reader: impl Read,
+    mode: ImportMode,
+    actor: Option<&Player>,
+) -> Result<ImportSummary, ImportError> {
+    let dump: Dump = match format {
+        DumpFormat::Json => {
+            serde_json::from_reader(reader).map_err(|e| ImportError::Decode(e.to_string()))?
+        }
+        DumpFormat::Cbor => {
+            ciborium::from_reader(reader).map_err(|e| ImportError::Decode(e.to_string()))?
+        }
+    };
+    validate(&dump)?;
+    let mut write_txn = storage.begin_write()?;
+    let replace = mode == ImportMode::Replace;
+    check_references(write_txn.as_ref(), &dump, replace)?;
+    // read before a replace removes the existing players
+    let players = dump
+        .players
+        .iter()
+        .map(|player| {
+            let existing = write_txn.get_player_for_update(player.uuid)?;
+            Ok(player.clone().into_player(existing))
+        })
+        .collect::<Result<Vec<Player>, InternalError>>()?;
+    AuthBackend::import_players(&mut *write_txn, &dump.roles, &players, replace)?;
+    GuessBackend::import_targets(&mut *write_txn, &dump.targets, replace)?;
+    let summary = ImportSummary {
+        roles: dump.roles.len(),
+        players: dump.players.len(),
+        targets: dump.targets.len(),
+        guesses: dump.targets.iter().map(|target| target.guesses.len()).sum(),
+    };
+    let event = AuditEvent {
+        subject: Some(mode.to_string()),
+        after: Some(summary.to_string()),
+        ..AuditEvent::new(AuditAction::DataImport, actor)
+    };
+    write_txn.record_events(&[event])?;
+    write_txn.commit()?;
+    info!("imported {} with {} mode", summary, mode);
+    Ok(summary)
+}
+
+// check the dump on its own, references are checked separately
+fn validate(dump: &Dump) -> Result<(), ImportError> {
+    if dump.version > DUMP_VERSION {
+        return Err(ImportError::NewerVersion(dump.version, DUMP_VERSION));
+    }
+    let mut role_uuids = HashSet::new();
+    for role in &dump.roles {
+        if !role_uuids.insert(role.uuid) {
+            return Err(ImportError::DuplicateRole(role.uuid));
+        }
+    }
+    let mut player_uuids = HashSet::new();
+    let mut player_names = HashSet::new();
+    for player in &dump.players {
+        if !player_uuids.insert(player.uuid) {
+            return Err(ImportError::DuplicatePlayer(player.uuid.to_string()));
+        }
+        if !player_names.insert(name_key(&player.name)) {
+            return Err(ImportError::DuplicatePlayer(player.name.clone()));
+        }
+    }
+    let mut heights = HashSet::new();
+    for target in &dump.targets {
+        if !heights.insert(target.height) {
+            return Err(ImportError::DuplicateTarget(target.height));
+        }
+        let mut nonces = HashSet::new();
+        let mut players = HashSet::new();
+        for guess in &target.guesses {
+            if !nonces.insert(guess.nonce) {
+                return Err(ImportError::DuplicateGuess(
+                    target.height,
+                    format!("nonce {}", guess.nonce),
+                ));
+            }
+            // guesses of deleted accounts are all kept under the same player
+            if guess.player != DELETED_PLAYER && !players.insert(guess.player) {
+                return Err(ImportError::DuplicateGuess(
+                    target.height,
+                    format!("player {}", guess.player),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// roles and players must be in the dump, or when merging may also be existing ones. Imported
+// names must not be taken by existing players the merge doesn't replace.
+fn check_references(
+    write_txn: &dyn WriteTxn,
+    dump: &Dump,
+    replace: bool,
+) -> Result<(), ImportError> {
+    let role_uuids = dump
+        .roles
+        .iter()
+        .map(|role| role.uuid)
+        .collect::<HashSet<Uuid>>();
+    let player_uuids = dump
+        .players
+        .iter()
+        .map(|player| player.uuid)
+        .collect::<HashSet<Uuid>>();
+    for player in &dump.players {
+        for role in &player.roles {
+            if !role_uuids.contains(role)
+                && (replace || write_txn.get_role_for_update(*role)?.is_none())
+            {
+                return Err(ImportError::UnknownRole(player.name.clone(), *role));
+            }
+        }
+        if replace {
+            continue;
+        }
+        if let Some(uuid) = write_txn.get_name_for_update(&player.name)? {
+            if uuid != player.uuid && !player_uuids.contains(&uuid) {
+                return Err(ImportError::NameTaken(player.name.clone()));
+            }
+        }
+    }
+    for target in &dump.targets {
+        for guess in &target.guesses {
+            if guess.player != DELETED_PLAYER
+                && !player_uuids.contains(&guess.player)
+                && (replace || write_txn.get_player_for_update(guess.player)?.is_none())
+            {
+                return Err(ImportError::UnknownPlayer(target.height, guess.player));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        export, import, Dump, DumpFormat, DumpPlayer, DumpTarget, ImportError, ImportMode,
+    };
+    use crate::audit::backend::AuditLog;
+    use crate::audit::types::{AuditAction, AuditFilter};
+    use crate::auth::backend::AuthBackend;
+    use crate::auth::config::AuthConfig;
+    use crate::auth::types::{Player, Role};
+    use crate::guess::backend::GuessBackend;
+    use crate::guess::types::{Guess, DELETED_PLAYER};
+    use crate::storage::redb::RedbStorage;
+    use crate::storage::Storage;
+    use chrono::Utc;
+    use redb::Database;
+    use reqwest::Url;
+    use std::sync::Arc;
+    use tempfile::NamedTempFile;
+    use uuid::Uuid;
+
+    fn backends() -> (Arc<dyn Storage>, AuthBackend, GuessBackend) {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        let db = Arc::new(Database::create(file).unwrap());
+        let storage: Arc<dyn Storage> = Arc::new(RedbStorage::new(db).unwrap());
+        let auth_backend =
+            AuthBackend::with_storage(storage.clone(), &AuthConfig::default()).unwrap();
+        let guess_backend = GuessBackend::with_storage(
+            storage.clone(),
+            reqwest::Client::new(),
+            Url::parse("http://localhost").unwrap(),
+        );
+        (storage, auth_backend, guess_backend)
+    }
+
+    // the admin, a player with a guess for a confirmed and an open target, and a deleted
+    // player's guess
+    async fn populate(auth_backend: &AuthBackend, guess_backend: &GuessBackend) -> Player {
+        let admin = auth_backend.get_players().await.unwrap().remove(0);
+        let player = Player {
+            uuid: Uuid::new_v4(),
+            name: "alice".to_string(),
+            roles: admin.roles.clone(),
+            ..Default::default()
+        };
+        auth_backend.insert_player(&player).await.unwrap();
+        guess_backend.insert_target(100, Some(42)).await.unwrap();
+        guess_backend.insert_target(200, None).await.unwrap();
+        for (height, guess) in [
+            (
+                100,
+                Guess {
+                    player: admin.uuid,
+                    nonce: 40,
+                },
+            ),
+            (
+                100,
+                Guess {
+                    player: player.uuid,
+                    nonce: 50,
+                },
+            ),
+            (
+                100,
+                Guess {
+                    player: DELETED_PLAYER,
+                    nonce: 60,
+                },
+            ),
+            (
+                200,
+                Guess {
+                    player: player.uuid,
+                    nonce: 70,
+                },
+            ),
+        ] {
+            guess_backend.insert_guess(height, guess).await.unwrap();
+        }
+        player
+    }
+
+    async fn sorted_players(auth_backend: &AuthBackend) -> Vec<Player> {
+        let mut players = auth_backend.get_players().await.unwrap();
+        players.sort_by(|a, b| a.name.cmp(&b.name));
+        players
+    }
+
+    fn export_dump(storage: &dyn Storage, format: DumpFormat, secrets: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        export(storage, format, &mut bytes, Utc::now(), secrets).unwrap();
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_export_import_replace() {
+        let (storage, auth_backend, guess_backend) = backends();
+        populate(&auth_backend, &guess_backend).await;
+        for format in [DumpFormat::Json, DumpFormat::Cbor] {
+            let bytes = export_dump(storage.as_ref(), format, true);
+            let (copy, copy_auth_backend, copy_guess_backend) = backends();
+            let summary = import(
+                copy.as_ref(),
+                format,
+                bytes.as_slice(),
+                ImportMode::Replace,
+                None,
+            )
+            .unwrap();
+            assert_eq!(
+                summary.to_string(),
+                "1 roles, 2 players, 2 targets, 4 guesses"
+            );
+            assert_eq!(
+                sorted_players(&copy_auth_backend).await,
+                sorted_players(&auth_backend).await
+            );
+            assert_eq!(
+                copy_auth_backend.get_roles().await.unwrap(),
+                auth_backend.get_roles().await.unwrap()
+            );
+            for height in [100, 200] {
+                assert_eq!(
+                    copy_guess_backend.get_target_nonce(height).await.unwrap(),
+                    guess_backend.get_target_nonce(height).await.unwrap()
+                );
+                assert_eq!(
+                    copy_guess_backend.target_guesses(height).await.unwrap(),
+                    guess_backend.target_guesses(height).await.unwrap()
+                );
+            }
+            let events = AuditLog::with_storage(copy)
+                .get_events(AuditFilter {
+                    action: Some(AuditAction::DataImport),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(events[0].subject, Some("replace".to_string()));
+        }
+
+        // the JSON export is a documented `Dump`, without secrets unless requested
+        let bytes = export_dump(storage.as_ref(), DumpFormat::Json, false);
+        let json = String::from_utf8(bytes.clone()).unwrap();
+        assert!(!json.contains("password_hash"));
+        assert!(!json.contains("session_key"));
+        let dump: Dump = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(dump.version, 1);
+        assert_eq!(
+            dump.targets[0],
+            DumpTarget {
+                height: 100,
+                nonce: Some(42),
+                guesses: guess_backend.target_guesses(100).await.unwrap(),
+            }
+        );
+        // new players imported without a password hash must set a password
+        let (copy, copy_auth_backend, _) = backends();
+        import(
+            copy.as_ref(),
+            DumpFormat::Json,
+            bytes.as_slice(),
+            ImportMode::Replace,
+            None,
+        )
+        .unwrap();
+        for player in sorted_players(&copy_auth_backend).await {
+            assert!(player.password_hash.is_empty());
+            assert!(player.must_change_password);
+            assert_eq!(player.session_key, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_merge() {
+        let (storage, auth_backend, guess_backend) = backends();
+        let player = populate(&auth_backend, &guess_backend).await;
+        let admin = sorted_players(&auth_backend).await.remove(0);
+        // the players swap names, a new role is added and target 100 gets new guesses
+        let role = Role {
+            uuid: Uuid::new_v4(),
+            name: "player".to_string(),
+            permissions: Default::default(),
+        };
+        let dump = Dump {
+            version: 1,
+            exported: Utc::now(),
+            roles: vec![role.clone()],
+            players: vec![
+                DumpPlayer::new(
+                    Player {
+                        name: "alice".to_string(),
+                        ..admin.clone()
+                    },
+                    false,
+                ),
+                DumpPlayer::new(
+                    Player {
+                        name: "admin".to_string(),
+                        roles: [role.uuid].into(),
+                        ..player.clone()
+                    },
+                    false,
+                ),
+            ],
+            targets: vec![DumpTarget {
+                height: 100,
+                nonce: Some(42),
+                guesses: vec![Guess {
+                    player: admin.uuid,
+                    nonce: 41,
+                }],
+            }],
+        };
+        let bytes = serde_json::to_vec(&dump).unwrap();
+        import(
+            storage.as_ref(),
+            DumpFormat::Json,
+            bytes.as_slice(),
+            ImportMode::Merge,
+            None,
+        )
+        .unwrap();
+        // players without a password hash in the dump keep their existing one
+        let merged_admin = auth_backend
+            .get_player_by_name("alice")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(merged_admin.uuid, admin.uuid);
+        assert_eq!(merged_admin.password_hash, admin.password_hash);
+        assert!(!merged_admin.password_hash.is_empty());
+        assert_eq!(
+            auth_backend
+                .get_player_by_name("admin")
+                .await
+                .unwrap()
+                .map(|player| player.roles),
+            Some([role.uuid].into())
+        );
+        assert_eq!(auth_backend.get_roles().await.unwrap().len(), 2);
+        assert_eq!(
+            guess_backend.target_guesses(100).await.unwrap(),
+            dump.targets[0].guesses
+        );
+        // targets missing from the dump are kept
+        assert_eq!(guess_backend.target_guesses(200).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_import_invalid() {
+        let (storage, auth_backend, guess_backend) = backends();
+        let player = populate(&auth_backend, &guess_backend).await;
+        let players = sorted_players(&auth_backend).await;
+        let target = |guesses| DumpTarget {
+            height: 300,
+            nonce: None,
+            guesses,
+        };
+        let dump = |players, targets| Dump {
+            version: 1,
+            exported: Utc::now(),
+            roles: vec![],
+            players,
+            targets,
+        };
+        let import_dump = |dump: Dump, mode| {
+            let bytes = serde_json::to_vec(&dump).unwrap();
+            import(
+                storage.as_ref(),
+                DumpFormat::Json,
+                bytes.as_slice(),
+                mode,
+                None,
+            )
+        };
+
+        let unknown = Uuid::new_v4();
+        let result = import_dump(
+            dump(
+                vec![],
+                vec![target(vec![Guess {
+                    player: unknown,
+                    nonce: 1,
+                }])],
+            ),
+            ImportMode::Merge,
+        );
+        assert!(matches!(result, Err(ImportError::UnknownPlayer(300, uuid)) if uuid == unknown));
+        // existing players are only known when merging
+        let guess = Guess {
+            player: player.uuid,
+            nonce: 1,
+        };
+        let result = import_dump(
+            dump(vec![], vec![target(vec![guess.clone()])]),
+            ImportMode::Replace,
+        );
+        assert!(matches!(result, Err(ImportError::UnknownPlayer(300, _))));
+        let result = import_dump(
+            dump(vec![], vec![target(vec![guess.clone(), guess.clone()])]),
+            ImportMode::Merge,
+        );
+        assert!(matches!(result, Err(ImportError::DuplicateGuess(300, _))));
+        // the admin role is not in the dump
+        let dump_players = players
+            .iter()
+            .map(|player| DumpPlayer::new(player.clone(), true))
+            .collect();
+        let result = import_dump(dump(dump_players, vec![]), ImportMode::Replace);
+        assert!(matches!(result, Err(ImportError::UnknownRole(_, _))));
+        let result = import_dump(
+            dump(
+                vec![DumpPlayer::new(
+                    Player {
+                        uuid: Uuid::new_v4(),
+                        ..player.clone()
+                    },
+                    true,
+                )],
+                vec![],
+            ),
+            ImportMode::Merge,
+        );
+        assert!(matches!(result, Err(ImportError::NameTaken(name)) if name == "alice"));
+        let result = import_dump(
+            Dump {
+                version: 2,
+                ..dump(vec![], vec![])
+            },
+            ImportMode::Merge,
+        );
+        assert!(matches!(result, Err(ImportError::NewerVersion(2, 1))));
+        let result = import(
+            storage.as_ref(),
+            DumpFormat::Cbor,
+            b"not cbor".as_slice(),
+            ImportMode::Replace,
+            None,
+        );
+        assert!(matches!(result, Err(ImportError::Decode(_))));
+
+        // nothing was changed by the failed imports
+        assert_eq!(sorted_players(&auth_backend).await, players);
+        assert_eq!(guess_backend.get_target_nonce(300).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,872 @@
+use crate::audit::store::AuditWrite;
+use crate::audit::types::{AuditAction, AuditEvent};
+use crate::backup::copy_table;
+use crate::encryption::{self, sealed_key_id, EncryptionError, Keyring, SEALED_MARKER};
+use crate::storage::UndecodableRecord;
+use crate::types::InternalError;
+use chrono::{DateTime, Utc};
+use redb::{
+    Key, MultimapTableDefinition, MultimapTableHandle, ReadTransaction, ReadableMultimapTable,
+    ReadableTable, TableDefinition, TableHandle, TypeName, Value, WriteTransaction,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::ops::Bound;
+use tracing::warn;
+
+// first byte of an enveloped value, followed by the encoding version and the CBOR payload.
+// 0xff is never the first byte of a CBOR data item, so values stored before the envelope
+// are still recognized.
+const ENVELOPE_MARKER: u8 = 0xff;
+
+const KEY_QUARANTINE: TableDefinition<String, Encoded<QuarantinedRecord>> =
+    TableDefinition::new("quarantine");
+
+/// A type stored as a versioned CBOR value.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// The redb type name of the table values, it must not change or the tables can't be
+    /// opened anymore.
+    const TYPE_NAME: &'static str;
+    /// The current encoding version. Increment it when the stored form changes in a way serde
+    /// defaults can't handle, and decode the previous version in `decode_version`.
+    const VERSION: u8 = 1;
+    /// Whether values are sealed when encryption at rest is configured, for types with
+    /// credentials or personal data.
+    const ENCRYPTED: bool = false;
+
+    /// Decode a value stored with an older encoding version, version 0 values were stored
+    /// before the envelope.
+    fn decode_version(_version: u8, payload: &[u8]) -> Result<Self, DecodeError> {
+        decode_cbor(payload)
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    #[error("{0} encoding version {1} is newer than the latest known version {2}")]
+    NewerVersion(&'static str, u8, u8),
+    #[error("invalid {0}: {1}")]
+    Invalid(&'static str, String),
+    #[error("{0} is encrypted with unknown key {1}, configure it as an old encryption key")]
+    UnknownKey(&'static str, String),
+}
+
+impl DecodeError {
+    /// Whether the stored value itself is broken. A value encrypted with a key that isn't
+    /// configured can be decoded once the key is, so it must not be quarantined.
+    pub fn is_corrupt(&self) -> bool {
+        !matches!(self, DecodeError::UnknownKey(..))
+    }
+}
+
+/// Decode a CBOR payload without the envelope.
+pub fn decode_cbor<T: Versioned>(payload: &[u8]) -> Result<T, DecodeError> {
+    ciborium::from_reader(payload).map_err(|e| DecodeError::Invalid(T::TYPE_NAME, e.to_string()))
+}
+
+/// The stored bytes of a [`Versioned`] value, decoded when used so a corrupt record is an
+/// error instead of a panic.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Encoded<T> {
+    bytes: Vec<u8>,
+    value_type: PhantomData<T>,
+}
+
+impl<T: Versioned> Encoded<T> {
+    /// Encode a value of a type that isn't encrypted, encrypted types are bound to their record
+    /// key with [`Encoded::keyed`].
+    pub fn new(value: &T) -> Self {
+        debug_assert!(!T::ENCRYPTED, "{} needs its record key", T::TYPE_NAME);
+        Self::with_keyring(value, &[], encryption::keyring())
+    }
+
+    /// Encode the value of the record with the key bytes `key`, as the table stores the key.
+    pub fn keyed(key: &[u8], value: &T) -> Self {
+        Self::with_keyring(value, key, encryption::keyring())
+    }
+
+    /// Encode a value, sealed with the primary key of the keyring if its type is encrypted.
+    pub(crate) fn with_keyring(value: &T, key: &[u8], keyring: Option<&Keyring>) -> Self {
+        let mut bytes = vec![ENVELOPE_MARKER, T::VERSION];
+        ciborium::into_writer(value, &mut bytes).expect("Failed to serialize value");
+        if T::ENCRYPTED {
+            if let Some(sealed) = keyring.and_then(|k| k.seal(&record_aad::<T>(key), &bytes)) {
+                bytes = sealed;
+            }
+        }
+        Self {
+            bytes,
+            value_type: PhantomData,
+        }
+    }
+
+    /// Decode a value of a type that isn't encrypted.
+    pub fn decode(&self) -> Result<T, DecodeError> {
+        debug_assert!(!T::ENCRYPTED, "{} needs its record key", T::TYPE_NAME);
+        self.decode_with(&[], encryption::keyring())
+    }
+
+    /// Decode the value of the record with the key bytes `key`.
+    pub fn decode_keyed(&self, key: &[u8]) -> Result<T, DecodeError> {
+        self.decode_with(key, encryption::keyring())
+    }
+
+    /// Decode a value, opening it with the keyring if it is sealed.
+    pub(crate) fn decode_with(
+        &self,
+        key: &[u8],
+        keyring: Option<&Keyring>,
+    ) -> Result<T, DecodeError> {
+        if let Some(key_id) = sealed_key_id(&self.bytes) {
+            let unknown_key = || DecodeError::UnknownKey(T::TYPE_NAME, hex::encode(key_id));
+            let opened = keyring
+                .ok_or_else(unknown_key)?
+                .open(&record_aad::<T>(key), &self.bytes)
+                .map_err(|e| match e {
+                    EncryptionError::UnknownKey(_) => unknown_key(),
+                    e => DecodeError::Invalid(T::TYPE_NAME, e.to_string()),
+                })?;
+            return Self::decode_plain(&opened);
+        }
+        if self.bytes.first() == Some(&SEALED_MARKER) {
+            return Err(DecodeError::Invalid(
+                T::TYPE_NAME,
+                "truncated encryption header".to_string(),
+            ));
+        }
+        Self::decode_plain(&self.bytes)
+    }
+
+    fn decode_plain(bytes: &[u8]) -> Result<T, DecodeError> {
+        match bytes {
+            [ENVELOPE_MARKER, version, payload @ ..] if *version == T::VERSION => {
+                decode_cbor(payload)
+            }
+            [ENVELOPE_MARKER, version, ..] if *version > T::VERSION => Err(
+                DecodeError::NewerVersion(T::TYPE_NAME, *version, T::VERSION),
+            ),
+            [ENVELOPE_MARKER, version, payload @ ..] => T::decode_version(*version, payload),
+            [ENVELOPE_MARKER] => Err(DecodeError::Invalid(
+                T::TYPE_NAME,
+                "missing encoding version".to_string(),
+            )),
+            legacy => T::decode_version(0, legacy),
+        }
+    }
+
+    /// Whether the value is stored the way it would be encoded with the keyring now, values
+    /// of types that aren't encrypted always are.
+    pub(crate) fn is_current(&self, keyring: Option<&Keyring>) -> bool {
+        !T::ENCRYPTED
+            || match keyring {
+                Some(keyring) => keyring.is_current(&self.bytes),
+                None => sealed_key_id(&self.bytes).is_none(),
+            }
+    }
+}
+
+// sealed values are bound to their type and record key, so a value copied to another record
+// or table can't be opened there. the type name has no nul byte, it ends the type name
+fn record_aad<T: Versioned>(key: &[u8]) -> Vec<u8> {
+    let mut aad = T::TYPE_NAME.as_bytes().to_vec();
+    aad.push(0);
+    aad.extend_from_slice(key);
+    aad
+}
+
+// values can contain secrets, only show the type and size
+impl<T: Versioned> Debug for Encoded<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Encoded<{}>({} bytes)", T::TYPE_NAME, self.bytes.len())
+    }
+}
+
+impl<T: Versioned> Value for Encoded<T> {
+    type SelfType<'a>
+        = Encoded<T>
+    where
+        Self: 'a;
+    type AsBytes<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        Encoded {
+            bytes: data.to_vec(),
+            value_type: PhantomData,
+        }
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'b,
+    {
+        &value.bytes
+    }
+
+    fn type_name() -> TypeName {
+        TypeName::new(T::TYPE_NAME)
+    }
+}
+
+/// Values that are also multimap table values are ordered by their decoded form, values
+/// that can't be decoded by their bytes.
+pub trait VersionedKey: Versioned {
+    fn compare(value1: &Self, value2: &Self) -> Ordering;
+}
+
+impl<T: VersionedKey> Key for Encoded<T> {
+    fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
+        match (
+            Self::from_bytes(data1).decode(),
+            Self::from_bytes(data2).decode(),
+        ) {
+            (Ok(value1), Ok(value2)) => T::compare(&value1, &value2),
+            _ => data1.cmp(data2),
+        }
+    }
+}
+
+/// An undecodable record moved out of its table.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct QuarantinedRecord {
+    pub table: String,
+    /// Hex encoded key bytes.
+    pub key: String,
+    pub bytes: Vec<u8>,
+    pub error: String,
+    pub quarantined: DateTime<Utc>,
+}
+
+impl Versioned for QuarantinedRecord {
+    const TYPE_NAME: &'static str = "nonce_guess::QuarantinedRecord";
+}
+
+/// Copy the quarantined records into another database.
+pub fn copy_quarantine_table(
+    read_txn: &ReadTransaction,
+    write_txn: &WriteTransaction,
+) -> Result<(), InternalError> {
+    copy_table(read_txn, write_txn, KEY_QUARANTINE)
+}
+
+// why a value can't be decoded, values encrypted with an unknown key aren't corrupt
+fn corruption<T: Versioned>(key: &[u8], value: &Encoded<T>) -> Option<DecodeError> {
+    value
+        .decode_keyed(key)
+        .err()
+        .filter(DecodeError::is_corrupt)
+}
+
+/// The records of a table that can't be decoded, without moving them.
+pub fn find_undecodable<K: Key + 'static, T: Versioned + 'static>(
+    read_txn: &ReadTransaction,
+    definition: TableDefinition<K, Encoded<T>>,
+) -> Result<Vec<UndecodableRecord>, InternalError> {
+    let table = read_txn.open_table(definition)?;
+    let mut records = Vec::new();
+    for result in table.iter()? {
+        let (key, value) = result?;
+        let key_bytes = K::as_bytes(&key.value()).as_ref().to_vec();
+        if let Some(e) = corruption(&key_bytes, &value.value()) {
+            records.push(UndecodableRecord {
+                table: definition.name().to_string(),
+                key: hex::encode(key_bytes),
+                error: e.to_string(),
+            });
+        }
+    }
+    Ok(records)
+}
+
+/// The values of a multimap table that can't be decoded, without moving them.
+pub fn find_undecodable_multimap<K: Key + 'static, T: VersionedKey + 'static>(
+    read_txn: &ReadTransaction,
+    definition: MultimapTableDefinition<K, Encoded<T>>,
+) -> Result<Vec<UndecodableRecord>, InternalError> {
+    let table = read_txn.open_multimap_table(definition)?;
+    let mut records = Vec::new();
+    for result in table.iter()? {
+        let (key, values) = result?;
+        let key_bytes = K::as_bytes(&key.value()).as_ref().to_vec();
+        for value in values {
+            if let Some(e) = corruption(&key_bytes, &value?.value()) {
+                records.push(UndecodableRecord {
+                    table: definition.name().to_string(),
+                    key: hex::encode(&key_bytes),
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Move the records of a table that can't be decoded to the quarantine table, so the rest of
+/// the table stays usable. Each record is logged and reported in the audit log.
+pub fn quarantine_table<K: Key + 'static, T: Versioned + 'static>(
+    write_txn: &mut WriteTransaction,
+    definition: TableDefinition<K, Encoded<T>>,
+) -> Result<usize, InternalError> {
+    let records = {
+        let mut table = write_txn.open_table(definition)?;
+        let mut records = Vec::new();
+        let is_corrupt = |key: K::SelfType<'_>, value: Encoded<T>| {
+            corruption(K::as_bytes(&key).as_ref(), &value).is_some()
+        };
+        for result in table.extract_if(is_corrupt)? {
+            let (key, value) = result?;
+            let key_bytes = K::as_bytes(&key.value()).as_ref().to_vec();
+            let value = value.value();
+            records.push(QuarantinedRecord {
+                table: definition.name().to_string(),
+                key: hex::encode(&key_bytes),
+                error: corruption(&key_bytes, &value)
+                    .map(|e| e.to_string())
+                    .unwrap_or_default(),
+                bytes: value.bytes,
+                quarantined: Utc::now(),
+            });
+        }
+        records
+    };
+    insert_quarantined(write_txn, &records)?;
+    Ok(records.len())
+}
+
+/// Move the values of a multimap table that can't be decoded to the quarantine table.
+pub fn quarantine_multimap_table<K: Key + 'static, T: VersionedKey + 'static>(
+    write_txn: &mut WriteTransaction,
+    definition: MultimapTableDefinition<K, Encoded<T>>,
+) -> Result<usize, InternalError> {
+    let records = {
+        let mut table = write_txn.open_multimap_table(definition)?;
+        // corrupt values can't be found by comparing them with the others, so the values of a
+        // key with a corrupt value are all removed and the decodable ones inserted again
+        let mut corrupt_keys = Vec::new();
+        for result in table.iter()? {
+            let (key, values) = result?;
+            let key_bytes = K::as_bytes(&key.value()).as_ref().to_vec();
+            for value in values {
+                if corruption(&key_bytes, &value?.value()).is_some() {
+                    corrupt_keys.push(key_bytes);
+                    break;
+                }
+            }
+        }
+        let mut records = Vec::new();
+        for key_bytes in &corrupt_keys {
+            let key = K::from_bytes(key_bytes);
+            let mut valid = Vec::new();
+            for value in table.remove_all(&key)? {
+                let value = value?.value();
+                match corruption(key_bytes, &value) {
+                    None => valid.push(value),
+                    Some(e) => records.push(QuarantinedRecord {
+                        table: definition.name().to_string(),
+                        key: hex::encode(key_bytes),
+                        bytes: value.bytes,
+                        error: e.to_string(),
+                        quarantined: Utc::now(),
+                    }),
+                }
+            }
+            for value in &valid {
+                table.insert(&key, value)?;
+            }
+        }
+        records
+    };
+    insert_quarantined(write_txn, &records)?;
+    Ok(records.len())
+}
+
+/// How far a re-encryption pass got. Each batch continues after the last record the previous
+/// one processed, so every record is looked at once.
+#[derive(Debug, Default)]
+pub struct ReencryptProgress {
+    // key bytes of the last processed record by table name, `None` once the table is done
+    tables: HashMap<String, Option<Vec<u8>>>,
+    /// The values written so far.
+    pub reencrypted: usize,
+}
+
+/// Encode up to `limit` values of a table again that aren't stored the way the keyring would
+/// store them now, sealing them with its primary key or decrypting them without one. Returns
+/// the number of stale values processed, values that can't be decoded are logged and left as
+/// they are.
+pub fn reencrypt_table<K: Key + 'static, T: Versioned + 'static>(
+    write_txn: &WriteTransaction,
+    definition: TableDefinition<K, Encoded<T>>,
+    keyring: Option<&Keyring>,
+    progress: &mut ReencryptProgress,
+    limit: usize,
+) -> Result<usize, InternalError> {
+    if !T::ENCRYPTED || limit == 0 {
+        return Ok(0);
+    }
+    let after = match progress.tables.get(definition.name()) {
+        Some(None) => return Ok(0),
+        Some(Some(key_bytes)) => Some(key_bytes.clone()),
+        None => None,
+    };
+    let mut table = write_txn.open_table(definition)?;
+    let mut stale = Vec::new();
+    let mut processed = 0;
+    let mut last_key = after.clone();
+    let mut done = true;
+    let start = match &after {
+        Some(key_bytes) => Bound::Excluded(K::from_bytes(key_bytes)),
+        None => Bound::Unbounded,
+    };
+    for result in table.range::<K::SelfType<'_>>((start, Bound::Unbounded))? {
+        if processed == limit {
+            done = false;
+            break;
+        }
+        let (key, value) = result?;
+        let key_bytes = K::as_bytes(&key.value()).as_ref().to_vec();
+        last_key = Some(key_bytes.clone());
+        let value = value.value();
+        if value.is_current(keyring) {
+            continue;
+        }
+        processed += 1;
+        match value.decode_with(&key_bytes, keyring) {
+            Ok(decoded) => stale.push((key_bytes, decoded)),
+            Err(e) => warn!(
+                "can't re-encrypt {} {}: {}",
+                definition.name(),
+                hex::encode(key_bytes),
+                e
+            ),
+        }
+    }
+    for (key_bytes, value) in &stale {
+        table.insert(
+            K::from_bytes(key_bytes),
+            Encoded::with_keyring(value, key_bytes, keyring),
+        )?;
+    }
+    progress.tables.insert(
+        definition.name().to_string(),
+        if done { None } else { last_key },
+    );
+    progress.reencrypted += stale.len();
+    Ok(processed)
+}
+
+fn insert_quarantined(
+    write_txn: &mut WriteTransaction,
+    records: &[QuarantinedRecord],
+) -> Result<(), InternalError> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    {
+        let mut key_quarantine = write_txn.open_table(KEY_QUARANTINE)?;
+        for record in records {
+            warn!(
+                "quarantined undecodable record {} {}: {}",
+                record.table, record.key, record.error
+            );
+            key_quarantine.insert(
+                format!("{}/{}/{}", record.table, record.key, record.quarantined),
+                &Encoded::new(record),
+            )?;
+        }
+    }
+    let events = records
+        .iter()
+        .map(|record| AuditEvent {
+            subject: Some(format!("{} {}", record.table, record.key)),
+            after: Some(record.error.clone()),
+            ..AuditEvent::new(AuditAction::RecordQuarantine, None)
+        })
+        .collect::<Vec<AuditEvent>>();
+    write_txn.record_events(&events)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        decode_cbor, quarantine_multimap_table, quarantine_table, reencrypt_table, DecodeError,
+        Encoded, ReencryptProgress, Versioned, ENVELOPE_MARKER, KEY_QUARANTINE,
+    };
+    use crate::audit::backend::AuditLog;
+    use crate::audit::types::{AuditAction, AuditFilter};
+    use crate::auth::types::Player;
+    use crate::encryption::{Keyring, MasterKey, SEALED_MARKER};
+    use crate::guess::types::Guess;
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use redb::{Database, MultimapTableDefinition, ReadableTable, TableDefinition};
+    use serde::{Deserialize, Serialize};
+    use std::marker::PhantomData;
+    use std::sync::Arc;
+    use tempfile::NamedTempFile;
+    use uuid::Uuid;
+
+    const NAME_PLAYER: TableDefinition<&str, Encoded<Player>> =
+        TableDefinition::new("test_name_player");
+    const HEIGHT_GUESSES: MultimapTableDefinition<u32, Encoded<Guess>> =
+        MultimapTableDefinition::new("test_height_guesses");
+
+    // version 1 stored the score as a string
+    #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+    struct Score {
+        points: u32,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ScoreV1 {
+        points: String,
+    }
+
+    impl Versioned for Score {
+        const TYPE_NAME: &'static str = "test::Score";
+        const VERSION: u8 = 2;
+
+        fn decode_version(version: u8, payload: &[u8]) -> Result<Self, DecodeError> {
+            match version {
+                1 => {
+                    let score: ScoreV1 = ciborium::from_reader(payload)
+                        .map_err(|e| DecodeError::Invalid(Self::TYPE_NAME, e.to_string()))?;
+                    let points = score
+                        .points
+                        .parse()
+                        .map_err(|_| DecodeError::Invalid(Self::TYPE_NAME, score.points))?;
+                    Ok(Score { points })
+                }
+                _ => decode_cbor(payload),
+            }
+        }
+    }
+
+    fn encoded<T>(bytes: Vec<u8>) -> Encoded<T> {
+        Encoded {
+            bytes,
+            value_type: PhantomData,
+        }
+    }
+
+    fn cbor(value: &impl Serialize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn temp_db() -> Arc<Database> {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        Arc::new(Database::create(file).unwrap())
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let score = Score { points: 42 };
+        let encoded_score = Encoded::new(&score);
+        assert_eq!(encoded_score.bytes[..2], [ENVELOPE_MARKER, 2]);
+        assert_eq!(encoded_score.decode(), Ok(score.clone()));
+
+        // older versions and values stored before the envelope
+        let mut v1 = vec![ENVELOPE_MARKER, 1];
+        v1.extend(cbor(&ScoreV1 {
+            points: "42".to_string(),
+        }));
+        assert_eq!(encoded::<Score>(v1).decode(), Ok(score.clone()));
+        assert_eq!(encoded::<Score>(cbor(&score)).decode(), Ok(score));
+
+        // newer and corrupt values are errors instead of panics
+        let mut v3 = vec![ENVELOPE_MARKER, 3];
+        v3.extend(cbor(&Score { points: 42 }));
+        assert_eq!(
+            encoded::<Score>(v3).decode(),
+            Err(DecodeError::NewerVersion("test::Score", 3, 2))
+        );
+        assert!(matches!(
+            encoded::<Score>(vec![ENVELOPE_MARKER]).decode(),
+            Err(DecodeError::Invalid("test::Score", _))
+        ));
+        assert!(matches!(
+            encoded::<Score>(vec![0x01, 0x02, 0x03]).decode(),
+            Err(DecodeError::Invalid("test::Score", _))
+        ));
+    }
+
+    #[test]
+    fn test_decode_unknown_fields() {
+        // a player stored by a newer server with an extra field
+        #[derive(Serialize)]
+        struct NewerPlayer {
+            #[serde(flatten)]
+            player: Player,
+            nickname: String,
+        }
+        let player = Player {
+            uuid: Uuid::new_v4(),
+            name: "alice".to_string(),
+            ..Default::default()
+        };
+        let newer_player = NewerPlayer {
+            player: player.clone(),
+            nickname: "al".to_string(),
+        };
+        assert_eq!(
+            encoded::<Player>(cbor(&newer_player)).decode_keyed(b"alice"),
+            Ok(player)
+        );
+    }
+
+    fn keyring(primary: Option<u8>, old: &[u8]) -> Keyring {
+        let key = |byte| MasterKey::from_base64(&BASE64_STANDARD.encode([byte; 32])).unwrap();
+        Keyring::new(primary.map(key), old.iter().copied().map(key).collect())
+    }
+
+    fn alice() -> Player {
+        Player {
+            uuid: Uuid::from_u128(1),
+            name: "alice".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let player = alice();
+        let primary = keyring(Some(1), &[]);
+        let sealed = Encoded::with_keyring(&player, b"alice", Some(&primary));
+        assert_eq!(sealed.bytes[0], SEALED_MARKER);
+        assert_eq!(
+            sealed.decode_with(b"alice", Some(&primary)),
+            Ok(player.clone())
+        );
+        assert!(sealed.is_current(Some(&primary)));
+        assert!(!sealed.is_current(None));
+        // a sealed value moved to another record doesn't open there
+        assert!(matches!(
+            sealed.decode_with(b"mallory", Some(&primary)),
+            Err(DecodeError::Invalid("nonce_guess::Player", _))
+        ));
+        // a sealed value without its key isn't corrupt
+        let e = sealed.decode_with(b"alice", None).unwrap_err();
+        assert!(matches!(
+            e,
+            DecodeError::UnknownKey("nonce_guess::Player", _)
+        ));
+        assert!(!e.is_corrupt());
+        assert!(matches!(
+            sealed.decode_with(b"alice", Some(&keyring(Some(2), &[]))),
+            Err(DecodeError::UnknownKey(..))
+        ));
+        // types that aren't encrypted are stored in plain
+        let guess = Guess {
+            player: player.uuid,
+            nonce: 1,
+        };
+        let plain = Encoded::with_keyring(&guess, b"1", Some(&primary));
+        assert_eq!(plain.bytes[0], ENVELOPE_MARKER);
+        assert!(plain.is_current(Some(&primary)));
+    }
+
+    #[test]
+    fn test_reencrypt_table() {
+        let player = alice();
+        let db = temp_db();
+        let (old, rotated) = (keyring(Some(1), &[]), keyring(Some(2), &[1]));
+        let write_txn = db.begin_write().unwrap();
+        {
+            let mut name_player = write_txn.open_table(NAME_PLAYER).unwrap();
+            for name in ["alice", "bob", "carol"] {
+                name_player
+                    .insert(
+                        name,
+                        Encoded::with_keyring(&player, name.as_bytes(), Some(&old)),
+                    )
+                    .unwrap();
+            }
+            name_player
+                .insert("dave", Encoded::keyed(b"dave", &player))
+                .unwrap();
+        }
+        // values are re-encrypted in batches until none are left
+        let mut progress = ReencryptProgress::default();
+        for expected in [3, 1, 0] {
+            assert_eq!(
+                reencrypt_table(&write_txn, NAME_PLAYER, Some(&rotated), &mut progress, 3).unwrap(),
+                expected
+            );
+        }
+        assert_eq!(progress.reencrypted, 4);
+        {
+            let name_player = write_txn.open_table(NAME_PLAYER).unwrap();
+            for result in name_player.iter().unwrap() {
+                let (key, value) = result.unwrap();
+                let value = value.value();
+                assert!(value.is_current(Some(&keyring(Some(2), &[]))));
+                assert_eq!(
+                    value.decode_with(key.value().as_bytes(), Some(&rotated)),
+                    Ok(player.clone())
+                );
+            }
+        }
+        // values without a known key are left as they are, and aren't quarantined or looked at
+        // again by the next batch
+        let mut progress = ReencryptProgress::default();
+        for expected in [2, 2, 0] {
+            assert_eq!(
+                reencrypt_table(&write_txn, NAME_PLAYER, Some(&old), &mut progress, 2).unwrap(),
+                expected
+            );
+        }
+        assert_eq!(progress.reencrypted, 0);
+        // without a primary key the values are decrypted
+        let decrypting = keyring(None, &[2]);
+        let mut progress = ReencryptProgress::default();
+        assert_eq!(
+            reencrypt_table(
+                &write_txn,
+                NAME_PLAYER,
+                Some(&decrypting),
+                &mut progress,
+                10
+            )
+            .unwrap(),
+            4
+        );
+        assert_eq!(progress.reencrypted, 4);
+        let name_player = write_txn.open_table(NAME_PLAYER).unwrap();
+        for result in name_player.iter().unwrap() {
+            let (key, value) = result.unwrap();
+            assert_eq!(
+                value.value().decode_keyed(key.value().as_bytes()),
+                Ok(player.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn test_quarantine_unknown_key() {
+        let player = alice();
+        let db = temp_db();
+        let mut write_txn = db.begin_write().unwrap();
+        write_txn
+            .open_table(NAME_PLAYER)
+            .unwrap()
+            .insert(
+                "alice",
+                Encoded::with_keyring(&player, b"alice", Some(&keyring(Some(1), &[]))),
+            )
+            .unwrap();
+        assert_eq!(quarantine_table(&mut write_txn, NAME_PLAYER).unwrap(), 0);
+        assert!(write_txn
+            .open_table(NAME_PLAYER)
+            .unwrap()
+            .get("alice")
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_table() {
+        let db = temp_db();
+        let player = Player {
+            uuid: Uuid::new_v4(),
+            name: "alice".to_string(),
+            ..Default::default()
+        };
+        let mut write_txn = db.begin_write().unwrap();
+        {
+            let mut name_player = write_txn.open_table(NAME_PLAYER).unwrap();
+            name_player
+                .insert("alice", Encoded::keyed(b"alice", &player))
+                .unwrap();
+            name_player
+                .insert("bob", encoded(vec![ENVELOPE_MARKER, 1, 0xff]))
+                .unwrap();
+        }
+        assert_eq!(quarantine_table(&mut write_txn, NAME_PLAYER).unwrap(), 1);
+        assert_eq!(quarantine_table(&mut write_txn, NAME_PLAYER).unwrap(), 0);
+        write_txn.commit().unwrap();
+
+        let read_txn = db.begin_read().unwrap();
+        let name_player = read_txn.open_table(NAME_PLAYER).unwrap();
+        assert_eq!(
+            name_player
+                .get("alice")
+                .unwrap()
+                .unwrap()
+                .value()
+                .decode_keyed(b"alice"),
+            Ok(player)
+        );
+        assert!(name_player.get("bob").unwrap().is_none());
+        let key_quarantine = read_txn.open_table(KEY_QUARANTINE).unwrap();
+        let records = key_quarantine
+            .iter()
+            .unwrap()
+            .map(|result| result.unwrap().1.value().decode().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].table, "test_name_player");
+        assert_eq!(records[0].key, hex::encode("bob"));
+        assert_eq!(records[0].bytes, vec![ENVELOPE_MARKER, 1, 0xff]);
+        let events = AuditLog::new(db)
+            .unwrap()
+            .get_events(AuditFilter {
+                action: Some(AuditAction::RecordQuarantine),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn test_quarantine_multimap_table() {
+        let db = temp_db();
+        let guess1 = Guess {
+            player: Uuid::new_v4(),
+            nonce: 1,
+        };
+        let guess2 = Guess {
+            player: Uuid::new_v4(),
+            nonce: 2,
+        };
+        let mut write_txn = db.begin_write().unwrap();
+        {
+            let mut height_guesses = write_txn.open_multimap_table(HEIGHT_GUESSES).unwrap();
+            height_guesses.insert(1, Encoded::new(&guess1)).unwrap();
+            height_guesses.insert(1, Encoded::new(&guess2)).unwrap();
+            height_guesses.insert(1, encoded(vec![0xf7])).unwrap();
+            height_guesses.insert(2, Encoded::new(&guess1)).unwrap();
+        }
+        assert_eq!(
+            quarantine_multimap_table(&mut write_txn, HEIGHT_GUESSES).unwrap(),
+            1
+        );
+        write_txn.commit().unwrap();
+
+        let read_txn = db.begin_read().unwrap();
+        let height_guesses = read_txn.open_multimap_table(HEIGHT_GUESSES).unwrap();
+        let guesses = |height| {
+            height_guesses
+                .get(height)
+                .unwrap()
+                .map(|result| result.unwrap().value().decode().unwrap())
+                .collect::<Vec<Guess>>()
+        };
+        assert_eq!(guesses(1), vec![guess1.clone(), guess2]);
+        assert_eq!(guesses(2), vec![guess1]);
+    }
+}
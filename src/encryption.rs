@@ -0,0 +1,309 @@
+use crate::auth::config::env_secret;
+use crate::encoding::ReencryptProgress;
+use crate::storage::Storage;
+use crate::types::InternalError;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, OnceLock};
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+// first byte of a sealed value. 0xfe is never the first byte of a CBOR data item or of the
+// encoding envelope, so sealed and plain values can be told apart.
+pub const SEALED_MARKER: u8 = 0xfe;
+
+// records re-encrypted per write transaction, so other writes aren't held up for long
+const REENCRYPT_BATCH_SIZE: usize = 100;
+
+const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+// marker, master key id, data key nonce and encrypted data key, data nonce
+const HEADER_LEN: usize = 1 + KEY_ID_LEN + NONCE_LEN + KEY_LEN + TAG_LEN + NONCE_LEN;
+
+#[derive(thiserror::Error, Debug, Clone, Eq, PartialEq)]
+pub enum EncryptionError {
+    #[error("invalid encryption key, it must be 32 base64 encoded bytes")]
+    InvalidKey,
+    #[error("encrypted with unknown key {0}")]
+    UnknownKey(String),
+    #[error("decryption failed")]
+    Decrypt,
+    #[error("the encryption keys can only be set once")]
+    AlreadyInstalled,
+}
+
+/// A key encryption key, the random data key of each sealed value is encrypted with it.
+pub struct MasterKey {
+    id: [u8; KEY_ID_LEN],
+    key: LessSafeKey,
+}
+
+impl MasterKey {
+    /// A key from 32 base64 encoded bytes, as generated by `openssl rand -base64 32`.
+    pub fn from_base64(encoded: &str) -> Result<Self, EncryptionError> {
+        let bytes = BASE64_STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| EncryptionError::InvalidKey)?;
+        if bytes.len() != KEY_LEN {
+            return Err(EncryptionError::InvalidKey);
+        }
+        // the key id is stored with each sealed value, it identifies the key without
+        // revealing it
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&digest(&SHA256, &bytes).as_ref()[..KEY_ID_LEN]);
+        Ok(Self {
+            id,
+            key: aead_key(&bytes),
+        })
+    }
+
+    pub fn id(&self) -> String {
+        hex::encode(self.id)
+    }
+}
+
+// never show the key itself
+impl Debug for MasterKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MasterKey({})", self.id())
+    }
+}
+
+/// The master keys values are sealed with. New values are sealed with the primary key, the old
+/// keys only open values until they are re-encrypted. Without a primary key values are
+/// written in plain and sealed values are decrypted when re-encrypted.
+#[derive(Debug, Default)]
+pub struct Keyring {
+    primary: Option<MasterKey>,
+    old: Vec<MasterKey>,
+}
+
+impl Keyring {
+    pub fn new(primary: Option<MasterKey>, old: Vec<MasterKey>) -> Self {
+        Self { primary, old }
+    }
+
+    /// The keys of `NONCE_GUESS_ENCRYPTION_KEY` and the comma separated
+    /// `NONCE_GUESS_ENCRYPTION_OLD_KEYS`, or their `_FILE` variants. `None` if neither is set.
+    pub fn from_env() -> Result<Option<Self>, EncryptionError> {
+        let primary = env_secret("NONCE_GUESS_ENCRYPTION_KEY")
+            .map(|key| MasterKey::from_base64(&key))
+            .transpose()?;
+        let old = env_secret("NONCE_GUESS_ENCRYPTION_OLD_KEYS")
+            .unwrap_or_default()
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|key| !key.is_empty())
+            .map(MasterKey::from_base64)
+            .collect::<Result<Vec<MasterKey>, EncryptionError>>()?;
+        if primary.is_none() && old.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self::new(primary, old)))
+    }
+
+    /// Whether a value is stored the way the primary key would store it now, sealed with it or
+    /// in plain without a primary key.
+    pub fn is_current(&self, bytes: &[u8]) -> bool {
+        sealed_key_id(bytes) == self.primary.as_ref().map(|key| key.id)
+    }
+
+    /// Seal a value with a new data key encrypted with the primary key, `None` without a
+    /// primary key. The `aad` must be given again to open it.
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Option<Vec<u8>> {
+        let primary = self.primary.as_ref()?;
+        let rng = SystemRandom::new();
+        let mut data_key = [0; KEY_LEN];
+        rng.fill(&mut data_key)
+            .expect("Failed to generate data key");
+        let mut sealed = Vec::with_capacity(HEADER_LEN + plaintext.len() + TAG_LEN);
+        sealed.push(SEALED_MARKER);
+        sealed.extend_from_slice(&primary.id);
+
+        let key_nonce = random_nonce(&rng);
+        let mut encrypted_key = data_key.to_vec();
+        primary
+            .key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(key_nonce),
+                Aad::from(primary.id),
+                &mut encrypted_key,
+            )
+            .expect("Failed to encrypt data key");
+        sealed.extend_from_slice(&key_nonce);
+        sealed.extend_from_slice(&encrypted_key);
+
+        let data_nonce = random_nonce(&rng);
+        let mut ciphertext = plaintext.to_vec();
+        aead_key(&data_key)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(data_nonce),
+                Aad::from(aad),
+                &mut ciphertext,
+            )
+            .expect("Failed to encrypt value");
+        sealed.extend_from_slice(&data_nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Some(sealed)
+    }
+
+    /// Open a sealed value with the master key it was sealed with.
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let Some(key_id) = sealed_key_id(sealed) else {
+            return Err(EncryptionError::Decrypt);
+        };
+        let master_key = self
+            .primary
+            .iter()
+            .chain(&self.old)
+            .find(|key| key.id == key_id)
+            .ok_or(EncryptionError::UnknownKey(hex::encode(key_id)))?;
+        if sealed.len() < HEADER_LEN + TAG_LEN {
+            return Err(EncryptionError::Decrypt);
+        }
+        let (key_nonce, rest) = sealed[1 + KEY_ID_LEN..].split_at(NONCE_LEN);
+        let (encrypted_key, rest) = rest.split_at(KEY_LEN + TAG_LEN);
+        let (data_nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let mut data_key = encrypted_key.to_vec();
+        let data_key = master_key
+            .key
+            .open_in_place(nonce(key_nonce)?, Aad::from(key_id), &mut data_key)
+            .map_err(|_| EncryptionError::Decrypt)?;
+        let mut plaintext = ciphertext.to_vec();
+        let len = aead_key(data_key)
+            .open_in_place(nonce(data_nonce)?, Aad::from(aad), &mut plaintext)
+            .map_err(|_| EncryptionError::Decrypt)?
+            .len();
+        plaintext.truncate(len);
+        Ok(plaintext)
+    }
+}
+
+/// The id of the master key a sealed value's data key is encrypted with, `None` for a value
+/// that isn't sealed.
+pub fn sealed_key_id(bytes: &[u8]) -> Option<[u8; KEY_ID_LEN]> {
+    match bytes {
+        [SEALED_MARKER, rest @ ..] if rest.len() >= KEY_ID_LEN => {
+            let mut id = [0; KEY_ID_LEN];
+            id.copy_from_slice(&rest[..KEY_ID_LEN]);
+            Some(id)
+        }
+        _ => None,
+    }
+}
+
+fn aead_key(bytes: &[u8]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, bytes).expect("key has the AES-256 length"))
+}
+
+fn random_nonce(rng: &SystemRandom) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    rng.fill(&mut nonce).expect("Failed to generate nonce");
+    nonce
+}
+
+fn nonce(bytes: &[u8]) -> Result<Nonce, EncryptionError> {
+    Nonce::try_assume_unique_for_key(bytes).map_err(|_| EncryptionError::Decrypt)
+}
+
+// redb values are encoded and decoded without access to the storage, so the keyring is set
+// once for the whole process at startup
+static KEYRING: OnceLock<Keyring> = OnceLock::new();
+
+/// Seal the values of encrypted types with the keyring from now on.
+pub fn install(keyring: Keyring) -> Result<(), EncryptionError> {
+    KEYRING
+        .set(keyring)
+        .map_err(|_| EncryptionError::AlreadyInstalled)
+}
+
+/// The installed keyring, `None` if encryption at rest is not configured.
+pub fn keyring() -> Option<&'static Keyring> {
+    KEYRING.get()
+}
+
+/// Re-encrypt the records that aren't sealed with the primary key in batches, after the keys
+/// were rotated or encryption at rest was turned on or off.
+pub async fn reencrypt_stale_records(storage: Arc<dyn Storage>) -> Result<(), InternalError> {
+    let mut progress = ReencryptProgress::default();
+    loop {
+        let storage = storage.clone();
+        let count;
+        (count, progress) = spawn_blocking(move || {
+            let count = storage.reencrypt_batch(&mut progress, REENCRYPT_BATCH_SIZE)?;
+            Ok::<_, InternalError>((count, progress))
+        })
+        .await??;
+        if count == 0 {
+            break;
+        }
+    }
+    if progress.reencrypted > 0 {
+        info!("re-encrypted {} records", progress.reencrypted);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sealed_key_id, EncryptionError, Keyring, MasterKey};
+
+    pub(crate) fn master_key(byte: u8) -> MasterKey {
+        use base64::prelude::{Engine, BASE64_STANDARD};
+        MasterKey::from_base64(&BASE64_STANDARD.encode([byte; 32])).unwrap()
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let keyring = Keyring::new(Some(master_key(1)), vec![]);
+        let sealed = keyring.seal(b"type", b"secret value").unwrap();
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+        assert_eq!(keyring.open(b"type", &sealed).unwrap(), b"secret value");
+        // a sealed value only opens as the type it was sealed as
+        assert_eq!(
+            keyring.open(b"other", &sealed),
+            Err(EncryptionError::Decrypt)
+        );
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            keyring.open(b"type", &tampered),
+            Err(EncryptionError::Decrypt)
+        );
+        assert!(keyring.is_current(&sealed));
+        assert!(!keyring.is_current(b"plain"));
+        assert!(MasterKey::from_base64("c2hvcnQ=").is_err());
+    }
+
+    #[test]
+    fn test_rotate() {
+        let old = Keyring::new(Some(master_key(1)), vec![]);
+        let sealed = old.seal(b"type", b"value").unwrap();
+        let unknown = Keyring::new(Some(master_key(2)), vec![]);
+        assert_eq!(
+            unknown.open(b"type", &sealed),
+            Err(EncryptionError::UnknownKey(master_key(1).id()))
+        );
+
+        // the old key still opens values sealed with it, new values use the primary key
+        let rotated = Keyring::new(Some(master_key(2)), vec![master_key(1)]);
+        assert_eq!(rotated.open(b"type", &sealed).unwrap(), b"value");
+        assert!(!rotated.is_current(&sealed));
+        let resealed = rotated.seal(b"type", b"value").unwrap();
+        assert!(rotated.is_current(&resealed));
+        assert_eq!(
+            sealed_key_id(&resealed).map(hex::encode),
+            Some(master_key(2).id())
+        );
+
+        // without a primary key values are stored in plain
+        let decrypting = Keyring::new(None, vec![master_key(1)]);
+        assert_eq!(decrypting.seal(b"type", b"value"), None);
+        assert!(decrypting.is_current(b"plain"));
+        assert!(!decrypting.is_current(&sealed));
+    }
+}
@@ -0,0 +1,551 @@
+use crate::audit::types::{AuditAction, AuditEvent};
+use crate::auth::types::{name_key, player_name_candidates, Player};
+use crate::guess::replay::ReplayedTargets;
+use crate::guess::types::DELETED_PLAYER;
+use crate::storage::{ReadTxn, Storage, UndecodableRecord, WriteTxn};
+use crate::types::InternalError;
+use chrono::Utc;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Whether a database check only reports the problems it finds.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FsckMode {
+    Check,
+    /// Also repair the problems: stale name index rows are removed and missing ones added,
+    /// renaming players whose name is taken. Targets and guesses are rebuilt from the guess
+    /// event log if they differ from it. Guesses of unknown players are kept as deleted
+    /// players' guesses, unknown roles are removed from players and undecodable records are
+    /// quarantined.
+    Repair,
+}
+
+impl Display for FsckMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsckMode::Check => write!(f, "check"),
+            FsckMode::Repair => write!(f, "repair"),
+        }
+    }
+}
+
+impl FromStr for FsckMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "check" => Ok(FsckMode::Check),
+            "repair" => Ok(FsckMode::Repair),
+            _ => Err(format!("unknown check mode: {}", s)),
+        }
+    }
+}
+
+/// An inconsistency between the stored records.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Problem {
+    /// A name index row of a player that doesn't exist, or whose name has another key.
+    OrphanName {
+        key: String,
+        player: Uuid,
+    },
+    /// A player whose name has no index row pointing at them.
+    MissingName {
+        player: Uuid,
+        name: String,
+    },
+    /// A guess of a player that doesn't exist.
+    UnknownGuessPlayer {
+        height: u32,
+        nonce: u32,
+        player: Uuid,
+    },
+    /// A player's role that doesn't exist.
+    UnknownRole {
+        player: Uuid,
+        role: Uuid,
+    },
+    /// A target or its guesses differ from the replayed guess event log.
+    GuessLogMismatch {
+        height: u32,
+    },
+    Undecodable(UndecodableRecord),
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::OrphanName { key, player } => {
+                write!(f, "name {} is indexed for player {}", key, player)
+            }
+            Problem::MissingName { player, name } => {
+                write!(f, "name {} of player {} is not indexed", name, player)
+            }
+            Problem::UnknownGuessPlayer {
+                height,
+                nonce,
+                player,
+            } => write!(
+                f,
+                "guess {:08x} for target {} is of unknown player {}",
+                nonce, height, player
+            ),
+            Problem::UnknownRole { player, role } => {
+                write!(f, "player {} has unknown role {}", player, role)
+            }
+            Problem::GuessLogMismatch { height } => {
+                write!(f, "target {} differs from the guess event log", height)
+            }
+            Problem::Undecodable(record) => write!(
+                f,
+                "record {} {} can't be decoded: {}",
+                record.table, record.key, record.error
+            ),
+        }
+    }
+}
+
+/// The problems a database check found.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FsckReport {
+    pub mode: FsckMode,
+    pub problems: Vec<Problem>,
+}
+
+impl Display for FsckReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.problems.is_empty() {
+            return write!(f, "no problems found");
+        }
+        write!(
+            f,
+            "{} problems found{}",
+            self.problems.len(),
+            if self.mode == FsckMode::Repair {
+                " and repaired"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+/// Database checks requested by an admin.
+#[derive(Debug)]
+pub struct Fsck {
+    storage: Arc<dyn Storage>,
+}
+
+impl Fsck {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    /// Check the database for an admin, a repair is recorded in the audit log.
+    pub async fn admin_fsck(
+        &self,
+        mode: FsckMode,
+        admin: &Player,
+    ) -> Result<FsckReport, InternalError> {
+        let storage = self.storage.clone();
+        let admin = admin.clone();
+        spawn_blocking(move || fsck(storage.as_ref(), mode, Some(&admin))).await?
+    }
+}
+
+/// Check the records for inconsistencies, and repair them in one write transaction in
+/// [`FsckMode::Repair`]. Each problem is logged. The check runs before the write transaction,
+/// so each problem is checked again before it is repaired and records changed since are kept.
+pub fn fsck(
+    storage: &dyn Storage,
+    mode: FsckMode,
+    actor: Option<&Player>,
+) -> Result<FsckReport, InternalError> {
+    let repair = mode == FsckMode::Repair;
+    // undecodable records are quarantined first, so a repair only reads records that decode
+    let mut problems = storage
+        .undecodable_records(repair)?
+        .into_iter()
+        .map(Problem::Undecodable)
+        .collect::<Vec<Problem>>();
+    problems.extend(check(storage.begin_read()?.as_ref())?);
+    for problem in &problems {
+        warn!("{}", problem);
+    }
+    let report = FsckReport { mode, problems };
+    if repair && !report.problems.is_empty() {
+        let mut write_txn = storage.begin_write()?;
+        fix(write_txn.as_mut(), &report.problems)?;
+        let event = AuditEvent {
+            after: Some(report.to_string()),
+            ..AuditEvent::new(AuditAction::DatabaseRepair, actor)
+        };
+        write_txn.record_events(&[event])?;
+        write_txn.commit()?;
+    }
+    info!("database {}: {}", mode, report);
+    Ok(report)
+}
+
+// records that can't be decoded are reported on their own, the other checks skip them
+fn decoded<T>(result: Result<T, InternalError>) -> Result<Option<T>, InternalError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(InternalError::Decode(_) | InternalError::Json(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn check(read_txn: &dyn ReadTxn) -> Result<Vec<Problem>, InternalError> {
+    let mut players = Vec::new();
+    for result in read_txn.iter_players()? {
+        players.extend(decoded(result)?);
+    }
+    let mut role_uuids = HashSet::new();
+    for result in read_txn.iter_roles()? {
+        role_uuids.extend(decoded(result)?.map(|role| role.uuid));
+    }
+    let names = read_txn.get_player_names()?;
+    let player_keys = players
+        .iter()
+        .map(|player| (player.uuid, name_key(&player.name)))
+        .collect::<HashMap<Uuid, String>>();
+
+    let mut problems = Vec::new();
+    for (key, uuid) in &names {
+        if player_keys.get(uuid) != Some(key) {
+            problems.push(Problem::OrphanName {
+                key: key.clone(),
+                player: *uuid,
+            });
+        }
+    }
+    let names = names.into_iter().collect::<HashSet<(String, Uuid)>>();
+    for player in &players {
+        if !names.contains(&(name_key(&player.name), player.uuid)) {
+            problems.push(Problem::MissingName {
+                player: player.uuid,
+                name: player.name.clone(),
+            });
+        }
+        for role in player.roles.iter().collect::<BTreeSet<&Uuid>>() {
+            if !role_uuids.contains(role) {
+                problems.push(Problem::UnknownRole {
+                    player: player.uuid,
+                    role: *role,
+                });
+            }
+        }
+    }
+    for target in read_txn.iter_targets()? {
+        let (height, _) = target?;
+        let Some(guesses) = decoded(read_txn.target_guesses(height))? else {
+            continue;
+        };
+        for guess in guesses {
+            if guess.player != DELETED_PLAYER && !player_keys.contains_key(&guess.player) {
+                problems.push(Problem::UnknownGuessPlayer {
+                    height,
+                    nonce: guess.nonce,
+                    player: guess.player,
+                });
+            }
+        }
+    }
+    let mut log = Vec::new();
+    for result in read_txn.iter_guess_log()? {
+        log.extend(decoded(result)?);
+    }
+    let replayed = ReplayedTargets::replay(log.iter().map(|entry| &entry.event));
+    let mut heights = replayed.heights();
+    for target in read_txn.iter_targets()? {
+        heights.insert(target?.0);
+    }
+    for height in heights {
+        let Some(guesses) = decoded(read_txn.target_guesses(height))? else {
+            continue;
+        };
+        if read_txn.get_target_nonce(height)? != replayed.nonce(height)
+            || guesses != replayed.guesses(height)
+        {
+            problems.push(Problem::GuessLogMismatch { height });
+        }
+    }
+    Ok(problems)
+}
+
+fn fix(write_txn: &mut dyn WriteTxn, problems: &[Problem]) -> Result<(), InternalError> {
+    // the other repairs change the rebuilt targets and guesses, and are logged themselves
+    if problems
+        .iter()
+        .any(|problem| matches!(problem, Problem::GuessLogMismatch { .. }))
+    {
+        let replayed = write_txn.rebuild_guess_projection()?;
+        warn!(
+            "rebuilt targets and guesses from {} logged events",
+            replayed
+        );
+    }
+    // stale index rows first, so their keys are free for the players missing one
+    for problem in problems {
+        if let Problem::OrphanName { key, player } = problem {
+            if is_orphan_name(write_txn, key, *player)? {
+                write_txn.repair_player_name(key, None)?;
+            }
+        }
+    }
+    for problem in problems {
+        match problem {
+            Problem::MissingName { player, .. } => {
+                let Some(player) = write_txn.get_player_for_update(*player)? else {
+                    continue;
+                };
+                match write_txn.get_name_for_update(&player.name)? {
+                    None => {
+                        write_txn.repair_player_name(&name_key(&player.name), Some(player.uuid))?
+                    }
+                    Some(uuid) if uuid == player.uuid => {}
+                    Some(uuid) => {
+                        let name = write_txn
+                            .available_player_name(&mut player_name_candidates(&player.name))?;
+                        warn!(
+                            "player name {} of {} is taken by {}, renamed to {}",
+                            player.name, player.uuid, uuid, name
+                        );
+                        write_txn.insert_player(Player {
+                            name,
+                            updated: Utc::now(),
+                            ..player
+                        })?;
+                    }
+                }
+            }
+            Problem::UnknownRole { player, role } => {
+                if write_txn.get_role_for_update(*role)?.is_some() {
+                    continue;
+                }
+                let Some(orig_player) = write_txn.get_player_for_update(*player)? else {
+                    continue;
+                };
+                let mut new_player = orig_player.clone();
+                new_player.roles.remove(role);
+                write_txn.change_player(orig_player, new_player)?;
+            }
+            Problem::UnknownGuessPlayer { player, .. } => {
+                if write_txn.get_player_for_update(*player)?.is_some() {
+                    continue;
+                }
+                // kept like the guesses of deleted accounts
+                write_txn.remove_player_guesses(*player, true)?;
+            }
+            Problem::OrphanName { .. }
+            | Problem::GuessLogMismatch { .. }
+            | Problem::Undecodable(_) => {}
+        }
+    }
+    Ok(())
+}
+
+// whether a name index row still points at a player that doesn't have the name, the name may
+// have been registered again since the check
+fn is_orphan_name(
+    write_txn: &dyn WriteTxn,
+    key: &str,
+    player: Uuid,
+) -> Result<bool, InternalError> {
+    if write_txn.get_name_key_for_update(key)? != Some(player) {
+        return Ok(false);
+    }
+    let player = write_txn.get_player_for_update(player)?;
+    Ok(player.is_none_or(|player| name_key(&player.name) != key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fix, fsck, FsckMode, Problem};
+    use crate::audit::types::{AuditAction, AuditFilter};
+    use crate::auth::types::{name_key, Player, Role};
+    use crate::guess::types::{Guess, DELETED_PLAYER};
+    use crate::storage::redb::RedbStorage;
+    use crate::storage::sqlite::SqliteStorage;
+    use crate::storage::Storage;
+    use redb::Database;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use tempfile::NamedTempFile;
+    use uuid::Uuid;
+
+    // alice has an unknown role, bob's name is not indexed, a stale name row, a guess of an
+    // unknown player and a guess missing from the guess event log
+    fn check_fsck(storage: &dyn Storage) -> Vec<Problem> {
+        let role = Uuid::new_v4();
+        let alice = Player {
+            uuid: Uuid::new_v4(),
+            name: "alice".to_string(),
+            roles: HashSet::from([role]),
+            ..Default::default()
+        };
+        let bob = Player {
+            uuid: Uuid::new_v4(),
+            name: "bob".to_string(),
+            ..Default::default()
+        };
+        let (ghost, stranger) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut write_txn = storage.begin_write().unwrap();
+        write_txn.insert_player(alice.clone()).unwrap();
+        write_txn.insert_player(bob.clone()).unwrap();
+        write_txn
+            .repair_player_name(&name_key("bob"), None)
+            .unwrap();
+        write_txn.repair_player_name("ghost", Some(ghost)).unwrap();
+        write_txn.insert_target(100, None).unwrap();
+        let guess = Guess {
+            player: stranger,
+            nonce: 1,
+        };
+        write_txn.insert_guess(100, guess).unwrap();
+        // a guess that isn't in the guess event log
+        let unlogged = Guess {
+            player: alice.uuid,
+            nonce: 9,
+        };
+        write_txn.add_guess(100, unlogged).unwrap();
+        write_txn.commit().unwrap();
+
+        let report = fsck(storage, FsckMode::Check, None).unwrap();
+        let problems = [
+            Problem::OrphanName {
+                key: "ghost".to_string(),
+                player: ghost,
+            },
+            Problem::MissingName {
+                player: bob.uuid,
+                name: "bob".to_string(),
+            },
+            Problem::UnknownRole {
+                player: alice.uuid,
+                role,
+            },
+            Problem::UnknownGuessPlayer {
+                height: 100,
+                nonce: 1,
+                player: stranger,
+            },
+            Problem::GuessLogMismatch { height: 100 },
+        ];
+        for problem in &problems {
+            assert!(report.problems.contains(problem), "{}", problem);
+        }
+        // a check changes nothing
+        assert_eq!(fsck(storage, FsckMode::Check, None).unwrap(), report);
+
+        let repaired = fsck(storage, FsckMode::Repair, None).unwrap();
+        assert_eq!(repaired.problems, report.problems);
+        assert!(fsck(storage, FsckMode::Check, None)
+            .unwrap()
+            .problems
+            .is_empty());
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(read_txn.get_player_by_name("BOB").unwrap(), Some(bob));
+        assert!(read_txn
+            .get_player_by_name("alice")
+            .unwrap()
+            .unwrap()
+            .roles
+            .is_empty());
+        // the unlogged guess is gone
+        let guesses = read_txn.target_guesses(100).unwrap();
+        assert_eq!(guesses.len(), 1);
+        assert_eq!(guesses[0].player, DELETED_PLAYER);
+        let filter = AuditFilter {
+            action: Some(AuditAction::DatabaseRepair),
+            ..Default::default()
+        };
+        assert_eq!(read_txn.get_events(&filter).unwrap().len(), 1);
+        report.problems
+    }
+
+    #[test]
+    fn test_fix_rechecks_problems() {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        let db = Arc::new(Database::create(file).unwrap());
+        let storage = RedbStorage::new(db).unwrap();
+        let role = Role {
+            uuid: Uuid::new_v4(),
+            name: "role".to_string(),
+            permissions: HashSet::new(),
+        };
+        let carol = Player {
+            uuid: Uuid::new_v4(),
+            name: "carol".to_string(),
+            roles: HashSet::from([role.uuid]),
+            ..Default::default()
+        };
+        let mut write_txn = storage.begin_write().unwrap();
+        write_txn.insert_role(role.clone()).unwrap();
+        write_txn.insert_player(carol.clone()).unwrap();
+        write_txn.insert_target(100, None).unwrap();
+        let guess = Guess {
+            player: carol.uuid,
+            nonce: 1,
+        };
+        write_txn.insert_guess(100, guess.clone()).unwrap();
+        write_txn.commit().unwrap();
+
+        // problems found before carol took the name, the role was created and carol registered
+        let problems = [
+            Problem::OrphanName {
+                key: name_key("carol"),
+                player: Uuid::new_v4(),
+            },
+            Problem::UnknownRole {
+                player: carol.uuid,
+                role: role.uuid,
+            },
+            Problem::UnknownGuessPlayer {
+                height: 100,
+                nonce: 1,
+                player: carol.uuid,
+            },
+        ];
+        let mut write_txn = storage.begin_write().unwrap();
+        fix(write_txn.as_mut(), &problems).unwrap();
+        write_txn.commit().unwrap();
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(read_txn.get_player_by_name("carol").unwrap(), Some(carol));
+        assert_eq!(read_txn.target_guesses(100).unwrap(), vec![guess]);
+    }
+
+    #[test]
+    fn test_redb_fsck() {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        let db = Arc::new(Database::create(file).unwrap());
+        let problems = check_fsck(&RedbStorage::new(db).unwrap());
+        assert_eq!(problems.len(), 5);
+    }
+
+    #[test]
+    fn test_sqlite_fsck() {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        let storage = SqliteStorage::new(file.to_path_buf()).unwrap();
+        rusqlite::Connection::open(&file)
+            .unwrap()
+            .execute(
+                "INSERT INTO auth_role (uuid, data) VALUES (?1, '{\"broken\"')",
+                [Uuid::new_v4().to_string()],
+            )
+            .unwrap();
+        let problems = check_fsck(&storage);
+        assert_eq!(problems.len(), 6);
+        assert!(
+            matches!(&problems[0], Problem::Undecodable(record) if record.table == "auth_role")
+        );
+        // the record was moved to quarantine
+        assert!(storage.undecodable_records(false).unwrap().is_empty());
+    }
+}
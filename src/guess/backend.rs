@@ -1,16 +1,22 @@
 use super::db::GuessDb;
-use super::types::{Block, Guess, GuessError};
+use super::replay::GuessLogReplay;
+use super::store::GuessWrite;
+use super::types::{Block, Guess, GuessError, GuessLogEntry};
+use crate::dump::{DumpTarget, ImportError};
+use crate::storage::lease::Lease;
+use crate::storage::redb::RedbStorage;
+use crate::storage::{Storage, UndecodableRecord};
 use crate::types::InternalError;
-use redb::Database;
+use redb::{Database, ReadTransaction, WriteTransaction};
 use reqwest::Url;
 use std::sync::Arc;
 use tokio::task::spawn_blocking;
-use tracing::info;
+use tracing::{info, warn};
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct GuessBackend {
-    pub guess_db: GuessDb,
+    storage: Arc<dyn Storage>,
     pub http_client: reqwest::Client,
     pub mempool_url: Url,
 }
@@ -21,12 +27,76 @@ impl GuessBackend {
         http_client: reqwest::Client,
         mempool_url: Url,
     ) -> Result<Self, InternalError> {
-        let guess_db = GuessDb::new(db)?;
-        Ok(Self {
-            guess_db,
+        Ok(Self::with_storage(
+            Arc::new(RedbStorage::new(db)?),
             http_client,
             mempool_url,
-        })
+        ))
+    }
+
+    pub fn with_storage(
+        storage: Arc<dyn Storage>,
+        http_client: reqwest::Client,
+        mempool_url: Url,
+    ) -> Self {
+        Self {
+            storage,
+            http_client,
+            mempool_url,
+        }
+    }
+
+    /// Create the guess tables if needed and quarantine undecodable records.
+    pub fn init_tables(write_txn: &mut WriteTransaction) -> Result<(), InternalError> {
+        GuessDb::init(write_txn)
+    }
+
+    /// Start the guess event log of a database with targets from before it was kept.
+    pub fn seed_guess_log(write_txn: &WriteTransaction) -> Result<(), InternalError> {
+        GuessDb::seed_guess_log(write_txn)
+    }
+
+    /// The guesses that can't be decoded.
+    pub fn find_undecodable(
+        read_txn: &ReadTransaction,
+    ) -> Result<Vec<UndecodableRecord>, InternalError> {
+        GuessDb::find_undecodable(read_txn)
+    }
+
+    /// Copy the guess tables into another database.
+    pub fn copy_tables(
+        read_txn: &ReadTransaction,
+        write_txn: &WriteTransaction,
+    ) -> Result<(), InternalError> {
+        GuessDb::copy_tables(read_txn, write_txn)
+    }
+
+    /// Insert or replace the targets of a validated data import, an imported target's
+    /// guesses replace its existing guesses. With `replace` all existing targets and guesses
+    /// are removed first.
+    pub fn import_targets(
+        write_txn: &mut dyn GuessWrite,
+        targets: &[DumpTarget],
+        replace: bool,
+    ) -> Result<(), ImportError> {
+        if replace {
+            let removed = write_txn.remove_all_targets()?;
+            warn!("import removed {} targets", removed);
+        }
+        for target in targets {
+            write_txn.insert_target(target.height, target.nonce)?;
+            write_txn.remove_target_guesses(target.height)?;
+            for guess in &target.guesses {
+                // the dump is validated first, so guesses are only rejected by a broken dump
+                write_txn
+                    .insert_guess(target.height, guess.clone())
+                    .map_err(|e| match e {
+                        GuessError::Internal(e) => ImportError::Internal(e),
+                        e => ImportError::DuplicateGuess(target.height, e.to_string()),
+                    })?;
+            }
+        }
+        Ok(())
     }
 
     pub async fn insert_target(
@@ -34,10 +104,10 @@ impl GuessBackend {
         height: u32,
         nonce: Option<u32>,
     ) -> Result<Option<u32>, InternalError> {
-        let guess_db = self.guess_db.clone();
+        let storage = self.storage.clone();
         spawn_blocking(move || {
-            let mut write_txn = guess_db.begin_write()?;
-            let insert_target_result = GuessDb::insert_target(&mut write_txn, height, nonce);
+            let mut write_txn = storage.begin_write()?;
+            let insert_target_result = write_txn.insert_target(height, nonce);
             write_txn.commit()?;
             insert_target_result
         })
@@ -48,29 +118,29 @@ impl GuessBackend {
         &self,
         height: u32,
     ) -> Result<Option<Option<u32>>, InternalError> {
-        let guess_db = self.guess_db.clone();
+        let storage = self.storage.clone();
         spawn_blocking(move || {
-            let read_txn = guess_db.begin_read()?;
-            GuessDb::get_target_nonce(&read_txn, height)
+            let read_txn = storage.begin_read()?;
+            read_txn.get_target_nonce(height)
         })
         .await
         .map_err(Into::<InternalError>::into)?
     }
 
     pub async fn get_last_target_nonce(&self) -> Result<Option<(u32, Option<u32>)>, InternalError> {
-        let guess_db = self.guess_db.clone();
+        let storage = self.storage.clone();
         spawn_blocking(move || {
-            let read_txn = guess_db.begin_read()?;
-            GuessDb::get_last_target_nonce(&read_txn)
+            let read_txn = storage.begin_read()?;
+            read_txn.get_last_target_nonce()
         })
         .await?
     }
 
     pub async fn remove_target_nonce(&self, height: u32) -> Result<Option<u32>, InternalError> {
-        let guess_db = self.guess_db.clone();
+        let storage = self.storage.clone();
         spawn_blocking(move || {
-            let mut write_txn = guess_db.begin_write()?;
-            let remove_target_result = GuessDb::remove_target_nonce(&mut write_txn, height);
+            let mut write_txn = storage.begin_write()?;
+            let remove_target_result = write_txn.remove_target_nonce(height);
             write_txn.commit()?;
             remove_target_result
         })
@@ -82,55 +152,112 @@ impl GuessBackend {
         old_height: u32,
         new_height: u32,
     ) -> Result<(), InternalError> {
-        let guess_db = self.guess_db.clone();
+        let storage = self.storage.clone();
         spawn_blocking(move || {
-            let mut write_txn = guess_db.begin_write()?;
-            GuessDb::replace_target(&mut write_txn, old_height, new_height)?;
-            write_txn.commit().map_err(Into::<InternalError>::into)
+            let mut write_txn = storage.begin_write()?;
+            write_txn.replace_target(old_height, new_height)?;
+            write_txn.commit()
         })
         .await?
     }
 
-    pub async fn insert_guess(&self, height: u32, guess: Guess) -> Result<bool, InternalError> {
-        let guess_db = self.guess_db.clone();
+    pub async fn insert_guess(&self, height: u32, guess: Guess) -> Result<(), GuessError> {
+        let storage = self.storage.clone();
         spawn_blocking(move || {
-            let mut write_txn = guess_db.begin_write()?;
-            let insert_guess_result = GuessDb::insert_guess(&mut write_txn, height, guess.clone());
+            let mut write_txn = storage.begin_write()?;
+            write_txn.insert_guess(height, guess)?;
             write_txn.commit()?;
-            insert_guess_result
+            Ok(())
         })
-        .await?
+        .await
+        .map_err(Into::<InternalError>::into)?
     }
 
     pub async fn any_guess(&self, height: u32, player_uuid: Uuid) -> Result<bool, GuessError> {
-        let guess_db = self.guess_db.clone();
+        let storage = self.storage.clone();
         spawn_blocking(move || {
-            let read_txn = guess_db.begin_read()?;
-            GuessDb::any_guess(&read_txn, height, player_uuid)
+            let read_txn = storage.begin_read()?;
+            read_txn.any_guess(height, player_uuid)
         })
         .await
         .map_err(Into::<InternalError>::into)?
     }
 
+    pub async fn player_guesses(
+        &self,
+        player_uuid: Uuid,
+    ) -> Result<Vec<(u32, Guess)>, InternalError> {
+        let storage = self.storage.clone();
+        spawn_blocking(move || {
+            let read_txn = storage.begin_read()?;
+            read_txn.player_guesses(player_uuid)
+        })
+        .await?
+    }
+
+    pub async fn remove_player_guesses(
+        &self,
+        player_uuid: Uuid,
+        anonymize: bool,
+    ) -> Result<usize, InternalError> {
+        let storage = self.storage.clone();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let remove_guesses_result = write_txn.remove_player_guesses(player_uuid, anonymize);
+            write_txn.commit()?;
+            remove_guesses_result
+        })
+        .await?
+    }
+
+    /// The guess event log up to and including sequence number `until`, or all of it, with
+    /// the targets and guesses it results in.
+    pub async fn replay_guess_log(
+        &self,
+        until: Option<u64>,
+    ) -> Result<GuessLogReplay, InternalError> {
+        let storage = self.storage.clone();
+        spawn_blocking(move || {
+            let read_txn = storage.begin_read()?;
+            let log = read_txn
+                .iter_guess_log()?
+                .take_while(|entry| {
+                    entry
+                        .as_ref()
+                        .map_or(true, |entry| until.is_none_or(|until| entry.seq <= until))
+                })
+                .collect::<Result<Vec<GuessLogEntry>, InternalError>>()?;
+            Ok(GuessLogReplay::new(log, until))
+        })
+        .await?
+    }
+
     pub async fn target_guesses(&self, height: u32) -> Result<Vec<Guess>, InternalError> {
-        let guess_db = self.guess_db.clone();
+        let storage = self.storage.clone();
         spawn_blocking(move || {
-            let read_txn = guess_db.begin_read()?;
-            GuessDb::target_guesses(&read_txn, height)
+            let read_txn = storage.begin_read()?;
+            read_txn.target_guesses(height)
         })
         .await
         .map_err(Into::<InternalError>::into)?
     }
 }
 
+/// How often the open target is checked for a confirmed block.
+pub const UPDATE_TARGET_NONCE_PERIOD: tokio::time::Duration = tokio::time::Duration::from_secs(30);
+
+/// Check for the open target's block, only on the replica holding the `lease`.
 pub async fn continuously_update_target_nonce(
     guess_backend: Arc<GuessBackend>,
+    lease: Lease,
 ) -> Result<(), InternalError> {
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+    let mut interval = tokio::time::interval(UPDATE_TARGET_NONCE_PERIOD);
     interval.tick().await; // The first tick completes immediately; skip.
     loop {
         interval.tick().await;
-        update_target_nonce(guess_backend.clone()).await?
+        if lease.acquire().await? {
+            update_target_nonce(guess_backend.clone()).await?
+        }
     }
 }
 
@@ -166,3 +293,85 @@ async fn update_target_nonce(guess_backend: Arc<GuessBackend>) -> Result<(), Int
         Ok::<(), InternalError>(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::GuessBackend;
+    use crate::guess::types::{Guess, DELETED_PLAYER};
+    use redb::Database;
+    use reqwest::Url;
+    use std::sync::Arc;
+    use tempfile::NamedTempFile;
+    use uuid::Uuid;
+
+    fn temp_backend() -> GuessBackend {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        let db = Arc::new(Database::create(file).unwrap());
+        let mempool_url = Url::parse("http://localhost:1").unwrap();
+        GuessBackend::new(db, reqwest::Client::new(), mempool_url).expect("new backend")
+    }
+
+    #[tokio::test]
+    async fn test_remove_player_guesses() {
+        let backend = temp_backend();
+        let player = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        for height in [100, 101] {
+            backend.insert_target(height, None).await.unwrap();
+            backend
+                .insert_guess(
+                    height,
+                    Guess {
+                        player,
+                        nonce: height,
+                    },
+                )
+                .await
+                .unwrap();
+            backend
+                .insert_guess(
+                    height,
+                    Guess {
+                        player: other,
+                        nonce: height + 1000,
+                    },
+                )
+                .await
+                .unwrap();
+        }
+        let mut player_guesses = backend.player_guesses(player).await.unwrap();
+        player_guesses.sort_by_key(|(height, _)| *height);
+        assert_eq!(
+            player_guesses,
+            vec![
+                (100, Guess { player, nonce: 100 }),
+                (101, Guess { player, nonce: 101 })
+            ]
+        );
+
+        // anonymized guesses are kept without the player
+        assert_eq!(
+            backend.remove_player_guesses(player, true).await.unwrap(),
+            2
+        );
+        assert!(backend.player_guesses(player).await.unwrap().is_empty());
+        assert_eq!(
+            backend.player_guesses(DELETED_PLAYER).await.unwrap().len(),
+            2
+        );
+        assert_eq!(backend.target_guesses(100).await.unwrap().len(), 2);
+
+        // removed guesses are gone
+        assert_eq!(
+            backend.remove_player_guesses(other, false).await.unwrap(),
+            2
+        );
+        assert_eq!(
+            backend.target_guesses(100).await.unwrap(),
+            vec![Guess {
+                player: DELETED_PLAYER,
+                nonce: 100
+            }]
+        );
+    }
+}
@@ -1,62 +1,117 @@
-use super::types::{Guess, GuessError};
+use super::replay::ReplayedTargets;
+use super::store::{GuessProjection, GuessRead, GuessWrite};
+use super::types::{Guess, GuessEvent, GuessLogEntry, DELETED_PLAYER};
+use crate::backup::{copy_multimap_table, copy_table};
+use crate::encoding::{
+    find_undecodable, find_undecodable_multimap, quarantine_multimap_table, quarantine_table,
+    Encoded, Versioned, VersionedKey,
+};
+use crate::storage::{RowIter, UndecodableRecord};
 use crate::types::InternalError;
+use chrono::{DateTime, Utc};
 use redb::{
-    Database, Key, MultimapTableDefinition, ReadTransaction, ReadableTable, TableDefinition,
-    TypeName, Value, WriteTransaction,
+    MultimapTableDefinition, ReadTransaction, ReadableMultimapTable, ReadableTable,
+    ReadableTableMetadata, TableDefinition, WriteTransaction,
 };
 use std::cmp::Ordering;
-use std::sync::Arc;
 use tracing::info;
 use uuid::Uuid;
 
 const HEIGHT_NONCE: TableDefinition<u32, Option<u32>> = TableDefinition::new("guess_height_nonce");
-const HEIGHT_GUESSES: MultimapTableDefinition<u32, Guess> =
+const HEIGHT_GUESSES: MultimapTableDefinition<u32, Encoded<Guess>> =
     MultimapTableDefinition::new("guess_height_guesses");
+const GUESS_LOG: TableDefinition<u64, Encoded<GuessLogEntry>> = TableDefinition::new("guess_log");
 
-#[derive(Debug, Clone)]
-pub struct GuessDb(Arc<Database>);
+pub struct GuessDb;
 
 impl GuessDb {
-    pub fn new(db: Arc<Database>) -> Result<Self, InternalError> {
-        let mut write_txn = db.begin_write()?;
-        Self::init(&mut write_txn)?;
-        write_txn.commit()?;
-        Ok(GuessDb(db))
-    }
-
-    fn init(write_txn: &mut WriteTransaction) -> Result<(), InternalError> {
+    pub fn init(write_txn: &mut WriteTransaction) -> Result<(), InternalError> {
         // open tables to make sure they exist
         write_txn.open_table(HEIGHT_NONCE)?;
         write_txn.open_multimap_table(HEIGHT_GUESSES)?;
-        info!("opened tables: {}, {}", HEIGHT_NONCE, HEIGHT_GUESSES);
+        write_txn.open_table(GUESS_LOG)?;
+        // move guesses that can't be decoded out of the way before they are read
+        quarantine_multimap_table(write_txn, HEIGHT_GUESSES)?;
+        quarantine_table(write_txn, GUESS_LOG)?;
+        info!(
+            "opened tables: {}, {}, {}",
+            HEIGHT_NONCE, HEIGHT_GUESSES, GUESS_LOG
+        );
         Ok(())
     }
 
-    pub fn begin_write(&self) -> Result<WriteTransaction, InternalError> {
-        Ok(self.0.begin_write()?)
+    /// Start the guess event log of a database from before it was kept with events that
+    /// recreate its targets and guesses.
+    pub fn seed_guess_log(write_txn: &WriteTransaction) -> Result<(), InternalError> {
+        let mut guess_log = write_txn.open_table(GUESS_LOG)?;
+        if !guess_log.is_empty()? {
+            return Ok(());
+        }
+        let mut current = ReplayedTargets::default();
+        for result in write_txn.open_table(HEIGHT_NONCE)?.iter()? {
+            let (height, nonce) = result?;
+            current.set_target(height.value(), nonce.value())?;
+        }
+        for result in write_txn.open_multimap_table(HEIGHT_GUESSES)?.iter()? {
+            let (height, guesses) = result?;
+            for guess in guesses {
+                current.add_guess(height.value(), guess?.value().decode()?)?;
+            }
+        }
+        let timestamp = Utc::now();
+        for (seq, event) in (1..).zip(current.baseline_events()) {
+            let entry = GuessLogEntry {
+                seq,
+                timestamp,
+                event,
+            };
+            guess_log.insert(seq, Encoded::new(&entry))?;
+        }
+        Ok(())
     }
 
-    pub fn begin_read(&self) -> Result<ReadTransaction, InternalError> {
-        Ok(self.0.begin_read()?)
+    /// The guesses that can't be decoded.
+    pub fn find_undecodable(
+        read_txn: &ReadTransaction,
+    ) -> Result<Vec<UndecodableRecord>, InternalError> {
+        let mut records = find_undecodable_multimap(read_txn, HEIGHT_GUESSES)?;
+        records.extend(find_undecodable(read_txn, GUESS_LOG)?);
+        Ok(records)
     }
 
-    pub fn insert_target(
-        write_txn: &mut WriteTransaction,
-        height: u32,
-        nonce: Option<u32>,
-    ) -> Result<Option<u32>, InternalError> {
-        let mut height_nonce = write_txn.open_table(HEIGHT_NONCE)?;
-        height_nonce
-            .insert(height, nonce)
-            .map(|opt| opt.and_then(|ag| ag.value()))
-            .map_err(Into::into)
+    /// Copy the guess tables into another database.
+    pub fn copy_tables(
+        read_txn: &ReadTransaction,
+        write_txn: &WriteTransaction,
+    ) -> Result<(), InternalError> {
+        copy_table(read_txn, write_txn, HEIGHT_NONCE)?;
+        copy_multimap_table(read_txn, write_txn, HEIGHT_GUESSES)?;
+        copy_table(read_txn, write_txn, GUESS_LOG)
     }
+}
 
-    pub fn get_target_nonce(
-        read_txn: &ReadTransaction,
-        height: u32,
-    ) -> Result<Option<Option<u32>>, InternalError> {
-        let height_nonce = read_txn
+// all of a player's guesses with their target height
+fn find_player_guesses(
+    height_guesses: &impl ReadableMultimapTable<u32, Encoded<Guess>>,
+    player_uuid: Uuid,
+) -> Result<Vec<(u32, Guess)>, InternalError> {
+    let mut player_guesses = Vec::new();
+    for result in height_guesses.iter()? {
+        let (height_ag, guesses) = result?;
+        let height = height_ag.value();
+        for guess_res in guesses {
+            let guess = guess_res?.value().decode()?;
+            if guess.player == player_uuid {
+                player_guesses.push((height, guess));
+            }
+        }
+    }
+    Ok(player_guesses)
+}
+
+impl GuessRead for ReadTransaction {
+    fn get_target_nonce(&self, height: u32) -> Result<Option<Option<u32>>, InternalError> {
+        let height_nonce = self
             .open_table(HEIGHT_NONCE)
             .map_err(Into::<InternalError>::into)?;
         height_nonce
@@ -65,35 +120,121 @@ impl GuessDb {
             .map_err(Into::into)
     }
 
-    pub fn get_last_target_nonce(
-        read_txn: &ReadTransaction,
-    ) -> Result<Option<(u32, Option<u32>)>, InternalError> {
-        let height_nonce = read_txn.open_table(HEIGHT_NONCE)?;
+    fn get_last_target_nonce(&self) -> Result<Option<(u32, Option<u32>)>, InternalError> {
+        let height_nonce = self.open_table(HEIGHT_NONCE)?;
         height_nonce
             .last()
             .map(|opt| opt.map(|(k_ag, v_ag)| (k_ag.value(), v_ag.value())))
             .map_err(Into::into)
     }
 
-    pub fn replace_target(
-        write_txn: &mut WriteTransaction,
-        old_height: u32,
-        new_height: u32,
-    ) -> Result<(), InternalError> {
-        let mut height_nonce = write_txn
+    fn iter_targets(&self) -> Result<RowIter<'_, (u32, Option<u32>)>, InternalError> {
+        let height_nonce = self.open_table(HEIGHT_NONCE)?;
+        let targets = height_nonce.range::<u32>(..)?.map(|result| {
+            result
+                .map(|(height_ag, nonce_ag)| (height_ag.value(), nonce_ag.value()))
+                .map_err(Into::into)
+        });
+        Ok(Box::new(targets))
+    }
+
+    fn target_guesses(&self, height: u32) -> Result<Vec<Guess>, InternalError> {
+        let height_guesses = self
+            .open_multimap_table(HEIGHT_GUESSES)
+            .map_err(Into::<InternalError>::into)?;
+        height_guesses
+            .get(height)
+            .map(|guess| {
+                guess
+                    .map(|ag_res| {
+                        ag_res
+                            .map_err(Into::<InternalError>::into)
+                            .and_then(|ag| ag.value().decode().map_err(Into::into))
+                    })
+                    .collect::<Result<Vec<Guess>, InternalError>>()
+            })
+            .map_err(Into::<InternalError>::into)?
+    }
+
+    fn iter_guess_log(&self) -> Result<RowIter<'_, GuessLogEntry>, InternalError> {
+        let guess_log = self.open_table(GUESS_LOG)?;
+        let entries = guess_log.range::<u64>(..)?.map(|result| {
+            let (_, entry_ag) = result?;
+            entry_ag.value().decode().map_err(Into::into)
+        });
+        Ok(Box::new(entries))
+    }
+
+    fn player_guesses(&self, player_uuid: Uuid) -> Result<Vec<(u32, Guess)>, InternalError> {
+        let height_guesses = self.open_multimap_table(HEIGHT_GUESSES)?;
+        find_player_guesses(&height_guesses, player_uuid)
+    }
+}
+
+impl GuessWrite for WriteTransaction {
+    fn append_guess_event(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        event: &GuessEvent,
+    ) -> Result<u64, InternalError> {
+        let mut guess_log = self.open_table(GUESS_LOG)?;
+        let seq = guess_log.last()?.map(|(seq, _)| seq.value()).unwrap_or(0) + 1;
+        let entry = GuessLogEntry {
+            seq,
+            timestamp,
+            event: event.clone(),
+        };
+        guess_log.insert(seq, Encoded::new(&entry))?;
+        Ok(seq)
+    }
+
+    fn guess_log_for_update(&self) -> Result<Vec<GuessLogEntry>, InternalError> {
+        let guess_log = self.open_table(GUESS_LOG)?;
+        let mut entries = Vec::new();
+        for result in guess_log.iter()? {
+            let (_, entry_ag) = result?;
+            entries.push(entry_ag.value().decode()?);
+        }
+        Ok(entries)
+    }
+
+    fn target_guesses_for_update(&self, height: u32) -> Result<Vec<Guess>, InternalError> {
+        let height_guesses = self.open_multimap_table(HEIGHT_GUESSES)?;
+        let mut guesses = Vec::new();
+        for result in height_guesses.get(height)? {
+            guesses.push(result?.value().decode()?);
+        }
+        Ok(guesses)
+    }
+}
+
+impl GuessProjection for WriteTransaction {
+    fn set_target(
+        &mut self,
+        height: u32,
+        nonce: Option<u32>,
+    ) -> Result<Option<u32>, InternalError> {
+        let mut height_nonce = self.open_table(HEIGHT_NONCE)?;
+        height_nonce
+            .insert(height, nonce)
+            .map(|opt| opt.and_then(|ag| ag.value()))
+            .map_err(Into::into)
+    }
+
+    fn move_target(&mut self, old_height: u32, new_height: u32) -> Result<(), InternalError> {
+        let mut height_nonce = self
             .open_table(HEIGHT_NONCE)
             .map_err(Into::<InternalError>::into)?;
         // remove old target nonce
         let _ = height_nonce
             .remove(&old_height)
-            //.map(|nonce_res| nonce_res.map(|ag| ag.value()).flatten())
             .map_err(Into::<InternalError>::into)?;
         // create new target nonce
         height_nonce
             .insert(new_height, None)
             .map_err(Into::<InternalError>::into)?;
 
-        let mut height_guesses = write_txn
+        let mut height_guesses = self
             .open_multimap_table(HEIGHT_GUESSES)
             .map_err(Into::<InternalError>::into)?;
         // remove guesses from old target
@@ -106,7 +247,7 @@ impl GuessDb {
                             .map(|ag| ag.value())
                             .map_err(Into::<InternalError>::into)
                     })
-                    .collect::<Vec<Guess>>()
+                    .collect::<Vec<Encoded<Guess>>>()
             })
             .map_err(Into::<InternalError>::into)?;
         // insert guesses from old target into new target
@@ -119,107 +260,80 @@ impl GuessDb {
         Ok(())
     }
 
-    pub fn remove_target_nonce(
-        write_txn: &mut WriteTransaction,
-        height: u32,
-    ) -> Result<Option<u32>, InternalError> {
-        let mut height_nonce = write_txn.open_table(HEIGHT_NONCE)?;
+    fn delete_target(&mut self, height: u32) -> Result<Option<u32>, InternalError> {
+        let mut height_nonce = self.open_table(HEIGHT_NONCE)?;
         height_nonce
             .remove(height)
             .map(|opt| opt.and_then(|ag| ag.value()))
             .map_err(Into::into)
     }
 
-    pub fn insert_guess(
-        write_txn: &mut WriteTransaction,
-        height: u32,
-        guess: Guess,
-    ) -> Result<bool, InternalError> {
-        let mut height_guesses = write_txn.open_multimap_table(HEIGHT_GUESSES)?;
-        height_guesses.insert(height, &guess).map_err(Into::into)
-    }
-
-    // check if a player has made any guess for the given target height
-    pub fn any_guess(
-        read_txn: &ReadTransaction,
-        height: u32,
-        player_uuid: Uuid,
-    ) -> Result<bool, GuessError> {
-        let target_opt =
-            Self::get_target_nonce(read_txn, height).map_err(Into::<GuessError>::into)?;
-        if target_opt.is_none() {
-            return Err(GuessError::MissingTarget(height));
-        };
-        let height_guesses = read_txn
-            .open_multimap_table(HEIGHT_GUESSES)
-            .map_err(Into::<InternalError>::into)?;
-        let mut guesses = height_guesses
-            .get(height)
-            .map_err(Into::<InternalError>::into)?;
-
-        guesses.try_fold(false, |any, guess_res| {
-            guess_res
-                .map(|ag| any || ag.value().player == player_uuid)
-                .map_err(Into::<InternalError>::into)
-                .map_err(Into::<GuessError>::into)
-        })
+    fn delete_all_targets(&mut self) -> Result<usize, InternalError> {
+        let mut height_nonce = self.open_table(HEIGHT_NONCE)?;
+        let before = height_nonce.len()?;
+        height_nonce.retain(|_, _| false)?;
+        let mut height_guesses = self.open_multimap_table(HEIGHT_GUESSES)?;
+        let heights = height_guesses
+            .iter()?
+            .map(|result| result.map(|(height_ag, _)| height_ag.value()))
+            .collect::<Result<Vec<u32>, _>>()?;
+        for height in heights {
+            height_guesses.remove_all(height)?;
+        }
+        Ok(before as usize)
     }
 
-    pub fn target_guesses(
-        read_txn: &ReadTransaction,
-        height: u32,
-    ) -> Result<Vec<Guess>, InternalError> {
-        let height_guesses = read_txn
-            .open_multimap_table(HEIGHT_GUESSES)
-            .map_err(Into::<InternalError>::into)?;
+    fn add_guess(&mut self, height: u32, guess: Guess) -> Result<bool, InternalError> {
+        let mut height_guesses = self.open_multimap_table(HEIGHT_GUESSES)?;
+        // guesses are stored by their encoding, so another player's guess of the same nonce
+        // isn't found by the insert
+        for result in height_guesses.get(height)? {
+            if result?.value().decode()?.nonce == guess.nonce {
+                return Ok(true);
+            }
+        }
         height_guesses
-            .get(height)
-            .map(|guess| {
-                guess
-                    .flat_map(|ag_res| {
-                        ag_res
-                            .map(|ag| ag.value())
-                            .map_err(Into::<InternalError>::into)
-                    })
-                    .collect::<Vec<Guess>>()
-            })
-            .map_err(Into::<InternalError>::into)
+            .insert(height, &Encoded::new(&guess))
+            .map_err(Into::into)
     }
-}
-
-impl Value for Guess {
-    type SelfType<'a> = Guess;
-    type AsBytes<'a> = Vec<u8>;
 
-    fn fixed_width() -> Option<usize> {
-        None
+    fn delete_target_guesses(&mut self, height: u32) -> Result<usize, InternalError> {
+        let mut height_guesses = self.open_multimap_table(HEIGHT_GUESSES)?;
+        let removed = height_guesses.remove_all(height)?.count();
+        Ok(removed)
     }
 
-    fn from_bytes<'a>(serialized_guess: &'a [u8]) -> Self::SelfType<'a>
-    where
-        Self: 'a,
-    {
-        ciborium::from_reader(serialized_guess).unwrap()
+    fn delete_player_guesses(
+        &mut self,
+        player_uuid: Uuid,
+        anonymize: bool,
+    ) -> Result<usize, InternalError> {
+        let mut height_guesses = self.open_multimap_table(HEIGHT_GUESSES)?;
+        let player_guesses = find_player_guesses(&height_guesses, player_uuid)?;
+        for (height, guess) in &player_guesses {
+            height_guesses.remove(height, &Encoded::new(guess))?;
+            if anonymize {
+                let anonymous_guess = Guess {
+                    player: DELETED_PLAYER,
+                    ..guess.clone()
+                };
+                height_guesses.insert(height, &Encoded::new(&anonymous_guess))?;
+            }
+        }
+        Ok(player_guesses.len())
     }
+}
 
-    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
-    where
-        Self: 'b,
-    {
-        let mut serialized_guess = Vec::<u8>::new();
-        ciborium::into_writer(value, &mut serialized_guess).expect("Failed to serialize guess");
-        serialized_guess
-    }
+impl Versioned for Guess {
+    const TYPE_NAME: &'static str = "nonce_guess::Guess";
+}
 
-    fn type_name() -> TypeName {
-        TypeName::new("nonce_guess::Guess")
-    }
+impl Versioned for GuessLogEntry {
+    const TYPE_NAME: &'static str = "nonce_guess::GuessLogEntry";
 }
 
-impl Key for Guess {
-    fn compare(uuid_key1: &[u8], uuid_key2: &[u8]) -> Ordering {
-        let guess1 = Guess::from_bytes(uuid_key1);
-        let guess2 = Guess::from_bytes(uuid_key2);
+impl VersionedKey for Guess {
+    fn compare(guess1: &Self, guess2: &Self) -> Ordering {
         guess1.nonce.cmp(&guess2.nonce)
     }
 }
@@ -1,4 +1,6 @@
 pub mod backend;
 mod db;
+pub mod replay;
+pub mod store;
 pub mod types;
 pub mod web;
@@ -0,0 +1,282 @@
+use super::store::GuessProjection;
+use super::types::{Guess, GuessEvent, GuessLogEntry, DELETED_PLAYER};
+use crate::dump::DumpTarget;
+use crate::types::InternalError;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+/// Targets and guesses in memory, built by replaying guess events.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ReplayedTargets {
+    targets: BTreeMap<u32, Option<u32>>,
+    // guessing players by target height and nonce
+    guesses: BTreeMap<u32, BTreeMap<u32, Uuid>>,
+}
+
+impl ReplayedTargets {
+    /// Replay events from an empty state.
+    pub fn replay<'a>(events: impl IntoIterator<Item = &'a GuessEvent>) -> Self {
+        let mut replayed = Self::default();
+        for event in events {
+            replayed
+                .apply_guess_event(event)
+                .expect("replaying in memory can't fail");
+        }
+        replayed
+    }
+
+    /// The nonce of a target, `Some(None)` if the target's block isn't confirmed yet.
+    pub fn nonce(&self, height: u32) -> Option<Option<u32>> {
+        self.targets.get(&height).copied()
+    }
+
+    /// The guesses for a target height, ordered by nonce.
+    pub fn guesses(&self, height: u32) -> Vec<Guess> {
+        self.guesses
+            .get(&height)
+            .into_iter()
+            .flatten()
+            .map(|(nonce, player)| Guess {
+                player: *player,
+                nonce: *nonce,
+            })
+            .collect()
+    }
+
+    /// The heights of the targets and of any guesses, guesses are kept when their target is
+    /// removed.
+    pub fn heights(&self) -> BTreeSet<u32> {
+        self.targets
+            .keys()
+            .chain(self.guesses.keys())
+            .copied()
+            .collect()
+    }
+
+    /// The targets with their guesses, ordered by height.
+    pub fn targets(&self) -> Vec<DumpTarget> {
+        self.targets
+            .iter()
+            .map(|(height, nonce)| DumpTarget {
+                height: *height,
+                nonce: *nonce,
+                guesses: self.guesses(*height),
+            })
+            .collect()
+    }
+
+    /// Events that recreate these targets and guesses, they start the log of a database with
+    /// targets from before the log was kept.
+    pub fn baseline_events(&self) -> Vec<GuessEvent> {
+        let mut events = Vec::new();
+        for height in self.heights() {
+            let nonce = self.nonce(height);
+            if nonce.is_some() {
+                events.push(GuessEvent::TargetOpened { height });
+            }
+            for guess in self.guesses(height) {
+                events.push(GuessEvent::GuessPlaced { height, guess });
+            }
+            if let Some(Some(nonce)) = nonce {
+                events.push(GuessEvent::TargetConfirmed { height, nonce });
+            }
+        }
+        events
+    }
+}
+
+impl GuessProjection for ReplayedTargets {
+    fn set_target(
+        &mut self,
+        height: u32,
+        nonce: Option<u32>,
+    ) -> Result<Option<u32>, InternalError> {
+        Ok(self.targets.insert(height, nonce).flatten())
+    }
+
+    fn move_target(&mut self, old_height: u32, new_height: u32) -> Result<(), InternalError> {
+        self.targets.remove(&old_height);
+        self.targets.insert(new_height, None);
+        if let Some(guesses) = self.guesses.remove(&old_height) {
+            self.guesses.entry(new_height).or_default().extend(guesses);
+        }
+        Ok(())
+    }
+
+    fn delete_target(&mut self, height: u32) -> Result<Option<u32>, InternalError> {
+        Ok(self.targets.remove(&height).flatten())
+    }
+
+    fn delete_all_targets(&mut self) -> Result<usize, InternalError> {
+        let removed = self.targets.len();
+        self.targets.clear();
+        self.guesses.clear();
+        Ok(removed)
+    }
+
+    fn add_guess(&mut self, height: u32, guess: Guess) -> Result<bool, InternalError> {
+        // like the tables, the first player to guess a nonce keeps it
+        let guesses = self.guesses.entry(height).or_default();
+        if guesses.contains_key(&guess.nonce) {
+            return Ok(true);
+        }
+        guesses.insert(guess.nonce, guess.player);
+        Ok(false)
+    }
+
+    fn delete_target_guesses(&mut self, height: u32) -> Result<usize, InternalError> {
+        Ok(self.guesses.remove(&height).unwrap_or_default().len())
+    }
+
+    fn delete_player_guesses(
+        &mut self,
+        player_uuid: Uuid,
+        anonymize: bool,
+    ) -> Result<usize, InternalError> {
+        let mut changed = 0;
+        for guesses in self.guesses.values_mut() {
+            let before = guesses.len();
+            if anonymize {
+                for player in guesses.values_mut() {
+                    if *player == player_uuid {
+                        *player = DELETED_PLAYER;
+                        changed += 1;
+                    }
+                }
+            } else {
+                guesses.retain(|_, player| *player != player_uuid);
+                changed += before - guesses.len();
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// The guess event log up to an event, with the targets and guesses it results in.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct GuessLogReplay {
+    pub events: Vec<GuessLogEntry>,
+    pub targets: Vec<DumpTarget>,
+}
+
+impl GuessLogReplay {
+    /// Replay the log entries up to and including sequence number `until`, or all of them.
+    pub fn new(log: Vec<GuessLogEntry>, until: Option<u64>) -> Self {
+        let events = log
+            .into_iter()
+            .take_while(|entry| until.is_none_or(|until| entry.seq <= until))
+            .collect::<Vec<GuessLogEntry>>();
+        let targets = ReplayedTargets::replay(events.iter().map(|entry| &entry.event)).targets();
+        Self { events, targets }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{GuessLogReplay, ReplayedTargets};
+    use crate::guess::types::{Guess, GuessEvent, GuessLogEntry, DELETED_PLAYER};
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_replay() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let events = [
+            GuessEvent::TargetOpened { height: 100 },
+            GuessEvent::GuessPlaced {
+                height: 100,
+                guess: Guess {
+                    player: alice,
+                    nonce: 50,
+                },
+            },
+            GuessEvent::GuessPlaced {
+                height: 100,
+                guess: Guess {
+                    player: bob,
+                    nonce: 40,
+                },
+            },
+            GuessEvent::TargetReplaced {
+                old_height: 100,
+                new_height: 110,
+            },
+            GuessEvent::PlayerGuessesRemoved {
+                player: bob,
+                anonymize: true,
+            },
+            GuessEvent::TargetConfirmed {
+                height: 110,
+                nonce: 45,
+            },
+        ];
+        let replayed = ReplayedTargets::replay(&events);
+        assert_eq!(replayed.nonce(100), None);
+        assert_eq!(replayed.nonce(110), Some(Some(45)));
+        assert_eq!(
+            replayed.guesses(110),
+            vec![
+                Guess {
+                    player: DELETED_PLAYER,
+                    nonce: 40
+                },
+                Guess {
+                    player: alice,
+                    nonce: 50
+                }
+            ]
+        );
+        // the baseline of a replayed state replays to the same state
+        assert_eq!(
+            ReplayedTargets::replay(&replayed.baseline_events()),
+            replayed
+        );
+
+        // a replay up to an event shows the targets as they were then
+        let log = events
+            .into_iter()
+            .enumerate()
+            .map(|(seq, event)| GuessLogEntry {
+                seq: seq as u64 + 1,
+                timestamp: Utc::now(),
+                event,
+            })
+            .collect();
+        let replay = GuessLogReplay::new(log, Some(3));
+        assert_eq!(replay.events.len(), 3);
+        assert_eq!(replay.targets.len(), 1);
+        assert_eq!(replay.targets[0].height, 100);
+        assert_eq!(replay.targets[0].guesses.len(), 2);
+    }
+
+    #[test]
+    fn test_replay_keeps_first_guess() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let events = [
+            GuessEvent::TargetOpened { height: 100 },
+            GuessEvent::GuessPlaced {
+                height: 100,
+                guess: Guess {
+                    player: alice,
+                    nonce: 50,
+                },
+            },
+            GuessEvent::GuessPlaced {
+                height: 100,
+                guess: Guess {
+                    player: bob,
+                    nonce: 50,
+                },
+            },
+        ];
+        let replayed = ReplayedTargets::replay(&events);
+        assert_eq!(
+            replayed.guesses(100),
+            vec![Guess {
+                player: alice,
+                nonce: 50
+            }]
+        );
+    }
+}
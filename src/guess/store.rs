@@ -0,0 +1,198 @@
+use crate::guess::types::{Guess, GuessError, GuessEvent, GuessLogEntry, DELETED_PLAYER};
+use crate::storage::RowIter;
+use crate::types::InternalError;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// Targets and guesses of a read transaction.
+pub trait GuessRead {
+    /// The nonce of a target, `Some(None)` if the target's block isn't confirmed yet.
+    fn get_target_nonce(&self, height: u32) -> Result<Option<Option<u32>>, InternalError>;
+
+    /// The highest target with its nonce.
+    fn get_last_target_nonce(&self) -> Result<Option<(u32, Option<u32>)>, InternalError>;
+
+    /// All target heights with their nonce, ordered by height.
+    fn iter_targets(&self) -> Result<RowIter<'_, (u32, Option<u32>)>, InternalError>;
+
+    /// The guesses for a target height, ordered by nonce.
+    fn target_guesses(&self, height: u32) -> Result<Vec<Guess>, InternalError>;
+
+    /// The guess event log in order.
+    fn iter_guess_log(&self) -> Result<RowIter<'_, GuessLogEntry>, InternalError>;
+
+    /// All of a player's guesses with their target height.
+    fn player_guesses(&self, player_uuid: Uuid) -> Result<Vec<(u32, Guess)>, InternalError>;
+
+    // check if a player has made any guess for the given target height
+    fn any_guess(&self, height: u32, player_uuid: Uuid) -> Result<bool, GuessError> {
+        if self.get_target_nonce(height)?.is_none() {
+            return Err(GuessError::MissingTarget(height));
+        };
+        let guesses = self.target_guesses(height)?;
+        Ok(guesses.iter().any(|guess| guess.player == player_uuid))
+    }
+}
+
+/// The target and guess tables of a write transaction, the projection of the guess event log.
+/// Only changed by applying logged events, [`GuessWrite`] appends them first.
+pub trait GuessProjection {
+    /// Insert or update a target, returns the previous nonce.
+    fn set_target(&mut self, height: u32, nonce: Option<u32>)
+        -> Result<Option<u32>, InternalError>;
+
+    /// Move a target and its guesses to a new height, the new target has no nonce.
+    fn move_target(&mut self, old_height: u32, new_height: u32) -> Result<(), InternalError>;
+
+    /// Remove a target, returns its nonce.
+    fn delete_target(&mut self, height: u32) -> Result<Option<u32>, InternalError>;
+
+    /// Remove all targets and their guesses, returns the number of targets removed.
+    fn delete_all_targets(&mut self) -> Result<usize, InternalError>;
+
+    /// Add a guess, returns true without adding it if the target already has a guess with the
+    /// same nonce, the first player to guess a nonce keeps it.
+    fn add_guess(&mut self, height: u32, guess: Guess) -> Result<bool, InternalError>;
+
+    /// Remove the guesses for a target height, returns the number of guesses removed.
+    fn delete_target_guesses(&mut self, height: u32) -> Result<usize, InternalError>;
+
+    /// Remove a player's guesses, or if `anonymize` keep them as
+    /// [`crate::guess::types::DELETED_PLAYER`] guesses. Returns the number of guesses changed.
+    fn delete_player_guesses(
+        &mut self,
+        player_uuid: Uuid,
+        anonymize: bool,
+    ) -> Result<usize, InternalError>;
+
+    /// Apply a logged event to the tables.
+    fn apply_guess_event(&mut self, event: &GuessEvent) -> Result<(), InternalError> {
+        match event {
+            GuessEvent::TargetOpened { height } => self.set_target(*height, None).map(|_| ()),
+            GuessEvent::TargetConfirmed { height, nonce } => {
+                self.set_target(*height, Some(*nonce)).map(|_| ())
+            }
+            GuessEvent::TargetReplaced {
+                old_height,
+                new_height,
+            } => self.move_target(*old_height, *new_height),
+            GuessEvent::TargetRemoved { height } => self.delete_target(*height).map(|_| ()),
+            GuessEvent::AllTargetsRemoved => self.delete_all_targets().map(|_| ()),
+            GuessEvent::GuessPlaced { height, guess } => {
+                self.add_guess(*height, guess.clone()).map(|_| ())
+            }
+            GuessEvent::TargetGuessesRemoved { height } => {
+                self.delete_target_guesses(*height).map(|_| ())
+            }
+            GuessEvent::PlayerGuessesRemoved { player, anonymize } => {
+                self.delete_player_guesses(*player, *anonymize).map(|_| ())
+            }
+        }
+    }
+}
+
+/// Targets and guesses of a write transaction. Each change is appended to the guess event log
+/// and then applied to the target and guess tables.
+pub trait GuessWrite: GuessProjection {
+    /// Append an event to the guess event log, returns its sequence number. The log is never
+    /// changed or removed from.
+    fn append_guess_event(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        event: &GuessEvent,
+    ) -> Result<u64, InternalError>;
+
+    // the whole guess event log inside a write transaction, before rebuilding the projection
+    fn guess_log_for_update(&self) -> Result<Vec<GuessLogEntry>, InternalError>;
+
+    // the guesses for a target height inside a write transaction, before adding a guess
+    fn target_guesses_for_update(&self, height: u32) -> Result<Vec<Guess>, InternalError>;
+
+    /// Insert or update a target, returns the previous nonce.
+    fn insert_target(
+        &mut self,
+        height: u32,
+        nonce: Option<u32>,
+    ) -> Result<Option<u32>, InternalError> {
+        let event = match nonce {
+            None => GuessEvent::TargetOpened { height },
+            Some(nonce) => GuessEvent::TargetConfirmed { height, nonce },
+        };
+        self.append_guess_event(Utc::now(), &event)?;
+        self.set_target(height, nonce)
+    }
+
+    /// Move a target and its guesses to a new height, the new target has no nonce.
+    fn replace_target(&mut self, old_height: u32, new_height: u32) -> Result<(), InternalError> {
+        let event = GuessEvent::TargetReplaced {
+            old_height,
+            new_height,
+        };
+        self.append_guess_event(Utc::now(), &event)?;
+        self.move_target(old_height, new_height)
+    }
+
+    fn remove_target_nonce(&mut self, height: u32) -> Result<Option<u32>, InternalError> {
+        self.append_guess_event(Utc::now(), &GuessEvent::TargetRemoved { height })?;
+        self.delete_target(height)
+    }
+
+    /// Remove all targets and their guesses, returns the number of targets removed.
+    fn remove_all_targets(&mut self) -> Result<usize, InternalError> {
+        self.append_guess_event(Utc::now(), &GuessEvent::AllTargetsRemoved)?;
+        self.delete_all_targets()
+    }
+
+    /// Insert a guess, unless its nonce was already guessed or the player already guessed the
+    /// target. Checked before the guess is logged, so the log only has guesses that were made.
+    fn insert_guess(&mut self, height: u32, guess: Guess) -> Result<(), GuessError> {
+        for existing in self.target_guesses_for_update(height)? {
+            if existing.nonce == guess.nonce {
+                return Err(GuessError::DuplicateNonce(format!("{:08x}", guess.nonce)));
+            }
+            // guesses of deleted accounts are all kept under the same player
+            if existing.player == guess.player && guess.player != DELETED_PLAYER {
+                return Err(GuessError::DuplicateGuess(height));
+            }
+        }
+        let event = GuessEvent::GuessPlaced {
+            height,
+            guess: guess.clone(),
+        };
+        self.append_guess_event(Utc::now(), &event)?;
+        self.add_guess(height, guess)?;
+        Ok(())
+    }
+
+    /// Remove the guesses for a target height, returns the number of guesses removed.
+    fn remove_target_guesses(&mut self, height: u32) -> Result<usize, InternalError> {
+        self.append_guess_event(Utc::now(), &GuessEvent::TargetGuessesRemoved { height })?;
+        self.delete_target_guesses(height)
+    }
+
+    /// Remove a player's guesses, or if `anonymize` keep them as
+    /// [`crate::guess::types::DELETED_PLAYER`] guesses. Returns the number of guesses changed.
+    fn remove_player_guesses(
+        &mut self,
+        player_uuid: Uuid,
+        anonymize: bool,
+    ) -> Result<usize, InternalError> {
+        let event = GuessEvent::PlayerGuessesRemoved {
+            player: player_uuid,
+            anonymize,
+        };
+        self.append_guess_event(Utc::now(), &event)?;
+        self.delete_player_guesses(player_uuid, anonymize)
+    }
+
+    /// Rebuild the target and guess tables by replaying the guess event log, returns the
+    /// number of events replayed.
+    fn rebuild_guess_projection(&mut self) -> Result<usize, InternalError> {
+        let log = self.guess_log_for_update()?;
+        self.delete_all_targets()?;
+        for entry in &log {
+            self.apply_guess_event(&entry.event)?;
+        }
+        Ok(log.len())
+    }
+}
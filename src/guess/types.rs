@@ -1,7 +1,11 @@
 use crate::types::InternalError;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// The player of guesses kept after their player deleted their account.
+pub const DELETED_PLAYER: Uuid = Uuid::nil();
+
 /// A players guess for a target block nonce.
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct Guess {
@@ -9,6 +13,54 @@ pub struct Guess {
     pub nonce: u32,
 }
 
+/// A change of the targets and guesses. Every change is appended to the guess event log, the
+/// target and guess tables are its projection.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(tag = "type")]
+pub enum GuessEvent {
+    /// A new target, or an existing target's nonce is cleared.
+    TargetOpened {
+        height: u32,
+    },
+    /// The nonce of the target's block.
+    TargetConfirmed {
+        height: u32,
+        nonce: u32,
+    },
+    /// A target and its guesses moved to a new height, the new target has no nonce.
+    TargetReplaced {
+        old_height: u32,
+        new_height: u32,
+    },
+    /// A target removed, its guesses are kept.
+    TargetRemoved {
+        height: u32,
+    },
+    /// All targets and guesses removed.
+    AllTargetsRemoved,
+    /// A guess for a target, it replaces a guess with the same nonce.
+    GuessPlaced {
+        height: u32,
+        guess: Guess,
+    },
+    TargetGuessesRemoved {
+        height: u32,
+    },
+    /// A player's guesses removed, or kept as [`DELETED_PLAYER`] guesses if `anonymize`.
+    PlayerGuessesRemoved {
+        player: Uuid,
+        anonymize: bool,
+    },
+}
+
+/// An event of the guess event log, with its position in the log.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct GuessLogEntry {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub event: GuessEvent,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum GuessError {
     #[error("player already made a guess for target height: {0}")]
@@ -43,8 +95,9 @@ pub struct Block {
 
 #[cfg(test)]
 mod test {
-    use crate::guess::types::Guess;
-    use redb::Value;
+    use crate::encoding::Encoded;
+    use crate::guess::types::{Guess, GuessEvent, GuessLogEntry};
+    use chrono::Utc;
     use uuid::Uuid;
 
     #[test]
@@ -53,8 +106,27 @@ mod test {
             player: Uuid::new_v4(),
             nonce: 12345678,
         };
-        let encoded_guess = Guess::as_bytes(&orig_guess);
-        let decoded_guess = Guess::from_bytes(&encoded_guess);
+        let encoded_guess = Encoded::new(&orig_guess);
+        let decoded_guess = encoded_guess.decode().unwrap();
         assert_eq!(orig_guess, decoded_guess);
     }
+
+    #[test]
+    fn test_guess_log_entry_encode_decode() {
+        let orig_entry = GuessLogEntry {
+            seq: 7,
+            timestamp: Utc::now(),
+            event: GuessEvent::GuessPlaced {
+                height: 880000,
+                guess: Guess {
+                    player: Uuid::new_v4(),
+                    nonce: 12345678,
+                },
+            },
+        };
+        let encoded_entry = Encoded::new(&orig_entry);
+        assert_eq!(orig_entry, encoded_entry.decode().unwrap());
+        let json = serde_json::to_string(&GuessEvent::AllTargetsRemoved).unwrap();
+        assert_eq!(json, r#"{"type":"AllTargetsRemoved"}"#);
+    }
 }
@@ -1,5 +1,6 @@
 use super::types::{Guess, GuessError, TargetError};
 use crate::app::AppState;
+use crate::audit::types::{AuditAction, AuditEvent};
 use crate::auth::backend::{AuthBackend, AuthSession};
 use crate::auth::types::Permission;
 use crate::types::InternalError;
@@ -17,6 +18,8 @@ use std::sync::Arc;
 use tracing::{error, info};
 use uuid::Uuid;
 
+const DELETED_PLAYER_NAME: &str = "[deleted]";
+
 pub fn router() -> Router<Arc<AppState>> {
     Router::new()
         .route("/target", post(target_form))
@@ -133,7 +136,11 @@ async fn guesses(
         guesses
             .into_iter()
             .map(|guess| {
-                let player_name = players.get(&guess.player).expect("player name").clone();
+                // guesses kept after a player deleted their account have no player
+                let player_name = players
+                    .get(&guess.player)
+                    .cloned()
+                    .unwrap_or_else(|| DELETED_PLAYER_NAME.to_string());
                 let nonce_hex = format!("{:x}", guess.nonce);
                 let nonce_decimal = guess.nonce;
                 GuessTableData {
@@ -272,6 +279,7 @@ pub struct TargetForm {
 }
 
 pub async fn target_form(
+    auth_session: AuthSession,
     State(app_state): State<Arc<AppState>>,
     Form(target_form): Form<TargetForm>,
 ) -> Result<impl IntoResponse, TargetError> {
@@ -280,7 +288,7 @@ pub async fn target_form(
         .get_last_target_nonce()
         .await
         .map_err(Into::<TargetError>::into)?;
-    match current_target {
+    let action = match current_target {
         None => {
             app_state
                 .guess_backend
@@ -288,6 +296,7 @@ pub async fn target_form(
                 .await
                 .map_err(Into::<TargetError>::into)?;
             info!("Created new target at height {}", target_form.height);
+            AuditAction::TargetCreate
         }
         Some((height, Some(_nonce))) if target_form.height > height => {
             app_state
@@ -296,6 +305,7 @@ pub async fn target_form(
                 .await
                 .map_err(Into::<TargetError>::into)?;
             info!("Created new target at height {}", target_form.height);
+            AuditAction::TargetCreate
         }
         Some((_height, Some(_nonce))) => {
             // new height is less than or equal to current height
@@ -313,12 +323,28 @@ pub async fn target_form(
                 "Replaced target at height {} with new target at height {}",
                 height, target_form.height
             );
+            AuditAction::TargetReplace
         }
         Some((_height, None)) => {
             // new height is less than or equal to current height
             return Err(TargetError::InvalidHeight(target_form.height));
         }
-    }
+    };
+    let event = AuditEvent {
+        subject: Some("target".to_string()),
+        before: current_target.map(|(height, nonce)| match nonce {
+            Some(nonce) => format!("height {} nonce {:08x}", height, nonce),
+            None => format!("height {}", height),
+        }),
+        after: Some(format!("height {}", target_form.height)),
+        ..AuditEvent::new(action, auth_session.user.as_ref())
+    };
+    auth_session
+        .backend
+        .audit_log
+        .record(event)
+        .await
+        .map_err(Into::<TargetError>::into)?;
     let response = StatusCode::OK.into_response();
     Ok(response)
 }
@@ -1,15 +1,31 @@
 use crate::app::App;
+use crate::auth::config::{env_secret, AuthConfig};
+use crate::backup::BackupConfig;
+use crate::dump::ImportMode;
+use crate::encryption::Keyring;
+use crate::fsck::FsckMode;
+use crate::storage::StorageBackend;
 use reqwest::Url;
 use std::path::PathBuf;
+use std::str::FromStr;
 use tracing::debug;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 
+pub mod admin;
 pub mod app;
+pub mod audit;
 pub mod auth;
+mod backup;
+mod dump;
+mod encoding;
+mod encryption;
+mod fsck;
 pub mod guess;
+mod migration;
 mod session_store;
+mod storage;
 mod types;
 
 #[tokio::main]
@@ -24,10 +40,83 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // get database file name from env
     let database_file = std::env::var("NONCE_GUESS_DB_FILE").ok().map(PathBuf::from);
     debug!("database_file: {:?}", &database_file);
+    let backend = std::env::var("NONCE_GUESS_DB_BACKEND")
+        .ok()
+        .filter(|backend| !backend.is_empty())
+        .map(|backend| StorageBackend::from_str(&backend))
+        .transpose()?
+        .unwrap_or_default();
+    debug!("backend: {}", backend);
+    // seal sensitive redb values with the encryption keys, before the database is opened
+    if let Some(keyring) = Keyring::from_env()? {
+        if backend != StorageBackend::Redb {
+            let error = format!(
+                "encryption at rest is not supported by the {} backend",
+                backend
+            );
+            return Err(error.into());
+        }
+        debug!("encryption keys: {:?}", &keyring);
+        encryption::install(keyring)?;
+    }
+    // the postgres connection url, it may contain a password so it is not logged
+    let database_url = env_secret("NONCE_GUESS_DB_URL");
     let mempool_url = std::env::var("NONCE_GUESS_MEMPOOL_URL")
         .ok()
         .map(|url| Url::parse(url.as_str()))
         .transpose()?;
     debug!("mempool_url: {:?}", &database_file);
-    App::new(database_file, mempool_url).await?.serve().await
+    // only report the pending schema migrations
+    if std::env::var("NONCE_GUESS_MIGRATE_DRY_RUN").is_ok_and(|dry_run| dry_run == "true") {
+        return App::dry_run_migrations(database_file, backend);
+    }
+    // replace the database with a backup snapshot before it is opened
+    if let Some(restore_file) = std::env::var("NONCE_GUESS_RESTORE_FILE")
+        .ok()
+        .filter(|file| !file.is_empty())
+    {
+        App::restore(database_file.clone(), backend, PathBuf::from(restore_file))?;
+    }
+    let auth_config = AuthConfig::from_env()?;
+    debug!("auth_config: {:?}", &auth_config);
+    let backup_config = BackupConfig::from_env();
+    debug!("backup_config: {:?}", &backup_config);
+    let app = App::new(
+        database_file,
+        database_url,
+        backend,
+        mempool_url,
+        auth_config,
+        backup_config,
+    )
+    .await?;
+    // write or read a game data dump instead of serving
+    if let Some(export_file) = std::env::var("NONCE_GUESS_EXPORT_FILE")
+        .ok()
+        .filter(|file| !file.is_empty())
+    {
+        let secrets =
+            std::env::var("NONCE_GUESS_EXPORT_SECRETS").is_ok_and(|secrets| secrets == "true");
+        return app.export_data(PathBuf::from(export_file), secrets);
+    }
+    if let Some(import_file) = std::env::var("NONCE_GUESS_IMPORT_FILE")
+        .ok()
+        .filter(|file| !file.is_empty())
+    {
+        let mode = std::env::var("NONCE_GUESS_IMPORT_MODE")
+            .ok()
+            .filter(|mode| !mode.is_empty())
+            .map(|mode| ImportMode::from_str(&mode))
+            .transpose()?
+            .unwrap_or(ImportMode::Merge);
+        return app.import_data(PathBuf::from(import_file), mode);
+    }
+    // check or repair the database integrity before serving
+    if let Some(mode) = std::env::var("NONCE_GUESS_FSCK")
+        .ok()
+        .filter(|mode| !mode.is_empty())
+    {
+        app.check_database(FsckMode::from_str(&mode)?)?;
+    }
+    app.serve().await
 }
@@ -0,0 +1,478 @@
+use crate::auth::backend::AuthBackend;
+use crate::backup::copy_table;
+use crate::guess::backend::GuessBackend;
+use crate::types::InternalError;
+use redb::{Database, ReadTransaction, ReadableTable, TableDefinition, WriteTransaction};
+use std::fmt::{Display, Formatter};
+use tracing::info;
+
+const COMPONENT_VERSION: TableDefinition<&str, u32> = TableDefinition::new("schema_version");
+
+/// Schema component of the game tables.
+pub const APP_SCHEMA: &str = "nonce_guess";
+
+/// Ordered game schema migrations, append new migrations with the next version.
+pub const APP_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "baseline schema, tables are created when the backends open them",
+        apply: |_| Ok(()),
+    },
+    Migration {
+        version: 2,
+        description: "case-insensitive player names, colliding names are renamed",
+        apply: AuthBackend::index_player_names,
+    },
+    Migration {
+        version: 3,
+        description: "guess event log, started with the current targets and guesses",
+        apply: GuessBackend::seed_guess_log,
+    },
+    Migration {
+        version: 4,
+        description: "invite codes are stored by their hash",
+        apply: AuthBackend::hash_invite_codes,
+    },
+];
+
+/// Copy the schema versions into another database.
+pub fn copy_schema_table(
+    read_txn: &ReadTransaction,
+    write_txn: &WriteTransaction,
+) -> Result<(), InternalError> {
+    copy_table(read_txn, write_txn, COMPONENT_VERSION)
+}
+
+/// A schema change applied once, in version order. Migrations also run on new empty
+/// databases, so they must not expect any table to exist.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub apply: fn(&WriteTransaction) -> Result<(), InternalError>,
+}
+
+/// The migrations a run applied, or would apply in a dry run.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MigrationReport {
+    pub component: String,
+    /// Schema version before the run, `None` for databases without a version.
+    pub from_version: Option<u32>,
+    pub to_version: u32,
+    pub applied: Vec<(u32, String)>,
+    pub dry_run: bool,
+}
+
+impl Display for MigrationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let from_version = self
+            .from_version
+            .map(|version| version.to_string())
+            .unwrap_or("none".to_string());
+        if self.applied.is_empty() {
+            return write!(
+                f,
+                "{} schema version {} is up to date",
+                self.component, from_version
+            );
+        }
+        let applied = self
+            .applied
+            .iter()
+            .map(|(version, description)| format!("{} ({})", version, description))
+            .collect::<Vec<String>>()
+            .join(", ");
+        write!(
+            f,
+            "{} schema version {} to {}{}: {}",
+            self.component,
+            from_version,
+            self.to_version,
+            if self.dry_run {
+                " would apply"
+            } else {
+                " applied"
+            },
+            applied
+        )
+    }
+}
+
+/// Bring a component's schema up to the latest of `migrations`, all pending migrations are
+/// applied in one write transaction. A dry run applies them and then aborts the transaction.
+/// A schema newer than the latest known migration is refused, an older server would
+/// otherwise misread the data.
+pub fn migrate(
+    db: &Database,
+    component: &str,
+    migrations: &[Migration],
+    dry_run: bool,
+) -> Result<MigrationReport, InternalError> {
+    debug_assert!(
+        migrations
+            .windows(2)
+            .all(|pair| pair[0].version < pair[1].version),
+        "migrations are in version order"
+    );
+    let latest = migrations
+        .last()
+        .map(|migration| migration.version)
+        .unwrap_or(0);
+    let write_txn = db.begin_write()?;
+    let from_version = write_txn
+        .open_table(COMPONENT_VERSION)?
+        .get(component)?
+        .map(|ag| ag.value());
+    let current = from_version.unwrap_or(0);
+    if current > latest {
+        return Err(InternalError::NewerSchema(
+            component.to_string(),
+            current,
+            latest,
+        ));
+    }
+    let mut applied = Vec::new();
+    for migration in migrations
+        .iter()
+        .filter(|migration| migration.version > current)
+    {
+        (migration.apply)(&write_txn).map_err(|e| {
+            InternalError::Migration(component.to_string(), migration.version, Box::new(e))
+        })?;
+        applied.push((migration.version, migration.description.to_string()));
+    }
+    if !applied.is_empty() {
+        write_txn
+            .open_table(COMPONENT_VERSION)?
+            .insert(component, latest)?;
+    }
+    let report = MigrationReport {
+        component: component.to_string(),
+        from_version,
+        to_version: latest.max(current),
+        applied,
+        dry_run,
+    };
+    if dry_run {
+        write_txn.abort()?;
+    } else {
+        write_txn.commit()?;
+    }
+    info!("{}", report);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        migrate, Migration, MigrationReport, APP_MIGRATIONS, APP_SCHEMA, COMPONENT_VERSION,
+    };
+    use crate::audit::types::{AuditAction, AuditFilter};
+    use crate::auth::backend::AuthBackend;
+    use crate::auth::config::AuthConfig;
+    use crate::auth::types::{hash_token, InviteCode, Player};
+    use crate::encoding::Encoded;
+    use crate::guess::types::{Guess, GuessEvent};
+    use crate::storage::redb::RedbStorage;
+    use crate::storage::Storage;
+    use crate::types::{InternalError, UuidKey};
+    use chrono::{TimeDelta, Utc};
+    use redb::{Database, ReadableTable, TableDefinition, TableError, WriteTransaction};
+    use std::sync::Arc;
+    use tempfile::NamedTempFile;
+    use uuid::Uuid;
+
+    const NAME_SCORE: TableDefinition<&str, u32> = TableDefinition::new("test_name_score");
+    // the baseline player tables, names were indexed as typed
+    const UUID_PLAYER: TableDefinition<UuidKey, Encoded<Player>> =
+        TableDefinition::new("auth_uuid_player");
+    const NAME_UUID: TableDefinition<String, UuidKey> =
+        TableDefinition::new("auth_player_name_uuid");
+    const NAME_LEVEL: TableDefinition<&str, u32> = TableDefinition::new("test_name_level");
+
+    // the schema version of a component, `None` if it was never migrated
+    fn schema_version(db: &Database, component: &str) -> Result<Option<u32>, InternalError> {
+        let read_txn = db.begin_read()?;
+        let component_version = match read_txn.open_table(COMPONENT_VERSION) {
+            Ok(component_version) => component_version,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let version = component_version.get(component)?.map(|ag| ag.value());
+        Ok(version)
+    }
+
+    fn temp_db() -> Arc<Database> {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        Arc::new(Database::create(file).unwrap())
+    }
+
+    fn create_scores(write_txn: &WriteTransaction) -> Result<(), InternalError> {
+        let mut name_score = write_txn.open_table(NAME_SCORE)?;
+        name_score.insert("alice", 250)?;
+        name_score.insert("bob", 1200)?;
+        Ok(())
+    }
+
+    // replace the scores table with levels derived from the scores
+    fn scores_to_levels(write_txn: &WriteTransaction) -> Result<(), InternalError> {
+        let name_score = write_txn.open_table(NAME_SCORE)?;
+        let mut name_level = write_txn.open_table(NAME_LEVEL)?;
+        for result in name_score.iter()? {
+            let (name, score) = result?;
+            name_level.insert(name.value(), score.value() / 100)?;
+        }
+        drop(name_score);
+        write_txn.delete_table(NAME_SCORE)?;
+        Ok(())
+    }
+
+    fn fail(_: &WriteTransaction) -> Result<(), InternalError> {
+        Err(InternalError::NewName("test".to_string(), 0))
+    }
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            description: "create scores",
+            apply: create_scores,
+        },
+        Migration {
+            version: 2,
+            description: "scores to levels",
+            apply: scores_to_levels,
+        },
+    ];
+
+    fn level(db: &Database, name: &str) -> Option<u32> {
+        let read_txn = db.begin_read().unwrap();
+        let name_level = read_txn.open_table(NAME_LEVEL).ok()?;
+        let level = name_level.get(name).unwrap().map(|ag| ag.value());
+        level
+    }
+
+    #[test]
+    fn test_migrate() {
+        let db = temp_db();
+        assert_eq!(schema_version(&db, "test").unwrap(), None);
+        let report = migrate(&db, "test", &MIGRATIONS[..1], false).unwrap();
+        assert_eq!(
+            report,
+            MigrationReport {
+                component: "test".to_string(),
+                from_version: None,
+                to_version: 1,
+                applied: vec![(1, "create scores".to_string())],
+                dry_run: false,
+            }
+        );
+        assert_eq!(schema_version(&db, "test").unwrap(), Some(1));
+
+        // only pending migrations are applied
+        let report = migrate(&db, "test", MIGRATIONS, false).unwrap();
+        assert_eq!(report.from_version, Some(1));
+        assert_eq!(report.applied, vec![(2, "scores to levels".to_string())]);
+        assert_eq!(level(&db, "alice"), Some(2));
+        assert_eq!(level(&db, "bob"), Some(12));
+        let report = migrate(&db, "test", MIGRATIONS, false).unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(schema_version(&db, "test").unwrap(), Some(2));
+        // components are versioned separately
+        assert_eq!(schema_version(&db, "other").unwrap(), None);
+    }
+
+    #[test]
+    fn test_migrate_dry_run() {
+        let db = temp_db();
+        let report = migrate(&db, "test", MIGRATIONS, true).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.to_version, 2);
+        assert_eq!(report.applied.len(), 2);
+        assert_eq!(schema_version(&db, "test").unwrap(), None);
+        assert_eq!(level(&db, "alice"), None);
+    }
+
+    #[test]
+    fn test_migrate_failure_rolls_back() {
+        let db = temp_db();
+        let migrations = [
+            Migration {
+                version: 1,
+                description: "create scores",
+                apply: create_scores,
+            },
+            Migration {
+                version: 2,
+                description: "fail",
+                apply: fail,
+            },
+        ];
+        let result = migrate(&db, "test", &migrations, false);
+        assert!(matches!(result, Err(InternalError::Migration(_, 2, _))));
+        assert_eq!(schema_version(&db, "test").unwrap(), None);
+        let read_txn = db.begin_read().unwrap();
+        assert!(read_txn.open_table(NAME_SCORE).is_err());
+    }
+
+    #[test]
+    fn test_migrate_refuses_newer_schema() {
+        let db = temp_db();
+        migrate(&db, "test", MIGRATIONS, false).unwrap();
+        let result = migrate(&db, "test", &MIGRATIONS[..1], false);
+        assert!(matches!(result, Err(InternalError::NewerSchema(_, 2, 1))));
+        assert!(migrate(&db, "test", &MIGRATIONS[..1], true).is_err());
+    }
+
+    #[test]
+    fn test_migrate_player_names() {
+        let db = temp_db();
+        migrate(&db, APP_SCHEMA, &APP_MIGRATIONS[..1], false).unwrap();
+        let storage = RedbStorage::new(db.clone()).unwrap();
+        let alice = Player {
+            uuid: Uuid::new_v4(),
+            name: "alice".to_string(),
+            created: Utc::now() - TimeDelta::days(1),
+            ..Default::default()
+        };
+        let upper_alice = Player {
+            uuid: Uuid::new_v4(),
+            name: "Alice".to_string(),
+            ..Default::default()
+        };
+        let write_txn = db.begin_write().unwrap();
+        {
+            let mut uuid_player = write_txn.open_table(UUID_PLAYER).unwrap();
+            let mut name_uuid = write_txn.open_table(NAME_UUID).unwrap();
+            for player in [&alice, &upper_alice] {
+                uuid_player
+                    .insert(
+                        &UuidKey(player.uuid),
+                        &Encoded::keyed(player.uuid.as_bytes(), player),
+                    )
+                    .unwrap();
+                name_uuid
+                    .insert(player.name.clone(), &UuidKey(player.uuid))
+                    .unwrap();
+            }
+        }
+        write_txn.commit().unwrap();
+
+        // the newer player's name collides and is renamed
+        migrate(&db, APP_SCHEMA, APP_MIGRATIONS, false).unwrap();
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(read_txn.get_player_by_name("ALICE").unwrap(), Some(alice));
+        let renamed = read_txn
+            .get_player_by_uuid(upper_alice.uuid)
+            .unwrap()
+            .unwrap();
+        assert_eq!(renamed.name, "Alice2");
+        assert!(renamed.renamed);
+        assert_eq!(
+            read_txn.get_player_by_name("alice2").unwrap(),
+            Some(renamed)
+        );
+        // the rename is recorded in the audit log
+        let events = read_txn
+            .get_events(&AuditFilter {
+                action: Some(AuditAction::NameChange),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].subject, Some("Alice2".to_string()));
+        assert_eq!(events[0].before, Some("Alice".to_string()));
+    }
+
+    #[test]
+    fn test_migrate_guess_log() {
+        let db = temp_db();
+        migrate(&db, APP_SCHEMA, &APP_MIGRATIONS[..2], false).unwrap();
+        let storage = RedbStorage::new(db.clone()).unwrap();
+        // targets and guesses from before the log, changed without logging them
+        let mut write_txn = storage.begin_write().unwrap();
+        write_txn.set_target(100, Some(42)).unwrap();
+        write_txn.set_target(200, None).unwrap();
+        let guess = Guess {
+            player: Uuid::new_v4(),
+            nonce: 7,
+        };
+        write_txn.add_guess(200, guess.clone()).unwrap();
+        write_txn.commit().unwrap();
+
+        migrate(&db, APP_SCHEMA, APP_MIGRATIONS, false).unwrap();
+        let read_txn = storage.begin_read().unwrap();
+        let events = read_txn
+            .iter_guess_log()
+            .unwrap()
+            .map(|entry| entry.map(|entry| entry.event))
+            .collect::<Result<Vec<GuessEvent>, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                GuessEvent::TargetOpened { height: 100 },
+                GuessEvent::TargetConfirmed {
+                    height: 100,
+                    nonce: 42
+                },
+                GuessEvent::TargetOpened { height: 200 },
+                GuessEvent::GuessPlaced { height: 200, guess },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_migrate_invite_codes() {
+        let db = temp_db();
+        migrate(&db, APP_SCHEMA, &APP_MIGRATIONS[..3], false).unwrap();
+        let storage = RedbStorage::new(db.clone()).unwrap();
+        // an invite code from before they were hashed, keyed by the plain code
+        let invite_code = InviteCode {
+            code_hash: "plaincode".to_string(),
+            max_uses: None,
+            uses: 0,
+            expires: None,
+            role: None,
+            created_by: Uuid::new_v4(),
+            created: Utc::now(),
+        };
+        let mut write_txn = storage.begin_write().unwrap();
+        write_txn.insert_invite_code(invite_code.clone()).unwrap();
+        write_txn.commit().unwrap();
+
+        // the code keeps working, looked up by its hash
+        migrate(&db, APP_SCHEMA, APP_MIGRATIONS, false).unwrap();
+        let mut write_txn = storage.begin_write().unwrap();
+        assert!(write_txn
+            .use_invite_code("plaincode", Utc::now())
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            write_txn
+                .use_invite_code(&hash_token("plaincode"), Utc::now())
+                .unwrap(),
+            Some(InviteCode {
+                code_hash: hash_token("plaincode"),
+                uses: 1,
+                ..invite_code
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_app_migrations() {
+        // a database created before schema versions were tracked
+        let db = temp_db();
+        let backend = AuthBackend::new(db.clone(), &AuthConfig::default()).unwrap();
+        let players = backend.get_players().await.unwrap();
+        assert_eq!(schema_version(&db, APP_SCHEMA).unwrap(), None);
+
+        let report = migrate(&db, APP_SCHEMA, APP_MIGRATIONS, false).unwrap();
+        assert_eq!(report.from_version, None);
+        assert_eq!(
+            schema_version(&db, APP_SCHEMA).unwrap(),
+            APP_MIGRATIONS.last().map(|migration| migration.version)
+        );
+        let backend = AuthBackend::new(db, &AuthConfig::default()).unwrap();
+        assert_eq!(backend.get_players().await.unwrap(), players);
+    }
+}
@@ -1,5 +1,20 @@
+use crate::auth::backend::AuthBackend;
+use crate::backup::{copy_multimap_table, copy_table};
+use crate::encoding::{
+    find_undecodable, quarantine_table, reencrypt_table, Encoded, ReencryptProgress, Versioned,
+};
+use crate::encryption::Keyring;
+use crate::migration::Migration;
+use crate::storage::lease::Lease;
+use crate::storage::redb::RedbStorage;
+use crate::storage::{Storage, UndecodableRecord};
+use crate::types::InternalError;
 use async_trait::async_trait;
-use redb::{Database, Key, TableDefinition, TypeName, Value};
+use redb::{
+    Database, Key, MultimapTableDefinition, ReadTransaction, ReadableMultimapTable, ReadableTable,
+    TableDefinition, TypeName, Value, WriteTransaction,
+};
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::sync::Arc;
 use tokio::task::spawn_blocking;
@@ -9,191 +24,420 @@ use tower_sessions::{
     session::{Id, Record},
     session_store, ExpiredDeletion, SessionStore,
 };
-use tracing::warn;
+use tracing::{info, warn};
 // TODO: extract this into it's own lib
 
-const ID_RECORD: TableDefinition<IdKey, RecordValue> = TableDefinition::new("session_id_record");
+const ID_RECORD: TableDefinition<IdKey, Encoded<RecordValue>> =
+    TableDefinition::new("session_id_record");
+const USER_ID: MultimapTableDefinition<&str, IdKey> =
+    MultimapTableDefinition::new("session_user_id");
+
+/// Schema component of the session tables.
+pub const SESSION_SCHEMA: &str = "redb_session_store";
+
+/// Ordered session store schema migrations.
+pub const SESSION_MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    description: "index sessions by user id",
+    apply: StorageSessionStore::index_user_sessions,
+}];
+
+// the session data where axum-login keeps the logged in user, `{ "user_id": .. }`. axum-login
+// is pinned in Cargo.toml and `test_auth_session_user_id` checks the layout
+const AUTH_DATA_KEY: &str = "axum-login.data";
+
+/// Session records of a read transaction.
+pub trait SessionRead {
+    fn load_session(&self, id: Id) -> Result<Option<Record>, InternalError>;
+
+    /// The session records of a user, including expired records.
+    fn user_sessions(&self, user_id: &str) -> Result<Vec<Record>, InternalError>;
+}
+
+/// Session records of a write transaction, the user id index is kept in sync with the
+/// records.
+pub trait SessionWrite {
+    fn session_exists_for_update(&self, id: Id) -> Result<bool, InternalError>;
+
+    /// Insert or replace a record.
+    fn save_session(&mut self, record: &Record) -> Result<(), InternalError>;
+
+    fn delete_session(&mut self, id: Id) -> Result<(), InternalError>;
 
-/// Session store backed by redb
+    /// Delete all sessions of a user except the `keep` session, returns the number deleted.
+    fn delete_user_sessions(
+        &mut self,
+        user_id: &str,
+        keep: Option<Id>,
+    ) -> Result<usize, InternalError>;
+
+    /// Delete the sessions that expired before `now`, returns the number deleted.
+    fn delete_expired_sessions(&mut self, now: OffsetDateTime) -> Result<usize, InternalError>;
+}
+
+/// Session store backed by the app [`Storage`]
 #[derive(Debug, Clone)]
-pub struct RedbSessionStore {
-    // the redb database which should be used for storage
-    db: Arc<Database>,
+pub struct StorageSessionStore {
+    storage: Arc<dyn Storage>,
 }
 
-impl RedbSessionStore {
-    /// Create a new RedbStore using a [`Database`]
-    pub fn new(db: Arc<Database>) -> Self {
-        // TODO: call session store database migrations here
-        Self { db }
+impl StorageSessionStore {
+    /// Create a new session store using a redb [`Database`], migrating the session tables
+    /// first and quarantining records that can't be decoded.
+    pub fn new(db: Arc<Database>) -> Result<Self, InternalError> {
+        Ok(Self::with_storage(Arc::new(RedbStorage::new(db)?)))
+    }
+
+    pub fn with_storage(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    /// Create the redb session tables if needed and quarantine undecodable records, after
+    /// the [`SESSION_MIGRATIONS`] ran.
+    pub fn init_tables(write_txn: &mut WriteTransaction) -> Result<(), InternalError> {
+        quarantine_table(write_txn, ID_RECORD)?;
+        write_txn.open_multimap_table(USER_ID)?;
+        info!("opened tables: {}, {}", ID_RECORD, USER_ID);
+        Ok(())
+    }
+
+    /// The redb session records that can't be decoded.
+    pub fn find_undecodable(
+        read_txn: &ReadTransaction,
+    ) -> Result<Vec<UndecodableRecord>, InternalError> {
+        find_undecodable(read_txn, ID_RECORD)
+    }
+
+    /// Re-encrypt up to `limit` redb session records that aren't sealed with the keyring's
+    /// primary key.
+    pub fn reencrypt(
+        write_txn: &WriteTransaction,
+        keyring: Option<&Keyring>,
+        progress: &mut ReencryptProgress,
+        limit: usize,
+    ) -> Result<usize, InternalError> {
+        reencrypt_table(write_txn, ID_RECORD, keyring, progress, limit)
+    }
+
+    // rebuild the user id index, sessions saved before it existed weren't listed or deleted
+    // with the user's other sessions
+    fn index_user_sessions(write_txn: &WriteTransaction) -> Result<(), InternalError> {
+        let id_record = write_txn.open_table(ID_RECORD)?;
+        let mut user_id_table = write_txn.open_multimap_table(USER_ID)?;
+        for result in id_record.iter()? {
+            let (id_key, record) = result?;
+            let user_id = record
+                .value()
+                .decode_keyed(&IdKey::as_bytes(&id_key.value()))
+                .ok()
+                .and_then(|record| Self::record_user_id(&record.0));
+            if let Some(user_id) = user_id {
+                user_id_table.insert(user_id.as_str(), &id_key.value())?;
+            }
+        }
+        Ok(())
     }
 
+    /// Copy the session tables into another database.
+    pub fn copy_tables(
+        read_txn: &ReadTransaction,
+        write_txn: &WriteTransaction,
+    ) -> Result<(), InternalError> {
+        copy_table(read_txn, write_txn, ID_RECORD)?;
+        copy_multimap_table(read_txn, write_txn, USER_ID)
+    }
+
+    /// Delete expired sessions and prune stale login failures every `period`, only on the
+    /// replica holding the `lease`.
     pub async fn continuously_delete_expired(
         self,
         period: tokio::time::Duration,
+        lease: Lease,
+        auth_backend: AuthBackend,
     ) -> session_store::Result<()> {
         let mut interval = tokio::time::interval(period);
         interval.tick().await; // The first tick completes immediately; skip.
         loop {
             interval.tick().await;
-            self.delete_expired().await?;
+            let acquired = lease
+                .acquire()
+                .await
+                .map_err(|e| Error::Backend(e.to_string()))?;
+            if acquired {
+                self.delete_expired().await?;
+                let pruned = auth_backend
+                    .prune_login_failures()
+                    .await
+                    .map_err(|e| Error::Backend(e.to_string()))?;
+                if pruned > 0 {
+                    info!("pruned {} stale login failures", pruned);
+                }
+            }
         }
     }
 
-    pub fn save_blocking(db: Arc<Database>, record: Record) -> session_store::Result<()> {
-        let write_txn = db
-            .begin_write()
-            .map_err(|e| Error::Backend(e.to_string()))?;
-        {
-            let mut id_record = write_txn
-                .open_table(ID_RECORD)
-                .map_err(|e| Error::Backend(e.to_string()))?;
-            id_record
-                .insert(&IdKey(record.id), &RecordValue(record))
+    /// The logged in user id of a session record, if any.
+    pub fn record_user_id(record: &Record) -> Option<String> {
+        record
+            .data
+            .get(AUTH_DATA_KEY)
+            .and_then(|data| data.get("user_id"))
+            .and_then(|user_id| user_id.as_str())
+            .map(ToString::to_string)
+    }
+
+    /// The unexpired session records of a user.
+    pub async fn user_sessions(&self, user_id: &str) -> session_store::Result<Vec<Record>> {
+        let storage = self.storage.clone();
+        let user_id = user_id.to_owned();
+        spawn_blocking(move || {
+            let now = OffsetDateTime::now_utc();
+            let records = storage
+                .begin_read()
+                .and_then(|read_txn| read_txn.user_sessions(&user_id))
                 .map_err(|e| Error::Backend(e.to_string()))?;
-        }
-        write_txn
-            .commit()
-            .map_err(|e| Error::Backend(e.to_string()))?;
-        Ok::<(), Error>(())
-    }
-
-    fn load_blocking(db: Arc<Database>, id_key: IdKey) -> session_store::Result<Option<Record>> {
-        let read_txn = db.begin_read().map_err(|e| Error::Backend(e.to_string()))?;
-        let id_record = read_txn
-            .open_table(ID_RECORD)
-            .map_err(|e| Error::Backend(e.to_string()))?;
-        let opt_record = id_record
-            .get(&id_key)
-            .map_err(|e| Error::Backend(e.to_string()))?
-            .map(|ag| ag.value().0);
-        Ok(opt_record)
+            Ok(records
+                .into_iter()
+                .filter(|record| record.expiry_date >= now)
+                .collect())
+        })
+        .await
+        .map_err(|e| Error::Backend(e.to_string()))?
+    }
+
+    /// Delete all sessions of a user except the `keep` session, returns the number deleted.
+    pub async fn delete_user_sessions(
+        &self,
+        user_id: &str,
+        keep: Option<Id>,
+    ) -> session_store::Result<usize> {
+        let storage = self.storage.clone();
+        let user_id = user_id.to_owned();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            let deleted = write_txn.delete_user_sessions(&user_id, keep)?;
+            write_txn.commit()?;
+            Ok::<usize, InternalError>(deleted)
+        })
+        .await
+        .map_err(|e| Error::Backend(e.to_string()))?
+        .map_err(|e| Error::Backend(e.to_string()))
     }
 }
 
 #[async_trait]
-impl SessionStore for RedbSessionStore {
+impl SessionStore for StorageSessionStore {
     async fn create(&self, record: &mut Record) -> session_store::Result<()> {
-        let db = self.db.clone();
-        let mut record = record.clone();
-        spawn_blocking(move || {
-            let mut id_key = IdKey(record.id);
-            while Self::load_blocking(db.clone(), id_key)
-                .unwrap_or(None)
-                .is_some()
-            {
+        let storage = self.storage.clone();
+        let mut new_record = record.clone();
+        let id = spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            while write_txn.session_exists_for_update(new_record.id)? {
                 // Session ID collision mitigation.
-                warn!("session record id collision: {}", &record.id.0);
-                record.id = Id::default();
-                id_key = IdKey(record.id);
+                warn!("session record id collision: {}", &new_record.id.0);
+                new_record.id = Id::default();
             }
-            Self::save_blocking(db.clone(), record)
+            write_txn.save_session(&new_record)?;
+            write_txn.commit()?;
+            Ok::<Id, InternalError>(new_record.id)
         })
         .await
         .map_err(|e| Error::Backend(e.to_string()))?
+        .map_err(|e| Error::Backend(e.to_string()))?;
+        record.id = id;
+        Ok(())
     }
 
     async fn save(&self, record: &Record) -> session_store::Result<()> {
-        let db = self.db.clone();
+        let storage = self.storage.clone();
         let record = record.clone();
-        spawn_blocking(move || RedbSessionStore::save_blocking(db, record))
-            .await
-            .map_err(|e| Error::Backend(e.to_string()))?
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            write_txn.save_session(&record)?;
+            write_txn.commit()
+        })
+        .await
+        .map_err(|e| Error::Backend(e.to_string()))?
+        .map_err(|e| Error::Backend(e.to_string()))
     }
 
     async fn load(&self, id: &Id) -> session_store::Result<Option<Record>> {
-        let db = self.db.clone();
-        let id_key = IdKey(*id);
-        spawn_blocking(move || match RedbSessionStore::load_blocking(db, id_key) {
-            Ok(r) => Ok(r),
-            Err(Error::Backend(_)) => Ok(None),
-            Err(e) => Err(e),
+        let storage = self.storage.clone();
+        let id = *id;
+        spawn_blocking(move || {
+            // a session that can't be read is the same as no session
+            Ok(storage
+                .begin_read()
+                .and_then(|read_txn| read_txn.load_session(id))
+                .unwrap_or(None))
         })
         .await
         .map_err(|e| Error::Backend(e.to_string()))?
     }
 
     async fn delete(&self, id: &Id) -> session_store::Result<()> {
-        let db = self.db.clone();
-        let id_key = IdKey(*id);
+        let storage = self.storage.clone();
+        let id = *id;
         spawn_blocking(move || {
-            let write_txn = db
-                .begin_write()
-                .map_err(|e| Error::Backend(e.to_string()))?;
-            {
-                let mut id_record = write_txn
-                    .open_table(ID_RECORD)
-                    .map_err(|e| Error::Backend(e.to_string()))?;
-                id_record
-                    .remove(&id_key)
-                    .map_err(|e| Error::Backend(e.to_string()))?;
-            }
-            write_txn
-                .commit()
-                .map_err(|e| Error::Backend(e.to_string()))?;
-            Ok(())
+            let mut write_txn = storage.begin_write()?;
+            write_txn.delete_session(id)?;
+            write_txn.commit()
         })
         .await
         .map_err(|e| Error::Backend(e.to_string()))?
+        .map_err(|e| Error::Backend(e.to_string()))
     }
 }
 
 #[async_trait]
-impl ExpiredDeletion for RedbSessionStore {
+impl ExpiredDeletion for StorageSessionStore {
     /// Deletes expired sessions from the session store
     async fn delete_expired(&self) -> session_store::Result<()> {
-        let db = self.db.clone();
-
-        spawn_blocking(move || -> session_store::Result<()> {
-            let now = OffsetDateTime::now_utc();
-            let write_txn = db
-                .begin_write()
-                .map_err(|e| Error::Backend(e.to_string()))?;
-            let mut id_record = write_txn
-                .open_table(ID_RECORD)
-                .map_err(|e| Error::Backend(e.to_string()))?;
-            id_record
-                .retain(|_, record| record.0.expiry_date >= now)
-                .map_err(|e| Error::Backend(e.to_string()))?;
-            Ok(())
+        let storage = self.storage.clone();
+        spawn_blocking(move || {
+            let mut write_txn = storage.begin_write()?;
+            write_txn.delete_expired_sessions(OffsetDateTime::now_utc())?;
+            write_txn.commit()
         })
         .await
-        .map_err(|e| session_store::Error::Backend(e.to_string()))?
+        .map_err(|e| Error::Backend(e.to_string()))?
+        .map_err(|e| Error::Backend(e.to_string()))
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-struct RecordValue(pub Record);
+// the decoded record of the table entry with the key `id_key`, if any
+fn decode_record(
+    id_key: &IdKey,
+    entry: Option<redb::AccessGuard<'_, Encoded<RecordValue>>>,
+) -> Result<Option<Record>, InternalError> {
+    Ok(entry
+        .map(|ag| ag.value().decode_keyed(&IdKey::as_bytes(id_key)))
+        .transpose()?
+        .map(|record_value| record_value.0))
+}
 
-impl Value for RecordValue {
-    type SelfType<'a> = RecordValue;
-    type AsBytes<'a> = Vec<u8>;
+impl SessionRead for ReadTransaction {
+    fn load_session(&self, id: Id) -> Result<Option<Record>, InternalError> {
+        let id_record = self.open_table(ID_RECORD)?;
+        let id_key = IdKey(id);
+        decode_record(&id_key, id_record.get(&id_key)?)
+    }
 
-    fn fixed_width() -> Option<usize> {
-        None
+    fn user_sessions(&self, user_id: &str) -> Result<Vec<Record>, InternalError> {
+        let user_id_table = self.open_multimap_table(USER_ID)?;
+        let id_record = self.open_table(ID_RECORD)?;
+        let mut records = Vec::new();
+        for id_key in user_id_table.get(user_id)? {
+            let id_key = id_key?.value();
+            records.extend(decode_record(&id_key, id_record.get(&id_key)?)?);
+        }
+        Ok(records)
     }
+}
 
-    fn from_bytes<'a>(serialized_record: &'a [u8]) -> Self::SelfType<'a>
-    where
-        Self: 'a,
-    {
-        let record: Record = ciborium::from_reader(serialized_record).unwrap();
-        RecordValue(record)
+// remove a record and its user id index entry
+fn delete_in_txn(write_txn: &WriteTransaction, id_key: &IdKey) -> Result<(), InternalError> {
+    let mut id_record = write_txn.open_table(ID_RECORD)?;
+    let user_id = id_record
+        .remove(id_key)?
+        .and_then(|ag| ag.value().decode_keyed(&IdKey::as_bytes(id_key)).ok())
+        .and_then(|record| StorageSessionStore::record_user_id(&record.0));
+    if let Some(user_id) = user_id {
+        let mut user_id_table = write_txn.open_multimap_table(USER_ID)?;
+        user_id_table.remove(user_id.as_str(), id_key)?;
     }
+    Ok(())
+}
 
-    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
-    where
-        Self: 'b,
-    {
-        let mut serialized_record = Vec::<u8>::new();
-        ciborium::into_writer(&value.0, &mut serialized_record)
-            .expect("Failed to serialize record");
-        serialized_record
+impl SessionWrite for WriteTransaction {
+    fn session_exists_for_update(&self, id: Id) -> Result<bool, InternalError> {
+        let id_record = self.open_table(ID_RECORD)?;
+        let exists = id_record.get(&IdKey(id))?.is_some();
+        Ok(exists)
     }
 
-    fn type_name() -> TypeName {
-        TypeName::new("redb_session_store::RecordValue")
+    fn save_session(&mut self, record: &Record) -> Result<(), InternalError> {
+        let mut id_record = self.open_table(ID_RECORD)?;
+        let user_id = StorageSessionStore::record_user_id(record);
+        let id_key = IdKey(record.id);
+        let key_bytes = IdKey::as_bytes(&id_key);
+        let orig_user_id = id_record
+            .insert(
+                &id_key,
+                &Encoded::keyed(&key_bytes, &RecordValue(record.clone())),
+            )?
+            .and_then(|ag| ag.value().decode_keyed(&key_bytes).ok())
+            .and_then(|orig_record| StorageSessionStore::record_user_id(&orig_record.0));
+        // keep the user id index in sync when a session logs in or out
+        if orig_user_id != user_id {
+            let mut user_id_table = self.open_multimap_table(USER_ID)?;
+            if let Some(orig_user_id) = orig_user_id {
+                user_id_table.remove(orig_user_id.as_str(), &IdKey(record.id))?;
+            }
+            if let Some(user_id) = user_id {
+                user_id_table.insert(user_id.as_str(), &IdKey(record.id))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_session(&mut self, id: Id) -> Result<(), InternalError> {
+        delete_in_txn(self, &IdKey(id))
     }
+
+    fn delete_user_sessions(
+        &mut self,
+        user_id: &str,
+        keep: Option<Id>,
+    ) -> Result<usize, InternalError> {
+        let id_keys = {
+            let user_id_table = self.open_multimap_table(USER_ID)?;
+            let id_keys = user_id_table
+                .get(user_id)?
+                .map(|result| result.map(|ag| ag.value()))
+                .collect::<Result<Vec<IdKey>, _>>()?;
+            id_keys
+                .into_iter()
+                .filter(|id_key| Some(id_key.0) != keep)
+                .collect::<Vec<IdKey>>()
+        };
+        for id_key in &id_keys {
+            delete_in_txn(self, id_key)?;
+        }
+        Ok(id_keys.len())
+    }
+
+    fn delete_expired_sessions(&mut self, now: OffsetDateTime) -> Result<usize, InternalError> {
+        let expired = {
+            let id_record = self.open_table(ID_RECORD)?;
+            let mut expired = Vec::new();
+            for result in id_record.iter()? {
+                let (id_key, record) = result?;
+                // undecodable records are left for the startup quarantine
+                let record = record
+                    .value()
+                    .decode_keyed(&IdKey::as_bytes(&id_key.value()));
+                if record.is_ok_and(|record| record.0.expiry_date < now) {
+                    expired.push(id_key.value());
+                }
+            }
+            expired
+        };
+        for id_key in &expired {
+            delete_in_txn(self, id_key)?;
+        }
+        Ok(expired.len())
+    }
+}
+
+// stored in the same form as the record, so records saved before the envelope still decode
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+struct RecordValue(pub Record);
+
+impl Versioned for RecordValue {
+    const TYPE_NAME: &'static str = "redb_session_store::RecordValue";
+    const ENCRYPTED: bool = true;
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -259,8 +503,9 @@ mod test {
             data,
             expiry_date: OffsetDateTime::now_utc().add(Duration::minutes(60)),
         });
-        let encoded_record_value = RecordValue::as_bytes(&orig_record_value);
-        let decoded_record_value = RecordValue::from_bytes(&encoded_record_value);
+        let key = IdKey::as_bytes(&IdKey(orig_record_value.0.id));
+        let encoded_record_value = Encoded::keyed(&key, &orig_record_value);
+        let decoded_record_value = encoded_record_value.decode_keyed(&key).unwrap();
         assert_eq!(orig_record_value, decoded_record_value);
     }
 
@@ -293,7 +538,7 @@ mod test {
     #[tokio::test]
     async fn test_create_load_save_record() {
         let db = temp_db();
-        let session_store = RedbSessionStore::new(db);
+        let session_store = StorageSessionStore::new(db).unwrap();
 
         // make sure no errors when loading from a new db
         assert_eq!(None, session_store.load(&Id::default()).await.unwrap());
@@ -360,7 +605,7 @@ mod test {
     #[tokio::test]
     async fn test_create_load_delete_record() {
         let db = temp_db();
-        let session_store = RedbSessionStore::new(db);
+        let session_store = StorageSessionStore::new(db).unwrap();
 
         let mut data1 = HashMap::new();
         data1.insert(
@@ -390,4 +635,229 @@ mod test {
             .expect("reloaded record1");
         assert_eq!(None, reloaded_record1);
     }
+
+    fn user_record(user_id: Option<&str>, expires_in: Duration) -> Record {
+        let mut data = HashMap::new();
+        if let Some(user_id) = user_id {
+            data.insert(
+                AUTH_DATA_KEY.to_string(),
+                serde_json::json!({ "user_id": user_id, "auth_hash": [1, 2, 3] }),
+            );
+        }
+        Record {
+            id: Default::default(),
+            data,
+            expiry_date: OffsetDateTime::now_utc().add(expires_in),
+        }
+    }
+
+    fn ids(records: Vec<Record>) -> Vec<Id> {
+        let mut ids = records
+            .into_iter()
+            .map(|record| record.id)
+            .collect::<Vec<Id>>();
+        ids.sort_by_key(|id| id.0);
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_user_sessions() {
+        let db = temp_db();
+        let session_store = StorageSessionStore::new(db).unwrap();
+
+        let mut record1 = user_record(Some("user1"), Duration::minutes(60));
+        let mut record2 = user_record(Some("user1"), Duration::minutes(60));
+        let mut record3 = user_record(Some("user2"), Duration::minutes(60));
+        let mut anonymous = user_record(None, Duration::minutes(60));
+        for record in [&mut record1, &mut record2, &mut record3, &mut anonymous] {
+            session_store.create(record).await.expect("created record");
+        }
+        let mut expected = vec![record1.id, record2.id];
+        expected.sort_by_key(|id| id.0);
+        assert_eq!(
+            ids(session_store.user_sessions("user1").await.unwrap()),
+            expected
+        );
+        assert_eq!(
+            ids(session_store.user_sessions("user2").await.unwrap()),
+            vec![record3.id]
+        );
+
+        // logging out of a session removes it from the index, logging in adds it
+        let logged_out = user_record(None, Duration::minutes(60));
+        session_store
+            .save(&Record {
+                id: record2.id,
+                ..logged_out
+            })
+            .await
+            .unwrap();
+        anonymous.data.insert(
+            AUTH_DATA_KEY.to_string(),
+            record3.data[AUTH_DATA_KEY].clone(),
+        );
+        session_store.save(&anonymous).await.unwrap();
+        assert_eq!(
+            ids(session_store.user_sessions("user1").await.unwrap()),
+            vec![record1.id]
+        );
+        let mut expected = vec![record3.id, anonymous.id];
+        expected.sort_by_key(|id| id.0);
+        assert_eq!(
+            ids(session_store.user_sessions("user2").await.unwrap()),
+            expected
+        );
+
+        // deleted sessions are removed from the index
+        session_store.delete(&record1.id).await.unwrap();
+        assert!(session_store
+            .user_sessions("user1")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    // the index relies on where axum-login keeps the user id in the session data, log in
+    // through a real auth session to catch a change of that layout
+    #[tokio::test]
+    async fn test_auth_session_user_id() {
+        use crate::auth::backend::AuthSession;
+        use crate::auth::config::AuthConfig;
+        use crate::auth::types::Player;
+        use axum::body::Body;
+        use axum::http::Request;
+        use axum::routing::get;
+        use axum::Router;
+        use axum_login::AuthManagerLayerBuilder;
+        use tower::ServiceExt;
+        use tower_sessions::SessionManagerLayer;
+        use uuid::Uuid;
+
+        let db = temp_db();
+        let session_store = StorageSessionStore::new(db.clone()).unwrap();
+        let backend = AuthBackend::new(db, &AuthConfig::default()).unwrap();
+        let player = Player {
+            uuid: Uuid::new_v4(),
+            name: "tester".to_string(),
+            session_key: Some("session key".to_string()),
+            ..Default::default()
+        };
+        let logged_in = player.clone();
+        let app = Router::new()
+            .route(
+                "/login",
+                get(|mut auth_session: AuthSession| async move {
+                    auth_session.login(&logged_in).await.unwrap();
+                }),
+            )
+            .layer(
+                AuthManagerLayerBuilder::new(
+                    backend,
+                    SessionManagerLayer::new(session_store.clone()),
+                )
+                .build(),
+            );
+        let request = Request::get("/login").body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap();
+
+        let records = session_store
+            .user_sessions(&player.uuid.to_string())
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            StorageSessionStore::record_user_id(&records[0]),
+            Some(player.uuid.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_user_sessions() {
+        let db = temp_db();
+        let session_store = StorageSessionStore::new(db).unwrap();
+
+        let mut record1 = user_record(Some("user1"), Duration::minutes(60));
+        let mut record2 = user_record(Some("user1"), Duration::minutes(60));
+        let mut record3 = user_record(Some("user1"), Duration::minutes(60));
+        let mut other = user_record(Some("user2"), Duration::minutes(60));
+        let mut expired = user_record(Some("user2"), Duration::minutes(-1));
+        for record in [
+            &mut record1,
+            &mut record2,
+            &mut record3,
+            &mut other,
+            &mut expired,
+        ] {
+            session_store.create(record).await.expect("created record");
+        }
+        // expired sessions are not listed
+        assert_eq!(
+            ids(session_store.user_sessions("user2").await.unwrap()),
+            vec![other.id]
+        );
+
+        // sign out everywhere else
+        let deleted = session_store
+            .delete_user_sessions("user1", Some(record1.id))
+            .await
+            .unwrap();
+        assert_eq!(deleted, 2);
+        assert_eq!(
+            ids(session_store.user_sessions("user1").await.unwrap()),
+            vec![record1.id]
+        );
+        assert_eq!(session_store.load(&record2.id).await.unwrap(), None);
+        assert_eq!(session_store.load(&record3.id).await.unwrap(), None);
+
+        // expired deletion keeps the index in sync
+        session_store.delete_expired().await.unwrap();
+        assert_eq!(session_store.load(&expired.id).await.unwrap(), None);
+        assert_eq!(
+            session_store
+                .delete_user_sessions("user2", None)
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(session_store.load(&other.id).await.unwrap().is_none());
+        assert!(session_store.load(&record1.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_user_sessions_index() {
+        // sessions saved before the user id index existed
+        let db = temp_db();
+        let record1 = user_record(Some("user1"), Duration::minutes(60));
+        let record2 = user_record(Some("user1"), Duration::minutes(60));
+        let anonymous = user_record(None, Duration::minutes(60));
+        let write_txn = db.begin_write().unwrap();
+        {
+            let mut id_record = write_txn.open_table(ID_RECORD).unwrap();
+            for record in [&record1, &record2, &anonymous] {
+                let id_key = IdKey(record.id);
+                id_record
+                    .insert(
+                        &id_key,
+                        &Encoded::keyed(&IdKey::as_bytes(&id_key), &RecordValue(record.clone())),
+                    )
+                    .unwrap();
+            }
+        }
+        write_txn.commit().unwrap();
+
+        let session_store = StorageSessionStore::new(db.clone()).unwrap();
+        let mut expected = vec![record1.id, record2.id];
+        expected.sort_by_key(|id| id.0);
+        assert_eq!(
+            ids(session_store.user_sessions("user1").await.unwrap()),
+            expected
+        );
+        // the index is only rebuilt once
+        session_store.delete(&record1.id).await.unwrap();
+        let session_store = StorageSessionStore::new(db).unwrap();
+        assert_eq!(
+            ids(session_store.user_sessions("user1").await.unwrap()),
+            vec![record2.id]
+        );
+    }
 }
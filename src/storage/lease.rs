@@ -0,0 +1,48 @@
+use crate::storage::Storage;
+use crate::types::InternalError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::spawn_blocking;
+use tracing::info;
+use uuid::Uuid;
+
+/// A named lease in the [`Storage`], so only one of the server replicas sharing a database
+/// runs a background task. The holder renews the lease each time it runs the task, if it
+/// stops another replica takes over once the lease expired.
+#[derive(Debug)]
+pub struct Lease {
+    storage: Arc<dyn Storage>,
+    name: &'static str,
+    holder: Uuid,
+    ttl: Duration,
+    held: AtomicBool,
+}
+
+impl Lease {
+    /// A lease for `holder`, the replica's id. The task must run more often than `ttl`.
+    pub fn new(storage: Arc<dyn Storage>, name: &'static str, holder: Uuid, ttl: Duration) -> Self {
+        Self {
+            storage,
+            name,
+            holder,
+            ttl,
+            held: AtomicBool::new(false),
+        }
+    }
+
+    /// Take or renew the lease, returns whether this replica should run the task.
+    pub async fn acquire(&self) -> Result<bool, InternalError> {
+        let storage = self.storage.clone();
+        let (name, holder, ttl) = (self.name, self.holder, self.ttl);
+        let acquired = spawn_blocking(move || storage.acquire_lease(name, holder, ttl)).await??;
+        if acquired != self.held.swap(acquired, Ordering::Relaxed) {
+            if acquired {
+                info!("acquired {} lease", self.name);
+            } else {
+                info!("lost {} lease to another replica", self.name);
+            }
+        }
+        Ok(acquired)
+    }
+}
@@ -0,0 +1,592 @@
+use crate::audit::store::{AuditRead, AuditWrite};
+use crate::audit::types::{AuditAction, AuditEvent};
+use crate::auth::lnurl::LnurlChallenge;
+use crate::auth::store::{AuthRead, AuthWrite};
+use crate::auth::totp::Totp;
+use crate::auth::types::{ApiToken, InviteCode, LoginFailures, Player, ResetToken, Role};
+use crate::encoding::ReencryptProgress;
+use crate::guess::store::{GuessRead, GuessWrite};
+use crate::guess::types::GuessLogEntry;
+use crate::session_store::{SessionRead, SessionWrite};
+use crate::types::InternalError;
+use serde::de::DeserializeOwned;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+use tower_sessions::session::Record;
+use uuid::Uuid;
+
+pub mod lease;
+pub mod postgres;
+pub mod redb;
+pub mod sqlite;
+
+/// Rows read lazily from a transaction, ending at the first error.
+pub type RowIter<'a, T> = Box<dyn Iterator<Item = Result<T, InternalError>> + 'a>;
+
+/// A stored record that can't be decoded.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UndecodableRecord {
+    pub table: String,
+    /// The record's key, hex encoded bytes for redb.
+    pub key: String,
+    pub error: String,
+}
+
+impl UndecodableRecord {
+    /// The audit event of moving the record to a quarantine table.
+    pub fn quarantine_event(&self) -> AuditEvent {
+        AuditEvent {
+            subject: Some(format!("{} {}", self.table, self.key)),
+            after: Some(self.error.clone()),
+            ..AuditEvent::new(AuditAction::RecordQuarantine, None)
+        }
+    }
+}
+
+// checks that a JSON record decodes
+type DecodeCheck = fn(&str) -> Result<(), serde_json::Error>;
+
+// the tables the SQL backends keep JSON records in, with their key column
+const JSON_TABLES: &[(&str, &str, DecodeCheck)] = &[
+    ("auth_player", "uuid", decodes::<Player>),
+    ("auth_role", "uuid", decodes::<Role>),
+    ("auth_login_failures", "key", decodes::<LoginFailures>),
+    ("auth_reset_token", "hash", decodes::<ResetToken>),
+    ("auth_invite_code", "hash", decodes::<InviteCode>),
+    ("auth_lnurl_challenge", "k1", decodes::<LnurlChallenge>),
+    ("auth_api_token", "hash", decodes::<ApiToken>),
+    ("auth_totp", "player", decodes::<Totp>),
+    ("guess_log", "seq", decodes::<GuessLogEntry>),
+    ("audit_event", "uuid", decodes::<AuditEvent>),
+    ("session_record", "id", decodes::<Record>),
+];
+
+fn decodes<T: DeserializeOwned>(data: &str) -> Result<(), serde_json::Error> {
+    serde_json::from_str::<T>(data).map(|_| ())
+}
+
+/// The database the backends keep their records in. Each transaction covers the auth, guess,
+/// audit and session records, so a change across them is committed atomically.
+pub trait Storage: Debug + Send + Sync {
+    /// Begin a read transaction, a consistent view of the committed records.
+    fn begin_read(&self) -> Result<Box<dyn ReadTxn + '_>, InternalError>;
+
+    /// Begin a write transaction, the changes are rolled back unless it is committed.
+    fn begin_write(&self) -> Result<Box<dyn WriteTxn + '_>, InternalError>;
+
+    /// Take the named lease for `holder`, or renew it if `holder` already has it, for `ttl`.
+    /// Returns whether `holder` has the lease. A database only one server can open always
+    /// grants it.
+    fn acquire_lease(
+        &self,
+        _name: &str,
+        _holder: Uuid,
+        _ttl: Duration,
+    ) -> Result<bool, InternalError> {
+        Ok(true)
+    }
+
+    /// Records that can't be decoded. With `quarantine` they are moved out of their tables
+    /// to a quarantine table, and reported in the audit log.
+    fn undecodable_records(
+        &self,
+        quarantine: bool,
+    ) -> Result<Vec<UndecodableRecord>, InternalError>;
+
+    /// Encode up to `limit` encrypted records again that aren't sealed with the primary
+    /// encryption key, after the keys were rotated, continuing after the records `progress`
+    /// has seen. Returns the number of stale records processed, the pass is done when it is 0.
+    /// Backends without encryption at rest have nothing to do.
+    fn reencrypt_batch(
+        &self,
+        _progress: &mut ReencryptProgress,
+        _limit: usize,
+    ) -> Result<usize, InternalError> {
+        Ok(0)
+    }
+}
+
+/// A read transaction of a [`Storage`].
+pub trait ReadTxn: AuthRead + GuessRead + AuditRead + SessionRead {}
+
+impl<T: AuthRead + GuessRead + AuditRead + SessionRead> ReadTxn for T {}
+
+/// A write transaction of a [`Storage`].
+pub trait WriteTxn: AuthWrite + GuessWrite + AuditWrite + SessionWrite {
+    fn commit(self: Box<Self>) -> Result<(), InternalError>;
+}
+
+/// The storage backend, chosen with `NONCE_GUESS_DB_BACKEND`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum StorageBackend {
+    /// Embedded redb database, the default.
+    #[default]
+    Redb,
+    /// SQLite database file.
+    Sqlite,
+    /// Postgres database, can be shared by several server replicas.
+    Postgres,
+}
+
+impl Display for StorageBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageBackend::Redb => write!(f, "redb"),
+            StorageBackend::Sqlite => write!(f, "sqlite"),
+            StorageBackend::Postgres => write!(f, "postgres"),
+        }
+    }
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redb" => Ok(StorageBackend::Redb),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            "postgres" => Ok(StorageBackend::Postgres),
+            _ => Err(format!("unknown database backend: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::postgres::PostgresStorage;
+    use super::redb::RedbStorage;
+    use super::sqlite::SqliteStorage;
+    use super::Storage;
+    use crate::audit::types::{AuditAction, AuditEvent, AuditFilter};
+    use crate::auth::types::{IdentityKey, InviteCode, Player, Role, ThrottleKey};
+    use crate::guess::replay::ReplayedTargets;
+    use crate::guess::types::{Guess, GuessError, GuessEvent, GuessLogEntry, DELETED_PLAYER};
+    use crate::session_store::StorageSessionStore;
+    use crate::types::InternalError;
+    use chrono::Utc;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration as StdDuration;
+    use tempfile::NamedTempFile;
+    use tower_sessions::cookie::time::{Duration, OffsetDateTime};
+    use tower_sessions::session::{Id, Record};
+    use uuid::Uuid;
+
+    fn player(name: &str) -> Player {
+        Player {
+            uuid: Uuid::new_v4(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn check_players(storage: &dyn Storage) {
+        let alice = player("alice");
+        let bob = player("bob");
+        let mut write_txn = storage.begin_write().unwrap();
+        assert!(write_txn.is_empty_for_update().unwrap());
+        write_txn.insert_player(alice.clone()).unwrap();
+        write_txn.insert_player(bob.clone()).unwrap();
+        let identity_key = IdentityKey::Nostr("pubkey".to_string());
+        write_txn.link_identity(&identity_key, bob.uuid).unwrap();
+        assert_eq!(
+            write_txn.get_name_for_update("AIice").unwrap(),
+            Some(alice.uuid)
+        );
+        // a name registered to another player isn't taken over
+        assert!(matches!(
+            write_txn.insert_player(player("ALICE")),
+            Err(InternalError::NameTaken(_))
+        ));
+        let renamed_bob = Player {
+            name: "AIice".to_string(),
+            ..bob.clone()
+        };
+        assert!(matches!(
+            write_txn.change_player(bob.clone(), renamed_bob),
+            Err(InternalError::NameTaken(_))
+        ));
+        write_txn
+            .add_login_failure(
+                &ThrottleKey::Name(bob.name.clone()),
+                Utc::now(),
+                &Default::default(),
+            )
+            .unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(
+            read_txn.get_player_by_name("alice").unwrap(),
+            Some(alice.clone())
+        );
+        // names are looked up regardless of case, the name is kept as typed
+        assert_eq!(
+            read_txn.get_player_by_name("ALICE").unwrap(),
+            Some(alice.clone())
+        );
+        let mut players = vec![alice.clone(), bob.clone()];
+        players.sort_by_key(|player| player.uuid);
+        assert_eq!(read_txn.get_players().unwrap(), players);
+        assert_eq!(
+            read_txn
+                .iter_players()
+                .unwrap()
+                .collect::<Result<Vec<Player>, _>>()
+                .unwrap(),
+            players
+        );
+        assert_eq!(
+            read_txn.get_player_identities(bob.uuid).unwrap(),
+            vec![identity_key.to_string()]
+        );
+        drop(read_txn);
+
+        // players can swap names within a write transaction
+        let mut write_txn = storage.begin_write().unwrap();
+        let swapped = [
+            Player {
+                name: bob.name.clone(),
+                ..alice.clone()
+            },
+            Player {
+                name: alice.name.clone(),
+                ..bob.clone()
+            },
+        ];
+        write_txn.upsert_players(&swapped).unwrap();
+        assert_eq!(
+            write_txn.get_name_for_update("bob").unwrap(),
+            Some(alice.uuid)
+        );
+        write_txn.commit().unwrap();
+
+        // removing a player removes everything linked to them
+        let mut write_txn = storage.begin_write().unwrap();
+        for key in [
+            ThrottleKey::NameIp("alice".to_string(), [127, 0, 0, 1].into()),
+            ThrottleKey::Account("alice".to_string()),
+        ] {
+            write_txn
+                .add_login_failure(&key, Utc::now(), &Default::default())
+                .unwrap();
+        }
+        let removed = write_txn.remove_player(bob.uuid).unwrap();
+        assert_eq!(removed.map(|player| player.name), Some("alice".to_string()));
+        assert_eq!(
+            write_txn.get_identity_for_update(&identity_key).unwrap(),
+            None
+        );
+        assert_eq!(write_txn.get_name_for_update("alice").unwrap(), None);
+        write_txn.commit().unwrap();
+        assert!(storage
+            .begin_read()
+            .unwrap()
+            .get_all_login_failures()
+            .unwrap()
+            .iter()
+            .all(|(key, _)| !ThrottleKey::is_for_name(key, "alice")));
+
+        // uncommitted changes are rolled back
+        let mut write_txn = storage.begin_write().unwrap();
+        assert_eq!(write_txn.remove_all_players().unwrap(), 1);
+        drop(write_txn);
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(read_txn.get_players().unwrap().len(), 1);
+    }
+
+    fn check_roles_and_codes(storage: &dyn Storage) {
+        let role = Role {
+            uuid: Uuid::new_v4(),
+            name: "admin".to_string(),
+            permissions: Default::default(),
+        };
+        let invite_code = InviteCode {
+            code_hash: "hash".to_string(),
+            max_uses: Some(1),
+            uses: 0,
+            expires: None,
+            role: Some(role.uuid),
+            created_by: Uuid::new_v4(),
+            created: Utc::now(),
+        };
+        let mut write_txn = storage.begin_write().unwrap();
+        assert_eq!(write_txn.insert_role(role.clone()).unwrap(), None);
+        write_txn.insert_invite_code(invite_code).unwrap();
+        write_txn.commit().unwrap();
+
+        let mut write_txn = storage.begin_write().unwrap();
+        assert!(write_txn
+            .use_invite_code("hash", Utc::now())
+            .unwrap()
+            .is_some());
+        assert!(write_txn
+            .use_invite_code("hash", Utc::now())
+            .unwrap()
+            .is_none());
+        write_txn.commit().unwrap();
+
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(read_txn.get_roles().unwrap(), vec![role.clone()]);
+        assert_eq!(read_txn.get_role_by_uuid(role.uuid).unwrap(), Some(role));
+        assert_eq!(read_txn.get_invite_codes().unwrap()[0].uses, 1);
+    }
+
+    fn check_guesses(storage: &dyn Storage) {
+        let player_uuid = Uuid::new_v4();
+        let other_uuid = Uuid::new_v4();
+        let mut write_txn = storage.begin_write().unwrap();
+        write_txn.insert_target(100, Some(42)).unwrap();
+        write_txn.insert_target(200, None).unwrap();
+        for (height, player, nonce) in [
+            (100, player_uuid, 7),
+            (200, player_uuid, 5),
+            (200, other_uuid, 3),
+        ] {
+            write_txn
+                .insert_guess(height, Guess { player, nonce })
+                .unwrap();
+        }
+        // a taken nonce or a second guess is rejected before it is logged
+        let taken = Guess {
+            player: Uuid::new_v4(),
+            nonce: 5,
+        };
+        assert!(matches!(
+            write_txn.insert_guess(200, taken),
+            Err(GuessError::DuplicateNonce(_))
+        ));
+        let second = Guess {
+            player: player_uuid,
+            nonce: 9,
+        };
+        assert!(matches!(
+            write_txn.insert_guess(200, second),
+            Err(GuessError::DuplicateGuess(200))
+        ));
+        // another player can't take over a guessed nonce
+        let taken = Guess {
+            player: Uuid::new_v4(),
+            nonce: 5,
+        };
+        assert!(write_txn.add_guess(200, taken).unwrap());
+        write_txn.replace_target(200, 300).unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(read_txn.get_target_nonce(100).unwrap(), Some(Some(42)));
+        assert_eq!(read_txn.get_target_nonce(200).unwrap(), None);
+        assert_eq!(read_txn.get_last_target_nonce().unwrap(), Some((300, None)));
+        assert_eq!(
+            read_txn
+                .iter_targets()
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            vec![(100, Some(42)), (300, None)]
+        );
+        let nonces = read_txn
+            .target_guesses(300)
+            .unwrap()
+            .iter()
+            .map(|guess| guess.nonce)
+            .collect::<Vec<u32>>();
+        assert_eq!(nonces, vec![3, 5]);
+        assert!(read_txn.any_guess(300, player_uuid).unwrap());
+        assert_eq!(read_txn.player_guesses(player_uuid).unwrap().len(), 2);
+        drop(read_txn);
+
+        let mut write_txn = storage.begin_write().unwrap();
+        assert_eq!(
+            write_txn.remove_player_guesses(player_uuid, true).unwrap(),
+            2
+        );
+        assert_eq!(write_txn.remove_target_nonce(100).unwrap(), Some(42));
+        write_txn.commit().unwrap();
+        let read_txn = storage.begin_read().unwrap();
+        let players = read_txn
+            .target_guesses(300)
+            .unwrap()
+            .iter()
+            .map(|guess| guess.player)
+            .collect::<Vec<Uuid>>();
+        assert_eq!(players, vec![other_uuid, DELETED_PLAYER]);
+
+        // every change is logged in order, replaying the log gives the tables
+        let log = read_txn
+            .iter_guess_log()
+            .unwrap()
+            .collect::<Result<Vec<GuessLogEntry>, _>>()
+            .unwrap();
+        assert_eq!(
+            log.iter().map(|entry| entry.seq).collect::<Vec<u64>>(),
+            (1..=8).collect::<Vec<u64>>()
+        );
+        assert_eq!(
+            log[5].event,
+            GuessEvent::TargetReplaced {
+                old_height: 200,
+                new_height: 300
+            }
+        );
+        let replayed = ReplayedTargets::replay(log.iter().map(|entry| &entry.event));
+        assert_eq!(replayed.nonce(100), None);
+        assert_eq!(replayed.guesses(300), read_txn.target_guesses(300).unwrap());
+        drop(read_txn);
+
+        // the tables are rebuilt from the log, without logging anything
+        let mut write_txn = storage.begin_write().unwrap();
+        write_txn.delete_all_targets().unwrap();
+        assert_eq!(write_txn.rebuild_guess_projection().unwrap(), 8);
+        write_txn.commit().unwrap();
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(read_txn.get_target_nonce(300).unwrap(), Some(None));
+        assert_eq!(read_txn.iter_guess_log().unwrap().count(), 8);
+        assert_eq!(read_txn.target_guesses(300).unwrap(), replayed.guesses(300));
+    }
+
+    fn check_audit(storage: &dyn Storage) {
+        let mut write_txn = storage.begin_write().unwrap();
+        write_txn
+            .record_events(&[
+                AuditEvent::new(AuditAction::TargetCreate, None),
+                AuditEvent::new(AuditAction::LoginFailed, None),
+            ])
+            .unwrap();
+        write_txn.commit().unwrap();
+        let read_txn = storage.begin_read().unwrap();
+        let actions = read_txn
+            .get_events(&AuditFilter::default())
+            .unwrap()
+            .iter()
+            .map(|event| event.action)
+            .collect::<Vec<AuditAction>>();
+        assert_eq!(
+            actions,
+            vec![AuditAction::LoginFailed, AuditAction::TargetCreate]
+        );
+    }
+
+    fn check_sessions(storage: &dyn Storage) {
+        let record = |user_id: &str, expires_in: Duration| Record {
+            id: Id::default(),
+            data: HashMap::from([(
+                "axum-login.data".to_string(),
+                serde_json::json!({ "user_id": user_id }),
+            )]),
+            expiry_date: OffsetDateTime::now_utc() + expires_in,
+        };
+        let current = record("alice", Duration::hours(1));
+        let other = record("alice", Duration::hours(1));
+        let expired = record("bob", Duration::hours(-1));
+        let mut write_txn = storage.begin_write().unwrap();
+        for record in [&current, &other, &expired] {
+            write_txn.save_session(record).unwrap();
+        }
+        write_txn.commit().unwrap();
+        assert_eq!(
+            StorageSessionStore::record_user_id(&current),
+            Some("alice".to_string())
+        );
+
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(
+            read_txn.load_session(current.id).unwrap(),
+            Some(current.clone())
+        );
+        assert_eq!(read_txn.user_sessions("alice").unwrap().len(), 2);
+        drop(read_txn);
+
+        let mut write_txn = storage.begin_write().unwrap();
+        assert_eq!(
+            write_txn
+                .delete_user_sessions("alice", Some(current.id))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            write_txn
+                .delete_expired_sessions(OffsetDateTime::now_utc())
+                .unwrap(),
+            1
+        );
+        write_txn.commit().unwrap();
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(read_txn.user_sessions("alice").unwrap(), vec![current]);
+        assert_eq!(read_txn.load_session(expired.id).unwrap(), None);
+    }
+
+    fn check_storage(storage: &dyn Storage) {
+        check_players(storage);
+        check_roles_and_codes(storage);
+        check_guesses(storage);
+        check_audit(storage);
+        check_sessions(storage);
+    }
+
+    #[test]
+    fn test_redb_storage() {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        let db = Arc::new(::redb::Database::create(file).unwrap());
+        check_storage(&RedbStorage::new(db).unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_storage() {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        check_storage(&SqliteStorage::new(file.to_path_buf()).unwrap());
+        // reopening an up to date database leaves it as it was
+        let storage = SqliteStorage::new(file.to_path_buf()).unwrap();
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(read_txn.get_players().unwrap().len(), 1);
+    }
+
+    // runs `test` with the url of a new database on the server of
+    // `NONCE_GUESS_TEST_POSTGRES_URL`, a key-value connection string such as
+    // "host=localhost user=postgres". The postgres tests are ignored unless run with
+    // `--include-ignored`, and then fail if it isn't set.
+    fn with_postgres_url(test: impl FnOnce(&str)) {
+        let server_url = std::env::var("NONCE_GUESS_TEST_POSTGRES_URL")
+            .expect("NONCE_GUESS_TEST_POSTGRES_URL must be set for the postgres tests");
+        let mut client = postgres::Client::connect(&server_url, postgres::NoTls).unwrap();
+        let dbname = format!("nonce_guess_test_{}", Uuid::new_v4().simple());
+        client
+            .batch_execute(&format!("CREATE DATABASE {}", dbname))
+            .unwrap();
+        test(&format!("{} dbname={}", server_url, dbname));
+        client
+            .batch_execute(&format!("DROP DATABASE {} WITH (FORCE)", dbname))
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a postgres server in NONCE_GUESS_TEST_POSTGRES_URL"]
+    fn test_postgres_storage() {
+        with_postgres_url(|url| {
+            check_storage(&PostgresStorage::new(url).unwrap());
+            // reconnecting to an up to date database leaves it as it was
+            let storage = PostgresStorage::new(url).unwrap();
+            let read_txn = storage.begin_read().unwrap();
+            assert_eq!(read_txn.get_players().unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    #[ignore = "needs a postgres server in NONCE_GUESS_TEST_POSTGRES_URL"]
+    fn test_postgres_lease() {
+        with_postgres_url(|url| {
+            let storage = PostgresStorage::new(url).unwrap();
+            let (replica, other) = (Uuid::new_v4(), Uuid::new_v4());
+            let ttl = StdDuration::from_millis(200);
+            assert!(storage.acquire_lease("task", replica, ttl).unwrap());
+            assert!(!storage.acquire_lease("task", other, ttl).unwrap());
+            assert!(storage.acquire_lease("other_task", other, ttl).unwrap());
+            // the holder renews the lease, another replica takes it over once it expired
+            assert!(storage.acquire_lease("task", replica, ttl).unwrap());
+            std::thread::sleep(ttl);
+            assert!(storage.acquire_lease("task", other, ttl).unwrap());
+            assert!(!storage.acquire_lease("task", replica, ttl).unwrap());
+        });
+    }
+}
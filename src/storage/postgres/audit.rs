@@ -0,0 +1,44 @@
+use super::{data_column, to_json, PostgresTxn};
+use crate::audit::store::{AuditRead, AuditWrite};
+use crate::audit::types::{AuditEvent, AuditFilter};
+use crate::types::InternalError;
+use postgres::GenericClient;
+
+/// Append an event, also used by migrations which only have the transaction.
+pub(super) fn insert_event(
+    client: &mut impl GenericClient,
+    event: &AuditEvent,
+) -> Result<(), InternalError> {
+    client.execute(
+        "INSERT INTO audit_event (uuid, timestamp, data) VALUES ($1, $2, $3)
+        ON CONFLICT (uuid) DO UPDATE SET timestamp = excluded.timestamp, data = excluded.data",
+        &[&event.uuid, &event.timestamp, &to_json(event)],
+    )?;
+    Ok(())
+}
+
+impl AuditRead for PostgresTxn<'_> {
+    fn get_events(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, InternalError> {
+        // v7 uuids sort by time, so the newest events come first
+        let rows = self
+            .client()
+            .query("SELECT data FROM audit_event ORDER BY uuid DESC", &[])?;
+        let mut events = Vec::new();
+        for row in &rows {
+            let event: AuditEvent = data_column(row, 0)?;
+            if filter.matches(&event) {
+                events.push(event);
+                if filter.limit.is_some_and(|limit| events.len() >= limit) {
+                    break;
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl AuditWrite for PostgresTxn<'_> {
+    fn insert_event(&mut self, event: &AuditEvent) -> Result<(), InternalError> {
+        insert_event(&mut *self.client(), event)
+    }
+}
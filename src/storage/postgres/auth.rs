@@ -0,0 +1,555 @@
+use super::audit::insert_event;
+use super::{data_column, to_json, PostgresTxn};
+use crate::audit::types::name_collision_event;
+use crate::auth::lnurl::LnurlChallenge;
+use crate::auth::store::{AuthRead, AuthWrite};
+use crate::auth::totp::Totp;
+use crate::auth::types::{
+    hash_token, index_player_names, name_key, ApiToken, IdentityKey, InviteCode, LoginFailures,
+    Player, ResetToken, Role, ThrottleKey,
+};
+use crate::storage::RowIter;
+use crate::types::InternalError;
+use postgres::Transaction;
+use uuid::Uuid;
+
+/// Rebuild the player name index by `name_key`, players whose names collide are renamed and
+/// each rename is recorded in the audit log.
+pub(super) fn rebuild_name_index(txn: &mut Transaction) -> Result<(), InternalError> {
+    let players = txn
+        .query("SELECT data FROM auth_player", &[])?
+        .iter()
+        .map(|row| data_column(row, 0))
+        .collect::<Result<Vec<Player>, InternalError>>()?;
+    let (index, collisions) = index_player_names(players);
+    txn.execute("DELETE FROM auth_player_name", &[])?;
+    for (key, uuid) in index {
+        txn.execute(
+            "INSERT INTO auth_player_name (key, uuid) VALUES ($1, $2)",
+            &[&key, &uuid],
+        )?;
+    }
+    for collision in collisions {
+        let player = &collision.player;
+        txn.execute(
+            "UPDATE auth_player SET data = $2 WHERE uuid = $1",
+            &[&player.uuid, &to_json(&player)],
+        )?;
+        insert_event(txn, &name_collision_event(&collision))?;
+    }
+    Ok(())
+}
+
+/// Key the invite codes by their hash, they were stored by the plain code before.
+pub(super) fn hash_invite_codes(txn: &mut Transaction) -> Result<(), InternalError> {
+    let invite_codes = txn
+        .query("SELECT data FROM auth_invite_code", &[])?
+        .iter()
+        .map(|row| data_column(row, 0))
+        .collect::<Result<Vec<InviteCode>, InternalError>>()?;
+    txn.execute("DELETE FROM auth_invite_code", &[])?;
+    for invite_code in invite_codes {
+        let invite_code = InviteCode {
+            code_hash: hash_token(&invite_code.code_hash),
+            ..invite_code
+        };
+        txn.execute(
+            "INSERT INTO auth_invite_code (hash, data) VALUES ($1, $2)",
+            &[&invite_code.code_hash, &to_json(&invite_code)],
+        )?;
+    }
+    Ok(())
+}
+
+impl AuthRead for PostgresTxn<'_> {
+    fn get_player_by_uuid(&self, uuid: Uuid) -> Result<Option<Player>, InternalError> {
+        self.query_data("SELECT data FROM auth_player WHERE uuid = $1", &[&uuid])
+    }
+
+    fn get_player_by_name(&self, name: &str) -> Result<Option<Player>, InternalError> {
+        self.query_data(
+            "SELECT data FROM auth_player_name JOIN auth_player USING (uuid) WHERE key = $1",
+            &[&name_key(name)],
+        )
+    }
+
+    fn get_players(&self) -> Result<Vec<Player>, InternalError> {
+        self.query_all_data("SELECT data FROM auth_player ORDER BY uuid", &[])
+    }
+
+    fn get_player_names(&self) -> Result<Vec<(String, Uuid)>, InternalError> {
+        let names = self
+            .client()
+            .query("SELECT key, uuid FROM auth_player_name ORDER BY key", &[])?
+            .iter()
+            .map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+            .collect::<Result<Vec<(String, Uuid)>, postgres::Error>>()?;
+        Ok(names)
+    }
+
+    fn iter_players(&self) -> Result<RowIter<'_, Player>, InternalError> {
+        Ok(self.paged(
+            "SELECT uuid, data FROM auth_player WHERE uuid > $1 ORDER BY uuid LIMIT $2",
+            Uuid::nil(),
+            |row| data_column(row, 1),
+        ))
+    }
+
+    fn get_role_by_uuid(&self, uuid: Uuid) -> Result<Option<Role>, InternalError> {
+        self.query_data("SELECT data FROM auth_role WHERE uuid = $1", &[&uuid])
+    }
+
+    fn get_roles(&self) -> Result<Vec<Role>, InternalError> {
+        self.query_all_data("SELECT data FROM auth_role ORDER BY uuid", &[])
+    }
+
+    fn iter_roles(&self) -> Result<RowIter<'_, Role>, InternalError> {
+        Ok(self.paged(
+            "SELECT uuid, data FROM auth_role WHERE uuid > $1 ORDER BY uuid LIMIT $2",
+            Uuid::nil(),
+            |row| data_column(row, 1),
+        ))
+    }
+
+    fn get_login_failures(
+        &self,
+        key: &ThrottleKey,
+    ) -> Result<Option<LoginFailures>, InternalError> {
+        self.query_data(
+            "SELECT data FROM auth_login_failures WHERE key = $1",
+            &[&key.to_string()],
+        )
+    }
+
+    fn get_all_login_failures(&self) -> Result<Vec<(String, LoginFailures)>, InternalError> {
+        let rows = self.client().query(
+            "SELECT key, data FROM auth_login_failures ORDER BY key",
+            &[],
+        )?;
+        rows.iter()
+            .map(|row| Ok((row.try_get(0)?, data_column(row, 1)?)))
+            .collect()
+    }
+
+    fn get_reset_token(&self, token_hash: String) -> Result<Option<ResetToken>, InternalError> {
+        self.query_data(
+            "SELECT data FROM auth_reset_token WHERE hash = $1",
+            &[&token_hash],
+        )
+    }
+
+    fn get_invite_codes(&self) -> Result<Vec<InviteCode>, InternalError> {
+        self.query_all_data("SELECT data FROM auth_invite_code ORDER BY hash", &[])
+    }
+
+    fn get_player_identities(&self, uuid: Uuid) -> Result<Vec<String>, InternalError> {
+        let rows = self.client().query(
+            "SELECT identity FROM auth_identity WHERE player = $1 ORDER BY identity",
+            &[&uuid],
+        )?;
+        let identities = rows
+            .iter()
+            .map(|row| row.try_get(0))
+            .collect::<Result<Vec<String>, postgres::Error>>()?;
+        Ok(identities)
+    }
+
+    fn get_lnurl_challenge(&self, k1: &str) -> Result<Option<LnurlChallenge>, InternalError> {
+        self.query_data(
+            "SELECT data FROM auth_lnurl_challenge WHERE k1 = $1",
+            &[&k1],
+        )
+    }
+
+    fn get_totp(&self, uuid: Uuid) -> Result<Option<Totp>, InternalError> {
+        self.query_data("SELECT data FROM auth_totp WHERE player = $1", &[&uuid])
+    }
+
+    fn get_player_api_tokens(&self, uuid: Uuid) -> Result<Vec<ApiToken>, InternalError> {
+        self.query_all_data(
+            "SELECT data FROM auth_api_token WHERE player = $1 ORDER BY hash",
+            &[&uuid],
+        )
+    }
+}
+
+impl AuthWrite for PostgresTxn<'_> {
+    fn insert_player(&mut self, player: Player) -> Result<Option<Player>, InternalError> {
+        let orig_player = self.get_player_by_uuid(player.uuid)?;
+        self.check_name_for_update(&player.name, player.uuid)?;
+        let mut client = self.client();
+        client.execute(
+            "INSERT INTO auth_player_name (key, uuid) VALUES ($1, $2)
+            ON CONFLICT (key) DO NOTHING",
+            &[&name_key(&player.name), &player.uuid],
+        )?;
+        client.execute(
+            "INSERT INTO auth_player (uuid, data) VALUES ($1, $2)
+            ON CONFLICT (uuid) DO UPDATE SET data = excluded.data",
+            &[&player.uuid, &to_json(&player)],
+        )?;
+        Ok(orig_player)
+    }
+
+    fn change_player(
+        &mut self,
+        orig_player: Player,
+        new_player: Player,
+    ) -> Result<Option<Player>, InternalError> {
+        if orig_player == new_player {
+            return Ok(None);
+        }
+        if orig_player.name != new_player.name {
+            self.check_name_for_update(&new_player.name, orig_player.uuid)?;
+            let mut client = self.client();
+            client.execute(
+                "DELETE FROM auth_player_name WHERE key = $1",
+                &[&name_key(&orig_player.name)],
+            )?;
+            client.execute(
+                "INSERT INTO auth_player_name (key, uuid) VALUES ($1, $2)",
+                &[&name_key(&new_player.name), &orig_player.uuid],
+            )?;
+        }
+        let stored_player = self.get_player_by_uuid(orig_player.uuid)?;
+        self.client().execute(
+            "INSERT INTO auth_player (uuid, data) VALUES ($1, $2)
+            ON CONFLICT (uuid) DO UPDATE SET data = excluded.data",
+            &[&orig_player.uuid, &to_json(&new_player)],
+        )?;
+        Ok(stored_player)
+    }
+
+    fn remove_player(&mut self, uuid: Uuid) -> Result<Option<Player>, InternalError> {
+        let player: Option<Player> = self.query_data(
+            "DELETE FROM auth_player WHERE uuid = $1 RETURNING data",
+            &[&uuid],
+        )?;
+        let mut client = self.client();
+        if let Some(player) = &player {
+            client.execute(
+                "DELETE FROM auth_player_name WHERE key = $1",
+                &[&name_key(&player.name)],
+            )?;
+        }
+        client.execute("DELETE FROM auth_identity WHERE player = $1", &[&uuid])?;
+        client.execute("DELETE FROM auth_reset_token WHERE player = $1", &[&uuid])?;
+        client.execute("DELETE FROM auth_api_token WHERE player = $1", &[&uuid])?;
+        client.execute("DELETE FROM auth_totp WHERE player = $1", &[&uuid])?;
+        drop(client);
+        // failures of the freed name would throttle the next player who takes it
+        if let Some(player) = &player {
+            self.remove_login_failures_if(&|key, _| ThrottleKey::is_for_name(key, &player.name))?;
+        }
+        Ok(player)
+    }
+
+    fn get_player_for_update(&self, uuid: Uuid) -> Result<Option<Player>, InternalError> {
+        self.get_player_by_uuid(uuid)
+    }
+
+    fn get_players_for_update(&self) -> Result<Vec<Player>, InternalError> {
+        self.get_players()
+    }
+
+    fn get_name_key_for_update(&self, key: &str) -> Result<Option<Uuid>, InternalError> {
+        let row = self
+            .client()
+            .query_opt("SELECT uuid FROM auth_player_name WHERE key = $1", &[&key])?;
+        let uuid = row.map(|row| row.try_get(0)).transpose()?;
+        Ok(uuid)
+    }
+
+    fn repair_player_name(&mut self, key: &str, uuid: Option<Uuid>) -> Result<(), InternalError> {
+        match uuid {
+            Some(uuid) => self.client().execute(
+                "INSERT INTO auth_player_name (key, uuid) VALUES ($1, $2)
+                ON CONFLICT (key) DO UPDATE SET uuid = excluded.uuid",
+                &[&key, &uuid],
+            )?,
+            None => self
+                .client()
+                .execute("DELETE FROM auth_player_name WHERE key = $1", &[&key])?,
+        };
+        Ok(())
+    }
+
+    fn is_empty_for_update(&self) -> Result<bool, InternalError> {
+        let empty = self
+            .client()
+            .query_one(
+                "SELECT NOT EXISTS (SELECT 1 FROM auth_role)
+                    AND NOT EXISTS (SELECT 1 FROM auth_player)
+                    AND NOT EXISTS (SELECT 1 FROM auth_player_name)",
+                &[],
+            )?
+            .try_get(0)?;
+        Ok(empty)
+    }
+
+    fn upsert_players(&mut self, players: &[Player]) -> Result<(), InternalError> {
+        for player in players {
+            self.client().execute(
+                "DELETE FROM auth_player_name WHERE uuid = $1",
+                &[&player.uuid],
+            )?;
+        }
+        for player in players {
+            self.insert_player(player.clone())?;
+        }
+        Ok(())
+    }
+
+    fn remove_all_players(&mut self) -> Result<usize, InternalError> {
+        let uuids = self
+            .client()
+            .query("SELECT uuid FROM auth_player", &[])?
+            .iter()
+            .map(|row| row.try_get(0))
+            .collect::<Result<Vec<Uuid>, postgres::Error>>()?;
+        for uuid in &uuids {
+            self.remove_player(*uuid)?;
+        }
+        self.client().execute("DELETE FROM auth_player_name", &[])?;
+        Ok(uuids.len())
+    }
+
+    fn insert_role(&mut self, role: Role) -> Result<Option<Role>, InternalError> {
+        let orig_role = self.get_role_by_uuid(role.uuid)?;
+        self.client().execute(
+            "INSERT INTO auth_role (uuid, data) VALUES ($1, $2)
+            ON CONFLICT (uuid) DO UPDATE SET data = excluded.data",
+            &[&role.uuid, &to_json(&role)],
+        )?;
+        Ok(orig_role)
+    }
+
+    fn get_role_for_update(&self, uuid: Uuid) -> Result<Option<Role>, InternalError> {
+        self.get_role_by_uuid(uuid)
+    }
+
+    fn remove_all_roles(&mut self) -> Result<usize, InternalError> {
+        let removed = self.client().execute("DELETE FROM auth_role", &[])?;
+        Ok(removed as usize)
+    }
+
+    fn get_login_failures_for_update(
+        &self,
+        key: &ThrottleKey,
+    ) -> Result<Option<LoginFailures>, InternalError> {
+        self.get_login_failures(key)
+    }
+
+    fn insert_login_failures(
+        &mut self,
+        key: &ThrottleKey,
+        login_failures: &LoginFailures,
+    ) -> Result<(), InternalError> {
+        self.client().execute(
+            "INSERT INTO auth_login_failures (key, data) VALUES ($1, $2)
+            ON CONFLICT (key) DO UPDATE SET data = excluded.data",
+            &[&key.to_string(), &to_json(login_failures)],
+        )?;
+        Ok(())
+    }
+
+    fn remove_login_failures(&mut self, key: &str) -> Result<Option<LoginFailures>, InternalError> {
+        self.query_data(
+            "DELETE FROM auth_login_failures WHERE key = $1 RETURNING data",
+            &[&key],
+        )
+    }
+
+    fn remove_login_failures_if(
+        &mut self,
+        remove: &dyn Fn(&str, &LoginFailures) -> bool,
+    ) -> Result<usize, InternalError> {
+        let mut removed = 0;
+        for (key, login_failures) in self.get_all_login_failures()? {
+            if remove(&key, &login_failures) {
+                removed += self
+                    .client()
+                    .execute("DELETE FROM auth_login_failures WHERE key = $1", &[&key])?;
+            }
+        }
+        Ok(removed as usize)
+    }
+
+    fn insert_reset_token(
+        &mut self,
+        token_hash: String,
+        reset_token: ResetToken,
+    ) -> Result<(), InternalError> {
+        let mut client = self.client();
+        client.execute(
+            "DELETE FROM auth_reset_token WHERE player = $1 OR expires <= now()",
+            &[&reset_token.player],
+        )?;
+        client.execute(
+            "INSERT INTO auth_reset_token (hash, player, expires, data) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (hash) DO UPDATE
+                SET player = excluded.player, expires = excluded.expires, data = excluded.data",
+            &[
+                &token_hash,
+                &reset_token.player,
+                &reset_token.expires,
+                &to_json(&reset_token),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove_reset_token(
+        &mut self,
+        token_hash: String,
+    ) -> Result<Option<ResetToken>, InternalError> {
+        self.query_data(
+            "DELETE FROM auth_reset_token WHERE hash = $1 RETURNING data",
+            &[&token_hash],
+        )
+    }
+
+    fn insert_invite_code(
+        &mut self,
+        invite_code: InviteCode,
+    ) -> Result<Option<InviteCode>, InternalError> {
+        let orig_invite_code = self.get_invite_code_for_update(&invite_code.code_hash)?;
+        self.client().execute(
+            "INSERT INTO auth_invite_code (hash, data) VALUES ($1, $2)
+            ON CONFLICT (hash) DO UPDATE SET data = excluded.data",
+            &[&invite_code.code_hash, &to_json(&invite_code)],
+        )?;
+        Ok(orig_invite_code)
+    }
+
+    fn get_invite_code_for_update(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<InviteCode>, InternalError> {
+        self.query_data(
+            "SELECT data FROM auth_invite_code WHERE hash = $1",
+            &[&code_hash],
+        )
+    }
+
+    fn remove_invite_code(&mut self, code_hash: &str) -> Result<Option<InviteCode>, InternalError> {
+        self.query_data(
+            "DELETE FROM auth_invite_code WHERE hash = $1 RETURNING data",
+            &[&code_hash],
+        )
+    }
+
+    fn link_identity(
+        &mut self,
+        identity_key: &IdentityKey,
+        player_uuid: Uuid,
+    ) -> Result<Option<Uuid>, InternalError> {
+        let orig_uuid = self.get_identity_for_update(identity_key)?;
+        self.client().execute(
+            "INSERT INTO auth_identity (identity, player) VALUES ($1, $2)
+            ON CONFLICT (identity) DO UPDATE SET player = excluded.player",
+            &[&identity_key.to_string(), &player_uuid],
+        )?;
+        Ok(orig_uuid)
+    }
+
+    fn get_identity_for_update(
+        &self,
+        identity_key: &IdentityKey,
+    ) -> Result<Option<Uuid>, InternalError> {
+        let row = self.client().query_opt(
+            "SELECT player FROM auth_identity WHERE identity = $1",
+            &[&identity_key.to_string()],
+        )?;
+        let uuid = row.map(|row| row.try_get(0)).transpose()?;
+        Ok(uuid)
+    }
+
+    fn insert_lnurl_challenge(&mut self, challenge: LnurlChallenge) -> Result<(), InternalError> {
+        let mut client = self.client();
+        client.execute(
+            "DELETE FROM auth_lnurl_challenge WHERE expires <= now()",
+            &[],
+        )?;
+        client.execute(
+            "INSERT INTO auth_lnurl_challenge (k1, expires, data) VALUES ($1, $2, $3)
+            ON CONFLICT (k1) DO UPDATE SET expires = excluded.expires, data = excluded.data",
+            &[&challenge.k1, &challenge.expires, &to_json(&challenge)],
+        )?;
+        Ok(())
+    }
+
+    fn get_lnurl_challenge_for_update(
+        &self,
+        k1: &str,
+    ) -> Result<Option<LnurlChallenge>, InternalError> {
+        self.get_lnurl_challenge(k1)
+    }
+
+    fn remove_lnurl_challenge(
+        &mut self,
+        k1: &str,
+    ) -> Result<Option<LnurlChallenge>, InternalError> {
+        self.query_data(
+            "DELETE FROM auth_lnurl_challenge WHERE k1 = $1 RETURNING data",
+            &[&k1],
+        )
+    }
+
+    fn insert_api_token(
+        &mut self,
+        token_hash: String,
+        api_token: ApiToken,
+    ) -> Result<(), InternalError> {
+        self.client().execute(
+            "INSERT INTO auth_api_token (hash, uuid, player, data) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (hash) DO UPDATE
+                SET uuid = excluded.uuid, player = excluded.player, data = excluded.data",
+            &[
+                &token_hash,
+                &api_token.uuid,
+                &api_token.player,
+                &to_json(&api_token),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_api_token_for_update(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<ApiToken>, InternalError> {
+        self.query_data(
+            "SELECT data FROM auth_api_token WHERE hash = $1",
+            &[&token_hash],
+        )
+    }
+
+    fn remove_api_token(&mut self, uuid: Uuid, token_uuid: Uuid) -> Result<bool, InternalError> {
+        let removed = self.client().execute(
+            "DELETE FROM auth_api_token WHERE uuid = $1 AND player = $2",
+            &[&token_uuid, &uuid],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn insert_totp(&mut self, uuid: Uuid, totp: Totp) -> Result<(), InternalError> {
+        self.client().execute(
+            "INSERT INTO auth_totp (player, data) VALUES ($1, $2)
+            ON CONFLICT (player) DO UPDATE SET data = excluded.data",
+            &[&uuid, &to_json(&totp)],
+        )?;
+        Ok(())
+    }
+
+    fn get_totp_for_update(&self, uuid: Uuid) -> Result<Option<Totp>, InternalError> {
+        self.get_totp(uuid)
+    }
+
+    fn remove_totp(&mut self, uuid: Uuid) -> Result<bool, InternalError> {
+        let removed = self
+            .client()
+            .execute("DELETE FROM auth_totp WHERE player = $1", &[&uuid])?;
+        Ok(removed > 0)
+    }
+}
@@ -0,0 +1,241 @@
+use super::{data_column, to_json, u32_column, PostgresTxn};
+use crate::guess::replay::ReplayedTargets;
+use crate::guess::store::{GuessProjection, GuessRead, GuessWrite};
+use crate::guess::types::{Guess, GuessEvent, GuessLogEntry, DELETED_PLAYER};
+use crate::storage::RowIter;
+use crate::types::InternalError;
+use chrono::{DateTime, Utc};
+use postgres::{GenericClient, Transaction};
+use uuid::Uuid;
+
+/// Start the guess event log of a database from before it was kept with events that recreate
+/// its targets and guesses.
+pub(super) fn seed_guess_log(txn: &mut Transaction) -> Result<(), InternalError> {
+    let mut current = ReplayedTargets::default();
+    for row in txn.query("SELECT height, nonce FROM guess_target", &[])? {
+        let (height, nonce) = read_target(&row)?;
+        current.set_target(height, nonce)?;
+    }
+    for row in txn.query("SELECT height, player, nonce FROM guess_guess", &[])? {
+        let guess = Guess {
+            player: row.try_get(1)?,
+            nonce: u32_column(&row, 2)?,
+        };
+        current.add_guess(u32_column(&row, 0)?, guess)?;
+    }
+    let timestamp = Utc::now();
+    for (seq, event) in (1..).zip(current.baseline_events()) {
+        insert_log_entry(
+            txn,
+            &GuessLogEntry {
+                seq,
+                timestamp,
+                event,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+fn insert_log_entry(
+    client: &mut impl GenericClient,
+    entry: &GuessLogEntry,
+) -> Result<(), InternalError> {
+    client.execute(
+        "INSERT INTO guess_log (seq, timestamp, data) VALUES ($1, $2, $3)",
+        &[&(entry.seq as i64), &entry.timestamp, &to_json(entry)],
+    )?;
+    Ok(())
+}
+
+impl GuessRead for PostgresTxn<'_> {
+    fn get_target_nonce(&self, height: u32) -> Result<Option<Option<u32>>, InternalError> {
+        let row = self.client().query_opt(
+            "SELECT nonce FROM guess_target WHERE height = $1",
+            &[&i64::from(height)],
+        )?;
+        let nonce = row
+            .map(|row| row.try_get::<_, Option<i64>>(0))
+            .transpose()?;
+        Ok(nonce.map(|nonce| nonce.map(|nonce| nonce as u32)))
+    }
+
+    fn get_last_target_nonce(&self) -> Result<Option<(u32, Option<u32>)>, InternalError> {
+        let row = self.client().query_opt(
+            "SELECT height, nonce FROM guess_target ORDER BY height DESC LIMIT 1",
+            &[],
+        )?;
+        row.map(|row| read_target(&row)).transpose()
+    }
+
+    fn iter_targets(&self) -> Result<RowIter<'_, (u32, Option<u32>)>, InternalError> {
+        Ok(self.paged(
+            "SELECT height, nonce FROM guess_target WHERE height > $1 ORDER BY height LIMIT $2",
+            -1_i64,
+            read_target,
+        ))
+    }
+
+    fn target_guesses(&self, height: u32) -> Result<Vec<Guess>, InternalError> {
+        let rows = self.client().query(
+            "SELECT player, nonce FROM guess_guess WHERE height = $1 ORDER BY nonce",
+            &[&i64::from(height)],
+        )?;
+        rows.iter()
+            .map(|row| {
+                Ok(Guess {
+                    player: row.try_get(0)?,
+                    nonce: u32_column(row, 1)?,
+                })
+            })
+            .collect()
+    }
+
+    fn iter_guess_log(&self) -> Result<RowIter<'_, GuessLogEntry>, InternalError> {
+        Ok(self.paged(
+            "SELECT seq, data FROM guess_log WHERE seq > $1 ORDER BY seq LIMIT $2",
+            0_i64,
+            |row| data_column(row, 1),
+        ))
+    }
+
+    fn player_guesses(&self, player_uuid: Uuid) -> Result<Vec<(u32, Guess)>, InternalError> {
+        let rows = self.client().query(
+            "SELECT height, nonce FROM guess_guess WHERE player = $1 ORDER BY height, nonce",
+            &[&player_uuid],
+        )?;
+        rows.iter()
+            .map(|row| {
+                let guess = Guess {
+                    player: player_uuid,
+                    nonce: u32_column(row, 1)?,
+                };
+                Ok((u32_column(row, 0)?, guess))
+            })
+            .collect()
+    }
+}
+
+impl GuessWrite for PostgresTxn<'_> {
+    fn append_guess_event(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        event: &GuessEvent,
+    ) -> Result<u64, InternalError> {
+        let mut client = self.client();
+        let last_seq = client
+            .query_one("SELECT COALESCE(MAX(seq), 0) FROM guess_log", &[])?
+            .try_get::<_, i64>(0)?;
+        let entry = GuessLogEntry {
+            seq: last_seq as u64 + 1,
+            timestamp,
+            event: event.clone(),
+        };
+        insert_log_entry(&mut *client, &entry)?;
+        Ok(entry.seq)
+    }
+
+    fn guess_log_for_update(&self) -> Result<Vec<GuessLogEntry>, InternalError> {
+        self.query_all_data("SELECT data FROM guess_log ORDER BY seq", &[])
+    }
+
+    fn target_guesses_for_update(&self, height: u32) -> Result<Vec<Guess>, InternalError> {
+        self.target_guesses(height)
+    }
+}
+
+impl GuessProjection for PostgresTxn<'_> {
+    fn set_target(
+        &mut self,
+        height: u32,
+        nonce: Option<u32>,
+    ) -> Result<Option<u32>, InternalError> {
+        let orig_nonce = self.get_target_nonce(height)?.flatten();
+        self.client().execute(
+            "INSERT INTO guess_target (height, nonce) VALUES ($1, $2)
+            ON CONFLICT (height) DO UPDATE SET nonce = excluded.nonce",
+            &[&i64::from(height), &nonce.map(i64::from)],
+        )?;
+        Ok(orig_nonce)
+    }
+
+    fn move_target(&mut self, old_height: u32, new_height: u32) -> Result<(), InternalError> {
+        let (old_height, new_height) = (i64::from(old_height), i64::from(new_height));
+        let mut client = self.client();
+        client.execute("DELETE FROM guess_target WHERE height = $1", &[&old_height])?;
+        client.execute(
+            "INSERT INTO guess_target (height, nonce) VALUES ($1, NULL)
+            ON CONFLICT (height) DO UPDATE SET nonce = NULL",
+            &[&new_height],
+        )?;
+        // the moved guesses replace any guesses already at the new height
+        client.execute(
+            "DELETE FROM guess_guess WHERE height = $2
+                AND nonce IN (SELECT nonce FROM guess_guess WHERE height = $1)",
+            &[&old_height, &new_height],
+        )?;
+        client.execute(
+            "UPDATE guess_guess SET height = $2 WHERE height = $1",
+            &[&old_height, &new_height],
+        )?;
+        Ok(())
+    }
+
+    fn delete_target(&mut self, height: u32) -> Result<Option<u32>, InternalError> {
+        let row = self.client().query_opt(
+            "DELETE FROM guess_target WHERE height = $1 RETURNING nonce",
+            &[&i64::from(height)],
+        )?;
+        let nonce = row
+            .map(|row| row.try_get::<_, Option<i64>>(0))
+            .transpose()?;
+        Ok(nonce.flatten().map(|nonce| nonce as u32))
+    }
+
+    fn delete_all_targets(&mut self) -> Result<usize, InternalError> {
+        let mut client = self.client();
+        let removed = client.execute("DELETE FROM guess_target", &[])?;
+        client.execute("DELETE FROM guess_guess", &[])?;
+        Ok(removed as usize)
+    }
+
+    fn add_guess(&mut self, height: u32, guess: Guess) -> Result<bool, InternalError> {
+        let inserted = self.client().execute(
+            "INSERT INTO guess_guess (height, nonce, player) VALUES ($1, $2, $3)
+            ON CONFLICT (height, nonce) DO NOTHING",
+            &[&i64::from(height), &i64::from(guess.nonce), &guess.player],
+        )?;
+        Ok(inserted == 0)
+    }
+
+    fn delete_target_guesses(&mut self, height: u32) -> Result<usize, InternalError> {
+        let removed = self.client().execute(
+            "DELETE FROM guess_guess WHERE height = $1",
+            &[&i64::from(height)],
+        )?;
+        Ok(removed as usize)
+    }
+
+    fn delete_player_guesses(
+        &mut self,
+        player_uuid: Uuid,
+        anonymize: bool,
+    ) -> Result<usize, InternalError> {
+        let changed = if anonymize {
+            self.client().execute(
+                "UPDATE guess_guess SET player = $2 WHERE player = $1",
+                &[&player_uuid, &DELETED_PLAYER],
+            )?
+        } else {
+            self.client()
+                .execute("DELETE FROM guess_guess WHERE player = $1", &[&player_uuid])?
+        };
+        Ok(changed as usize)
+    }
+}
+
+// a target height and nonce row
+fn read_target(row: &postgres::Row) -> Result<(u32, Option<u32>), InternalError> {
+    let nonce = row.try_get::<_, Option<i64>>(1)?;
+    Ok((u32_column(row, 0)?, nonce.map(|nonce| nonce as u32)))
+}
@@ -0,0 +1,76 @@
+use super::{to_json, PostgresTxn};
+use crate::session_store::{SessionRead, SessionWrite, StorageSessionStore};
+use crate::types::InternalError;
+use tower_sessions::cookie::time::OffsetDateTime;
+use tower_sessions::session::{Id, Record};
+
+impl SessionRead for PostgresTxn<'_> {
+    fn load_session(&self, id: Id) -> Result<Option<Record>, InternalError> {
+        self.query_data(
+            "SELECT data FROM session_record WHERE id = $1",
+            &[&id.to_string()],
+        )
+    }
+
+    fn user_sessions(&self, user_id: &str) -> Result<Vec<Record>, InternalError> {
+        self.query_all_data(
+            "SELECT data FROM session_record WHERE user_id = $1",
+            &[&user_id],
+        )
+    }
+}
+
+impl SessionWrite for PostgresTxn<'_> {
+    fn session_exists_for_update(&self, id: Id) -> Result<bool, InternalError> {
+        let exists = self
+            .client()
+            .query_opt(
+                "SELECT 1 FROM session_record WHERE id = $1",
+                &[&id.to_string()],
+            )?
+            .is_some();
+        Ok(exists)
+    }
+
+    fn save_session(&mut self, record: &Record) -> Result<(), InternalError> {
+        self.client().execute(
+            "INSERT INTO session_record (id, user_id, expiry, data) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (id) DO UPDATE
+                SET user_id = excluded.user_id, expiry = excluded.expiry, data = excluded.data",
+            &[
+                &record.id.to_string(),
+                &StorageSessionStore::record_user_id(record),
+                &record.expiry_date,
+                &to_json(record),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_session(&mut self, id: Id) -> Result<(), InternalError> {
+        self.client().execute(
+            "DELETE FROM session_record WHERE id = $1",
+            &[&id.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn delete_user_sessions(
+        &mut self,
+        user_id: &str,
+        keep: Option<Id>,
+    ) -> Result<usize, InternalError> {
+        let deleted = self.client().execute(
+            "DELETE FROM session_record WHERE user_id = $1 AND id IS DISTINCT FROM $2",
+            &[&user_id, &keep.map(|id| id.to_string())],
+        )?;
+        Ok(deleted as usize)
+    }
+
+    fn delete_expired_sessions(&mut self, now: OffsetDateTime) -> Result<usize, InternalError> {
+        let deleted = self
+            .client()
+            .execute("DELETE FROM session_record WHERE expiry < $1", &[&now])?;
+        Ok(deleted as usize)
+    }
+}
@@ -0,0 +1,85 @@
+use crate::audit::backend::AuditLog;
+use crate::auth::backend::AuthBackend;
+use crate::encoding::ReencryptProgress;
+use crate::encryption;
+use crate::guess::backend::GuessBackend;
+use crate::migration::migrate;
+use crate::session_store::{StorageSessionStore, SESSION_MIGRATIONS, SESSION_SCHEMA};
+use crate::storage::{ReadTxn, Storage, UndecodableRecord, WriteTxn};
+use crate::types::InternalError;
+use redb::{Database, WriteTransaction};
+use std::sync::Arc;
+
+/// [`Storage`] in an embedded redb database, records are kept in per-domain tables.
+#[derive(Debug, Clone)]
+pub struct RedbStorage {
+    db: Arc<Database>,
+}
+
+impl RedbStorage {
+    /// Open the storage, migrating the session tables, creating any missing tables and
+    /// quarantining records that can't be decoded.
+    pub fn new(db: Arc<Database>) -> Result<Self, InternalError> {
+        migrate(&db, SESSION_SCHEMA, SESSION_MIGRATIONS, false)?;
+        let storage = Self { db };
+        storage.init_tables()?;
+        Ok(storage)
+    }
+
+    fn init_tables(&self) -> Result<(), InternalError> {
+        let mut write_txn = self.db.begin_write()?;
+        AuthBackend::init_tables(&mut write_txn)?;
+        GuessBackend::init_tables(&mut write_txn)?;
+        AuditLog::init_tables(&mut write_txn)?;
+        StorageSessionStore::init_tables(&mut write_txn)?;
+        write_txn.commit()?;
+        Ok(())
+    }
+}
+
+impl Storage for RedbStorage {
+    fn begin_read(&self) -> Result<Box<dyn ReadTxn + '_>, InternalError> {
+        Ok(Box::new(self.db.begin_read()?))
+    }
+
+    fn begin_write(&self) -> Result<Box<dyn WriteTxn + '_>, InternalError> {
+        Ok(Box::new(self.db.begin_write()?))
+    }
+
+    fn undecodable_records(
+        &self,
+        quarantine: bool,
+    ) -> Result<Vec<UndecodableRecord>, InternalError> {
+        let read_txn = self.db.begin_read()?;
+        let mut records = AuthBackend::find_undecodable(&read_txn)?;
+        records.extend(GuessBackend::find_undecodable(&read_txn)?);
+        records.extend(AuditLog::find_undecodable(&read_txn)?);
+        records.extend(StorageSessionStore::find_undecodable(&read_txn)?);
+        drop(read_txn);
+        if quarantine && !records.is_empty() {
+            // opening the tables quarantines their undecodable records
+            self.init_tables()?;
+        }
+        Ok(records)
+    }
+
+    fn reencrypt_batch(
+        &self,
+        progress: &mut ReencryptProgress,
+        limit: usize,
+    ) -> Result<usize, InternalError> {
+        let keyring = encryption::keyring();
+        let write_txn = self.db.begin_write()?;
+        let mut count = AuthBackend::reencrypt(&write_txn, keyring, progress, limit)?;
+        count += StorageSessionStore::reencrypt(&write_txn, keyring, progress, limit - count)?;
+        write_txn.commit()?;
+        Ok(count)
+    }
+}
+
+impl WriteTxn for WriteTransaction {
+    fn commit(self: Box<Self>) -> Result<(), InternalError> {
+        WriteTransaction::commit(*self)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use super::{from_json, to_json, SqliteTxn};
+use crate::audit::store::{AuditRead, AuditWrite};
+use crate::audit::types::{AuditEvent, AuditFilter};
+use crate::types::InternalError;
+use rusqlite::{params, Connection};
+
+/// Append an event, also used by migrations which only have the connection.
+pub(super) fn insert_event(conn: &Connection, event: &AuditEvent) -> Result<(), InternalError> {
+    conn.execute(
+        "INSERT OR REPLACE INTO audit_event (uuid, timestamp, data) VALUES (?1, ?2, ?3)",
+        params![
+            event.uuid.to_string(),
+            event.timestamp.to_rfc3339(),
+            to_json(event)?
+        ],
+    )?;
+    Ok(())
+}
+
+impl AuditRead for SqliteTxn<'_> {
+    fn get_events(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, InternalError> {
+        // v7 uuids sort by time, so the newest events come first
+        let mut stmt = self
+            .conn()
+            .prepare_cached("SELECT data FROM audit_event ORDER BY uuid DESC")?;
+        let mut rows = stmt.query([])?;
+        let mut events = Vec::new();
+        while let Some(row) = rows.next()? {
+            let event: AuditEvent = from_json(&row.get::<_, String>(0)?)?;
+            if filter.matches(&event) {
+                events.push(event);
+                if filter.limit.is_some_and(|limit| events.len() >= limit) {
+                    break;
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl AuditWrite for SqliteTxn<'_> {
+    fn insert_event(&mut self, event: &AuditEvent) -> Result<(), InternalError> {
+        insert_event(self.conn(), event)
+    }
+}
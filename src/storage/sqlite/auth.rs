@@ -0,0 +1,581 @@
+use super::audit::insert_event;
+use super::{from_json, paged, query_all_data, query_data, to_json, uuid_column, SqliteTxn};
+use crate::audit::types::name_collision_event;
+use crate::auth::lnurl::LnurlChallenge;
+use crate::auth::store::{AuthRead, AuthWrite};
+use crate::auth::totp::Totp;
+use crate::auth::types::{
+    hash_token, index_player_names, name_key, ApiToken, IdentityKey, InviteCode, LoginFailures,
+    Player, ResetToken, Role, ThrottleKey,
+};
+use crate::storage::RowIter;
+use crate::types::InternalError;
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+/// Rebuild the player name index by `name_key`, players whose names collide are renamed and
+/// each rename is recorded in the audit log.
+pub(super) fn rebuild_name_index(conn: &Connection) -> Result<(), InternalError> {
+    let players = query_all_data(conn, "SELECT data FROM auth_player", [])?;
+    let (index, collisions) = index_player_names(players);
+    conn.execute("DELETE FROM auth_player_name", [])?;
+    for (key, uuid) in index {
+        conn.execute(
+            "INSERT INTO auth_player_name (key, uuid) VALUES (?1, ?2)",
+            params![key, uuid.to_string()],
+        )?;
+    }
+    for collision in collisions {
+        let player = &collision.player;
+        conn.execute(
+            "UPDATE auth_player SET data = ?2 WHERE uuid = ?1",
+            params![player.uuid.to_string(), to_json(player)?],
+        )?;
+        insert_event(conn, &name_collision_event(&collision))?;
+    }
+    Ok(())
+}
+
+/// Key the invite codes by their hash, they were stored by the plain code before.
+pub(super) fn hash_invite_codes(conn: &Connection) -> Result<(), InternalError> {
+    let invite_codes: Vec<InviteCode> =
+        query_all_data(conn, "SELECT data FROM auth_invite_code", [])?;
+    conn.execute("DELETE FROM auth_invite_code", [])?;
+    for invite_code in invite_codes {
+        let invite_code = InviteCode {
+            code_hash: hash_token(&invite_code.code_hash),
+            ..invite_code
+        };
+        conn.execute(
+            "INSERT INTO auth_invite_code (hash, data) VALUES (?1, ?2)",
+            params![invite_code.code_hash, to_json(&invite_code)?],
+        )?;
+    }
+    Ok(())
+}
+
+impl AuthRead for SqliteTxn<'_> {
+    fn get_player_by_uuid(&self, uuid: Uuid) -> Result<Option<Player>, InternalError> {
+        query_data(
+            self.conn(),
+            "SELECT data FROM auth_player WHERE uuid = ?1",
+            [uuid.to_string()],
+        )
+    }
+
+    fn get_player_by_name(&self, name: &str) -> Result<Option<Player>, InternalError> {
+        query_data(
+            self.conn(),
+            "SELECT data FROM auth_player_name JOIN auth_player USING (uuid) WHERE key = ?1",
+            [name_key(name)],
+        )
+    }
+
+    fn get_players(&self) -> Result<Vec<Player>, InternalError> {
+        query_all_data(
+            self.conn(),
+            "SELECT data FROM auth_player ORDER BY uuid",
+            [],
+        )
+    }
+
+    fn get_player_names(&self) -> Result<Vec<(String, Uuid)>, InternalError> {
+        let names = self
+            .conn()
+            .prepare_cached("SELECT key, uuid FROM auth_player_name ORDER BY key")?
+            .query_map([], |row| Ok((row.get(0)?, uuid_column(row, 1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, Uuid)>>>()?;
+        Ok(names)
+    }
+
+    fn iter_players(&self) -> Result<RowIter<'_, Player>, InternalError> {
+        let rows = paged(
+            self.conn(),
+            "SELECT uuid, data FROM auth_player WHERE uuid > ?1 ORDER BY uuid LIMIT ?2",
+            String::new(),
+            |row| Ok((row.get(0)?, row.get::<_, String>(1)?)),
+        );
+        Ok(Box::new(rows.map(|data| from_json(&data?))))
+    }
+
+    fn get_role_by_uuid(&self, uuid: Uuid) -> Result<Option<Role>, InternalError> {
+        query_data(
+            self.conn(),
+            "SELECT data FROM auth_role WHERE uuid = ?1",
+            [uuid.to_string()],
+        )
+    }
+
+    fn get_roles(&self) -> Result<Vec<Role>, InternalError> {
+        query_all_data(self.conn(), "SELECT data FROM auth_role ORDER BY uuid", [])
+    }
+
+    fn iter_roles(&self) -> Result<RowIter<'_, Role>, InternalError> {
+        let rows = paged(
+            self.conn(),
+            "SELECT uuid, data FROM auth_role WHERE uuid > ?1 ORDER BY uuid LIMIT ?2",
+            String::new(),
+            |row| Ok((row.get(0)?, row.get::<_, String>(1)?)),
+        );
+        Ok(Box::new(rows.map(|data| from_json(&data?))))
+    }
+
+    fn get_login_failures(
+        &self,
+        key: &ThrottleKey,
+    ) -> Result<Option<LoginFailures>, InternalError> {
+        query_data(
+            self.conn(),
+            "SELECT data FROM auth_login_failures WHERE key = ?1",
+            [key.to_string()],
+        )
+    }
+
+    fn get_all_login_failures(&self) -> Result<Vec<(String, LoginFailures)>, InternalError> {
+        let mut stmt = self
+            .conn()
+            .prepare_cached("SELECT key, data FROM auth_login_failures ORDER BY key")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        rows.map(|row| {
+            let (key, data) = row?;
+            Ok((key, from_json(&data)?))
+        })
+        .collect()
+    }
+
+    fn get_reset_token(&self, token_hash: String) -> Result<Option<ResetToken>, InternalError> {
+        query_data(
+            self.conn(),
+            "SELECT data FROM auth_reset_token WHERE hash = ?1",
+            [token_hash],
+        )
+    }
+
+    fn get_invite_codes(&self) -> Result<Vec<InviteCode>, InternalError> {
+        query_all_data(
+            self.conn(),
+            "SELECT data FROM auth_invite_code ORDER BY hash",
+            [],
+        )
+    }
+
+    fn get_player_identities(&self, uuid: Uuid) -> Result<Vec<String>, InternalError> {
+        let mut stmt = self.conn().prepare_cached(
+            "SELECT identity FROM auth_identity WHERE player = ?1 ORDER BY identity",
+        )?;
+        let identities = stmt
+            .query_map([uuid.to_string()], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(identities)
+    }
+
+    fn get_lnurl_challenge(&self, k1: &str) -> Result<Option<LnurlChallenge>, InternalError> {
+        query_data(
+            self.conn(),
+            "SELECT data FROM auth_lnurl_challenge WHERE k1 = ?1",
+            [k1],
+        )
+    }
+
+    fn get_totp(&self, uuid: Uuid) -> Result<Option<Totp>, InternalError> {
+        query_data(
+            self.conn(),
+            "SELECT data FROM auth_totp WHERE player = ?1",
+            [uuid.to_string()],
+        )
+    }
+
+    fn get_player_api_tokens(&self, uuid: Uuid) -> Result<Vec<ApiToken>, InternalError> {
+        query_all_data(
+            self.conn(),
+            "SELECT data FROM auth_api_token WHERE player = ?1 ORDER BY hash",
+            [uuid.to_string()],
+        )
+    }
+}
+
+impl AuthWrite for SqliteTxn<'_> {
+    fn insert_player(&mut self, player: Player) -> Result<Option<Player>, InternalError> {
+        let orig_player = self.get_player_by_uuid(player.uuid)?;
+        self.check_name_for_update(&player.name, player.uuid)?;
+        self.conn().execute(
+            "INSERT OR IGNORE INTO auth_player_name (key, uuid) VALUES (?1, ?2)",
+            params![name_key(&player.name), player.uuid.to_string()],
+        )?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO auth_player (uuid, data) VALUES (?1, ?2)",
+            params![player.uuid.to_string(), to_json(&player)?],
+        )?;
+        Ok(orig_player)
+    }
+
+    fn change_player(
+        &mut self,
+        orig_player: Player,
+        new_player: Player,
+    ) -> Result<Option<Player>, InternalError> {
+        if orig_player == new_player {
+            return Ok(None);
+        }
+        if orig_player.name != new_player.name {
+            self.check_name_for_update(&new_player.name, orig_player.uuid)?;
+            self.conn().execute(
+                "DELETE FROM auth_player_name WHERE key = ?1",
+                [name_key(&orig_player.name)],
+            )?;
+            self.conn().execute(
+                "INSERT INTO auth_player_name (key, uuid) VALUES (?1, ?2)",
+                params![name_key(&new_player.name), orig_player.uuid.to_string()],
+            )?;
+        }
+        let stored_player = self.get_player_by_uuid(orig_player.uuid)?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO auth_player (uuid, data) VALUES (?1, ?2)",
+            params![orig_player.uuid.to_string(), to_json(&new_player)?],
+        )?;
+        Ok(stored_player)
+    }
+
+    fn remove_player(&mut self, uuid: Uuid) -> Result<Option<Player>, InternalError> {
+        let conn = self.conn();
+        let uuid_text = uuid.to_string();
+        let player: Option<Player> = query_data(
+            conn,
+            "DELETE FROM auth_player WHERE uuid = ?1 RETURNING data",
+            [&uuid_text],
+        )?;
+        if let Some(player) = &player {
+            conn.execute(
+                "DELETE FROM auth_player_name WHERE key = ?1",
+                [name_key(&player.name)],
+            )?;
+        }
+        conn.execute("DELETE FROM auth_identity WHERE player = ?1", [&uuid_text])?;
+        conn.execute(
+            "DELETE FROM auth_reset_token WHERE player = ?1",
+            [&uuid_text],
+        )?;
+        conn.execute("DELETE FROM auth_api_token WHERE player = ?1", [&uuid_text])?;
+        conn.execute("DELETE FROM auth_totp WHERE player = ?1", [&uuid_text])?;
+        // failures of the freed name would throttle the next player who takes it
+        if let Some(player) = &player {
+            self.remove_login_failures_if(&|key, _| ThrottleKey::is_for_name(key, &player.name))?;
+        }
+        Ok(player)
+    }
+
+    fn get_player_for_update(&self, uuid: Uuid) -> Result<Option<Player>, InternalError> {
+        self.get_player_by_uuid(uuid)
+    }
+
+    fn get_players_for_update(&self) -> Result<Vec<Player>, InternalError> {
+        self.get_players()
+    }
+
+    fn get_name_key_for_update(&self, key: &str) -> Result<Option<Uuid>, InternalError> {
+        let uuid = self
+            .conn()
+            .prepare_cached("SELECT uuid FROM auth_player_name WHERE key = ?1")?
+            .query_row([key], |row| uuid_column(row, 0))
+            .optional()?;
+        Ok(uuid)
+    }
+
+    fn repair_player_name(&mut self, key: &str, uuid: Option<Uuid>) -> Result<(), InternalError> {
+        match uuid {
+            Some(uuid) => self.conn().execute(
+                "INSERT OR REPLACE INTO auth_player_name (key, uuid) VALUES (?1, ?2)",
+                params![key, uuid.to_string()],
+            )?,
+            None => self
+                .conn()
+                .execute("DELETE FROM auth_player_name WHERE key = ?1", [key])?,
+        };
+        Ok(())
+    }
+
+    fn is_empty_for_update(&self) -> Result<bool, InternalError> {
+        let empty = self.conn().query_row(
+            "SELECT NOT EXISTS (SELECT 1 FROM auth_role)
+                AND NOT EXISTS (SELECT 1 FROM auth_player)
+                AND NOT EXISTS (SELECT 1 FROM auth_player_name)",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(empty)
+    }
+
+    fn upsert_players(&mut self, players: &[Player]) -> Result<(), InternalError> {
+        for player in players {
+            self.conn().execute(
+                "DELETE FROM auth_player_name WHERE uuid = ?1",
+                [player.uuid.to_string()],
+            )?;
+        }
+        for player in players {
+            self.insert_player(player.clone())?;
+        }
+        Ok(())
+    }
+
+    fn remove_all_players(&mut self) -> Result<usize, InternalError> {
+        let uuids = {
+            let mut stmt = self.conn().prepare_cached("SELECT uuid FROM auth_player")?;
+            let uuids = stmt
+                .query_map([], |row| uuid_column(row, 0))?
+                .collect::<rusqlite::Result<Vec<Uuid>>>()?;
+            uuids
+        };
+        for uuid in &uuids {
+            self.remove_player(*uuid)?;
+        }
+        self.conn().execute("DELETE FROM auth_player_name", [])?;
+        Ok(uuids.len())
+    }
+
+    fn insert_role(&mut self, role: Role) -> Result<Option<Role>, InternalError> {
+        let orig_role = self.get_role_by_uuid(role.uuid)?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO auth_role (uuid, data) VALUES (?1, ?2)",
+            params![role.uuid.to_string(), to_json(&role)?],
+        )?;
+        Ok(orig_role)
+    }
+
+    fn get_role_for_update(&self, uuid: Uuid) -> Result<Option<Role>, InternalError> {
+        self.get_role_by_uuid(uuid)
+    }
+
+    fn remove_all_roles(&mut self) -> Result<usize, InternalError> {
+        let removed = self.conn().execute("DELETE FROM auth_role", [])?;
+        Ok(removed)
+    }
+
+    fn get_login_failures_for_update(
+        &self,
+        key: &ThrottleKey,
+    ) -> Result<Option<LoginFailures>, InternalError> {
+        self.get_login_failures(key)
+    }
+
+    fn insert_login_failures(
+        &mut self,
+        key: &ThrottleKey,
+        login_failures: &LoginFailures,
+    ) -> Result<(), InternalError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO auth_login_failures (key, data) VALUES (?1, ?2)",
+            params![key.to_string(), to_json(login_failures)?],
+        )?;
+        Ok(())
+    }
+
+    fn remove_login_failures(&mut self, key: &str) -> Result<Option<LoginFailures>, InternalError> {
+        query_data(
+            self.conn(),
+            "DELETE FROM auth_login_failures WHERE key = ?1 RETURNING data",
+            [key],
+        )
+    }
+
+    fn remove_login_failures_if(
+        &mut self,
+        remove: &dyn Fn(&str, &LoginFailures) -> bool,
+    ) -> Result<usize, InternalError> {
+        let mut removed = 0;
+        for (key, login_failures) in self.get_all_login_failures()? {
+            if remove(&key, &login_failures) {
+                removed += self
+                    .conn()
+                    .execute("DELETE FROM auth_login_failures WHERE key = ?1", [&key])?;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn insert_reset_token(
+        &mut self,
+        token_hash: String,
+        reset_token: ResetToken,
+    ) -> Result<(), InternalError> {
+        self.conn().execute(
+            "DELETE FROM auth_reset_token WHERE player = ?1 OR expires <= ?2",
+            params![
+                reset_token.player.to_string(),
+                Utc::now().timestamp_millis()
+            ],
+        )?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO auth_reset_token (hash, player, expires, data)
+                VALUES (?1, ?2, ?3, ?4)",
+            params![
+                token_hash,
+                reset_token.player.to_string(),
+                reset_token.expires.timestamp_millis(),
+                to_json(&reset_token)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove_reset_token(
+        &mut self,
+        token_hash: String,
+    ) -> Result<Option<ResetToken>, InternalError> {
+        query_data(
+            self.conn(),
+            "DELETE FROM auth_reset_token WHERE hash = ?1 RETURNING data",
+            [token_hash],
+        )
+    }
+
+    fn insert_invite_code(
+        &mut self,
+        invite_code: InviteCode,
+    ) -> Result<Option<InviteCode>, InternalError> {
+        let orig_invite_code = self.get_invite_code_for_update(&invite_code.code_hash)?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO auth_invite_code (hash, data) VALUES (?1, ?2)",
+            params![invite_code.code_hash, to_json(&invite_code)?],
+        )?;
+        Ok(orig_invite_code)
+    }
+
+    fn get_invite_code_for_update(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<InviteCode>, InternalError> {
+        query_data(
+            self.conn(),
+            "SELECT data FROM auth_invite_code WHERE hash = ?1",
+            [code_hash],
+        )
+    }
+
+    fn remove_invite_code(&mut self, code_hash: &str) -> Result<Option<InviteCode>, InternalError> {
+        query_data(
+            self.conn(),
+            "DELETE FROM auth_invite_code WHERE hash = ?1 RETURNING data",
+            [code_hash],
+        )
+    }
+
+    fn link_identity(
+        &mut self,
+        identity_key: &IdentityKey,
+        player_uuid: Uuid,
+    ) -> Result<Option<Uuid>, InternalError> {
+        let orig_uuid = self.get_identity_for_update(identity_key)?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO auth_identity (identity, player) VALUES (?1, ?2)",
+            params![identity_key.to_string(), player_uuid.to_string()],
+        )?;
+        Ok(orig_uuid)
+    }
+
+    fn get_identity_for_update(
+        &self,
+        identity_key: &IdentityKey,
+    ) -> Result<Option<Uuid>, InternalError> {
+        let uuid = self
+            .conn()
+            .prepare_cached("SELECT player FROM auth_identity WHERE identity = ?1")?
+            .query_row([identity_key.to_string()], |row| uuid_column(row, 0))
+            .optional()?;
+        Ok(uuid)
+    }
+
+    fn insert_lnurl_challenge(&mut self, challenge: LnurlChallenge) -> Result<(), InternalError> {
+        self.conn().execute(
+            "DELETE FROM auth_lnurl_challenge WHERE expires <= ?1",
+            [Utc::now().timestamp_millis()],
+        )?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO auth_lnurl_challenge (k1, expires, data) VALUES (?1, ?2, ?3)",
+            params![
+                challenge.k1,
+                challenge.expires.timestamp_millis(),
+                to_json(&challenge)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_lnurl_challenge_for_update(
+        &self,
+        k1: &str,
+    ) -> Result<Option<LnurlChallenge>, InternalError> {
+        self.get_lnurl_challenge(k1)
+    }
+
+    fn remove_lnurl_challenge(
+        &mut self,
+        k1: &str,
+    ) -> Result<Option<LnurlChallenge>, InternalError> {
+        query_data(
+            self.conn(),
+            "DELETE FROM auth_lnurl_challenge WHERE k1 = ?1 RETURNING data",
+            [k1],
+        )
+    }
+
+    fn insert_api_token(
+        &mut self,
+        token_hash: String,
+        api_token: ApiToken,
+    ) -> Result<(), InternalError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO auth_api_token (hash, uuid, player, data)
+                VALUES (?1, ?2, ?3, ?4)",
+            params![
+                token_hash,
+                api_token.uuid.to_string(),
+                api_token.player.to_string(),
+                to_json(&api_token)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_api_token_for_update(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<ApiToken>, InternalError> {
+        query_data(
+            self.conn(),
+            "SELECT data FROM auth_api_token WHERE hash = ?1",
+            [token_hash],
+        )
+    }
+
+    fn remove_api_token(&mut self, uuid: Uuid, token_uuid: Uuid) -> Result<bool, InternalError> {
+        let removed = self.conn().execute(
+            "DELETE FROM auth_api_token WHERE uuid = ?1 AND player = ?2",
+            params![token_uuid.to_string(), uuid.to_string()],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn insert_totp(&mut self, uuid: Uuid, totp: Totp) -> Result<(), InternalError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO auth_totp (player, data) VALUES (?1, ?2)",
+            params![uuid.to_string(), to_json(&totp)?],
+        )?;
+        Ok(())
+    }
+
+    fn get_totp_for_update(&self, uuid: Uuid) -> Result<Option<Totp>, InternalError> {
+        self.get_totp(uuid)
+    }
+
+    fn remove_totp(&mut self, uuid: Uuid) -> Result<bool, InternalError> {
+        let removed = self.conn().execute(
+            "DELETE FROM auth_totp WHERE player = ?1",
+            [uuid.to_string()],
+        )?;
+        Ok(removed > 0)
+    }
+}
@@ -0,0 +1,239 @@
+use super::{from_json, paged, query_all_data, to_json, uuid_column, SqliteTxn};
+use crate::guess::replay::ReplayedTargets;
+use crate::guess::store::{GuessProjection, GuessRead, GuessWrite};
+use crate::guess::types::{Guess, GuessEvent, GuessLogEntry, DELETED_PLAYER};
+use crate::storage::RowIter;
+use crate::types::InternalError;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+/// Start the guess event log of a database from before it was kept with events that recreate
+/// its targets and guesses.
+pub(super) fn seed_guess_log(conn: &Connection) -> Result<(), InternalError> {
+    let mut current = ReplayedTargets::default();
+    let mut stmt = conn.prepare("SELECT height, nonce FROM guess_target")?;
+    let targets = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(u32, Option<u32>)>>>()?;
+    for (height, nonce) in targets {
+        current.set_target(height, nonce)?;
+    }
+    let mut stmt = conn.prepare("SELECT height, player, nonce FROM guess_guess")?;
+    let guesses = stmt
+        .query_map([], |row| {
+            let guess = Guess {
+                player: uuid_column(row, 1)?,
+                nonce: row.get(2)?,
+            };
+            Ok((row.get(0)?, guess))
+        })?
+        .collect::<rusqlite::Result<Vec<(u32, Guess)>>>()?;
+    for (height, guess) in guesses {
+        current.add_guess(height, guess)?;
+    }
+    let timestamp = Utc::now();
+    for (seq, event) in (1..).zip(current.baseline_events()) {
+        insert_log_entry(
+            conn,
+            &GuessLogEntry {
+                seq,
+                timestamp,
+                event,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+fn insert_log_entry(conn: &Connection, entry: &GuessLogEntry) -> Result<(), InternalError> {
+    conn.execute(
+        "INSERT INTO guess_log (seq, timestamp, data) VALUES (?1, ?2, ?3)",
+        params![
+            entry.seq as i64,
+            entry.timestamp.to_rfc3339(),
+            to_json(entry)?
+        ],
+    )?;
+    Ok(())
+}
+
+impl GuessRead for SqliteTxn<'_> {
+    fn get_target_nonce(&self, height: u32) -> Result<Option<Option<u32>>, InternalError> {
+        let nonce = self
+            .conn()
+            .prepare_cached("SELECT nonce FROM guess_target WHERE height = ?1")?
+            .query_row([height], |row| row.get(0))
+            .optional()?;
+        Ok(nonce)
+    }
+
+    fn get_last_target_nonce(&self) -> Result<Option<(u32, Option<u32>)>, InternalError> {
+        let target = self
+            .conn()
+            .prepare_cached("SELECT height, nonce FROM guess_target ORDER BY height DESC LIMIT 1")?
+            .query_row([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?;
+        Ok(target)
+    }
+
+    fn iter_targets(&self) -> Result<RowIter<'_, (u32, Option<u32>)>, InternalError> {
+        let targets = paged(
+            self.conn(),
+            "SELECT height, nonce FROM guess_target WHERE height > ?1 ORDER BY height LIMIT ?2",
+            -1,
+            |row| {
+                let height = row.get::<_, u32>(0)?;
+                Ok((i64::from(height), (height, row.get(1)?)))
+            },
+        );
+        Ok(targets)
+    }
+
+    fn target_guesses(&self, height: u32) -> Result<Vec<Guess>, InternalError> {
+        let mut stmt = self.conn().prepare_cached(
+            "SELECT player, nonce FROM guess_guess WHERE height = ?1 ORDER BY nonce",
+        )?;
+        let guesses = stmt
+            .query_map([height], |row| {
+                Ok(Guess {
+                    player: uuid_column(row, 0)?,
+                    nonce: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Guess>>>()?;
+        Ok(guesses)
+    }
+
+    fn iter_guess_log(&self) -> Result<RowIter<'_, GuessLogEntry>, InternalError> {
+        let entries = paged(
+            self.conn(),
+            "SELECT seq, data FROM guess_log WHERE seq > ?1 ORDER BY seq LIMIT ?2",
+            0,
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        );
+        Ok(Box::new(entries.map(|data| from_json(&data?))))
+    }
+
+    fn player_guesses(&self, player_uuid: Uuid) -> Result<Vec<(u32, Guess)>, InternalError> {
+        let mut stmt = self.conn().prepare_cached(
+            "SELECT height, nonce FROM guess_guess WHERE player = ?1 ORDER BY height, nonce",
+        )?;
+        let guesses = stmt
+            .query_map([player_uuid.to_string()], |row| {
+                let guess = Guess {
+                    player: player_uuid,
+                    nonce: row.get(1)?,
+                };
+                Ok((row.get(0)?, guess))
+            })?
+            .collect::<rusqlite::Result<Vec<(u32, Guess)>>>()?;
+        Ok(guesses)
+    }
+}
+
+impl GuessWrite for SqliteTxn<'_> {
+    fn append_guess_event(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        event: &GuessEvent,
+    ) -> Result<u64, InternalError> {
+        let last_seq = self
+            .conn()
+            .prepare_cached("SELECT COALESCE(MAX(seq), 0) FROM guess_log")?
+            .query_row([], |row| row.get::<_, i64>(0))?;
+        let entry = GuessLogEntry {
+            seq: last_seq as u64 + 1,
+            timestamp,
+            event: event.clone(),
+        };
+        insert_log_entry(self.conn(), &entry)?;
+        Ok(entry.seq)
+    }
+
+    fn guess_log_for_update(&self) -> Result<Vec<GuessLogEntry>, InternalError> {
+        query_all_data(self.conn(), "SELECT data FROM guess_log ORDER BY seq", [])
+    }
+
+    fn target_guesses_for_update(&self, height: u32) -> Result<Vec<Guess>, InternalError> {
+        self.target_guesses(height)
+    }
+}
+
+impl GuessProjection for SqliteTxn<'_> {
+    fn set_target(
+        &mut self,
+        height: u32,
+        nonce: Option<u32>,
+    ) -> Result<Option<u32>, InternalError> {
+        let orig_nonce = self.get_target_nonce(height)?.flatten();
+        self.conn().execute(
+            "INSERT OR REPLACE INTO guess_target (height, nonce) VALUES (?1, ?2)",
+            params![height, nonce],
+        )?;
+        Ok(orig_nonce)
+    }
+
+    fn move_target(&mut self, old_height: u32, new_height: u32) -> Result<(), InternalError> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM guess_target WHERE height = ?1", [old_height])?;
+        conn.execute(
+            "INSERT OR REPLACE INTO guess_target (height, nonce) VALUES (?1, NULL)",
+            [new_height],
+        )?;
+        conn.execute(
+            "UPDATE OR REPLACE guess_guess SET height = ?2 WHERE height = ?1",
+            [old_height, new_height],
+        )?;
+        Ok(())
+    }
+
+    fn delete_target(&mut self, height: u32) -> Result<Option<u32>, InternalError> {
+        let nonce = self
+            .conn()
+            .prepare_cached("DELETE FROM guess_target WHERE height = ?1 RETURNING nonce")?
+            .query_row([height], |row| row.get::<_, Option<u32>>(0))
+            .optional()?;
+        Ok(nonce.flatten())
+    }
+
+    fn delete_all_targets(&mut self) -> Result<usize, InternalError> {
+        let removed = self.conn().execute("DELETE FROM guess_target", [])?;
+        self.conn().execute("DELETE FROM guess_guess", [])?;
+        Ok(removed)
+    }
+
+    fn add_guess(&mut self, height: u32, guess: Guess) -> Result<bool, InternalError> {
+        let inserted = self.conn().execute(
+            "INSERT OR IGNORE INTO guess_guess (height, nonce, player) VALUES (?1, ?2, ?3)",
+            params![height, guess.nonce, guess.player.to_string()],
+        )?;
+        Ok(inserted == 0)
+    }
+
+    fn delete_target_guesses(&mut self, height: u32) -> Result<usize, InternalError> {
+        let removed = self
+            .conn()
+            .execute("DELETE FROM guess_guess WHERE height = ?1", [height])?;
+        Ok(removed)
+    }
+
+    fn delete_player_guesses(
+        &mut self,
+        player_uuid: Uuid,
+        anonymize: bool,
+    ) -> Result<usize, InternalError> {
+        let changed = if anonymize {
+            self.conn().execute(
+                "UPDATE guess_guess SET player = ?2 WHERE player = ?1",
+                [player_uuid.to_string(), DELETED_PLAYER.to_string()],
+            )?
+        } else {
+            self.conn().execute(
+                "DELETE FROM guess_guess WHERE player = ?1",
+                [player_uuid.to_string()],
+            )?
+        };
+        Ok(changed)
+    }
+}
@@ -0,0 +1,553 @@
+use crate::audit::store::AuditWrite;
+use crate::storage::{ReadTxn, RowIter, Storage, UndecodableRecord, WriteTxn, JSON_TABLES};
+use crate::types::InternalError;
+use chrono::Utc;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Params, Row, ToSql};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+mod audit;
+mod auth;
+mod guess;
+mod session;
+
+/// Schema component of the SQLite tables, versioned with `PRAGMA user_version`.
+const SQLITE_SCHEMA: &str = "sqlite";
+
+/// Ordered SQLite schema migrations. Records are kept as JSON in a `data` column, with the
+/// columns they are looked up by next to it.
+const SQLITE_MIGRATIONS: &[SqliteMigration] = &[
+    SqliteMigration {
+        version: 1,
+        description: "baseline schema",
+        sql: "CREATE TABLE auth_player (uuid TEXT PRIMARY KEY, data TEXT NOT NULL);
+        CREATE TABLE auth_player_name (name TEXT PRIMARY KEY, uuid TEXT NOT NULL);
+        CREATE TABLE auth_role (uuid TEXT PRIMARY KEY, data TEXT NOT NULL);
+        CREATE TABLE auth_login_failures (key TEXT PRIMARY KEY, data TEXT NOT NULL);
+        CREATE TABLE auth_reset_token (
+            hash TEXT PRIMARY KEY,
+            player TEXT NOT NULL,
+            expires INTEGER NOT NULL,
+            data TEXT NOT NULL
+        );
+        CREATE TABLE auth_invite_code (code TEXT PRIMARY KEY, data TEXT NOT NULL);
+        CREATE TABLE auth_identity (identity TEXT PRIMARY KEY, player TEXT NOT NULL);
+        CREATE INDEX auth_identity_player ON auth_identity (player);
+        CREATE TABLE auth_lnurl_challenge (
+            k1 TEXT PRIMARY KEY,
+            expires INTEGER NOT NULL,
+            data TEXT NOT NULL
+        );
+        CREATE TABLE auth_api_token (
+            hash TEXT PRIMARY KEY,
+            uuid TEXT NOT NULL,
+            player TEXT NOT NULL,
+            data TEXT NOT NULL
+        );
+        CREATE INDEX auth_api_token_player ON auth_api_token (player);
+        CREATE TABLE auth_totp (player TEXT PRIMARY KEY, data TEXT NOT NULL);
+        CREATE TABLE guess_target (height INTEGER PRIMARY KEY, nonce INTEGER);
+        CREATE TABLE guess_guess (
+            height INTEGER NOT NULL,
+            nonce INTEGER NOT NULL,
+            player TEXT NOT NULL,
+            PRIMARY KEY (height, nonce)
+        );
+        CREATE INDEX guess_guess_player ON guess_guess (player);
+        CREATE TABLE audit_event (uuid TEXT PRIMARY KEY, timestamp TEXT NOT NULL, data TEXT NOT NULL);
+        CREATE TABLE session_record (
+            id TEXT PRIMARY KEY,
+            user_id TEXT,
+            expiry INTEGER NOT NULL,
+            data TEXT NOT NULL
+        );
+        CREATE INDEX session_record_user_id ON session_record (user_id);
+        CREATE INDEX session_record_expiry ON session_record (expiry);",
+        apply: |_| Ok(()),
+    },
+    SqliteMigration {
+        version: 2,
+        description: "case-insensitive player names, colliding names are renamed",
+        sql: "ALTER TABLE auth_player_name RENAME COLUMN name TO key;",
+        apply: auth::rebuild_name_index,
+    },
+    SqliteMigration {
+        version: 3,
+        description: "quarantine table for undecodable records",
+        sql: "CREATE TABLE quarantine (
+            id INTEGER PRIMARY KEY,
+            table_name TEXT NOT NULL,
+            key TEXT NOT NULL,
+            data TEXT NOT NULL,
+            error TEXT NOT NULL,
+            quarantined TEXT NOT NULL
+        );",
+        apply: |_| Ok(()),
+    },
+    SqliteMigration {
+        version: 4,
+        description: "guess event log, started with the current targets and guesses",
+        sql: "CREATE TABLE guess_log (seq INTEGER PRIMARY KEY, timestamp TEXT NOT NULL, data TEXT NOT NULL);",
+        apply: guess::seed_guess_log,
+    },
+    SqliteMigration {
+        version: 5,
+        description: "invite codes are stored by their hash",
+        sql: "ALTER TABLE auth_invite_code RENAME COLUMN code TO hash;",
+        apply: auth::hash_invite_codes,
+    },
+];
+
+/// A schema change, the `sql` runs before `apply` rewrites any records, in one transaction.
+struct SqliteMigration {
+    version: u32,
+    description: &'static str,
+    sql: &'static str,
+    apply: fn(&Connection) -> Result<(), InternalError>,
+}
+
+// rows read per query by the row iterators
+const PAGE_SIZE: i64 = 100;
+
+/// [`Storage`] in a SQLite database file, shared by the connections of a small pool.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    file: PathBuf,
+    // connections not used by a transaction
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Open the database file, creating it if needed, and apply the schema migrations.
+    pub fn new(file: PathBuf) -> Result<Self, InternalError> {
+        let storage = Self {
+            file,
+            idle: Mutex::new(Vec::new()),
+        };
+        let conn = storage.open()?;
+        migrate(&conn)?;
+        storage.release(conn);
+        Ok(storage)
+    }
+
+    fn open(&self) -> Result<Connection, InternalError> {
+        let conn = Connection::open(&self.file)?;
+        // readers don't block the writer, and writers wait for each other
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Ok(conn)
+    }
+
+    fn release(&self, conn: Connection) {
+        self.idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(conn);
+    }
+
+    fn begin(&self, sql: &str) -> Result<SqliteTxn<'_>, InternalError> {
+        let idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => self.open()?,
+        };
+        let txn = SqliteTxn {
+            storage: self,
+            conn: Some(conn),
+        };
+        txn.conn().execute_batch(sql)?;
+        Ok(txn)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn begin_read(&self) -> Result<Box<dyn ReadTxn + '_>, InternalError> {
+        Ok(Box::new(self.begin("BEGIN")?))
+    }
+
+    fn begin_write(&self) -> Result<Box<dyn WriteTxn + '_>, InternalError> {
+        // take the write lock up front, so the transaction doesn't fail on its first write
+        Ok(Box::new(self.begin("BEGIN IMMEDIATE")?))
+    }
+
+    fn undecodable_records(
+        &self,
+        quarantine: bool,
+    ) -> Result<Vec<UndecodableRecord>, InternalError> {
+        let mut txn = self.begin("BEGIN IMMEDIATE")?;
+        let mut records = Vec::new();
+        for (table, key_column, decodes) in JSON_TABLES {
+            let rows = txn
+                .conn()
+                .prepare(&format!(
+                    "SELECT CAST({} AS TEXT), data FROM {}",
+                    key_column, table
+                ))?
+                .query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+            for (key, data) in rows {
+                let Err(e) = decodes(&data) else {
+                    continue;
+                };
+                let record = UndecodableRecord {
+                    table: table.to_string(),
+                    key,
+                    error: e.to_string(),
+                };
+                if quarantine {
+                    warn!(
+                        "quarantined undecodable record {} {}: {}",
+                        record.table, record.key, record.error
+                    );
+                    txn.conn().execute(
+                        "INSERT INTO quarantine (table_name, key, data, error, quarantined)
+                        VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![
+                            record.table,
+                            record.key,
+                            data,
+                            record.error,
+                            Utc::now().to_rfc3339()
+                        ],
+                    )?;
+                    txn.conn().execute(
+                        &format!(
+                            "DELETE FROM {} WHERE CAST({} AS TEXT) = ?1",
+                            table, key_column
+                        ),
+                        [&record.key],
+                    )?;
+                    txn.insert_event(&record.quarantine_event())?;
+                }
+                records.push(record);
+            }
+        }
+        if quarantine {
+            Box::new(txn).commit()?;
+        }
+        Ok(records)
+    }
+}
+
+// apply the migrations newer than the schema version, each in its own transaction
+fn migrate(conn: &Connection) -> Result<(), InternalError> {
+    let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let latest = SQLITE_MIGRATIONS
+        .last()
+        .map(|migration| migration.version)
+        .unwrap_or_default();
+    if version > latest {
+        return Err(InternalError::NewerSchema(
+            SQLITE_SCHEMA.to_string(),
+            version,
+            latest,
+        ));
+    }
+    for migration in SQLITE_MIGRATIONS {
+        if migration.version <= version {
+            continue;
+        }
+        conn.execute_batch("BEGIN IMMEDIATE")?;
+        let applied = conn
+            .execute_batch(migration.sql)
+            .map_err(Into::into)
+            .and_then(|_| (migration.apply)(conn))
+            .and_then(|_| {
+                conn.execute_batch(&format!(
+                    "PRAGMA user_version = {}; COMMIT;",
+                    migration.version
+                ))
+                .map_err(Into::into)
+            });
+        if let Err(e) = applied {
+            conn.execute_batch("ROLLBACK")?;
+            return Err(InternalError::Migration(
+                SQLITE_SCHEMA.to_string(),
+                migration.version,
+                Box::new(e),
+            ));
+        }
+        info!(
+            "{} schema migration {} applied: {}",
+            SQLITE_SCHEMA, migration.version, migration.description
+        );
+    }
+    Ok(())
+}
+
+/// A read or write transaction on a pooled connection, rolled back if dropped without a
+/// commit.
+pub struct SqliteTxn<'a> {
+    storage: &'a SqliteStorage,
+    conn: Option<Connection>,
+}
+
+impl SqliteTxn<'_> {
+    fn conn(&self) -> &Connection {
+        self.conn
+            .as_ref()
+            .expect("connection is only taken on drop")
+    }
+}
+
+impl WriteTxn for SqliteTxn<'_> {
+    fn commit(self: Box<Self>) -> Result<(), InternalError> {
+        self.conn().execute_batch("COMMIT")?;
+        Ok(())
+    }
+}
+
+impl Drop for SqliteTxn<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            // a connection still in a transaction can't be reused, so it is closed instead
+            if conn.is_autocommit() || conn.execute_batch("ROLLBACK").is_ok() {
+                self.storage.release(conn);
+            }
+        }
+    }
+}
+
+// a uuid column, stored as text
+fn uuid_column(row: &Row, index: usize) -> rusqlite::Result<Uuid> {
+    let uuid = row.get::<_, String>(index)?;
+    Uuid::parse_str(&uuid)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e)))
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, InternalError> {
+    Ok(serde_json::to_string(value)?)
+}
+
+fn from_json<T: DeserializeOwned>(data: &str) -> Result<T, InternalError> {
+    Ok(serde_json::from_str(data)?)
+}
+
+// the decoded `data` of the first row of a query, if any
+fn query_data<T: DeserializeOwned>(
+    conn: &Connection,
+    sql: &str,
+    params: impl Params,
+) -> Result<Option<T>, InternalError> {
+    let data = conn
+        .prepare_cached(sql)?
+        .query_row(params, |row| row.get::<_, String>(0))
+        .optional()?;
+    data.map(|data| from_json(&data)).transpose()
+}
+
+// the decoded `data` of all rows of a query
+fn query_all_data<T: DeserializeOwned>(
+    conn: &Connection,
+    sql: &str,
+    params: impl Params,
+) -> Result<Vec<T>, InternalError> {
+    let mut stmt = conn.prepare_cached(sql)?;
+    let rows = stmt.query_map(params, |row| row.get::<_, String>(0))?;
+    rows.map(|data| from_json(&data?)).collect()
+}
+
+// the rows of a keyset paged query, read a page at a time as the iterator advances. The query
+// takes the last key read and the page size, and returns the key as its first column.
+fn paged<'a, K, R>(
+    conn: &'a Connection,
+    sql: &'static str,
+    start: K,
+    read_row: fn(&Row) -> rusqlite::Result<(K, R)>,
+) -> RowIter<'a, R>
+where
+    K: ToSql + 'a,
+    R: 'a,
+{
+    let mut after = start;
+    let mut page = Vec::new().into_iter();
+    let mut done = false;
+    Box::new(std::iter::from_fn(move || loop {
+        if let Some((key, row)) = page.next() {
+            after = key;
+            return Some(Ok(row));
+        }
+        if done {
+            return None;
+        }
+        let rows = conn.prepare_cached(sql).and_then(|mut stmt| {
+            stmt.query_map(params![after, PAGE_SIZE], read_row)?
+                .collect::<rusqlite::Result<Vec<(K, R)>>>()
+        });
+        match rows {
+            Ok(rows) => {
+                done = (rows.len() as i64) < PAGE_SIZE;
+                page = rows.into_iter();
+            }
+            Err(e) => {
+                done = true;
+                return Some(Err(e.into()));
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SqliteStorage, SQLITE_MIGRATIONS};
+    use crate::audit::types::{AuditAction, AuditFilter};
+    use crate::auth::types::{hash_token, Player};
+    use crate::guess::replay::ReplayedTargets;
+    use crate::guess::types::GuessLogEntry;
+    use crate::storage::Storage;
+    use chrono::{TimeDelta, Utc};
+    use rusqlite::{params, Connection};
+    use tempfile::NamedTempFile;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_migrate_player_names() {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        let alice = Player {
+            uuid: Uuid::new_v4(),
+            name: "alice".to_string(),
+            created: Utc::now() - TimeDelta::days(1),
+            ..Default::default()
+        };
+        let upper_alice = Player {
+            uuid: Uuid::new_v4(),
+            name: "Alice".to_string(),
+            ..Default::default()
+        };
+        // a database of the baseline schema, names were unique as typed
+        let conn = Connection::open(&file).unwrap();
+        conn.execute_batch(SQLITE_MIGRATIONS[0].sql).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        for player in [&alice, &upper_alice] {
+            conn.execute(
+                "INSERT INTO auth_player_name (name, uuid) VALUES (?1, ?2)",
+                params![player.name, player.uuid.to_string()],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO auth_player (uuid, data) VALUES (?1, ?2)",
+                params![
+                    player.uuid.to_string(),
+                    serde_json::to_string(player).unwrap()
+                ],
+            )
+            .unwrap();
+        }
+        drop(conn);
+
+        // the newer player's name collides and is renamed
+        let storage = SqliteStorage::new(file.to_path_buf()).unwrap();
+        let read_txn = storage.begin_read().unwrap();
+        assert_eq!(read_txn.get_player_by_name("ALICE").unwrap(), Some(alice));
+        let renamed = read_txn
+            .get_player_by_uuid(upper_alice.uuid)
+            .unwrap()
+            .unwrap();
+        assert_eq!(renamed.name, "Alice2");
+        assert!(renamed.renamed);
+        assert_eq!(
+            read_txn.get_player_by_name("alice2").unwrap(),
+            Some(renamed)
+        );
+        // the rename is recorded in the audit log
+        let events = read_txn
+            .get_events(&AuditFilter {
+                action: Some(AuditAction::NameChange),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].subject, Some("Alice2".to_string()));
+        assert_eq!(events[0].before, Some("Alice".to_string()));
+    }
+
+    #[test]
+    fn test_migrate_guess_log() {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        let player = Uuid::new_v4();
+        let conn = Connection::open(&file).unwrap();
+        conn.execute_batch(SQLITE_MIGRATIONS[0].sql).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute_batch(
+            "INSERT INTO guess_target (height, nonce) VALUES (100, 42), (200, NULL)",
+        )
+        .unwrap();
+        for (height, nonce) in [(100, 40), (200, 7)] {
+            conn.execute(
+                "INSERT INTO guess_guess (height, nonce, player) VALUES (?1, ?2, ?3)",
+                params![height, nonce, player.to_string()],
+            )
+            .unwrap();
+        }
+        drop(conn);
+
+        // the log starts with events that recreate the existing targets and guesses
+        let storage = SqliteStorage::new(file.to_path_buf()).unwrap();
+        let read_txn = storage.begin_read().unwrap();
+        let log = read_txn
+            .iter_guess_log()
+            .unwrap()
+            .collect::<Result<Vec<GuessLogEntry>, _>>()
+            .unwrap();
+        assert_eq!(log.len(), 5);
+        let replayed = ReplayedTargets::replay(log.iter().map(|entry| &entry.event));
+        for height in [100, 200] {
+            assert_eq!(
+                replayed.nonce(height),
+                read_txn.get_target_nonce(height).unwrap()
+            );
+            assert_eq!(
+                replayed.guesses(height),
+                read_txn.target_guesses(height).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_migrate_invite_codes() {
+        let file = NamedTempFile::new().unwrap().into_temp_path();
+        let conn = Connection::open(&file).unwrap();
+        conn.execute_batch(SQLITE_MIGRATIONS[0].sql).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        // an invite code from before they were hashed, keyed by the plain code
+        let data = serde_json::json!({
+            "code": "plaincode",
+            "uses": 0,
+            "created_by": Uuid::new_v4(),
+            "created": Utc::now(),
+        });
+        conn.execute(
+            "INSERT INTO auth_invite_code (code, data) VALUES (?1, ?2)",
+            params!["plaincode", data.to_string()],
+        )
+        .unwrap();
+        drop(conn);
+
+        // the code keeps working, looked up by its hash
+        let storage = SqliteStorage::new(file.to_path_buf()).unwrap();
+        let mut write_txn = storage.begin_write().unwrap();
+        assert!(write_txn
+            .use_invite_code("plaincode", Utc::now())
+            .unwrap()
+            .is_none());
+        let invite_code = write_txn
+            .use_invite_code(&hash_token("plaincode"), Utc::now())
+            .unwrap()
+            .unwrap();
+        assert_eq!(invite_code.code_hash, hash_token("plaincode"));
+        assert_eq!(invite_code.uses, 1);
+    }
+}
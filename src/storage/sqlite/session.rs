@@ -0,0 +1,79 @@
+use super::{query_all_data, query_data, to_json, SqliteTxn};
+use crate::session_store::{SessionRead, SessionWrite, StorageSessionStore};
+use crate::types::InternalError;
+use rusqlite::params;
+use tower_sessions::cookie::time::OffsetDateTime;
+use tower_sessions::session::{Id, Record};
+
+// expiry dates are stored as unix milliseconds, so they can be compared in queries
+fn expiry_millis(expiry_date: OffsetDateTime) -> i64 {
+    (expiry_date.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+impl SessionRead for SqliteTxn<'_> {
+    fn load_session(&self, id: Id) -> Result<Option<Record>, InternalError> {
+        query_data(
+            self.conn(),
+            "SELECT data FROM session_record WHERE id = ?1",
+            [id.to_string()],
+        )
+    }
+
+    fn user_sessions(&self, user_id: &str) -> Result<Vec<Record>, InternalError> {
+        query_all_data(
+            self.conn(),
+            "SELECT data FROM session_record WHERE user_id = ?1",
+            [user_id],
+        )
+    }
+}
+
+impl SessionWrite for SqliteTxn<'_> {
+    fn session_exists_for_update(&self, id: Id) -> Result<bool, InternalError> {
+        let exists = self
+            .conn()
+            .prepare_cached("SELECT 1 FROM session_record WHERE id = ?1")?
+            .exists([id.to_string()])?;
+        Ok(exists)
+    }
+
+    fn save_session(&mut self, record: &Record) -> Result<(), InternalError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO session_record (id, user_id, expiry, data)
+                VALUES (?1, ?2, ?3, ?4)",
+            params![
+                record.id.to_string(),
+                StorageSessionStore::record_user_id(record),
+                expiry_millis(record.expiry_date),
+                to_json(record)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_session(&mut self, id: Id) -> Result<(), InternalError> {
+        self.conn()
+            .execute("DELETE FROM session_record WHERE id = ?1", [id.to_string()])?;
+        Ok(())
+    }
+
+    fn delete_user_sessions(
+        &mut self,
+        user_id: &str,
+        keep: Option<Id>,
+    ) -> Result<usize, InternalError> {
+        let deleted = self.conn().execute(
+            "DELETE FROM session_record WHERE user_id = ?1 AND id IS NOT ?2",
+            params![user_id, keep.map(|id| id.to_string())],
+        )?;
+        Ok(deleted)
+    }
+
+    fn delete_expired_sessions(&mut self, now: OffsetDateTime) -> Result<usize, InternalError> {
+        let deleted = self.conn().execute(
+            "DELETE FROM session_record WHERE expiry < ?1",
+            [expiry_millis(now)],
+        )?;
+        Ok(deleted)
+    }
+}
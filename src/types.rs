@@ -53,20 +53,69 @@ pub enum InternalError {
     Rinja(#[from] rinja::Error),
     #[error("failed to generate new {0} uuid after {1} tries")]
     NewUuid(String, u8),
+    #[error("failed to find an unused name for {0} after {1} tries")]
+    NewName(String, u8),
+    #[error("player name {0} is taken by another player")]
+    NameTaken(String),
+    #[error("{0} schema version {1} is newer than the latest known version {2}")]
+    NewerSchema(String, u32, u32),
+    #[error("{0} schema migration {1} failed: {2}")]
+    Migration(String, u32, Box<InternalError>),
     #[error(transparent)]
-    RedbTable(#[from] redb::TableError),
+    Decode(#[from] crate::encoding::DecodeError),
+    #[error("table {0} is not copied by the backup")]
+    BackupTable(String),
+    #[error("failed to write data export: {0}")]
+    Export(String),
     #[error(transparent)]
-    RedbTransaction(#[from] redb::TransactionError),
+    Io(#[from] std::io::Error),
     #[error(transparent)]
-    RedbStorage(#[from] redb::StorageError),
+    RedbDatabase(Box<redb::DatabaseError>),
     #[error(transparent)]
-    RedbCommit(#[from] redb::CommitError),
+    RedbTable(Box<redb::TableError>),
+    #[error(transparent)]
+    RedbTransaction(Box<redb::TransactionError>),
+    #[error(transparent)]
+    RedbStorage(Box<redb::StorageError>),
+    #[error(transparent)]
+    RedbCommit(Box<redb::CommitError>),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Postgres(#[from] postgres::Error),
+    #[error(transparent)]
+    Tls(#[from] native_tls::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
     #[error(transparent)]
     Join(#[from] tokio::task::JoinError),
     #[error(transparent)]
     Reqwest(#[from] reqwest::Error),
+    #[error(transparent)]
+    Ldap(#[from] ldap3::LdapError),
+    #[error(transparent)]
+    SessionStore(#[from] tower_sessions::session_store::Error),
 }
 
+// the redb errors are boxed, they are several times larger than the other variants
+macro_rules! from_boxed {
+    ($($variant:ident($error:ty)),* $(,)?) => {
+        $(impl From<$error> for InternalError {
+            fn from(e: $error) -> Self {
+                InternalError::$variant(Box::new(e))
+            }
+        })*
+    };
+}
+
+from_boxed!(
+    RedbDatabase(redb::DatabaseError),
+    RedbTable(redb::TableError),
+    RedbTransaction(redb::TransactionError),
+    RedbStorage(redb::StorageError),
+    RedbCommit(redb::CommitError),
+);
+
 impl IntoResponse for InternalError {
     fn into_response(self) -> Response {
         error!("{}", self);